@@ -0,0 +1,44 @@
+use std::error::Error;
+
+use async_trait::async_trait;
+use geo::Rect;
+
+use crate::{
+    droneguide::{fetch_geozones, DroneguideUrls},
+    geozone_cache::GeozoneCache,
+};
+
+use super::{AirspaceProvider, Geozone};
+
+/// Geozones from the Belgian droneguide service, optionally through an
+/// offline cache.
+#[derive(Debug, Clone)]
+pub struct DroneguideProvider {
+    urls: DroneguideUrls,
+    cache: Option<GeozoneCache>,
+}
+
+impl DroneguideProvider {
+    pub fn new(urls: DroneguideUrls) -> Self {
+        DroneguideProvider { urls, cache: None }
+    }
+
+    pub fn with_cache(mut self, cache: GeozoneCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+}
+
+#[async_trait]
+impl AirspaceProvider for DroneguideProvider {
+    fn describe(&self) -> String {
+        format!("droneguide ({})", self.urls.geozone_url)
+    }
+
+    async fn zones(&self, bbox: Rect<f64>) -> Result<Vec<Geozone>, Box<dyn Error + Send + Sync>> {
+        match &self.cache {
+            Some(cache) => cache.fetch_geozones(&self.urls, bbox).await,
+            None => Ok(fetch_geozones(&self.urls, bbox).await?.geozones),
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use std::{error::Error, path::PathBuf};
+
+use async_trait::async_trait;
+use geo::{Geometry, MultiPolygon, Rect};
+use geojson::{FeatureCollection, GeoJson};
+use serde::Deserialize;
+
+use crate::geozone_schedule::Activation;
+
+use super::{AMSLHeightMeters, AirspaceProvider, Geozone};
+
+/// Properties expected on every feature. Altitudes are in meters AMSL.
+#[derive(Deserialize, Debug)]
+struct ZoneProperties {
+    name: String,
+    category: String,
+    #[serde(default)]
+    lower: f64,
+    #[serde(default = "unlimited")]
+    upper: f64,
+    #[serde(default)]
+    conditions: Vec<String>,
+    #[serde(default)]
+    activation: Activation,
+}
+
+fn unlimited() -> f64 {
+    f64::INFINITY
+}
+
+/// Parse a GeoJSON FeatureCollection of (Multi)Polygon features in WGS84.
+pub fn parse_geojson_zones(text: &str) -> Result<Vec<Geozone>, Box<dyn Error + Send + Sync>> {
+    let feature_collection = FeatureCollection::try_from(text.parse::<GeoJson>()?)?;
+    feature_collection
+        .features
+        .into_iter()
+        .enumerate()
+        .map(|(index, feature)| -> Result<Geozone, Box<dyn Error + Send + Sync>> {
+            let invalid = |reason: String| format!("Invalid airspace feature {}: {}", index, reason);
+            let properties = feature.properties.ok_or_else(|| invalid("no properties".to_owned()))?;
+            let properties: ZoneProperties = serde_json::from_value(properties.into())
+                .map_err(|err| invalid(err.to_string()))?;
+            let geometry = feature.geometry.ok_or_else(|| invalid("no geometry".to_owned()))?;
+            let geometry = match Geometry::try_from(geometry.value).map_err(|err| invalid(err.to_string()))? {
+                Geometry::MultiPolygon(multi_polygon) => multi_polygon,
+                Geometry::Polygon(polygon) => MultiPolygon(vec![polygon]),
+                _ => return Err(invalid("expected Polygon or MultiPolygon".to_owned()).into()),
+            };
+            Ok(Geozone {
+                name: properties.name,
+                category: properties.category.to_lowercase(),
+                lower: properties.lower.floor() as AMSLHeightMeters,
+                upper: properties.upper.ceil() as AMSLHeightMeters,
+                conditions: properties.conditions,
+                activation: properties.activation,
+                geometry,
+            })
+        })
+        .collect()
+}
+
+/// Zones from a local GeoJSON file, see `parse_geojson_zones`.
+#[derive(Debug, Clone)]
+pub struct GeoJsonProvider {
+    path: PathBuf,
+}
+
+impl GeoJsonProvider {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        GeoJsonProvider { path: path.into() }
+    }
+}
+
+#[async_trait]
+impl AirspaceProvider for GeoJsonProvider {
+    fn describe(&self) -> String {
+        format!("GeoJSON file {}", self.path.display())
+    }
+
+    async fn zones(&self, bbox: Rect<f64>) -> Result<Vec<Geozone>, Box<dyn Error + Send + Sync>> {
+        let text = tokio::fs::read_to_string(&self.path).await?;
+        let mut zones = parse_geojson_zones(&text)?;
+        zones.retain(|zone| zone.intersects_bbox(&bbox));
+        return Ok(zones);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use super::parse_geojson_zones;
+
+    #[test]
+    fn parse_geojson_zones__defaults() {
+        let zones = parse_geojson_zones(
+            r#"{ "type": "FeatureCollection", "features": [
+                { "type": "Feature",
+                  "geometry": { "type": "Polygon", "coordinates": [[[4.4, 50.9], [4.5, 50.9], [4.5, 51.0], [4.4, 50.9]]] },
+                  "properties": { "name": "Event", "category": "Temporary", "upper": 120.5 } }
+            ] }"#,
+        )
+        .unwrap();
+        assert_eq!(zones.len(), 1);
+        assert_eq!(zones[0].category, "temporary");
+        assert_eq!((zones[0].lower, zones[0].upper), (0, 121));
+        assert!(zones[0].activation.is_permanent());
+    }
+
+    #[test]
+    fn parse_geojson_zones__unlimited_upper() {
+        let zones = parse_geojson_zones(
+            r#"{ "type": "FeatureCollection", "features": [
+                { "type": "Feature",
+                  "geometry": { "type": "Polygon", "coordinates": [[[4.4, 50.9], [4.5, 50.9], [4.5, 51.0], [4.4, 50.9]]] },
+                  "properties": { "name": "Prison", "category": "P", "lower": 0 } }
+            ] }"#,
+        )
+        .unwrap();
+        assert!(zones[0].overlaps_altitude(10_000));
+    }
+
+    #[test]
+    fn parse_geojson_zones__rejects_points() {
+        let result = parse_geojson_zones(
+            r#"{ "type": "FeatureCollection", "features": [
+                { "type": "Feature",
+                  "geometry": { "type": "Point", "coordinates": [4.4, 50.9] },
+                  "properties": { "name": "Point", "category": "P" } }
+            ] }"#,
+        );
+        assert!(result.is_err());
+    }
+}
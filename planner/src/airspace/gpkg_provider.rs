@@ -0,0 +1,147 @@
+//! Airspace zones from a features layer of a GeoPackage, like the restricted
+//! airspace exported from droneguide.be
+
+use std::{error::Error, path::PathBuf};
+
+use async_trait::async_trait;
+use geo::Rect;
+use proj::Transform;
+use serde_json::Value;
+
+use crate::{
+    crs::{with_proj, ETRS_CRS, WSG_CRS},
+    geo_io::{Attributes, FeatureSource, GpkgSource, SourceFeature},
+};
+
+use super::{AMSLHeightMeters, AirspaceProvider, Geozone};
+
+/// Category of zones without a `category` attribute
+const DEFAULT_CATEGORY: &str = "restricted";
+
+fn number_attribute(attributes: &Attributes, name: &str) -> Option<f64> {
+    match attributes.get(name)? {
+        Value::Number(number) => number.as_f64(),
+        Value::String(text) => text.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+fn text_attribute(attributes: &Attributes, name: &str) -> Option<String> {
+    match attributes.get(name)? {
+        Value::String(text) => Some(text.clone()),
+        Value::Number(number) => Some(number.to_string()),
+        _ => None,
+    }
+}
+
+/// Zone for a feature of the layer `layer`, in WGS84. The optional
+/// attributes `name`, `category`, `lower` and `upper` (meters AMSL) are used
+/// when present; zones without them span all altitudes.
+fn feature_to_geozone(layer: &str, index: usize, feature: SourceFeature) -> Geozone {
+    let attributes = &feature.attributes;
+    let lower = number_attribute(attributes, "lower").unwrap_or(0.0);
+    let upper = number_attribute(attributes, "upper").unwrap_or(f64::INFINITY);
+    Geozone {
+        name: text_attribute(attributes, "name").unwrap_or_else(|| format!("{} {}", layer, index)),
+        category: text_attribute(attributes, "category")
+            .unwrap_or_else(|| DEFAULT_CATEGORY.to_owned())
+            .to_lowercase(),
+        lower: lower.floor() as AMSLHeightMeters,
+        upper: upper.ceil() as AMSLHeightMeters,
+        conditions: Vec::new(),
+        activation: Default::default(),
+        geometry: feature.geometry,
+    }
+}
+
+/// Zones from a features layer of a GeoPackage, see `feature_to_geozone`.
+/// Without a layer, the GeoPackage must have a single features layer.
+#[derive(Debug, Clone)]
+pub struct GpkgProvider {
+    path: PathBuf,
+    layer: Option<String>,
+}
+
+impl GpkgProvider {
+    pub fn new(path: impl Into<PathBuf>, layer: Option<String>) -> Self {
+        GpkgProvider { path: path.into(), layer }
+    }
+}
+
+#[async_trait]
+impl AirspaceProvider for GpkgProvider {
+    fn describe(&self) -> String {
+        match &self.layer {
+            Some(layer) => format!("GeoPackage {} layer {}", self.path.display(), layer),
+            None => format!("GeoPackage {}", self.path.display()),
+        }
+    }
+
+    async fn zones(&self, bbox: Rect<f64>) -> Result<Vec<Geozone>, Box<dyn Error + Send + Sync>> {
+        let source = GpkgSource::new(&self.path, self.layer.clone());
+        let source_crs = source.crs().await?.unwrap_or_else(|| ETRS_CRS.to_owned());
+        let mut features = source.features(None).await?;
+        if source_crs != WSG_CRS {
+            with_proj(&source_crs, WSG_CRS, |proj| {
+                features.iter_mut().try_for_each(|feature| feature.geometry.transform(proj))
+            })??;
+        }
+        let layer = self.layer.as_deref().unwrap_or("zone");
+        let mut zones = features
+            .into_iter()
+            .enumerate()
+            .map(|(index, feature)| feature_to_geozone(layer, index, feature))
+            .collect::<Vec<_>>();
+        zones.retain(|zone| zone.intersects_bbox(&bbox));
+        return Ok(zones);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use geo::{coord, MultiPolygon, Rect};
+    use serde_json::Value;
+
+    use crate::{
+        airspace::AirspaceProvider,
+        crs::InternalCrs,
+        geo_io::{write_gpkg_features, Attributes, SourceFeature},
+        test_util::{local_square, temp_path},
+    };
+
+    use super::GpkgProvider;
+
+    #[tokio::test]
+    async fn gpkg_provider__attributes_and_defaults() {
+        let square = |x: f64, y: f64| MultiPolygon(vec![local_square(x, y, 100.0)]);
+        let mut attributes = Attributes::new();
+        attributes.insert("name".to_owned(), Value::from("Prison"));
+        attributes.insert("category".to_owned(), Value::from("P"));
+        attributes.insert("upper".to_owned(), Value::from(120.5));
+        let features = vec![
+            SourceFeature { geometry: square(0.0, 0.0), attributes },
+            SourceFeature { geometry: square(500.0, 0.0), attributes: Attributes::new() },
+        ];
+        let path = temp_path("gpkg-provider.gpkg");
+        write_gpkg_features(&path, "restricted-airspace", &features, &InternalCrs::EtrsLaea).await.unwrap();
+
+        let provider = GpkgProvider::new(&path, Some("restricted-airspace".to_owned()));
+        let bbox = Rect::new(coord! { x: 2.0, y: 49.0 }, coord! { x: 7.0, y: 53.0 });
+        let zones = provider.zones(bbox).await.unwrap();
+        assert_eq!(zones.len(), 2);
+        assert_eq!(zones[0].name, "Prison");
+        assert_eq!(zones[0].category, "p");
+        assert_eq!((zones[0].lower, zones[0].upper), (0, 121));
+        assert_eq!(zones[1].name, "restricted-airspace 1");
+        assert_eq!(zones[1].category, "restricted");
+        assert!(zones[1].overlaps_altitude(10_000));
+        // In WGS84
+        let first_coord = zones[0].geometry.0[0].exterior().0[0];
+        assert!(bbox.min().x < first_coord.x && first_coord.x < bbox.max().x);
+
+        let far_away = Rect::new(coord! { x: 10.0, y: 40.0 }, coord! { x: 11.0, y: 41.0 });
+        assert!(provider.zones(far_away).await.unwrap().is_empty());
+    }
+}
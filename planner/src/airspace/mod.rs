@@ -0,0 +1,151 @@
+//! Restricted airspace from interchangeable sources
+//!
+//! Every source is normalized to `Geozone`s: a (multi)polygon with an
+//! altitude band, a category and optional conditions and activation schedule.
+
+mod droneguide_provider;
+mod geojson_provider;
+mod gpkg_provider;
+mod openair;
+
+use std::{error::Error, path::Path};
+
+use async_trait::async_trait;
+use geo::{prelude::BoundingRect, MultiPolygon, Rect};
+use serde::{Deserialize, Serialize};
+
+use crate::geozone_schedule::Activation;
+
+pub use self::{
+    droneguide_provider::DroneguideProvider, geojson_provider::GeoJsonProvider,
+    gpkg_provider::GpkgProvider, openair::OpenAirProvider,
+};
+
+pub type AMSLHeightMeters = u32;
+
+/// Restricted airspace zone. The geometry is in WGS84 (`WSG_CRS`).
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Geozone {
+    pub name: String,
+    /// Generic category, lowercased. Policy rules are keyed by it (see
+    /// `PolicyRules`).
+    pub category: String,
+    pub lower: AMSLHeightMeters,
+    pub upper: AMSLHeightMeters,
+    /// Free-text conditions for entering the zone
+    pub conditions: Vec<String>,
+    #[serde(default)]
+    pub activation: Activation,
+    pub geometry: MultiPolygon<f64>,
+}
+
+impl Geozone {
+    /// Whether a drone flying at `altitude` would be inside this zone's
+    /// vertical band (bounds inclusive).
+    pub fn overlaps_altitude(&self, altitude: AMSLHeightMeters) -> bool {
+        self.lower <= altitude && altitude <= self.upper
+    }
+
+    /// Whether the zone's bounding box intersects `bbox` (WGS84 degrees).
+    pub fn intersects_bbox(&self, bbox: &Rect<f64>) -> bool {
+        match self.geometry.bounding_rect() {
+            Some(zone_bbox) => {
+                zone_bbox.min().x <= bbox.max().x
+                    && bbox.min().x <= zone_bbox.max().x
+                    && zone_bbox.min().y <= bbox.max().y
+                    && bbox.min().y <= zone_bbox.max().y
+            }
+            None => false,
+        }
+    }
+}
+
+#[async_trait]
+pub trait AirspaceProvider: Send + Sync {
+    /// Human readable description of the source, for logging
+    fn describe(&self) -> String;
+
+    /// All zones that intersect `bbox` (WGS84 degrees).
+    async fn zones(&self, bbox: Rect<f64>) -> Result<Vec<Geozone>, Box<dyn Error + Send + Sync>>;
+}
+
+/// Provider for a local airspace file, chosen by extension: `.txt` or
+/// `.openair` for OpenAir, `.geojson` or `.json` for GeoJSON and `.gpkg` for
+/// a GeoPackage with a single features layer.
+pub fn provider_for_path(
+    path: impl AsRef<Path>,
+) -> Result<Box<dyn AirspaceProvider>, Box<dyn Error + Send + Sync>> {
+    let path = path.as_ref();
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase());
+    match extension.as_deref() {
+        Some("txt" | "openair") => Ok(Box::new(OpenAirProvider::new(path))),
+        Some("geojson" | "json") => Ok(Box::new(GeoJsonProvider::new(path))),
+        Some("gpkg") => Ok(Box::new(GpkgProvider::new(path, None))),
+        _ => Err(format!("Unknown airspace file type: {}", path.display()).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use geo::{coord, MultiPolygon, Rect};
+
+    use super::{provider_for_path, Geozone};
+
+    fn geozone_with_band(lower: u32, upper: u32) -> Geozone {
+        Geozone {
+            name: "test".to_owned(),
+            category: "test".to_owned(),
+            lower,
+            upper,
+            conditions: Vec::new(),
+            activation: Default::default(),
+            geometry: MultiPolygon(vec![Rect::new(coord! { x: 4.4, y: 50.9 }, coord! { x: 4.5, y: 51.0 }).to_polygon()]),
+        }
+    }
+
+    #[test]
+    fn overlaps_altitude__within() {
+        assert!(geozone_with_band(0, 60).overlaps_altitude(40));
+    }
+
+    #[test]
+    fn overlaps_altitude__above() {
+        assert!(!geozone_with_band(0, 60).overlaps_altitude(110));
+    }
+
+    #[test]
+    fn overlaps_altitude__below() {
+        assert!(!geozone_with_band(90, 300).overlaps_altitude(40));
+    }
+
+    #[test]
+    fn overlaps_altitude__on_bound() {
+        assert!(geozone_with_band(0, 60).overlaps_altitude(60));
+        assert!(geozone_with_band(60, 300).overlaps_altitude(60));
+    }
+
+    #[test]
+    fn intersects_bbox__overlapping() {
+        let bbox = Rect::new(coord! { x: 4.45, y: 50.95 }, coord! { x: 4.8, y: 51.1 });
+        assert!(geozone_with_band(0, 60).intersects_bbox(&bbox));
+    }
+
+    #[test]
+    fn intersects_bbox__disjoint() {
+        let bbox = Rect::new(coord! { x: 5.0, y: 50.95 }, coord! { x: 5.1, y: 51.1 });
+        assert!(!geozone_with_band(0, 60).intersects_bbox(&bbox));
+    }
+
+    #[test]
+    fn provider_for_path__by_extension() {
+        assert!(provider_for_path("data/airspace/belgium.txt").is_ok());
+        assert!(provider_for_path("data/airspace/zones.GeoJSON").is_ok());
+        assert!(provider_for_path("data/airspace/zones.gpkg").is_ok());
+        assert!(provider_for_path("data/airspace/zones.kml").is_err());
+    }
+}
@@ -0,0 +1,398 @@
+//! OpenAir airspace files
+//!
+//! Supports the records needed for zone outlines: `AC`, `AN`, `AL`, `AH`,
+//! `DP`, `V X=`, `V D=`, `DA`, `DB` and `DC`. Other records (labels, pen
+//! styles...) are ignored.
+//!
+//! See: http://www.winpilot.com/UsersGuide/UserAirspace.asp
+
+use std::{error::Error, path::PathBuf};
+
+use async_trait::async_trait;
+use derive_more::Display;
+use geo::{Coordinate, LineString, MultiPolygon, Polygon, Rect};
+
+use super::{AMSLHeightMeters, AirspaceProvider, Geozone};
+
+const EARTH_RADIUS_METERS: f64 = 6_371_008.8;
+const METERS_PER_NAUTICAL_MILE: f64 = 1852.0;
+const METERS_PER_FOOT: f64 = 0.3048;
+/// Angle between consecutive vertices when approximating arcs and circles
+const ARC_STEP_DEGREES: f64 = 5.0;
+
+#[derive(Debug, Display)]
+#[display(fmt = "OpenAir line {}: {}", line, message)]
+pub struct OpenAirError {
+    pub line: usize,
+    pub message: String,
+}
+impl Error for OpenAirError {}
+
+/// Zone being read, up to the next `AC` record
+#[derive(Debug)]
+struct PartialZone {
+    class: String,
+    name: Option<String>,
+    lower: f64,
+    upper: f64,
+    vertices: Vec<Coordinate<f64>>,
+}
+
+impl PartialZone {
+    fn into_geozone(self) -> Result<Geozone, String> {
+        let name = self.name.unwrap_or_else(|| "?".to_owned());
+        if self.vertices.len() < 3 {
+            return Err(format!("zone {} has fewer than 3 vertices", name));
+        }
+        Ok(Geozone {
+            name,
+            category: self.class.to_lowercase(),
+            lower: self.lower.floor() as AMSLHeightMeters,
+            upper: self.upper.ceil() as AMSLHeightMeters,
+            conditions: Vec::new(),
+            activation: Default::default(),
+            geometry: MultiPolygon(vec![Polygon::new(LineString(self.vertices), vec![])]),
+        })
+    }
+}
+
+/// Parse all zones in an OpenAir file. Coordinates are WGS84 degrees.
+pub fn parse_openair(text: &str) -> Result<Vec<Geozone>, OpenAirError> {
+    let mut zones = Vec::new();
+    let mut maybe_zone: Option<(usize, PartialZone)> = None;
+    let mut center: Option<Coordinate<f64>> = None;
+    let mut clockwise = true;
+
+    for (line_index, line) in text.lines().enumerate() {
+        let line_number = line_index + 1;
+        let error = |message: String| OpenAirError { line: line_number, message };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('*') {
+            continue;
+        }
+        let (record, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let value = value.trim();
+
+        if record.eq_ignore_ascii_case("AC") {
+            if let Some((start_line, zone)) = maybe_zone.take() {
+                zones.push(zone.into_geozone().map_err(|message| OpenAirError { line: start_line, message })?);
+            }
+            maybe_zone = Some((line_number, PartialZone {
+                class: value.to_owned(),
+                name: None,
+                lower: 0.0,
+                upper: f64::INFINITY,
+                vertices: Vec::new(),
+            }));
+            center = None;
+            clockwise = true;
+            continue;
+        }
+
+        let record = record.to_uppercase();
+        if record == "V" {
+            let (variable, variable_value) = value
+                .split_once('=')
+                .ok_or_else(|| error(format!("invalid variable assignment {:?}", value)))?;
+            match variable.trim().to_uppercase().as_str() {
+                "X" => center = Some(parse_coordinate(variable_value).map_err(error)?),
+                "D" => clockwise = variable_value.trim() != "-",
+                _ => {}
+            }
+            continue;
+        }
+
+        let zone = match &mut maybe_zone {
+            Some((_, zone)) => zone,
+            // Records before the first `AC` do not belong to a zone
+            None => continue,
+        };
+        match record.as_str() {
+            "AN" => zone.name = Some(value.to_owned()),
+            "AL" => zone.lower = parse_altitude(value).map_err(error)?,
+            "AH" => zone.upper = parse_altitude(value).map_err(error)?,
+            "DP" => zone.vertices.push(parse_coordinate(value).map_err(error)?),
+            "DC" => {
+                let center = center.ok_or_else(|| error("DC without center (V X=)".to_owned()))?;
+                let radius = parse_number(value).map_err(error)? * METERS_PER_NAUTICAL_MILE;
+                zone.vertices.extend(arc(center, radius, 0.0, 360.0, true));
+            }
+            "DA" => {
+                let center = center.ok_or_else(|| error("DA without center (V X=)".to_owned()))?;
+                let parts = value.split(',').map(parse_number).collect::<Result<Vec<_>, _>>().map_err(error)?;
+                if let [radius, start_bearing, end_bearing] = parts[..] {
+                    zone.vertices.extend(arc(
+                        center, radius * METERS_PER_NAUTICAL_MILE, start_bearing, end_bearing, clockwise,
+                    ));
+                } else {
+                    return Err(error(format!("expected radius, start and end angle: {:?}", value)));
+                }
+            }
+            "DB" => {
+                let center = center.ok_or_else(|| error("DB without center (V X=)".to_owned()))?;
+                let (start, end) = value
+                    .split_once(',')
+                    .ok_or_else(|| error(format!("expected two coordinates: {:?}", value)))?;
+                let start = parse_coordinate(start).map_err(error)?;
+                let end = parse_coordinate(end).map_err(error)?;
+                let radius = distance(center, start);
+                zone.vertices.extend(arc(
+                    center, radius, bearing(center, start), bearing(center, end), clockwise,
+                ));
+            }
+            _ => {}
+        }
+    }
+    if let Some((start_line, zone)) = maybe_zone {
+        zones.push(zone.into_geozone().map_err(|message| OpenAirError { line: start_line, message })?);
+    }
+    return Ok(zones);
+}
+
+fn parse_number(value: &str) -> Result<f64, String> {
+    value.trim().parse().map_err(|_| format!("invalid number {:?}", value))
+}
+
+/// Parse an altitude to meters AMSL. Values without unit are in feet, flight
+/// levels use the standard atmosphere. Heights above ground are treated as
+/// AMSL as terrain elevation is not known, which underestimates them.
+fn parse_altitude(value: &str) -> Result<f64, String> {
+    let normalized = value.trim().to_uppercase();
+    if ["SFC", "GND", "0"].contains(&normalized.as_str()) {
+        return Ok(0.0);
+    }
+    if normalized.starts_with("UNL") {
+        return Ok(f64::INFINITY);
+    }
+    if let Some(flight_level) = normalized.strip_prefix("FL") {
+        return Ok(parse_number(flight_level)? * 100.0 * METERS_PER_FOOT);
+    }
+
+    let number_end = normalized
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(normalized.len());
+    let (number, rest) = normalized.split_at(number_end);
+    let number = parse_number(number).map_err(|_| format!("invalid altitude {:?}", value))?;
+    let unit_meters = match rest.trim_start().split_whitespace().next() {
+        Some("M") => 1.0,
+        Some("FT" | "F" | "MSL" | "AMSL" | "AGL" | "GND" | "SFC") | None => METERS_PER_FOOT,
+        _ => return Err(format!("invalid altitude {:?}", value)),
+    };
+    return Ok(number * unit_meters);
+}
+
+/// Parse a coordinate such as `50:51:30 N 004:29:00 E`, `50:51.5N 4:29E` or
+/// `50.858 N 4.483 E`.
+fn parse_coordinate(value: &str) -> Result<Coordinate<f64>, String> {
+    let invalid = || format!("invalid coordinate {:?}", value);
+    let normalized = value.trim().to_uppercase();
+    let lat_end = normalized.find(|c| c == 'N' || c == 'S').ok_or_else(invalid)?;
+    let (lat, rest) = normalized.split_at(lat_end);
+    let (lat_hemisphere, lon) = rest.split_at(1);
+    let lon = lon.trim();
+    let lon_end = lon.find(|c| c == 'E' || c == 'W').ok_or_else(invalid)?;
+    let (lon, lon_hemisphere) = lon.split_at(lon_end);
+
+    let lat = parse_sexagesimal(lat).ok_or_else(invalid)?;
+    let lon = parse_sexagesimal(lon).ok_or_else(invalid)?;
+    return Ok(Coordinate {
+        x: if lon_hemisphere.starts_with('W') { -lon } else { lon },
+        y: if lat_hemisphere == "S" { -lat } else { lat },
+    });
+}
+
+/// `DD:MM:SS`, `DD:MM` or `DD` (every part may have decimals) to degrees
+fn parse_sexagesimal(value: &str) -> Option<f64> {
+    let parts = value
+        .trim()
+        .split(':')
+        .map(|part| part.trim().parse::<f64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    if parts.is_empty() || parts.len() > 3 {
+        return None;
+    }
+    return Some(parts.iter().zip([1.0, 60.0, 3600.0]).map(|(part, divisor)| part / divisor).sum());
+}
+
+/// Great circle distance in meters
+fn distance(from: Coordinate<f64>, to: Coordinate<f64>) -> f64 {
+    let (lat1, lat2) = (from.y.to_radians(), to.y.to_radians());
+    let d_lat = lat2 - lat1;
+    let d_lon = (to.x - from.x).to_radians();
+    let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_METERS * a.sqrt().asin()
+}
+
+/// Initial bearing in degrees clockwise from north
+fn bearing(from: Coordinate<f64>, to: Coordinate<f64>) -> f64 {
+    let (lat1, lat2) = (from.y.to_radians(), to.y.to_radians());
+    let d_lon = (to.x - from.x).to_radians();
+    let y = d_lon.sin() * lat2.cos();
+    let x = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * d_lon.cos();
+    y.atan2(x).to_degrees().rem_euclid(360.0)
+}
+
+fn destination(from: Coordinate<f64>, distance: f64, bearing: f64) -> Coordinate<f64> {
+    let angular_distance = distance / EARTH_RADIUS_METERS;
+    let bearing = bearing.to_radians();
+    let lat1 = from.y.to_radians();
+    let lon1 = from.x.to_radians();
+    let lat2 = (lat1.sin() * angular_distance.cos()
+        + lat1.cos() * angular_distance.sin() * bearing.cos())
+    .asin();
+    let lon2 = lon1
+        + (bearing.sin() * angular_distance.sin() * lat1.cos())
+            .atan2(angular_distance.cos() - lat1.sin() * lat2.sin());
+    Coordinate { x: lon2.to_degrees(), y: lat2.to_degrees() }
+}
+
+/// Vertices of the arc around `center` from `start_bearing` to `end_bearing`
+/// (both included).
+fn arc(
+    center: Coordinate<f64>,
+    radius: f64,
+    start_bearing: f64,
+    end_bearing: f64,
+    clockwise: bool,
+) -> Vec<Coordinate<f64>> {
+    let mut sweep = if clockwise {
+        (end_bearing - start_bearing).rem_euclid(360.0)
+    } else {
+        -(start_bearing - end_bearing).rem_euclid(360.0)
+    };
+    if sweep == 0.0 {
+        // Full circle
+        sweep = if clockwise { 360.0 } else { -360.0 };
+    }
+    let steps = ((sweep.abs() / ARC_STEP_DEGREES).ceil() as usize).max(1);
+    (0..=steps)
+        .map(|step| {
+            let bearing = start_bearing + sweep * step as f64 / steps as f64;
+            destination(center, radius, bearing)
+        })
+        .collect()
+}
+
+/// Zones from a local OpenAir file, see `parse_openair`.
+#[derive(Debug, Clone)]
+pub struct OpenAirProvider {
+    path: PathBuf,
+}
+
+impl OpenAirProvider {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        OpenAirProvider { path: path.into() }
+    }
+}
+
+#[async_trait]
+impl AirspaceProvider for OpenAirProvider {
+    fn describe(&self) -> String {
+        format!("OpenAir file {}", self.path.display())
+    }
+
+    async fn zones(&self, bbox: Rect<f64>) -> Result<Vec<Geozone>, Box<dyn Error + Send + Sync>> {
+        // OpenAir files are often Latin-1 encoded; names may be mangled but
+        // the outline is plain ASCII.
+        let bytes = tokio::fs::read(&self.path).await?;
+        let mut zones = parse_openair(&String::from_utf8_lossy(&bytes))?;
+        zones.retain(|zone| zone.intersects_bbox(&bbox));
+        return Ok(zones);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use approx::assert_relative_eq;
+    use geo::Coordinate;
+
+    use super::{distance, parse_altitude, parse_coordinate, parse_openair};
+
+    const OPENAIR: &str = "
+* Belgian test airspace
+AC R
+AN EBR57 EEPOEL
+AL GND
+AH 2000ft AMSL
+DP 50:54:00 N 004:24:00 E
+DP 50:54:00 N 004:30:00 E
+DP 51:00:00 N 004:30:00 E
+DP 51:00:00 N 004:24:00 E
+
+AC CTR
+AN EBBR CTR
+AL SFC
+AH FL45
+V X=50:54:07 N 004:29:02 E
+DC 5
+";
+
+    #[test]
+    fn parse_openair__polygon_and_circle() {
+        let zones = parse_openair(OPENAIR).unwrap();
+        assert_eq!(zones.len(), 2);
+
+        let restricted = &zones[0];
+        assert_eq!(restricted.name, "EBR57 EEPOEL");
+        assert_eq!(restricted.category, "r");
+        assert_eq!((restricted.lower, restricted.upper), (0, 610));
+        assert_eq!(restricted.geometry.0[0].exterior().0.len(), 5);
+
+        let ctr = &zones[1];
+        assert_eq!(ctr.category, "ctr");
+        assert_eq!(ctr.upper, 1372);
+        let center = parse_coordinate("50:54:07 N 004:29:02 E").unwrap();
+        for &vertex in ctr.geometry.0[0].exterior().0.iter() {
+            assert_relative_eq!(distance(center, vertex), 5.0 * 1852.0, epsilon = 1e-3);
+        }
+    }
+
+    #[test]
+    fn parse_openair__too_few_vertices() {
+        let result = parse_openair("AC P\nAN Prison\nDP 50:54:00 N 004:24:00 E\n");
+        assert_eq!(result.unwrap_err().line, 1);
+    }
+
+    #[test]
+    fn parse_openair__clockwise_arc() {
+        let zones = parse_openair("
+AC Q
+AN Arc
+V X=51:00:00 N 004:00:00 E
+DP 51:00:00 N 004:00:00 E
+DA 1,0,90
+").unwrap();
+        let vertices = &zones[0].geometry.0[0].exterior().0;
+        // Center, 18 arc steps of 5 degrees (19 vertices), closing vertex
+        assert_eq!(vertices.len(), 21);
+        // Arc starts north of the center and ends east of it
+        assert!(vertices[1].y > 51.0);
+        assert!(vertices[19].x > 4.0);
+    }
+
+    #[test]
+    fn parse_altitude__units() {
+        assert_relative_eq!(parse_altitude("1000ft AMSL").unwrap(), 304.8);
+        assert_relative_eq!(parse_altitude("1000 FT MSL").unwrap(), 304.8);
+        assert_relative_eq!(parse_altitude("1000").unwrap(), 304.8);
+        assert_relative_eq!(parse_altitude("300m").unwrap(), 300.0);
+        assert_relative_eq!(parse_altitude("300 M AMSL").unwrap(), 300.0);
+        assert_relative_eq!(parse_altitude("FL 65").unwrap(), 1981.2, epsilon = 1e-9);
+        assert_eq!(parse_altitude("UNL").unwrap(), f64::INFINITY);
+        assert!(parse_altitude("high").is_err());
+    }
+
+    #[test]
+    fn parse_coordinate__formats() {
+        let expected = Coordinate { x: 4.5, y: 50.8583333333 };
+        for value in ["50:51:30 N 004:30:00 E", "50:51.5N 4:30E", "50.8583333333 N 4.5 E"] {
+            let coord = parse_coordinate(value).unwrap();
+            assert_relative_eq!(coord.x, expected.x, epsilon = 1e-9);
+            assert_relative_eq!(coord.y, expected.y, epsilon = 1e-9);
+        }
+        let west = parse_coordinate("50:00:00 S 001:00:00 W").unwrap();
+        assert_eq!((west.x, west.y), (-1.0, -50.0));
+    }
+}
@@ -8,6 +8,7 @@ use std::{
 
 use geo::{prelude::EuclideanDistance, Coordinate};
 use ordered_float::OrderedFloat;
+use serde::{Deserialize, Serialize};
 
 pub trait AngleTo {
     /// Returns the angle to `other` in radians in the range [0 .. TAU] with
@@ -76,7 +77,7 @@ pub fn cmp_distance(
     return angle_a.total_cmp(&angle_b);
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct OrderedCoordinate(pub Coordinate<f64>);
 
 impl Hash for OrderedCoordinate {
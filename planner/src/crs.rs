@@ -1,36 +1,339 @@
 //! Coordinate Reference System (CRS) transformation
 
-use proj::Proj;
+use std::{cell::RefCell, collections::HashMap, f64::consts::PI, rc::Rc};
 
-/// CRS for internal calculations (like buffering geometries or calculating
-/// distances)  
+use geo::{prelude::GeodesicLength, Coordinate, LineString, Rect};
+use proj::{Proj, ProjCreateError, ProjError, Transform};
+use serde::{Deserialize, Serialize};
+
+/// Default CRS for internal calculations (like buffering geometries or
+/// calculating distances), and the CRS of the preprocessed layers
 ///
-/// - 3035:  
+/// - 3035:
 ///   - name: ETRS89-extended / LAEA Europe
-///   - unit: metre  
-///   - area of use: Europe  
+///   - unit: metre
+///   - area of use: Europe
 ///
 /// https://epsg.io/3035
 pub static ETRS_CRS: &str = "EPSG:3035";
 
 /// Good old fashioned web mercator WSG84 for external use (e.g. sending to UI
-/// or autopilot)  
+/// or autopilot)
 ///
 /// https://epsg.io/4326
 pub static WSG_CRS: &str = "EPSG:4326";
 
-/// Projection from internal to external representation.
-pub fn create_to_ext_proj() -> Proj {
-    // proj::Proj is `!Send`, so we can neither store it in a global static
-    // once_cell, nor pass it around in async functions (`!Send` values cannot
-    // be used between `.await`'s for spawned tasks).
-    // As such, we have to create a fresh instance for each projection job :(
-    // It'd probably be a good idea to restrict the area of use
-    Proj::new_known_crs(ETRS_CRS, WSG_CRS, None).unwrap()
+/// Area of use of `ETRS_CRS` (WGS84 degrees: west, south, east, north)
+const ETRS_AREA_OF_USE: [f64; 4] = [-35.58, 24.6, 44.83, 84.73];
+
+/// UTM is only defined between these latitudes, the poles use UPS
+const UTM_LATITUDES: [f64; 2] = [-80.0, 84.0];
+
+/// Max distance (degrees of longitude) of an area from the central meridian
+/// of its UTM zone. Zones are 6 degrees wide, a little spill-over into the
+/// neighbouring zones keeps the scale error below 0.2%.
+const UTM_MAX_MERIDIAN_DISTANCE: f64 = 4.5;
+
+/// `InternalCrs::max_scale_factor` samples the scale at the points of a grid
+/// of this many cells per side over the area, in this many directions, along
+/// lines of this length (metres)
+const SCALE_SAMPLE_GRID_SIZE: usize = 4;
+const SCALE_SAMPLE_DIRECTIONS: usize = 8;
+const SCALE_SAMPLE_LENGTH: f64 = 100.0;
+
+const WGS84_GEOGCS_WKT: &str = "GEOGCS[\"WGS 84\",DATUM[\"WGS_1984\",SPHEROID[\"WGS 84\",6378137,298.257223563]],PRIMEM[\"Greenwich\",0],UNIT[\"degree\",0.0174532925199433]]";
+
+/// Metric CRS the planner calculates in, chosen per dataset so distances and
+/// areas stay accurate wherever it plans.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum InternalCrs {
+    /// `ETRS_CRS`, for areas in Europe
+    EtrsLaea,
+    /// WGS 84 / UTM zone `zone` (1 to 60), EPSG:326xx in the northern and
+    /// EPSG:327xx in the southern hemisphere
+    Utm { zone: u8, north: bool },
+    /// Lambert azimuthal equal-area on the WGS 84 ellipsoid centred on the
+    /// area, for areas UTM does not cover well (polar, or spanning zones)
+    LocalLaea { center: Coordinate<f64> },
+}
+
+impl Default for InternalCrs {
+    fn default() -> Self {
+        InternalCrs::EtrsLaea
+    }
+}
+
+impl InternalCrs {
+    /// CRS for the area `bbox` (WGS84 degrees): `ETRS_CRS` within Europe,
+    /// the UTM zone of the area if it fits in one, a local azimuthal
+    /// projection otherwise.
+    pub fn for_area(bbox: Rect<f64>) -> Self {
+        let [west, south, east, north] = ETRS_AREA_OF_USE;
+        if bbox.min().x >= west && bbox.max().x <= east && bbox.min().y >= south && bbox.max().y <= north {
+            return InternalCrs::EtrsLaea;
+        }
+        let center = bbox.center();
+        let zone = (((center.x + 180.0) / 6.0).floor() as i32).clamp(0, 59) + 1;
+        let central_meridian = zone as f64 * 6.0 - 183.0;
+        let [min_latitude, max_latitude] = UTM_LATITUDES;
+        let fits_zone = bbox.min().x >= central_meridian - UTM_MAX_MERIDIAN_DISTANCE
+            && bbox.max().x <= central_meridian + UTM_MAX_MERIDIAN_DISTANCE
+            && bbox.min().y >= min_latitude
+            && bbox.max().y <= max_latitude;
+        if fits_zone {
+            return InternalCrs::Utm { zone: zone as u8, north: center.y >= 0.0 };
+        }
+        // Rounded so the same area always gets the same definition
+        let round = |degrees: f64| (degrees * 1000.0).round() / 1000.0;
+        return InternalCrs::LocalLaea {
+            center: Coordinate { x: round(center.x), y: round(center.y) },
+        };
+    }
+
+    /// EPSG code, `None` for the local projections
+    pub fn epsg_code(&self) -> Option<i32> {
+        match self {
+            InternalCrs::EtrsLaea => Some(3035),
+            InternalCrs::Utm { zone, north: true } => Some(32600 + *zone as i32),
+            InternalCrs::Utm { zone, north: false } => Some(32700 + *zone as i32),
+            InternalCrs::LocalLaea { .. } => None,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            InternalCrs::EtrsLaea => "ETRS89-extended / LAEA Europe".to_owned(),
+            InternalCrs::Utm { zone, north } => {
+                format!("WGS 84 / UTM zone {}{}", zone, if *north { "N" } else { "S" })
+            }
+            InternalCrs::LocalLaea { center } => {
+                format!("WGS 84 / LAEA {:.3} {:.3}", center.y, center.x)
+            }
+        }
+    }
+
+    /// Definition to create a `proj::Proj` with
+    pub fn definition(&self) -> String {
+        match (self, self.epsg_code()) {
+            (_, Some(code)) => format!("EPSG:{}", code),
+            (InternalCrs::LocalLaea { center }, None) => format!(
+                "+proj=laea +lat_0={} +lon_0={} +x_0=0 +y_0=0 +datum=WGS84 +units=m +no_defs",
+                center.y, center.x
+            ),
+            _ => unreachable!("Only the local projections lack an EPSG code"),
+        }
+    }
+
+    /// OGC WKT (version 1) definition, as GeoPackages store it
+    pub fn wkt(&self) -> String {
+        let projected_wkt = |name: &str, projection: &str, parameters: &[(&str, f64)]| {
+            let parameters = parameters
+                .iter()
+                .map(|(name, value)| format!(",PARAMETER[\"{}\",{}]", name, value))
+                .collect::<String>();
+            format!(
+                "PROJCS[\"{}\",{},PROJECTION[\"{}\"]{},UNIT[\"metre\",1]]",
+                name, WGS84_GEOGCS_WKT, projection, parameters
+            )
+        };
+        match self {
+            InternalCrs::EtrsLaea => "PROJCS[\"ETRS89-extended / LAEA Europe\",GEOGCS[\"ETRS89\",DATUM[\"European_Terrestrial_Reference_System_1989\",SPHEROID[\"GRS 1980\",6378137,298.257222101]],PRIMEM[\"Greenwich\",0],UNIT[\"degree\",0.0174532925199433]],PROJECTION[\"Lambert_Azimuthal_Equal_Area\"],PARAMETER[\"latitude_of_center\",52],PARAMETER[\"longitude_of_center\",10],PARAMETER[\"false_easting\",4321000],PARAMETER[\"false_northing\",3210000],UNIT[\"metre\",1]]".to_owned(),
+            InternalCrs::Utm { zone, north } => projected_wkt(&self.name(), "Transverse_Mercator", &[
+                ("latitude_of_origin", 0.0),
+                ("central_meridian", *zone as f64 * 6.0 - 183.0),
+                ("scale_factor", 0.9996),
+                ("false_easting", 500000.0),
+                ("false_northing", if *north { 0.0 } else { 10000000.0 }),
+            ]),
+            InternalCrs::LocalLaea { center } => projected_wkt(&self.name(), "Lambert_Azimuthal_Equal_Area", &[
+                ("latitude_of_center", center.y),
+                ("longitude_of_center", center.x),
+                ("false_easting", 0.0),
+                ("false_northing", 0.0),
+            ]),
+        }
+    }
+
+    /// Largest ratio of projected to geodesic length within `area` (in this
+    /// CRS), sampled in several directions on a grid over the area. A
+    /// geodesic distance times this is at least as long as its projection
+    /// anywhere in the area, up to the sampling error.
+    pub fn max_scale_factor(&self, area: Rect<f64>) -> Result<f64, ProjError> {
+        let mut max_scale_factor: f64 = 0.0;
+        for i in 0..=SCALE_SAMPLE_GRID_SIZE {
+            for j in 0..=SCALE_SAMPLE_GRID_SIZE {
+                let start = Coordinate {
+                    x: area.min().x + area.width() * i as f64 / SCALE_SAMPLE_GRID_SIZE as f64,
+                    y: area.min().y + area.height() * j as f64 / SCALE_SAMPLE_GRID_SIZE as f64,
+                };
+                // Opposite directions have the same scale
+                for direction in 0..SCALE_SAMPLE_DIRECTIONS {
+                    let angle = PI * direction as f64 / SCALE_SAMPLE_DIRECTIONS as f64;
+                    let end = Coordinate {
+                        x: start.x + SCALE_SAMPLE_LENGTH * angle.cos(),
+                        y: start.y + SCALE_SAMPLE_LENGTH * angle.sin(),
+                    };
+                    let mut line = LineString(vec![start, end]);
+                    self.to_ext(&mut line)?;
+                    max_scale_factor = max_scale_factor.max(SCALE_SAMPLE_LENGTH / line.geodesic_length());
+                }
+            }
+        }
+        return Ok(max_scale_factor);
+    }
+
+    /// Transform `coords` from internal to external representation, in one
+    /// batch.
+    pub fn to_ext_coords(&self, coords: &mut [Coordinate<f64>]) -> Result<(), ProjError> {
+        with_proj(&self.definition(), WSG_CRS, |proj| proj.convert_array(coords).map(|_| ()))
+            .expect("Internal CRS definitions are valid")
+    }
+
+    /// Transform `coords` from external to internal representation, in one
+    /// batch.
+    pub fn to_int_coords(&self, coords: &mut [Coordinate<f64>]) -> Result<(), ProjError> {
+        with_proj(WSG_CRS, &self.definition(), |proj| proj.convert_array(coords).map(|_| ()))
+            .expect("Internal CRS definitions are valid")
+    }
+
+    /// Transform `geometry` from internal to external representation.
+    pub fn to_ext<G: Transform<f64>>(&self, geometry: &mut G) -> Result<(), ProjError> {
+        with_proj(&self.definition(), WSG_CRS, |proj| geometry.transform(proj))
+            .expect("Internal CRS definitions are valid")
+    }
+
+    /// Transform `geometry` from external to internal representation.
+    pub fn to_int<G: Transform<f64>>(&self, geometry: &mut G) -> Result<(), ProjError> {
+        with_proj(WSG_CRS, &self.definition(), |proj| geometry.transform(proj))
+            .expect("Internal CRS definitions are valid")
+    }
+}
+
+thread_local! {
+    /// Projections created on this thread, by source and target CRS
+    /// definition
+    static PROJ_CACHE: RefCell<HashMap<(String, String), Rc<Proj>>> = RefCell::new(HashMap::new());
 }
 
-/// Projection from external to internal representation.
-pub fn create_to_int_proj() -> Proj {
-    // Same comments as `create_to_ext_proj`
-    Proj::new_known_crs(WSG_CRS, ETRS_CRS, None).unwrap()
+/// Call `f` with the projection from `from` to `to` (any definitions
+/// `proj::Proj::new_known_crs` accepts).
+///
+/// `proj::Proj` is `!Send`: it can neither be stored in a global static nor
+/// held across an `.await` in a spawned task. Creating one is expensive
+/// though (PROJ looks the CRSes up in its database), so each thread keeps the
+/// projections it created. As `f` cannot `.await`, async code can use this
+/// freely.
+pub fn with_proj<T>(from: &str, to: &str, f: impl FnOnce(&Proj) -> T) -> Result<T, ProjCreateError> {
+    let key = (from.to_owned(), to.to_owned());
+    let cached = PROJ_CACHE.with(|cache| cache.borrow().get(&key).cloned());
+    let proj = match cached {
+        Some(proj) => proj,
+        None => {
+            let proj = Rc::new(Proj::new_known_crs(from, to, None)?);
+            PROJ_CACHE.with(|cache| cache.borrow_mut().insert(key, proj.clone()));
+            proj
+        }
+    };
+    // Not borrowing the cache while calling `f`, it may project as well
+    return Ok(f(&proj));
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use approx::assert_abs_diff_eq;
+    use geo::{coord, prelude::{BoundingRect, EuclideanDistance, GeodesicLength}, LineString, Rect};
+    use proj::Proj;
+
+    use super::{with_proj, InternalCrs, ETRS_CRS, WSG_CRS};
+
+    fn bbox(west: f64, south: f64, east: f64, north: f64) -> Rect<f64> {
+        Rect::new(coord! { x: west, y: south }, coord! { x: east, y: north })
+    }
+
+    #[test]
+    fn for_area__europe() {
+        // Zaventem
+        assert_eq!(InternalCrs::for_area(bbox(4.4, 50.8, 4.6, 50.95)), InternalCrs::EtrsLaea);
+    }
+
+    #[test]
+    fn for_area__utm() {
+        // Lake Titicaca
+        let crs = InternalCrs::for_area(bbox(-70.0, -16.6, -68.5, -15.2));
+        assert_eq!(crs, InternalCrs::Utm { zone: 19, north: false });
+        assert_eq!(crs.definition(), "EPSG:32719");
+        // Minnesota
+        let crs = InternalCrs::for_area(bbox(-94.0, 44.5, -93.0, 45.5));
+        assert_eq!(crs, InternalCrs::Utm { zone: 15, north: true });
+    }
+
+    #[test]
+    fn for_area__local() {
+        // Spans three UTM zones
+        let crs = InternalCrs::for_area(bbox(-100.0, 40.0, -86.0, 48.0));
+        assert_eq!(crs, InternalCrs::LocalLaea { center: coord! { x: -93.0, y: 44.0 } });
+        assert_eq!(crs.epsg_code(), None);
+        // Greenland
+        let crs = InternalCrs::for_area(bbox(-60.0, 70.0, -50.0, 85.0));
+        assert!(matches!(crs, InternalCrs::LocalLaea { .. }));
+    }
+
+    #[test]
+    fn max_scale_factor__bounds_projected_lengths() {
+        // Zaventem, about 400 km from the centre of ETRS89-LAEA
+        let crs = InternalCrs::EtrsLaea;
+        let mut area = bbox(4.4, 50.8, 4.6, 50.95).to_polygon();
+        crs.to_int(&mut area).unwrap();
+        let area = area.bounding_rect().unwrap();
+        let max_scale_factor = crs.max_scale_factor(area).unwrap();
+        assert!(max_scale_factor > 1.0 && max_scale_factor < 1.01, "{}", max_scale_factor);
+
+        // A diagonal across the area is no longer in the projection than its
+        // geodesic length times the factor
+        let diagonal = LineString(vec![area.min(), area.max()]);
+        let projected_length = area.min().euclidean_distance(&area.max());
+        let mut geodesic_diagonal = diagonal.clone();
+        crs.to_ext(&mut geodesic_diagonal).unwrap();
+        assert!(projected_length <= geodesic_diagonal.geodesic_length() * max_scale_factor * 1.0001);
+    }
+
+    #[test]
+    fn projections__roundtrip() {
+        let coord = coord! { x: -69.2, y: -15.8 };
+        for crs in [
+            InternalCrs::for_area(bbox(-70.0, -16.6, -68.5, -15.2)),
+            InternalCrs::LocalLaea { center: coord! { x: -69.0, y: -16.0 } },
+        ] {
+            let mut coords = [coord];
+            crs.to_int_coords(&mut coords).unwrap();
+            assert!(coords[0].x.abs() > 180.0, "Not projected: {:?}", coords[0]);
+            crs.to_ext_coords(&mut coords).unwrap();
+            assert_abs_diff_eq!(coords[0].x, coord.x, epsilon = 1e-9);
+            assert_abs_diff_eq!(coords[0].y, coord.y, epsilon = 1e-9);
+        }
+    }
+
+    #[test]
+    fn projections__metric() {
+        // One minute of latitude is about a nautical mile
+        let crs = InternalCrs::for_area(bbox(-70.0, -16.6, -68.5, -15.2));
+        let mut coords = [coord! { x: -69.2, y: -15.8 }, coord! { x: -69.2, y: -15.8 + 1.0 / 60.0 }];
+        crs.to_int_coords(&mut coords).unwrap();
+        assert_abs_diff_eq!(coords[0].euclidean_distance(&coords[1]), 1843.0, epsilon = 5.0);
+    }
+
+    #[test]
+    fn with_proj__cached_per_thread() {
+        let proj_address = || with_proj(WSG_CRS, ETRS_CRS, |proj| proj as *const Proj as usize).unwrap();
+        assert_eq!(proj_address(), proj_address());
+        let other_thread_address = std::thread::spawn(proj_address).join().unwrap();
+        assert_ne!(proj_address(), other_thread_address);
+    }
+
+    #[test]
+    fn with_proj__invalid_definition() {
+        assert!(with_proj("EPSG:0", WSG_CRS, |_| ()).is_err());
+    }
 }
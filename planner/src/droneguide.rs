@@ -1,10 +1,16 @@
 use std::{collections::HashMap, fmt::Display, iter};
 
+use derive_more::Display;
 use geo::{CoordNum, Geometry, MultiPolygon, Rect};
-use geojson::{Feature, FeatureCollection, GeoJson};
+use geojson::Feature;
 use log::warn;
-use reqwest::Url;
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
+
+use crate::{
+    airspace::{AMSLHeightMeters, Geozone},
+    feature_server::{FeatureServerClient, FeatureServerError},
+    geozone_schedule::{Activation, DailySchedule, UnixMillis},
+};
 
 const GEOZONE_URL: &str = "https://services3.arcgis.com/om3vWi08kAyoBbj3/ArcGIS/rest/services/Geozone_Download_Prod/FeatureServer/0/query";
 const CONDITION_URL: &str = "https://services3.arcgis.com/om3vWi08kAyoBbj3/ArcGIS/rest/services/Condition_Download_Prod/FeatureServer/0/query";
@@ -34,50 +40,64 @@ struct ConditionsResponseFeatureAttributes {
     category: String,
 }
 
-#[derive(Deserialize, Debug)]
-struct ConditionsResponseFeature {
-    attributes: ConditionsResponseFeatureAttributes,
+pub type CategoryConditions = HashMap<String, Vec<String>>;
+
+/// Location of the droneguide FeatureServer layers. Defaults to the public
+/// production service; tests point this at a local stand-in server.
+#[derive(Debug, Clone)]
+pub struct DroneguideUrls {
+    pub geozone_url: String,
+    pub condition_url: String,
 }
 
-#[derive(Deserialize, Debug)]
-struct ConditionsResponse {
-    features: Vec<ConditionsResponseFeature>,
+impl Default for DroneguideUrls {
+    fn default() -> Self {
+        DroneguideUrls {
+            geozone_url: GEOZONE_URL.to_owned(),
+            condition_url: CONDITION_URL.to_owned(),
+        }
+    }
 }
 
-type CategoryConditions = HashMap<String, Vec<String>>;
+/// A feature returned by the service that could not be used. Rejected
+/// features are reported instead of aborting the whole load.
+#[derive(Debug, Clone, Display)]
+#[display(fmt = "{} (feature {}): {}", name, index, reason)]
+pub struct RejectedFeature {
+    /// Index of the feature in the service's response
+    pub index: usize,
+    /// Name of the feature, or `"?"` if it could not be determined
+    pub name: String,
+    pub reason: String,
+}
 
-async fn fetch_conditions() -> CategoryConditions {
-    let url = Url::parse_with_params(
-        CONDITION_URL,
-        &[
-            ("f", "pjson"),
+async fn fetch_conditions(
+    condition_url: &str,
+) -> Result<(CategoryConditions, Vec<RejectedFeature>), FeatureServerError> {
+    let attributes = FeatureServerClient::new(condition_url)
+        .query_attributes(&[
             ("Where", "1=1"), // A 'where' condition is obligated; use a dummy as the dataset is small enough to be fetched in its entirety
             ("outFields", "*"), // All fields
-        ],
-    )
-    .unwrap();
-
-    let body: String = reqwest::get(url)
-        .await
-        .expect("conditions request to be successful")
-        .text()
-        .await
-        .expect("conditions response to have a body");
-
-    let conditions = serde_json::from_str::<ConditionsResponse>(&body)
-        .expect("conditions response to be json")
-        .features;
+        ])
+        .await?;
 
     let mut m = CategoryConditions::new();
-    for condition in conditions {
+    let mut rejected = Vec::new();
+    for (index, attributes) in attributes.into_iter().enumerate() {
+        let condition = match serde_json::from_value::<ConditionsResponseFeatureAttributes>(attributes) {
+            Ok(condition) => condition,
+            Err(err) => {
+                rejected.push(RejectedFeature { index, name: "?".to_owned(), reason: err.to_string() });
+                continue;
+            }
+        };
         // Category is case-insensitive (see for example EBR57_EEPOEL: Mil R areas)
-        let category = condition.attributes.category.to_lowercase();
-        let condition_desc = condition.attributes.condition_en;
+        let category = condition.category.to_lowercase();
         m.entry(category)
             .or_insert_with(Vec::new)
-            .push(condition_desc);
+            .push(condition.condition_en);
     }
-    return m;
+    return Ok((m, rejected));
 }
 
 #[derive(Deserialize, Debug)]
@@ -86,13 +106,47 @@ struct GeozoneProperties {
     lowerLimit: f64,
     upperLimit: f64,
     categories: String,
+    // Activation schedule, absent for permanent zones. Dates are epoch
+    // milliseconds, times of day `HH:MM` UTC.
+    #[serde(default)]
+    startDateTime: Option<UnixMillis>,
+    #[serde(default)]
+    endDateTime: Option<UnixMillis>,
+    #[serde(default)]
+    scheduleDays: Option<String>,
+    #[serde(default)]
+    scheduleStartTime: Option<String>,
+    #[serde(default)]
+    scheduleEndTime: Option<String>,
+}
+
+fn properties_to_activation(properties: &GeozoneProperties) -> Result<Activation, String> {
+    let daily = match (
+        &properties.scheduleDays,
+        &properties.scheduleStartTime,
+        &properties.scheduleEndTime,
+    ) {
+        (None, None, None) => None,
+        (days, Some(start_time), Some(end_time)) => Some(DailySchedule::parse(
+            days.as_deref().unwrap_or("ANY"),
+            start_time,
+            end_time,
+        )?),
+        _ => return Err("incomplete daily schedule".to_owned()),
+    };
+    return Ok(Activation {
+        from: properties.startDateTime,
+        until: properties.endDateTime,
+        daily,
+    });
 }
 
-async fn fetch_geozones_features<T: CoordNum + Display>(bbox: Rect<T>) -> Vec<Feature> {
-    let url = Url::parse_with_params(
-        GEOZONE_URL,
-        &[
-            ("f", "pgeojson"),
+async fn fetch_geozones_features<T: CoordNum + Display>(
+    geozone_url: &str,
+    bbox: Rect<T>,
+) -> Result<Vec<Feature>, FeatureServerError> {
+    FeatureServerClient::new(geozone_url)
+        .query_geojson(&[
             ("geometryType", "esriGeometryEnvelope"),
             ("geometry", &bbox.url_param_fmt()),
             ("spatialRel", "esriSpatialRelIntersects"), // Areas that intersect the bbox
@@ -100,53 +154,36 @@ async fn fetch_geozones_features<T: CoordNum + Display>(bbox: Rect<T>) -> Vec<Fe
             ("returnGeometry", "true"),
             ("inSR", "4326"), // WGS84 degrees https://epsg.io/4326
             ("outSR", "4326"),
-        ],
-    )
-    .unwrap();
-
-    let response = reqwest::get(url)
-        .await
-        .expect("geozone request to be successful");
-
-    // let users: Vec<User> = response.json().await?;
-    let body = response
-        .text()
+        ])
         .await
-        .expect("geozone response to have a body");
-    // println!("{:?}", body);
-
-    let geojson = body
-        .parse::<GeoJson>()
-        .expect("geozone response to be parsable geoJSON");
-    let feature_collection =
-        FeatureCollection::try_from(geojson).expect("geozone geoJSON to be a FeatureCollection");
-
-    return feature_collection.features;
-    // println!("{:?}", features.features[0].properties.as_ref().expect("feature has properties"));
 }
 
-type AMSLHeightMeters = u32;
+fn feature_to_geozone(
+    index: usize,
+    feature: Feature,
+    category_conditions: &CategoryConditions,
+) -> Result<Geozone, RejectedFeature> {
+    let name = feature
+        .property("name")
+        .and_then(|name| name.as_str())
+        .unwrap_or("?")
+        .to_owned();
+    let reject = |reason: String| RejectedFeature { index, name: name.clone(), reason };
 
-#[derive(Deserialize, Serialize, Debug)]
-struct Geozone {
-    name: String,
-    lower: AMSLHeightMeters,
-    upper: AMSLHeightMeters,
-    conditions: Vec<String>,
-    geometry: MultiPolygon<f64>,
-}
-
-fn feature_to_geozone(feature: Feature, category_conditions: &CategoryConditions) -> Geozone {
-    let properties: GeozoneProperties =
-        serde_json::from_value(feature.properties.unwrap().try_into().unwrap()).unwrap();
-    let geometry = Geometry::try_from(feature.geometry.unwrap().value).unwrap();
+    let properties = feature.properties.ok_or_else(|| reject("no properties".to_owned()))?;
+    let properties: GeozoneProperties = serde_json::from_value(properties.into())
+        .map_err(|err| reject(err.to_string()))?;
+    let activation = properties_to_activation(&properties).map_err(reject)?;
+    let geometry = feature.geometry.ok_or_else(|| reject("no geometry".to_owned()))?;
+    let geometry = Geometry::try_from(geometry.value).map_err(|err| reject(err.to_string()))?;
     let multi_polygon = match geometry {
         Geometry::MultiPolygon(geometry) => geometry,
         Geometry::Polygon(geometry) => MultiPolygon::from_iter(iter::once(geometry)),
-        _ => panic!("expected Polygon or MultiPolygon"),
+        _ => return Err(reject("expected Polygon or MultiPolygon".to_owned())),
     };
 
-    let conditions_opt = category_conditions.get(&properties.categories.to_lowercase());
+    let category = properties.categories.to_lowercase();
+    let conditions_opt = category_conditions.get(&category);
     let conditions = match conditions_opt {
         Some(v) => v.clone(),
         None => {
@@ -155,11 +192,105 @@ fn feature_to_geozone(feature: Feature, category_conditions: &CategoryConditions
         }
     };
 
-    Geozone {
+    Ok(Geozone {
         name: properties.name,
-        lower: properties.lowerLimit as u32,
-        upper: properties.upperLimit.ceil() as u32,
+        category,
+        lower: properties.lowerLimit as AMSLHeightMeters,
+        upper: properties.upperLimit.ceil() as AMSLHeightMeters,
         conditions: conditions,
+        activation,
         geometry: multi_polygon,
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct FetchedGeozones {
+    pub geozones: Vec<Geozone>,
+    /// Geozone and condition features that could not be parsed
+    pub rejected: Vec<RejectedFeature>,
+}
+
+/// Fetch all geozones that intersect `bbox` (WGS84 degrees), together with
+/// the conditions of their category.
+pub async fn fetch_geozones(
+    urls: &DroneguideUrls,
+    bbox: Rect<f64>,
+) -> Result<FetchedGeozones, FeatureServerError> {
+    let (conditions, mut rejected) = fetch_conditions(&urls.condition_url).await?;
+    let geozone_features = fetch_geozones_features(&urls.geozone_url, bbox).await?;
+    let mut geozones = Vec::new();
+    for (index, feature) in geozone_features.into_iter().enumerate() {
+        match feature_to_geozone(index, feature, &conditions) {
+            Ok(geozone) => geozones.push(geozone),
+            Err(rejected_feature) => rejected.push(rejected_feature),
+        }
+    }
+    for rejected_feature in &rejected {
+        warn!("Skipping droneguide feature {}", rejected_feature);
+    }
+    Ok(FetchedGeozones { geozones, rejected })
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use geo::{coord, Rect};
+
+    use crate::test_util::serve_canned_http;
+
+    use super::{fetch_geozones, DroneguideUrls};
+
+    #[tokio::test]
+    async fn fetch_geozones__rejects_unparsable_features() {
+        let address = serve_canned_http(|target| {
+            let body = if target.starts_with("/Condition/") {
+                r#"{ "features": [
+                    { "attributes": { "condition_en": "Prohibited", "Generieke_categorie_geozone": "Mil R" } },
+                    { "attributes": { "Generieke_categorie_geozone": "CTR" } }
+                ] }"#
+            } else {
+                r#"{ "type": "FeatureCollection", "features": [
+                    { "type": "Feature",
+                      "geometry": { "type": "Polygon", "coordinates": [[[4.4, 50.9], [4.5, 50.9], [4.5, 51.0], [4.4, 50.9]]] },
+                      "properties": { "name": "valid", "lowerLimit": 0, "upperLimit": 100, "categories": "Mil R" } },
+                    { "type": "Feature",
+                      "geometry": { "type": "Point", "coordinates": [4.4, 50.9] },
+                      "properties": { "name": "point", "lowerLimit": 0, "upperLimit": 100, "categories": "Mil R" } },
+                    { "type": "Feature",
+                      "geometry": { "type": "Polygon", "coordinates": [[[4.4, 50.9], [4.5, 50.9], [4.5, 51.0], [4.4, 50.9]]] },
+                      "properties": { "name": "no limits", "categories": "Mil R" } },
+                    { "type": "Feature",
+                      "geometry": { "type": "Polygon", "coordinates": [[[4.4, 50.9], [4.5, 50.9], [4.5, 51.0], [4.4, 50.9]]] },
+                      "properties": { "name": "scheduled", "lowerLimit": 0, "upperLimit": 100, "categories": "Mil R",
+                        "startDateTime": 1654473600000, "scheduleDays": "MON,TUE", "scheduleStartTime": "08:00", "scheduleEndTime": "12:00" } },
+                    { "type": "Feature",
+                      "geometry": { "type": "Polygon", "coordinates": [[[4.4, 50.9], [4.5, 50.9], [4.5, 51.0], [4.4, 50.9]]] },
+                      "properties": { "name": "half schedule", "lowerLimit": 0, "upperLimit": 100, "categories": "Mil R",
+                        "scheduleStartTime": "08:00" } }
+                ] }"#
+            };
+            (200, body.to_owned())
+        }).await;
+        let urls = DroneguideUrls {
+            geozone_url: format!("http://{}/Geozone/FeatureServer/0/query", address),
+            condition_url: format!("http://{}/Condition/FeatureServer/0/query", address),
+        };
+        let bbox = Rect::new(coord! { x: 4.3, y: 50.8 }, coord! { x: 4.8, y: 51.1 });
+
+        let fetched = fetch_geozones(&urls, bbox).await.unwrap();
+        assert_eq!(fetched.geozones.len(), 2);
+        assert_eq!(fetched.geozones[0].name, "valid");
+        assert_eq!(fetched.geozones[0].conditions, vec!["Prohibited".to_owned()]);
+        assert!(fetched.geozones[0].activation.is_permanent());
+        let activation = &fetched.geozones[1].activation;
+        assert_eq!(activation.from, Some(1654473600000));
+        assert_eq!(activation.until, None);
+        let daily = activation.daily.as_ref().unwrap();
+        assert_eq!(daily.weekdays, [true, true, false, false, false, false, false]);
+        assert_eq!((daily.start_minute, daily.end_minute), (8 * 60, 12 * 60));
+        let mut rejected_names = fetched.rejected.iter().map(|r| r.name.as_str()).collect::<Vec<_>>();
+        rejected_names.sort();
+        assert_eq!(rejected_names, vec!["?", "half schedule", "no limits", "point"]);
     }
 }
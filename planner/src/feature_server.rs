@@ -0,0 +1,328 @@
+//! Client for ArcGIS REST FeatureServer layer queries
+//!
+//! A layer only returns up to its `maxRecordCount` features per request and
+//! signals there are more with `exceededTransferLimit`. This client pages
+//! through the results with `resultOffset`/`resultRecordCount` and retries
+//! transient failures.
+//!
+//! See: https://developers.arcgis.com/rest/services-reference/enterprise/query-feature-service-layer-.htm
+
+use std::{error::Error, time::Duration};
+
+use derive_more::Display;
+use geojson::{Feature, FeatureCollection};
+use log::warn;
+use reqwest::Url;
+use serde_json::Value;
+
+#[derive(Debug, Display)]
+pub enum FeatureServerError {
+    #[display(fmt = "invalid FeatureServer URL: {}", _0)]
+    InvalidUrl(String),
+    #[display(fmt = "FeatureServer request failed: {}", _0)]
+    Request(reqwest::Error),
+    #[display(fmt = "FeatureServer responded with HTTP status {}", _0)]
+    Status(u16),
+    #[display(fmt = "malformed FeatureServer response: {}", _0)]
+    MalformedResponse(String),
+    /// ArcGIS reports errors in the JSON body, often with a 200 status.
+    #[display(fmt = "FeatureServer error {}: {}", code, message)]
+    Service { code: i64, message: String },
+}
+impl Error for FeatureServerError {}
+
+impl FeatureServerError {
+    /// Whether retrying the same request might succeed.
+    fn is_transient(&self) -> bool {
+        match self {
+            FeatureServerError::Request(_) => true,
+            FeatureServerError::Status(status) => *status == 429 || *status >= 500,
+            FeatureServerError::Service { code, .. } => *code == 429 || *code >= 500,
+            FeatureServerError::InvalidUrl(_) | FeatureServerError::MalformedResponse(_) => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FeatureServerClient {
+    /// Query endpoint of the layer, e.g. `.../FeatureServer/0/query`
+    query_url: String,
+    page_size: usize,
+    max_retries: u32,
+    retry_delay: Duration,
+    http: reqwest::Client,
+}
+
+impl FeatureServerClient {
+    pub fn new(query_url: impl Into<String>) -> Self {
+        FeatureServerClient {
+            query_url: query_url.into(),
+            page_size: 1000,
+            max_retries: 3,
+            retry_delay: Duration::from_millis(500),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    /// Number of features requested per page. Should not exceed the layer's
+    /// `maxRecordCount`.
+    pub fn with_page_size(mut self, page_size: usize) -> Self {
+        self.page_size = page_size;
+        self
+    }
+
+    /// Retry transient failures up to `max_retries` times, doubling `delay`
+    /// after every attempt.
+    pub fn with_retries(mut self, max_retries: u32, delay: Duration) -> Self {
+        self.max_retries = max_retries;
+        self.retry_delay = delay;
+        self
+    }
+
+    /// Query all features matching `params` as GeoJSON, following pages until
+    /// the layer no longer reports `exceededTransferLimit`.
+    pub async fn query_geojson(
+        &self,
+        params: &[(&str, &str)],
+    ) -> Result<Vec<Feature>, FeatureServerError> {
+        let mut features = Vec::new();
+        loop {
+            let page = self.query_page("geojson", params, features.len()).await?;
+            let exceeded_transfer_limit = exceeded_transfer_limit(&page);
+            let page_features = FeatureCollection::from_json_value(page)
+                .map_err(|err| FeatureServerError::MalformedResponse(err.to_string()))?
+                .features;
+            let page_len = page_features.len();
+            features.extend(page_features);
+            if !exceeded_transfer_limit || page_len == 0 {
+                return Ok(features);
+            }
+        }
+    }
+
+    /// Query the `attributes` of all features matching `params` (Esri JSON),
+    /// following pages like `query_geojson`.
+    pub async fn query_attributes(
+        &self,
+        params: &[(&str, &str)],
+    ) -> Result<Vec<Value>, FeatureServerError> {
+        let mut attributes = Vec::new();
+        loop {
+            let mut page = self.query_page("json", params, attributes.len()).await?;
+            let exceeded_transfer_limit = exceeded_transfer_limit(&page);
+            let page_features = match page.get_mut("features").map(Value::take) {
+                Some(Value::Array(page_features)) => page_features,
+                _ => {
+                    return Err(FeatureServerError::MalformedResponse(
+                        "expected a `features` array".to_owned(),
+                    ))
+                }
+            };
+            let page_len = page_features.len();
+            attributes.extend(page_features.into_iter().map(|mut feature| {
+                feature.get_mut("attributes").map(Value::take).unwrap_or(Value::Null)
+            }));
+            if !exceeded_transfer_limit || page_len == 0 {
+                return Ok(attributes);
+            }
+        }
+    }
+
+    async fn query_page(
+        &self,
+        format: &str,
+        params: &[(&str, &str)],
+        offset: usize,
+    ) -> Result<Value, FeatureServerError> {
+        let offset = offset.to_string();
+        let page_size = self.page_size.to_string();
+        let url = Url::parse_with_params(
+            &self.query_url,
+            params.iter().copied().chain([
+                ("f", format),
+                ("resultOffset", offset.as_str()),
+                ("resultRecordCount", page_size.as_str()),
+            ]),
+        )
+        .map_err(|err| FeatureServerError::InvalidUrl(err.to_string()))?;
+
+        let mut attempt = 0;
+        loop {
+            match self.get_json(url.clone()).await {
+                Err(err) if err.is_transient() && attempt < self.max_retries => {
+                    warn!("{}, retrying (attempt {}/{})", err, attempt + 1, self.max_retries);
+                    tokio::time::sleep(self.retry_delay * 2u32.pow(attempt)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn get_json(&self, url: Url) -> Result<Value, FeatureServerError> {
+        let response = self
+            .http
+            .get(url)
+            .send()
+            .await
+            .map_err(FeatureServerError::Request)?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(FeatureServerError::Status(status.as_u16()));
+        }
+        let body = response.text().await.map_err(FeatureServerError::Request)?;
+        let value: Value = serde_json::from_str(&body)
+            .map_err(|err| FeatureServerError::MalformedResponse(err.to_string()))?;
+        if let Some(error) = value.get("error") {
+            return Err(FeatureServerError::Service {
+                code: error.get("code").and_then(Value::as_i64).unwrap_or(0),
+                message: error
+                    .get("message")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_owned(),
+            });
+        }
+        return Ok(value);
+    }
+}
+
+/// Esri JSON has `exceededTransferLimit` at the top level; GeoJSON output has
+/// it either as a foreign member or inside `properties`, depending on the
+/// server version.
+fn exceeded_transfer_limit(page: &Value) -> bool {
+    page.get("exceededTransferLimit")
+        .or_else(|| page.get("properties").and_then(|p| p.get("exceededTransferLimit")))
+        .and_then(Value::as_bool)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use reqwest::Url;
+
+    use crate::test_util::{serve_canned_http, unreachable_address};
+
+    use super::{FeatureServerClient, FeatureServerError};
+
+    fn query_params(target: &str) -> HashMap<String, String> {
+        Url::parse(&format!("http://localhost{}", target))
+            .unwrap()
+            .query_pairs()
+            .into_owned()
+            .collect()
+    }
+
+    fn point_feature(id: usize) -> String {
+        format!(
+            r#"{{ "type": "Feature", "geometry": {{ "type": "Point", "coordinates": [4.5, 51.0] }}, "properties": {{ "id": {} }} }}"#,
+            id
+        )
+    }
+
+    /// Serves `total` features, at most `max_record_count` per page.
+    fn paged_geojson_response(target: &str, total: usize, max_record_count: usize) -> (u16, String) {
+        let params = query_params(target);
+        let offset: usize = params["resultOffset"].parse().unwrap();
+        let count: usize = params["resultRecordCount"].parse::<usize>().unwrap().min(max_record_count);
+        let end = (offset + count).min(total);
+        let features = (offset..end).map(point_feature).collect::<Vec<_>>().join(",");
+        let body = format!(
+            r#"{{ "type": "FeatureCollection", "features": [{}], "properties": {{ "exceededTransferLimit": {} }} }}"#,
+            features,
+            end < total
+        );
+        (200, body)
+    }
+
+    #[tokio::test]
+    async fn query_geojson__follows_pages() {
+        let address = serve_canned_http(|target| paged_geojson_response(target, 5, 2)).await;
+        let client = FeatureServerClient::new(format!("http://{}/query", address)).with_page_size(2);
+
+        let features = client.query_geojson(&[("where", "1=1")]).await.unwrap();
+        let ids = features
+            .iter()
+            .map(|f| f.property("id").unwrap().as_u64().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn query_attributes__follows_pages() {
+        let address = serve_canned_http(|target| {
+            let offset: usize = query_params(target)["resultOffset"].parse().unwrap();
+            let body = if offset == 0 {
+                r#"{ "features": [{ "attributes": { "id": 0 } }], "exceededTransferLimit": true }"#
+            } else {
+                r#"{ "features": [{ "attributes": { "id": 1 } }] }"#
+            };
+            (200, body.to_owned())
+        }).await;
+        let client = FeatureServerClient::new(format!("http://{}/query", address)).with_page_size(1);
+
+        let attributes = client.query_attributes(&[]).await.unwrap();
+        assert_eq!(attributes.len(), 2);
+        assert_eq!(attributes[1]["id"], 1);
+    }
+
+    #[tokio::test]
+    async fn query_geojson__retries_transient_failure() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let server_requests = requests.clone();
+        let address = serve_canned_http(move |target| {
+            if server_requests.fetch_add(1, Ordering::SeqCst) == 0 {
+                (503, "{}".to_owned())
+            } else {
+                paged_geojson_response(target, 1, 10)
+            }
+        }).await;
+        let client = FeatureServerClient::new(format!("http://{}/query", address))
+            .with_retries(2, Duration::from_millis(1));
+
+        let features = client.query_geojson(&[]).await.unwrap();
+        assert_eq!(features.len(), 1);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn query_geojson__service_error() {
+        let address = serve_canned_http(|_| {
+            (200, r#"{ "error": { "code": 400, "message": "Invalid query parameters" } }"#.to_owned())
+        }).await;
+        let client = FeatureServerClient::new(format!("http://{}/query", address));
+
+        let result = client.query_geojson(&[]).await;
+        assert!(matches!(result, Err(FeatureServerError::Service { code: 400, .. })));
+    }
+
+    #[tokio::test]
+    async fn query_geojson__malformed_json() {
+        let address = serve_canned_http(|_| (200, "{ not json".to_owned())).await;
+        let client = FeatureServerClient::new(format!("http://{}/query", address));
+
+        let result = client.query_geojson(&[]).await;
+        assert!(matches!(result, Err(FeatureServerError::MalformedResponse(_))));
+    }
+
+    #[tokio::test]
+    async fn query_geojson__unreachable() {
+        let address = unreachable_address().await;
+        let client = FeatureServerClient::new(format!("http://{}/query", address))
+            .with_retries(1, Duration::from_millis(1));
+
+        let result = client.query_geojson(&[]).await;
+        assert!(matches!(result, Err(FeatureServerError::Request(_))));
+    }
+}
@@ -2,26 +2,21 @@
 
 use geo::{Geometry, MultiPolygon, Point, MultiPoint};
 use geojson::Feature;
-use proj::Transform;
 
-use crate::crs::create_to_ext_proj;
+use crate::crs::InternalCrs;
 
-pub fn geometry_to_feature(mut geometry: Geometry<f64>) -> geojson::Feature {
-    let proj = create_to_ext_proj();
-    geometry.transform(&proj).unwrap();
+pub fn geometry_to_feature(mut geometry: Geometry<f64>, crs: &InternalCrs) -> geojson::Feature {
+    crs.to_ext(&mut geometry).unwrap();
     return Feature::from(geojson::Geometry::from(&geometry));
 }
 
-pub fn multi_polygon_to_feature(mut multi_polygon: MultiPolygon<f64>) -> geojson::Feature {
-    let proj = create_to_ext_proj();
-    multi_polygon.transform(&proj).unwrap();
+pub fn multi_polygon_to_feature(mut multi_polygon: MultiPolygon<f64>, crs: &InternalCrs) -> geojson::Feature {
+    crs.to_ext(&mut multi_polygon).unwrap();
     return Feature::from(geojson::Geometry::from(&multi_polygon));
 }
 
-pub fn feature_from_points(points: impl Iterator<Item = Point<f64>>) -> geojson::Feature {
-    let proj = create_to_ext_proj();
-    let multi_point = MultiPoint(points.map(|point| {
-        point.transformed(&proj).unwrap()
-    }).collect::<Vec<_>>());
+pub fn feature_from_points(points: impl Iterator<Item = Point<f64>>, crs: &InternalCrs) -> geojson::Feature {
+    let mut multi_point = MultiPoint(points.collect::<Vec<_>>());
+    crs.to_ext(&mut multi_point).unwrap();
     return Feature::from(geojson::Geometry::from(&multi_point));
 }
@@ -0,0 +1,177 @@
+//! Polygon features from any of the supported file formats
+//!
+//! The planner only works with areas (obstacles, waters...), so sources yield
+//! (multi-)polygon features and reject other geometry types.
+
+use std::{error::Error, path::Path};
+
+use async_trait::async_trait;
+use geo::{prelude::BoundingRect, Coordinate, Geometry, MultiPolygon, Rect};
+use proj::Transform;
+use serde_json::{Map, Value};
+
+use crate::{
+    crs::{with_proj, InternalCrs, ETRS_CRS, WSG_CRS},
+    winding::ensure_sfa_winding,
+};
+
+use super::{
+    flatgeobuf_source::FlatGeobufSource, geojson_source::GeoJsonSource, gpkg_reader::GpkgSource,
+    shapefile_source::ShapefileSource,
+};
+
+pub type Attributes = Map<String, Value>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceFeature {
+    /// With SFA winding
+    pub geometry: MultiPolygon<f64>,
+    pub attributes: Attributes,
+}
+
+#[async_trait]
+pub trait FeatureSource: Send + Sync {
+    fn describe(&self) -> String;
+
+    /// CRS of the features, as a definition `proj::Proj` accepts. `None` if
+    /// the source does not specify one.
+    async fn crs(&self) -> Result<Option<String>, Box<dyn Error + Send + Sync>>;
+
+    /// All features, or only the ones whose envelope intersects `bbox` (in
+    /// the source's CRS).
+    async fn features(
+        &self,
+        bbox: Option<Rect<f64>>,
+    ) -> Result<Vec<SourceFeature>, Box<dyn Error + Send + Sync>>;
+}
+
+/// Source for the file at `path`, chosen by extension: `.gpkg` (GeoPackage),
+/// `.shp` or `.zip` (zipped shapefile), `.geojson` or `.json` (GeoJSON) and
+/// `.fgb` (FlatGeobuf). `layer` selects the table of a GeoPackage or the
+/// shapefile in an archive; without it the file must have a single one.
+pub fn feature_source_for_path(
+    path: impl AsRef<Path>,
+    layer: Option<&str>,
+) -> Result<Box<dyn FeatureSource>, Box<dyn Error + Send + Sync>> {
+    let path = path.as_ref();
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase());
+    let layer = layer.map(str::to_owned);
+    match extension.as_deref() {
+        Some("gpkg") => Ok(Box::new(GpkgSource::new(path, layer))),
+        Some("shp" | "zip") => Ok(Box::new(ShapefileSource::new(path, layer))),
+        Some("geojson" | "json") => Ok(Box::new(GeoJsonSource::new(path))),
+        Some("fgb") => Ok(Box::new(FlatGeobufSource::new(path))),
+        _ => Err(format!("Unknown feature file type: {}", path.display()).into()),
+    }
+}
+
+/// `geometry` as a multi-polygon with SFA winding, `None` if it is not a
+/// (multi-)polygon.
+pub fn polygonal(geometry: Geometry<f64>) -> Option<MultiPolygon<f64>> {
+    let mut multi_polygon = match geometry {
+        Geometry::MultiPolygon(multi_polygon) => multi_polygon,
+        Geometry::Polygon(polygon) => MultiPolygon(vec![polygon]),
+        _ => return None,
+    };
+    ensure_sfa_winding(&mut multi_polygon);
+    return Some(multi_polygon);
+}
+
+/// Whether the envelope of `multi_polygon` intersects `bbox` (always true
+/// without a `bbox`).
+pub fn envelope_intersects(multi_polygon: &MultiPolygon<f64>, bbox: Option<Rect<f64>>) -> bool {
+    match (bbox, multi_polygon.bounding_rect()) {
+        (None, _) => true,
+        (Some(_), None) => false,
+        (Some(bbox), Some(envelope)) => {
+            envelope.min().x <= bbox.max().x
+                && envelope.max().x >= bbox.min().x
+                && envelope.min().y <= bbox.max().y
+                && envelope.max().y >= bbox.min().y
+        }
+    }
+}
+
+/// Envelope of all `features`
+pub fn features_bounding_rect(features: &[SourceFeature]) -> Option<Rect<f64>> {
+    features
+        .iter()
+        .filter_map(|feature| feature.geometry.bounding_rect())
+        .reduce(|a, b| {
+            Rect::new(
+                Coordinate { x: a.min().x.min(b.min().x), y: a.min().y.min(b.min().y) },
+                Coordinate { x: a.max().x.max(b.max().x), y: a.max().y.max(b.max().y) },
+            )
+        })
+}
+
+/// All features from `source`, transformed to `crs`. Without a `crs`, the
+/// internal CRS for their extent is chosen (see `InternalCrs::for_area`).
+/// Sources without a CRS are assumed to be in `ETRS_CRS`.
+pub async fn load_features(
+    source: &dyn FeatureSource,
+    crs: Option<&InternalCrs>,
+) -> Result<(Vec<SourceFeature>, InternalCrs), Box<dyn Error + Send + Sync>> {
+    println!("Loading {}", source.describe());
+    let source_crs = source.crs().await?.unwrap_or_else(|| ETRS_CRS.to_owned());
+    let mut features = source.features(None).await?;
+    let crs = match (crs, features_bounding_rect(&features)) {
+        (Some(crs), _) => crs.clone(),
+        (None, Some(bbox)) => {
+            let mut bbox_polygon = bbox.to_polygon();
+            with_proj(&source_crs, WSG_CRS, |proj| bbox_polygon.transform(proj))??;
+            InternalCrs::for_area(bbox_polygon.bounding_rect().unwrap())
+        }
+        (None, None) => InternalCrs::default(),
+    };
+    if source_crs != crs.definition() {
+        with_proj(&source_crs, &crs.definition(), |proj| {
+            features.iter_mut().try_for_each(|feature| feature.geometry.transform(proj))
+        })??;
+    }
+    return Ok((features, crs));
+}
+
+/// All polygons from `source`, merged into one multi-polygon, see
+/// `load_features`.
+pub async fn load_multi_polygon(
+    source: &dyn FeatureSource,
+    crs: Option<&InternalCrs>,
+) -> Result<(MultiPolygon<f64>, InternalCrs), Box<dyn Error + Send + Sync>> {
+    let (features, crs) = load_features(source, crs).await?;
+    let multi_polygon = MultiPolygon::from_iter(
+        features.into_iter().flat_map(|feature| feature.geometry.0),
+    );
+    return Ok((multi_polygon, crs));
+}
+
+/// `load_multi_polygon` for the file at `path`, see `feature_source_for_path`.
+pub async fn load_file_multi_polygon(
+    path: impl AsRef<Path>,
+    layer: Option<&str>,
+    crs: Option<&InternalCrs>,
+) -> Result<(MultiPolygon<f64>, InternalCrs), Box<dyn Error + Send + Sync>> {
+    let source = feature_source_for_path(path, layer)?;
+    return load_multi_polygon(source.as_ref(), crs).await;
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use super::feature_source_for_path;
+
+    #[test]
+    fn feature_source_for_path__by_extension() {
+        let describe = |path: &str| feature_source_for_path(path, None).unwrap().describe();
+        assert!(describe("data/osm-water/water.gpkg").starts_with("GeoPackage"));
+        assert!(describe("data/iv-grb/GRBgis_10002_Shapefile.zip").starts_with("Shapefile"));
+        assert!(describe("data/obstacles.SHP").starts_with("Shapefile"));
+        assert!(describe("data/waters.geojson").starts_with("GeoJSON"));
+        assert!(describe("data/waters.fgb").starts_with("FlatGeobuf"));
+        assert!(feature_source_for_path("data/waters.kml", None).is_err());
+    }
+}
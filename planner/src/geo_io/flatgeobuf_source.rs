@@ -0,0 +1,89 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use flatgeobuf::{FallibleStreamingIterator, FgbReader};
+use geo::Rect;
+use geozero::{FeatureProperties, ToGeo};
+use serde_json::Value;
+
+use super::feature_source::{polygonal, Attributes, FeatureSource, SourceFeature};
+
+/// CRS from the header of the FlatGeobuf file at `path`
+fn read_flatgeobuf_crs(path: &Path) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let fgb = FgbReader::open(&mut file)?;
+    let crs = fgb.header().crs().and_then(|crs| {
+        if crs.code() > 0 {
+            Some(format!("{}:{}", crs.org().unwrap_or("EPSG"), crs.code()))
+        } else {
+            crs.wkt().map(str::to_owned)
+        }
+    });
+    return Ok(crs);
+}
+
+/// Polygon features of the FlatGeobuf file at `path`, using the file's
+/// spatial index when filtering by `bbox`. Attribute values are read as
+/// strings.
+fn read_flatgeobuf(
+    path: &Path,
+    bbox: Option<Rect<f64>>,
+) -> Result<Vec<SourceFeature>, Box<dyn Error + Send + Sync>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut fgb = FgbReader::open(&mut file)?;
+    match bbox {
+        Some(bbox) => fgb.select_bbox(bbox.min().x, bbox.min().y, bbox.max().x, bbox.max().y)?,
+        None => fgb.select_all()?,
+    };
+
+    let mut features = Vec::new();
+    while let Some(feature) = fgb.next()? {
+        let geometry = polygonal(feature.to_geo()?)
+            .ok_or_else(|| format!("Expected polygon features in {}", path.display()))?;
+        let attributes = feature
+            .properties()?
+            .into_iter()
+            .map(|(name, value)| (name, Value::from(value)))
+            .collect::<Attributes>();
+        features.push(SourceFeature { geometry, attributes });
+    }
+    return Ok(features);
+}
+
+/// FlatGeobuf file
+#[derive(Debug, Clone)]
+pub struct FlatGeobufSource {
+    path: PathBuf,
+}
+
+impl FlatGeobufSource {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FlatGeobufSource { path: path.into() }
+    }
+}
+
+#[async_trait]
+impl FeatureSource for FlatGeobufSource {
+    fn describe(&self) -> String {
+        format!("FlatGeobuf {}", self.path.display())
+    }
+
+    async fn crs(&self) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        let path = self.path.clone();
+        return tokio::task::spawn_blocking(move || read_flatgeobuf_crs(&path)).await?;
+    }
+
+    async fn features(
+        &self,
+        bbox: Option<Rect<f64>>,
+    ) -> Result<Vec<SourceFeature>, Box<dyn Error + Send + Sync>> {
+        let path = self.path.clone();
+        // Decoding is blocking
+        return tokio::task::spawn_blocking(move || read_flatgeobuf(&path, bbox)).await?;
+    }
+}
@@ -0,0 +1,109 @@
+use std::{error::Error, path::PathBuf};
+
+use async_trait::async_trait;
+use geo::{Geometry, Rect};
+use geojson::{FeatureCollection, GeoJson};
+
+use crate::crs::WSG_CRS;
+
+use super::feature_source::{envelope_intersects, polygonal, FeatureSource, SourceFeature};
+
+/// Polygon features of a GeoJSON FeatureCollection.
+pub fn parse_geojson_features(text: &str) -> Result<Vec<SourceFeature>, Box<dyn Error + Send + Sync>> {
+    let feature_collection = FeatureCollection::try_from(text.parse::<GeoJson>()?)?;
+    feature_collection
+        .features
+        .into_iter()
+        .enumerate()
+        .map(|(index, feature)| -> Result<SourceFeature, Box<dyn Error + Send + Sync>> {
+            let invalid = |reason: &str| format!("Invalid feature {}: {}", index, reason);
+            let geometry = feature.geometry.ok_or_else(|| invalid("no geometry"))?;
+            let geometry = Geometry::try_from(geometry.value).map_err(|err| invalid(&err.to_string()))?;
+            let geometry = polygonal(geometry).ok_or_else(|| invalid("expected Polygon or MultiPolygon"))?;
+            Ok(SourceFeature { geometry, attributes: feature.properties.unwrap_or_default() })
+        })
+        .collect()
+}
+
+/// GeoJSON file. Always WGS84 (RFC 7946).
+#[derive(Debug, Clone)]
+pub struct GeoJsonSource {
+    path: PathBuf,
+}
+
+impl GeoJsonSource {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        GeoJsonSource { path: path.into() }
+    }
+}
+
+#[async_trait]
+impl FeatureSource for GeoJsonSource {
+    fn describe(&self) -> String {
+        format!("GeoJSON {}", self.path.display())
+    }
+
+    async fn crs(&self) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        Ok(Some(WSG_CRS.to_owned()))
+    }
+
+    async fn features(
+        &self,
+        bbox: Option<Rect<f64>>,
+    ) -> Result<Vec<SourceFeature>, Box<dyn Error + Send + Sync>> {
+        let text = tokio::fs::read_to_string(&self.path).await?;
+        let mut features = parse_geojson_features(&text)?;
+        features.retain(|feature| envelope_intersects(&feature.geometry, bbox));
+        return Ok(features);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use geo::{coord, Rect};
+    use serde_json::Value;
+
+    use crate::{geo_io::feature_source::FeatureSource, test_util::temp_path};
+
+    use super::{parse_geojson_features, GeoJsonSource};
+
+    const WATERS: &str = r#"{ "type": "FeatureCollection", "features": [
+        { "type": "Feature",
+          "geometry": { "type": "Polygon", "coordinates": [[[4.4, 50.9], [4.5, 50.9], [4.5, 51.0], [4.4, 50.9]]] },
+          "properties": { "name": "Lake", "depth": 3 } },
+        { "type": "Feature",
+          "geometry": { "type": "MultiPolygon", "coordinates": [[[[5.4, 50.9], [5.5, 50.9], [5.5, 51.0], [5.4, 50.9]]]] },
+          "properties": null }
+    ] }"#;
+
+    #[test]
+    fn parse_geojson_features__polygons_and_properties() {
+        let features = parse_geojson_features(WATERS).unwrap();
+        assert_eq!(features.len(), 2);
+        assert_eq!(features[0].attributes["name"], Value::from("Lake"));
+        assert!(features[1].attributes.is_empty());
+    }
+
+    #[test]
+    fn parse_geojson_features__rejects_lines() {
+        let result = parse_geojson_features(
+            r#"{ "type": "FeatureCollection", "features": [
+                { "type": "Feature", "geometry": { "type": "LineString", "coordinates": [[4.4, 50.9], [4.5, 50.9]] }, "properties": {} }
+            ] }"#,
+        );
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn features__bbox() {
+        let path = temp_path("waters.geojson");
+        tokio::fs::write(&path, WATERS).await.unwrap();
+        let source = GeoJsonSource::new(&path);
+        let bbox = Rect::new(coord! { x: 4.0, y: 50.0 }, coord! { x: 5.0, y: 51.0 });
+        let features = source.features(Some(bbox)).await.unwrap();
+        assert_eq!(features.len(), 1);
+        assert_eq!(features[0].attributes["name"], Value::from("Lake"));
+    }
+}
@@ -0,0 +1,650 @@
+//! Reading features layers from a GeoPackage
+//!
+//! Layers are discovered through `gpkg_contents`, their geometry column and
+//! CRS through `gpkg_geometry_columns` and `gpkg_spatial_ref_sys`. A bounding
+//! box filter uses the layer's R-tree spatial index (`rtree_<table>_<column>`)
+//! if it has one.
+//!
+//! Table and column names cannot be bound as query parameters. Only names
+//! found in the GeoPackage metadata are used, and always as quoted
+//! identifiers.
+
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use derive_more::Display;
+use geo::{coord, Geometry, MultiPolygon, Rect};
+use geozero::wkb;
+use serde_json::{Map, Value};
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions, SqliteRow},
+    Row, ValueRef,
+};
+
+use crate::winding::ensure_sfa_winding;
+
+use super::feature_source::{envelope_intersects, FeatureSource, SourceFeature};
+
+#[derive(Debug, Display)]
+pub enum GpkgError {
+    #[display(fmt = "GeoPackage query failed: {}", _0)]
+    Query(sqlx::Error),
+    #[display(fmt = "no layer {:?} in GeoPackage", _0)]
+    UnknownLayer(String),
+    #[display(fmt = "layer {:?} is not a features layer", _0)]
+    NotFeatures(String),
+    #[display(fmt = "layer {:?} has no CRS", _0)]
+    MissingCrs(String),
+    #[display(fmt = "feature {} of layer {:?} is a {}, expected (Multi)Polygon", fid, layer, geometry_type)]
+    UnsupportedGeometry { layer: String, fid: i64, geometry_type: String },
+}
+impl Error for GpkgError {}
+
+impl From<sqlx::Error> for GpkgError {
+    fn from(err: sqlx::Error) -> Self {
+        GpkgError::Query(err)
+    }
+}
+
+/// Entry of `gpkg_contents`
+#[derive(Debug, Clone, PartialEq)]
+pub struct GpkgLayer {
+    pub table_name: String,
+    /// `features`, `attributes`, `tiles`...
+    pub data_type: String,
+    pub identifier: Option<String>,
+    pub description: Option<String>,
+    pub srs_id: Option<i32>,
+    /// Informative extent, may be absent or outdated
+    pub bbox: Option<Rect<f64>>,
+}
+
+impl GpkgLayer {
+    pub fn is_features(&self) -> bool {
+        self.data_type == "features"
+    }
+}
+
+/// Entry of `gpkg_spatial_ref_sys`
+#[derive(Debug, Clone, PartialEq)]
+pub struct GpkgCrs {
+    pub srs_id: i32,
+    pub organization: String,
+    pub organization_coordsys_id: i32,
+    /// WKT
+    pub definition: String,
+}
+
+impl GpkgCrs {
+    /// Definition to create a `proj::Proj` with: `AUTHORITY:CODE` where
+    /// possible, the WKT definition otherwise.
+    pub fn proj_definition(&self) -> String {
+        if self.organization.eq_ignore_ascii_case("EPSG") {
+            format!("EPSG:{}", self.organization_coordsys_id)
+        } else {
+            self.definition.clone()
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GpkgFeature {
+    pub fid: i64,
+    /// Polygons are read as a multi-polygon with one member, a NULL geometry
+    /// as an empty one
+    pub geometry: MultiPolygon<f64>,
+    /// Attribute columns by name, BLOB columns excluded
+    pub attributes: Map<String, Value>,
+}
+
+/// Column of a features table
+#[derive(Debug, Clone)]
+struct Column {
+    name: String,
+    declared_type: String,
+}
+
+/// Geometry types a GeoPackage column can be declared with. Their values are
+/// geometry BLOBs, though e.g. `POINT` gets INTEGER affinity by SQLite's rules.
+const GEOMETRY_TYPE_NAMES: [&str; 16] = [
+    "GEOMETRY", "POINT", "CURVE", "LINESTRING", "SURFACE", "CURVEPOLYGON", "POLYGON", "GEOMCOLLECTION",
+    "MULTIPOINT", "MULTICURVE", "MULTILINESTRING", "MULTISURFACE", "MULTIPOLYGON", "CIRCULARSTRING",
+    "COMPOUNDCURVE", "GEOMETRYCOLLECTION",
+];
+
+/// Type affinity of a column, see https://www.sqlite.org/datatype3.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Affinity {
+    Integer,
+    Text,
+    Blob,
+    Real,
+    Numeric,
+}
+
+impl Column {
+    /// Whether the column holds attribute values, not geometries or other
+    /// binary data
+    fn is_attribute(&self) -> bool {
+        return !GEOMETRY_TYPE_NAMES.contains(&self.declared_type.as_str()) && !self.declared_type.contains("BLOB");
+    }
+
+    /// Affinity by SQLite's rules for the declared type, in their order
+    fn affinity(&self) -> Affinity {
+        let declared_type = self.declared_type.as_str();
+        if declared_type.contains("INT") {
+            return Affinity::Integer;
+        }
+        if ["CHAR", "CLOB", "TEXT"].iter().any(|name| declared_type.contains(name)) {
+            return Affinity::Text;
+        }
+        if declared_type.contains("BLOB") || declared_type.is_empty() {
+            return Affinity::Blob;
+        }
+        if ["REAL", "FLOA", "DOUB"].iter().any(|name| declared_type.contains(name)) {
+            return Affinity::Real;
+        }
+        return Affinity::Numeric;
+    }
+}
+
+/// Quote `name` as an SQL identifier.
+pub(super) fn quote_identifier(name: &str) -> String {
+    let mut quoted = String::with_capacity(name.len() + 2);
+    quoted.push('"');
+    quoted.push_str(&name.replace('"', "\"\""));
+    quoted.push('"');
+    return quoted;
+}
+
+/// Read-only connection to a GeoPackage.
+#[derive(Debug, Clone)]
+pub struct GpkgReader {
+    gpkg_pool: SqlitePool,
+}
+
+impl GpkgReader {
+    pub async fn open(path: &Path) -> Result<Self, GpkgError> {
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .read_only(true);
+        let gpkg_pool = SqlitePoolOptions::new()
+            .max_connections(5)
+            .connect_with(options)
+            .await?;
+        return Ok(GpkgReader { gpkg_pool });
+    }
+
+    /// All layers in `gpkg_contents`, sorted by table name.
+    pub async fn layers(&self) -> Result<Vec<GpkgLayer>, GpkgError> {
+        let rows: Vec<(String, String, Option<String>, Option<String>, Option<i32>, Option<f64>, Option<f64>, Option<f64>, Option<f64>)> =
+            sqlx::query_as(
+                "SELECT table_name, data_type, identifier, description, srs_id, min_x, min_y, max_x, max_y
+                FROM gpkg_contents ORDER BY table_name",
+            )
+            .fetch_all(&self.gpkg_pool)
+            .await?;
+        let layers = rows
+            .into_iter()
+            .map(|(table_name, data_type, identifier, description, srs_id, min_x, min_y, max_x, max_y)| {
+                let bbox = match (min_x, min_y, max_x, max_y) {
+                    (Some(min_x), Some(min_y), Some(max_x), Some(max_y)) => {
+                        Some(Rect::new(coord! { x: min_x, y: min_y }, coord! { x: max_x, y: max_y }))
+                    }
+                    _ => None,
+                };
+                GpkgLayer { table_name, data_type, identifier, description, srs_id, bbox }
+            })
+            .collect();
+        return Ok(layers);
+    }
+
+    /// The features layer `table_name`
+    pub async fn layer(&self, table_name: &str) -> Result<GpkgLayer, GpkgError> {
+        let layer = self
+            .layers()
+            .await?
+            .into_iter()
+            .find(|layer| layer.table_name == table_name)
+            .ok_or_else(|| GpkgError::UnknownLayer(table_name.to_owned()))?;
+        if !layer.is_features() {
+            return Err(GpkgError::NotFeatures(table_name.to_owned()));
+        }
+        return Ok(layer);
+    }
+
+    /// Geometry column name and `srs_id` of the features layer `table_name`
+    async fn geometry_column(&self, table_name: &str) -> Result<(String, i32), GpkgError> {
+        sqlx::query_as("SELECT column_name, srs_id FROM gpkg_geometry_columns WHERE table_name = ?")
+            .bind(table_name)
+            .fetch_optional(&self.gpkg_pool)
+            .await?
+            .ok_or_else(|| GpkgError::NotFeatures(table_name.to_owned()))
+    }
+
+    /// CRS of the geometries of the features layer `table_name`
+    pub async fn layer_crs(&self, table_name: &str) -> Result<GpkgCrs, GpkgError> {
+        let (_, srs_id) = self.geometry_column(table_name).await?;
+        let (organization, organization_coordsys_id, definition): (String, i32, String) = sqlx::query_as(
+            "SELECT organization, organization_coordsys_id, definition FROM gpkg_spatial_ref_sys WHERE srs_id = ?",
+        )
+        .bind(srs_id)
+        .fetch_optional(&self.gpkg_pool)
+        .await?
+        .ok_or_else(|| GpkgError::MissingCrs(table_name.to_owned()))?;
+        return Ok(GpkgCrs { srs_id, organization, organization_coordsys_id, definition });
+    }
+
+    async fn columns(&self, table_name: &str) -> Result<(String, Vec<Column>), GpkgError> {
+        let rows: Vec<(String, String, i64)> =
+            sqlx::query_as("SELECT name, type, pk FROM pragma_table_info(?) ORDER BY cid")
+                .bind(table_name)
+                .fetch_all(&self.gpkg_pool)
+                .await?;
+        let primary_key = rows
+            .iter()
+            .find(|(_, _, pk)| *pk == 1)
+            .map(|(name, ..)| name.clone())
+            .unwrap_or_else(|| "rowid".to_owned());
+        let columns = rows
+            .into_iter()
+            .filter(|(name, ..)| *name != primary_key)
+            .map(|(name, declared_type, _)| Column { name, declared_type: declared_type.to_uppercase() })
+            .collect();
+        return Ok((primary_key, columns));
+    }
+
+    async fn has_table(&self, table_name: &str) -> Result<bool, GpkgError> {
+        let row: Option<(i64,)> = sqlx::query_as("SELECT 1 FROM sqlite_master WHERE name = ?")
+            .bind(table_name)
+            .fetch_optional(&self.gpkg_pool)
+            .await?;
+        return Ok(row.is_some());
+    }
+
+    /// All features of the layer `table_name`, in the layer's CRS (see
+    /// `layer_crs`), ordered by feature id. With a `bbox` (in the same CRS),
+    /// only features whose envelope intersects it.
+    pub async fn read_features(
+        &self,
+        table_name: &str,
+        bbox: Option<Rect<f64>>,
+    ) -> Result<Vec<GpkgFeature>, GpkgError> {
+        self.layer(table_name).await?;
+        let (geometry_column, _) = self.geometry_column(table_name).await?;
+        let (primary_key, columns) = self.columns(table_name).await?;
+        let attribute_columns = columns
+            .into_iter()
+            .filter(|column| column.name != geometry_column && column.is_attribute())
+            .collect::<Vec<_>>();
+
+        let mut query = String::from("SELECT ");
+        query.push_str(&quote_identifier(&primary_key));
+        query.push_str(", ");
+        query.push_str(&quote_identifier(&geometry_column));
+        for column in &attribute_columns {
+            query.push_str(", ");
+            query.push_str(&quote_identifier(&column.name));
+        }
+        query.push_str(" FROM ");
+        query.push_str(&quote_identifier(table_name));
+
+        let rtree_table = ["rtree", table_name, &geometry_column].join("_");
+        let use_rtree = bbox.is_some() && self.has_table(&rtree_table).await?;
+        if use_rtree {
+            query.push_str(" WHERE ");
+            query.push_str(&quote_identifier(&primary_key));
+            query.push_str(" IN (SELECT id FROM ");
+            query.push_str(&quote_identifier(&rtree_table));
+            query.push_str(" WHERE minx <= ? AND maxx >= ? AND miny <= ? AND maxy >= ?)");
+        }
+        query.push_str(" ORDER BY ");
+        query.push_str(&quote_identifier(&primary_key));
+
+        let mut rows_query = sqlx::query(&query);
+        if use_rtree && let Some(bbox) = bbox {
+            rows_query = rows_query
+                .bind(bbox.max().x)
+                .bind(bbox.min().x)
+                .bind(bbox.max().y)
+                .bind(bbox.min().y);
+        }
+        let rows = rows_query.fetch_all(&self.gpkg_pool).await?;
+
+        let mut features = Vec::with_capacity(rows.len());
+        for row in rows {
+            let feature = row_to_feature(table_name, &row, &attribute_columns)?;
+            // Without an index, filter here
+            if use_rtree || envelope_intersects(&feature.geometry, bbox) {
+                features.push(feature);
+            }
+        }
+        return Ok(features);
+    }
+
+    pub async fn close(self) {
+        self.gpkg_pool.close().await;
+    }
+}
+
+/// Features layer of a GeoPackage
+#[derive(Debug, Clone)]
+pub struct GpkgSource {
+    path: PathBuf,
+    /// Without a layer, the GeoPackage must have a single features layer
+    layer: Option<String>,
+}
+
+impl GpkgSource {
+    pub fn new(path: impl Into<PathBuf>, layer: Option<String>) -> Self {
+        GpkgSource { path: path.into(), layer }
+    }
+
+    async fn layer_name(&self, reader: &GpkgReader) -> Result<String, Box<dyn Error + Send + Sync>> {
+        if let Some(layer) = &self.layer {
+            return Ok(layer.clone());
+        }
+        let mut features_layers = reader.layers().await?;
+        features_layers.retain(GpkgLayer::is_features);
+        match &features_layers[..] {
+            [layer] => Ok(layer.table_name.clone()),
+            _ => Err(format!(
+                "{} has {} features layers, specify one",
+                self.path.display(),
+                features_layers.len()
+            )
+            .into()),
+        }
+    }
+}
+
+#[async_trait]
+impl FeatureSource for GpkgSource {
+    fn describe(&self) -> String {
+        match &self.layer {
+            Some(layer) => format!("GeoPackage {} layer {}", self.path.display(), layer),
+            None => format!("GeoPackage {}", self.path.display()),
+        }
+    }
+
+    async fn crs(&self) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        let reader = GpkgReader::open(&self.path).await?;
+        let layer = self.layer_name(&reader).await?;
+        let crs = reader.layer_crs(&layer).await?;
+        reader.close().await;
+        // srs_id -1 and 0 are the undefined cartesian and geographic systems
+        return Ok(if crs.srs_id > 0 { Some(crs.proj_definition()) } else { None });
+    }
+
+    async fn features(
+        &self,
+        bbox: Option<Rect<f64>>,
+    ) -> Result<Vec<SourceFeature>, Box<dyn Error + Send + Sync>> {
+        let reader = GpkgReader::open(&self.path).await?;
+        let layer = self.layer_name(&reader).await?;
+        let features = reader.read_features(&layer, bbox).await?;
+        reader.close().await;
+        return Ok(features
+            .into_iter()
+            .map(|feature| SourceFeature { geometry: feature.geometry, attributes: feature.attributes })
+            .collect());
+    }
+}
+
+fn row_to_feature(table_name: &str, row: &SqliteRow, attribute_columns: &[Column]) -> Result<GpkgFeature, GpkgError> {
+    let fid: i64 = row.try_get(0)?;
+    let geometry: wkb::Decode<Geometry<f64>> = row.try_get(1)?;
+    let mut geometry = match geometry.geometry {
+        Some(Geometry::MultiPolygon(multi_polygon)) => multi_polygon,
+        Some(Geometry::Polygon(polygon)) => MultiPolygon(vec![polygon]),
+        None => MultiPolygon(vec![]),
+        Some(other) => {
+            return Err(GpkgError::UnsupportedGeometry {
+                layer: table_name.to_owned(),
+                fid,
+                geometry_type: geometry_type_name(&other).to_owned(),
+            })
+        }
+    };
+    ensure_sfa_winding(&mut geometry);
+
+    let mut attributes = Map::new();
+    for (i, column) in attribute_columns.iter().enumerate() {
+        let index = i + 2;
+        let value = if row.try_get_raw(index)?.is_null() {
+            Value::Null
+        } else if column.declared_type == "BOOLEAN" {
+            Value::Bool(row.try_get::<i64, _>(index)? != 0)
+        } else {
+            match column.affinity() {
+                Affinity::Integer => Value::from(row.try_get::<i64, _>(index)?),
+                Affinity::Real => Value::from(row.try_get::<f64, _>(index)?),
+                Affinity::Text => Value::from(row.try_get::<String, _>(index)?),
+                // Any storage class, e.g. DATE and DATETIME are stored as text
+                Affinity::Numeric | Affinity::Blob => row
+                    .try_get::<i64, _>(index)
+                    .map(Value::from)
+                    .or_else(|_| row.try_get::<f64, _>(index).map(Value::from))
+                    .or_else(|_| row.try_get::<String, _>(index).map(Value::from))?,
+            }
+        };
+        attributes.insert(column.name.clone(), value);
+    }
+
+    return Ok(GpkgFeature { fid, geometry, attributes });
+}
+
+fn geometry_type_name(geometry: &Geometry<f64>) -> &'static str {
+    match geometry {
+        Geometry::Point(_) => "Point",
+        Geometry::Line(_) => "Line",
+        Geometry::LineString(_) => "LineString",
+        Geometry::Polygon(_) => "Polygon",
+        Geometry::MultiPoint(_) => "MultiPoint",
+        Geometry::MultiLineString(_) => "MultiLineString",
+        Geometry::MultiPolygon(_) => "MultiPolygon",
+        Geometry::GeometryCollection(_) => "GeometryCollection",
+        Geometry::Rect(_) => "Rect",
+        Geometry::Triangle(_) => "Triangle",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use geo::{coord, polygon, Geometry, LineString, MultiPolygon, Point, Rect};
+    use geozero::wkb;
+    use serde_json::Value;
+
+    use crate::{
+        crs::InternalCrs,
+        geo_io::{ensure_gpkg_srs, open_gpkg_for_writing, register_gpkg_features_table},
+        test_util::temp_path,
+    };
+
+    use super::{GpkgError, GpkgReader};
+
+    /// GeoPackage with a `buildings` layer of a polygon, a multi-polygon and a
+    /// far away polygon, with an R-tree index if `with_rtree`.
+    async fn create_test_gpkg(name: &str, with_rtree: bool) -> std::path::PathBuf {
+        let path = temp_path(name);
+        let gpkg_pool = open_gpkg_for_writing(&path).await.unwrap();
+        sqlx::query(
+            "CREATE TABLE buildings (
+                fid INTEGER PRIMARY KEY AUTOINCREMENT,
+                geom GEOMETRY,
+                name TEXT,
+                height REAL,
+                floors INTEGER,
+                listed BOOLEAN
+            )",
+        )
+        .execute(&gpkg_pool)
+        .await
+        .unwrap();
+        let square = polygon![(x: 0., y: 0.), (x: 10., y: 0.), (x: 10., y: 10.), (x: 0., y: 10.)];
+        let far_square = polygon![(x: 1000., y: 1000.), (x: 1010., y: 1000.), (x: 1010., y: 1010.), (x: 1000., y: 1010.)];
+        let rows = [
+            (Geometry::Polygon(square.clone()), Some("house"), 8.5, 2, true),
+            (Geometry::MultiPolygon(MultiPolygon(vec![square.clone()])), None, 20., 6, false),
+            (Geometry::Polygon(far_square), Some("farm"), 5., 1, false),
+        ];
+        for (geometry, name, height, floors, listed) in rows {
+            sqlx::query("INSERT INTO buildings (geom, name, height, floors, listed) VALUES (?, ?, ?, ?, ?)")
+                .bind(wkb::Encode(geometry))
+                .bind(name)
+                .bind(height)
+                .bind(floors)
+                .bind(listed)
+                .execute(&gpkg_pool)
+                .await
+                .unwrap();
+        }
+        if with_rtree {
+            sqlx::query("CREATE VIRTUAL TABLE rtree_buildings_geom USING rtree(id, minx, maxx, miny, maxy)")
+                .execute(&gpkg_pool)
+                .await
+                .unwrap();
+            sqlx::query(
+                "INSERT INTO rtree_buildings_geom VALUES (1, 0, 10, 0, 10), (2, 0, 10, 0, 10), (3, 1000, 1010, 1000, 1010)",
+            )
+            .execute(&gpkg_pool)
+            .await
+            .unwrap();
+        }
+        let srs_id = ensure_gpkg_srs(&gpkg_pool, &InternalCrs::EtrsLaea).await.unwrap();
+        register_gpkg_features_table(&gpkg_pool, "buildings", "GEOMETRY", srs_id, None)
+            .await
+            .unwrap();
+        gpkg_pool.close().await;
+        path
+    }
+
+    fn near_origin() -> Rect<f64> {
+        Rect::new(coord! { x: -5., y: -5. }, coord! { x: 5., y: 5. })
+    }
+
+    #[tokio::test]
+    async fn layers__lists_gpkg_contents() {
+        let path = create_test_gpkg("gpkg-reader-layers.gpkg", false).await;
+        let reader = GpkgReader::open(&path).await.unwrap();
+        let layers = reader.layers().await.unwrap();
+        assert_eq!(layers.len(), 1);
+        assert_eq!(layers[0].table_name, "buildings");
+        assert!(layers[0].is_features());
+        assert_eq!(layers[0].srs_id, Some(3035));
+    }
+
+    #[tokio::test]
+    async fn layer_crs__from_spatial_ref_sys() {
+        let path = create_test_gpkg("gpkg-reader-crs.gpkg", false).await;
+        let reader = GpkgReader::open(&path).await.unwrap();
+        let crs = reader.layer_crs("buildings").await.unwrap();
+        assert_eq!(crs.proj_definition(), "EPSG:3035");
+    }
+
+    #[tokio::test]
+    async fn read_features__all_rows_and_attributes() {
+        let path = create_test_gpkg("gpkg-reader-features.gpkg", false).await;
+        let reader = GpkgReader::open(&path).await.unwrap();
+        let features = reader.read_features("buildings", None).await.unwrap();
+        assert_eq!(features.iter().map(|feature| feature.fid).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(features[0].geometry, features[1].geometry);
+        assert_eq!(features[0].attributes["name"], Value::from("house"));
+        assert_eq!(features[0].attributes["height"], Value::from(8.5));
+        assert_eq!(features[0].attributes["floors"], Value::from(2));
+        assert_eq!(features[0].attributes["listed"], Value::Bool(true));
+        assert_eq!(features[1].attributes["name"], Value::Null);
+    }
+
+    #[tokio::test]
+    async fn read_features__column_affinities() {
+        let path = temp_path("gpkg-reader-affinities.gpkg");
+        let gpkg_pool = open_gpkg_for_writing(&path).await.unwrap();
+        sqlx::query(
+            "CREATE TABLE parcels (
+                fid INTEGER PRIMARY KEY AUTOINCREMENT,
+                geom MULTIPOLYGON,
+                entrance POINT,
+                storeys MEDIUMINT,
+                label VARCHAR(20),
+                area DOUBLE PRECISION,
+                surveyed DATE,
+                thumbnail BLOB
+            )",
+        )
+        .execute(&gpkg_pool)
+        .await
+        .unwrap();
+        let square = polygon![(x: 0., y: 0.), (x: 10., y: 0.), (x: 10., y: 10.), (x: 0., y: 10.)];
+        sqlx::query(
+            "INSERT INTO parcels (geom, entrance, storeys, label, area, surveyed, thumbnail)
+                VALUES (?, ?, 3, 'A1', 100.5, '2022-05-01', x'00ff')",
+        )
+        .bind(wkb::Encode(Geometry::MultiPolygon(MultiPolygon(vec![square]))))
+        .bind(wkb::Encode(Geometry::Point(Point::new(5., 0.))))
+        .execute(&gpkg_pool)
+        .await
+        .unwrap();
+        let srs_id = ensure_gpkg_srs(&gpkg_pool, &InternalCrs::EtrsLaea).await.unwrap();
+        register_gpkg_features_table(&gpkg_pool, "parcels", "MULTIPOLYGON", srs_id, None)
+            .await
+            .unwrap();
+        gpkg_pool.close().await;
+
+        let reader = GpkgReader::open(&path).await.unwrap();
+        let features = reader.read_features("parcels", None).await.unwrap();
+        let attributes = &features[0].attributes;
+        assert!(!attributes.contains_key("entrance"));
+        assert!(!attributes.contains_key("thumbnail"));
+        assert_eq!(attributes["storeys"], Value::from(3));
+        assert_eq!(attributes["label"], Value::from("A1"));
+        assert_eq!(attributes["area"], Value::from(100.5));
+        assert_eq!(attributes["surveyed"], Value::from("2022-05-01"));
+    }
+
+    #[tokio::test]
+    async fn read_features__bbox_with_rtree() {
+        let path = create_test_gpkg("gpkg-reader-rtree.gpkg", true).await;
+        let reader = GpkgReader::open(&path).await.unwrap();
+        let features = reader.read_features("buildings", Some(near_origin())).await.unwrap();
+        assert_eq!(features.iter().map(|feature| feature.fid).collect::<Vec<_>>(), vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn read_features__bbox_without_rtree() {
+        let path = create_test_gpkg("gpkg-reader-no-rtree.gpkg", false).await;
+        let reader = GpkgReader::open(&path).await.unwrap();
+        let features = reader.read_features("buildings", Some(near_origin())).await.unwrap();
+        assert_eq!(features.iter().map(|feature| feature.fid).collect::<Vec<_>>(), vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn read_features__rejects_lines() {
+        let path = create_test_gpkg("gpkg-reader-lines.gpkg", false).await;
+        let gpkg_pool = open_gpkg_for_writing(&path).await.unwrap();
+        let line = LineString::from(vec![(0., 0.), (1., 1.)]);
+        sqlx::query("INSERT INTO buildings (geom) VALUES (?)")
+            .bind(wkb::Encode(Geometry::LineString(line)))
+            .execute(&gpkg_pool)
+            .await
+            .unwrap();
+        gpkg_pool.close().await;
+
+        let reader = GpkgReader::open(&path).await.unwrap();
+        let result = reader.read_features("buildings", None).await;
+        assert!(matches!(result, Err(GpkgError::UnsupportedGeometry { fid: 4, .. })));
+    }
+
+    #[tokio::test]
+    async fn read_features__unknown_layer() {
+        let path = create_test_gpkg("gpkg-reader-unknown.gpkg", false).await;
+        let reader = GpkgReader::open(&path).await.unwrap();
+        let result = reader.read_features("buildings\"; DROP TABLE buildings; --", None).await;
+        assert!(matches!(result, Err(GpkgError::UnknownLayer(_))));
+    }
+}
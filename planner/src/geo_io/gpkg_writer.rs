@@ -0,0 +1,445 @@
+//! Writing planner inputs and results (imported features, nav graphs,
+//! planned legs) to a GeoPackage, to inspect them in e.g. QGIS next to each
+//! other
+
+use std::{error::Error, path::Path};
+
+use geo::{prelude::BoundingRect, Geometry, Line, MultiPoint, MultiLineString, MultiPolygon, Point};
+use geozero::wkb;
+use petgraph::visit::EdgeRef;
+use serde_json::Value;
+use sqlx::{Sqlite, Transaction};
+
+use crate::{
+    crs::InternalCrs,
+    nav_graph::{NavGraph, NodeData, PlannedLeg},
+};
+
+use super::{
+    check_gpkg_table_name, ensure_gpkg_srs, feature_source::SourceFeature, gpkg_reader::quote_identifier,
+    open_gpkg_for_writing, register_gpkg_features_table,
+};
+
+/// Replace the features table `table_name` with an empty one with a `geom`
+/// column of `geometry_type` and `attribute_columns` (name, SQL type).
+async fn replace_features_table(
+    tx: &mut Transaction<'_, Sqlite>,
+    table_name: &str,
+    geometry_type: &str,
+    attribute_columns: &[(&str, &str)],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    check_gpkg_table_name(table_name)?;
+    sqlx::query(&format!("DROP TABLE IF EXISTS \"{}\"", table_name))
+        .execute(&mut *tx)
+        .await?;
+    let columns = attribute_columns
+        .iter()
+        .map(|(name, sql_type)| format!(", {} {}", quote_identifier(name), sql_type))
+        .collect::<String>();
+    sqlx::query(&format!(
+        "CREATE TABLE \"{}\" (fid INTEGER PRIMARY KEY AUTOINCREMENT, geom {} NOT NULL{})",
+        table_name, geometry_type, columns
+    ))
+    .execute(&mut *tx)
+    .await?;
+    return Ok(());
+}
+
+fn node_kind(node_data: &NodeData) -> &'static str {
+    match node_data {
+        NodeData::PartOfWater(_) => "water",
+        NodeData::PartOfObstacle(_) => "obstacle",
+        NodeData::Arbitrary(_) => "arbitrary",
+    }
+}
+
+/// Write the vertices and edges of `nav_graph` to the features tables
+/// `<name>-nodes` and `<name>-edges` in the GeoPackage at `path`, replacing
+/// existing ones, in the nav graph's CRS.
+pub async fn write_gpkg_nav_graph(
+    path: &Path,
+    name: &str,
+    nav_graph: &NavGraph,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let nodes_table = format!("{}-nodes", name);
+    let edges_table = format!("{}-edges", name);
+    let graph = &nav_graph.graph;
+    let features = &nav_graph.features;
+
+    let gpkg_pool = open_gpkg_for_writing(path).await?;
+    let mut tx = gpkg_pool.begin().await?;
+    replace_features_table(
+        &mut tx,
+        &nodes_table,
+        "POINT",
+        &[("node_index", "INTEGER NOT NULL"), ("kind", "TEXT NOT NULL"), ("degree", "INTEGER NOT NULL")],
+    )
+    .await?;
+    for node_index in graph.node_indices() {
+        let node_data = graph.node_weight(node_index).unwrap();
+        sqlx::query(&format!(
+            "INSERT INTO \"{}\" (geom, node_index, kind, degree) VALUES (?, ?, ?, ?)",
+            nodes_table
+        ))
+        .bind(wkb::Encode(Geometry::Point(Point(features.coord(node_data)))))
+        .bind(node_index.index() as i64)
+        .bind(node_kind(node_data))
+        .bind(graph.edges(node_index).count() as i64)
+        .execute(&mut tx)
+        .await?;
+    }
+
+    replace_features_table(
+        &mut tx,
+        &edges_table,
+        "LINESTRING",
+        &[
+            ("from_node", "INTEGER NOT NULL"),
+            ("to_node", "INTEGER NOT NULL"),
+            ("length", "DOUBLE NOT NULL"),
+            ("cost", "DOUBLE NOT NULL"),
+        ],
+    )
+    .await?;
+    for edge in graph.edge_references() {
+        let line = Line::new(
+            features.coord(graph.node_weight(edge.source()).unwrap()),
+            features.coord(graph.node_weight(edge.target()).unwrap()),
+        );
+        sqlx::query(&format!(
+            "INSERT INTO \"{}\" (geom, from_node, to_node, length, cost) VALUES (?, ?, ?, ?, ?)",
+            edges_table
+        ))
+        .bind(wkb::Encode(Geometry::LineString(line.into())))
+        .bind(edge.source().index() as i64)
+        .bind(edge.target().index() as i64)
+        .bind(edge.weight().length)
+        .bind(edge.weight().cost)
+        .execute(&mut tx)
+        .await?;
+    }
+    tx.commit().await?;
+
+    let nodes_bbox = MultiPoint::from_iter(graph.node_weights().map(|node_data| Point(features.coord(node_data))))
+        .bounding_rect();
+    let srs_id = ensure_gpkg_srs(&gpkg_pool, &nav_graph.crs).await?;
+    register_gpkg_features_table(&gpkg_pool, &nodes_table, "POINT", srs_id, nodes_bbox).await?;
+    // Every edge is between two nodes
+    register_gpkg_features_table(&gpkg_pool, &edges_table, "LINESTRING", srs_id, nodes_bbox).await?;
+    gpkg_pool.close().await;
+    return Ok(());
+}
+
+/// SQL type for the attribute values of a column, from the first non-null
+/// value
+fn attribute_sql_type<'a>(mut values: impl Iterator<Item = &'a Value>) -> &'static str {
+    match values.find(|value| !value.is_null()) {
+        Some(Value::Bool(_)) => "BOOLEAN",
+        Some(Value::Number(number)) if number.is_i64() => "INTEGER",
+        Some(Value::Number(_)) => "DOUBLE",
+        _ => "TEXT",
+    }
+}
+
+/// Write `features` to the features table `table_name` in the GeoPackage at
+/// `path` (in `crs`), replacing an existing one. Every attribute key becomes
+/// a column; nested values are stored as JSON text.
+pub async fn write_gpkg_features(
+    path: &Path,
+    table_name: &str,
+    features: &[SourceFeature],
+    crs: &InternalCrs,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut column_names = features
+        .iter()
+        .flat_map(|feature| feature.attributes.keys().cloned())
+        .collect::<Vec<_>>();
+    column_names.sort();
+    column_names.dedup();
+    let columns = column_names
+        .iter()
+        .map(|name| {
+            let values = features.iter().filter_map(|feature| feature.attributes.get(name));
+            (name.as_str(), attribute_sql_type(values))
+        })
+        .collect::<Vec<_>>();
+
+    let gpkg_pool = open_gpkg_for_writing(path).await?;
+    let mut tx = gpkg_pool.begin().await?;
+    replace_features_table(&mut tx, table_name, "MULTIPOLYGON", &columns).await?;
+    let mut insert = String::from("INSERT INTO ");
+    insert.push_str(&quote_identifier(table_name));
+    insert.push_str(" (geom");
+    for name in &column_names {
+        insert.push_str(", ");
+        insert.push_str(&quote_identifier(name));
+    }
+    insert.push_str(") VALUES (?");
+    insert.push_str(&", ?".repeat(column_names.len()));
+    insert.push(')');
+    for feature in features {
+        let mut query = sqlx::query(&insert).bind(wkb::Encode(Geometry::MultiPolygon(feature.geometry.clone())));
+        for name in &column_names {
+            query = match feature.attributes.get(name) {
+                None | Some(Value::Null) => query.bind(None::<String>),
+                Some(Value::Bool(value)) => query.bind(*value),
+                Some(Value::Number(number)) if number.is_i64() => query.bind(number.as_i64()),
+                Some(Value::Number(number)) => query.bind(number.as_f64()),
+                Some(Value::String(value)) => query.bind(value.clone()),
+                Some(value) => query.bind(value.to_string()),
+            };
+        }
+        query.execute(&mut tx).await?;
+    }
+    tx.commit().await?;
+
+    let bbox = MultiPolygon::from_iter(features.iter().flat_map(|feature| feature.geometry.0.iter().cloned()))
+        .bounding_rect();
+    let srs_id = ensure_gpkg_srs(&gpkg_pool, crs).await?;
+    register_gpkg_features_table(&gpkg_pool, table_name, "MULTIPOLYGON", srs_id, bbox).await?;
+    gpkg_pool.close().await;
+    return Ok(());
+}
+
+/// Write planned `legs` to the features tables `<name>-legs` (one line per
+/// leg) and `<name>-recharge-points` in the GeoPackage at `path`, replacing
+/// existing ones. The legs are in `crs`, the CRS of the nav graph they were
+/// planned on.
+pub async fn write_gpkg_plan(
+    path: &Path,
+    name: &str,
+    legs: &[PlannedLeg],
+    crs: &InternalCrs,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let legs_table = format!("{}-legs", name);
+    let recharge_points_table = format!("{}-recharge-points", name);
+
+    let gpkg_pool = open_gpkg_for_writing(path).await?;
+    let mut tx = gpkg_pool.begin().await?;
+    replace_features_table(
+        &mut tx,
+        &legs_table,
+        "LINESTRING",
+        &[
+            ("leg_index", "INTEGER NOT NULL"),
+            ("length", "DOUBLE NOT NULL"),
+            ("geodesic_length", "DOUBLE NOT NULL"),
+            ("distortion", "DOUBLE NOT NULL"),
+            ("cost", "DOUBLE NOT NULL"),
+        ],
+    )
+    .await?;
+    replace_features_table(&mut tx, &recharge_points_table, "POINT", &[("leg_index", "INTEGER NOT NULL")]).await?;
+    for (leg_index, leg) in legs.iter().enumerate() {
+        sqlx::query(&format!(
+            "INSERT INTO \"{}\" (geom, leg_index, length, geodesic_length, distortion, cost) VALUES (?, ?, ?, ?, ?, ?)",
+            legs_table
+        ))
+        .bind(wkb::Encode(Geometry::LineString(leg.path.clone())))
+        .bind(leg_index as i64)
+        .bind(leg.length)
+        .bind(leg.geodesic_length)
+        .bind(leg.distortion())
+        .bind(leg.cost)
+        .execute(&mut tx)
+        .await?;
+        if let Some(recharge_point) = leg.recharge_point {
+            sqlx::query(&format!(
+                "INSERT INTO \"{}\" (geom, leg_index) VALUES (?, ?)",
+                recharge_points_table
+            ))
+            .bind(wkb::Encode(Geometry::Point(Point(recharge_point))))
+            .bind(leg_index as i64)
+            .execute(&mut tx)
+            .await?;
+        }
+    }
+    tx.commit().await?;
+
+    let bbox = MultiLineString(legs.iter().map(|leg| leg.path.clone()).collect()).bounding_rect();
+    let srs_id = ensure_gpkg_srs(&gpkg_pool, crs).await?;
+    register_gpkg_features_table(&gpkg_pool, &legs_table, "LINESTRING", srs_id, bbox).await?;
+    register_gpkg_features_table(&gpkg_pool, &recharge_points_table, "POINT", srs_id, bbox).await?;
+    gpkg_pool.close().await;
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use std::path::Path;
+
+    use geo::{coord, line_string, polygon, Geometry, LineString, MultiPolygon, Point};
+    use geozero::wkb;
+    use petgraph::graph::NodeIndex;
+    use serde_json::Value;
+    use sqlx::{sqlite::SqlitePoolOptions, SqlitePool};
+
+    use crate::{
+        crs::InternalCrs,
+        geo_io::{Attributes, GpkgReader, SourceFeature},
+        nav_graph::{create_nav_graph, graph_types::Features, PlannedLeg, VisibilityOptimizationMode},
+        test_util::temp_path,
+    };
+
+    use super::{write_gpkg_features, write_gpkg_nav_graph, write_gpkg_plan};
+
+    async fn open_gpkg(path: &Path) -> SqlitePool {
+        SqlitePoolOptions::new()
+            .connect(&format!("sqlite://{}", path.display()))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn write_gpkg_features__attributes_roundtrip() {
+        let mut attributes = Attributes::new();
+        attributes.insert("name".to_owned(), Value::from("Lake"));
+        attributes.insert("depth".to_owned(), Value::from(3));
+        let features = vec![
+            SourceFeature {
+                geometry: MultiPolygon(vec![polygon![(x: 0., y: 0.), (x: 10., y: 0.), (x: 10., y: 10.)]]),
+                attributes,
+            },
+            SourceFeature {
+                geometry: MultiPolygon(vec![polygon![(x: 20., y: 0.), (x: 30., y: 0.), (x: 30., y: 10.)]]),
+                attributes: Attributes::new(),
+            },
+        ];
+        let path = temp_path("gpkg-writer-features.gpkg");
+        write_gpkg_features(&path, "waters", &features, &InternalCrs::EtrsLaea).await.unwrap();
+
+        let reader = GpkgReader::open(&path).await.unwrap();
+        let read = reader.read_features("waters", None).await.unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[0].geometry, features[0].geometry);
+        assert_eq!(read[1].geometry, features[1].geometry);
+        assert_eq!(read[0].attributes["name"], Value::from("Lake"));
+        assert_eq!(read[0].attributes["depth"], Value::from(3));
+        assert_eq!(read[1].attributes["name"], Value::Null);
+    }
+
+    #[tokio::test]
+    async fn write_gpkg_nav_graph__nodes_and_edges() {
+        let features = Features {
+            obstacles: MultiPolygon(vec![polygon![
+                (x: 0., y: 0.), (x: 10., y: 0.), (x: 10., y: 10.), (x: 0., y: 10.)
+            ]]),
+            waters: MultiPolygon(vec![]),
+            penalized_areas: Vec::new(),
+            arbitrary: Vec::new(),
+        };
+        let (nav_graph, _) = create_nav_graph(&features, &InternalCrs::EtrsLaea, None, VisibilityOptimizationMode::Sweep, None);
+        let path = temp_path("gpkg-writer-nav-graph.gpkg");
+        write_gpkg_nav_graph(&path, "graph", &nav_graph).await.unwrap();
+
+        let reader = GpkgReader::open(&path).await.unwrap();
+        let layer_names = reader.layers().await.unwrap().into_iter().map(|layer| layer.table_name).collect::<Vec<_>>();
+        assert_eq!(layer_names, vec!["graph-edges", "graph-nodes"]);
+
+        let gpkg_pool = open_gpkg(&path).await;
+        let nodes: Vec<(wkb::Decode<Geometry<f64>>, i64, String, i64)> =
+            sqlx::query_as("SELECT geom, node_index, kind, degree FROM \"graph-nodes\" ORDER BY fid")
+                .fetch_all(&gpkg_pool)
+                .await
+                .unwrap();
+        assert_eq!(nodes.len(), nav_graph.graph.node_count());
+        for (geom, node_index, kind, degree) in nodes {
+            let node_index = NodeIndex::new(node_index as usize);
+            let node_data = nav_graph.graph.node_weight(node_index).unwrap();
+            let coord = nav_graph.features.coord(node_data);
+            assert_eq!(geom.geometry, Some(Geometry::Point(Point(coord))));
+            assert_eq!(kind, "obstacle");
+            assert_eq!(degree, nav_graph.graph.edges(node_index).count() as i64);
+        }
+
+        let edges: Vec<(wkb::Decode<Geometry<f64>>, i64, i64, f64, f64)> =
+            sqlx::query_as("SELECT geom, from_node, to_node, length, cost FROM \"graph-edges\" ORDER BY fid")
+                .fetch_all(&gpkg_pool)
+                .await
+                .unwrap();
+        assert_eq!(edges.len(), nav_graph.graph.edge_count());
+        for (geom, from_node, to_node, length, cost) in edges {
+            let from_node = NodeIndex::new(from_node as usize);
+            let to_node = NodeIndex::new(to_node as usize);
+            let edge = nav_graph.graph.find_edge(from_node, to_node).unwrap();
+            let edge_weight = nav_graph.graph.edge_weight(edge).unwrap();
+            assert_eq!(length, edge_weight.length);
+            assert_eq!(cost, edge_weight.cost);
+            let from = nav_graph.features.coord(nav_graph.graph.node_weight(from_node).unwrap());
+            let to = nav_graph.features.coord(nav_graph.graph.node_weight(to_node).unwrap());
+            assert_eq!(geom.geometry, Some(Geometry::LineString(LineString(vec![from, to]))));
+        }
+    }
+
+    #[tokio::test]
+    async fn write_gpkg_plan__legs_and_recharge_points() {
+        let legs = vec![
+            PlannedLeg {
+                path: line_string![(x: 0., y: 0.), (x: 100., y: 0.)],
+                length: 100.,
+                geodesic_length: 100.1,
+                cost: 100.,
+                recharge_point: Some(coord! { x: 100., y: 0. }),
+            },
+            PlannedLeg {
+                path: line_string![(x: 100., y: 0.), (x: 100., y: 50.)],
+                length: 50.,
+                geodesic_length: 50.05,
+                cost: 75.,
+                recharge_point: None,
+            },
+        ];
+        let path = temp_path("gpkg-writer-plan.gpkg");
+        write_gpkg_plan(&path, "plan", &legs, &InternalCrs::EtrsLaea).await.unwrap();
+
+        let gpkg_pool = open_gpkg(&path).await;
+        let read_legs: Vec<(wkb::Decode<Geometry<f64>>, i64, f64, f64, f64, f64)> = sqlx::query_as(
+            "SELECT geom, leg_index, length, geodesic_length, distortion, cost FROM \"plan-legs\" ORDER BY fid",
+        )
+        .fetch_all(&gpkg_pool)
+        .await
+        .unwrap();
+        assert_eq!(read_legs.len(), 2);
+        for (leg_index, (geom, read_leg_index, length, geodesic_length, distortion, cost)) in
+            read_legs.into_iter().enumerate()
+        {
+            let leg = &legs[leg_index];
+            assert_eq!(geom.geometry, Some(Geometry::LineString(leg.path.clone())));
+            assert_eq!(read_leg_index, leg_index as i64);
+            assert_eq!(length, leg.length);
+            assert_eq!(geodesic_length, leg.geodesic_length);
+            assert_eq!(distortion, leg.distortion());
+            assert_eq!(cost, leg.cost);
+        }
+
+        let recharge_points: Vec<(wkb::Decode<Geometry<f64>>, i64)> =
+            sqlx::query_as("SELECT geom, leg_index FROM \"plan-recharge-points\" ORDER BY fid")
+                .fetch_all(&gpkg_pool)
+                .await
+                .unwrap();
+        assert_eq!(recharge_points.len(), 1);
+        assert_eq!(recharge_points[0].0.geometry, Some(Geometry::Point(Point(coord! { x: 100., y: 0. }))));
+        assert_eq!(recharge_points[0].1, 0);
+    }
+
+    #[tokio::test]
+    async fn write_gpkg_features__local_crs() {
+        let crs = InternalCrs::LocalLaea { center: coord! { x: -93.0, y: 44.0 } };
+        let features = vec![SourceFeature {
+            geometry: MultiPolygon(vec![polygon![(x: 0., y: 0.), (x: 10., y: 0.), (x: 10., y: 10.)]]),
+            attributes: Attributes::new(),
+        }];
+        let path = temp_path("gpkg-writer-local-crs.gpkg");
+        write_gpkg_features(&path, "waters", &features, &crs).await.unwrap();
+        write_gpkg_features(&path, "more-waters", &features, &crs).await.unwrap();
+
+        let reader = GpkgReader::open(&path).await.unwrap();
+        let layer_crs = reader.layer_crs("waters").await.unwrap();
+        assert!(layer_crs.srs_id >= 100000);
+        assert_eq!(layer_crs.proj_definition(), crs.wkt());
+        // Registered once
+        assert_eq!(reader.layer_crs("more-waters").await.unwrap().srs_id, layer_crs.srs_id);
+    }
+}
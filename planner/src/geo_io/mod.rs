@@ -0,0 +1,225 @@
+//! I/O operations for geographic data (geopackage, shapefile...)
+
+mod feature_source;
+mod flatgeobuf_source;
+mod geojson_source;
+mod gpkg_reader;
+mod gpkg_writer;
+mod shapefile_source;
+
+use std::{error::Error, path::Path};
+
+use geo::{prelude::BoundingRect, Geometry, MultiPolygon, Rect};
+use geozero::wkb;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
+
+use crate::crs::InternalCrs;
+
+pub use feature_source::{
+    envelope_intersects, feature_source_for_path, features_bounding_rect, load_features,
+    load_file_multi_polygon, load_multi_polygon, Attributes, FeatureSource, SourceFeature,
+};
+pub use gpkg_reader::{GpkgCrs, GpkgError, GpkgFeature, GpkgLayer, GpkgReader, GpkgSource};
+pub use gpkg_writer::{write_gpkg_features, write_gpkg_nav_graph, write_gpkg_plan};
+pub use shapefile_source::{read_zipped_shapefile, ZippedShapefile};
+
+/// First `srs_id` for systems without an EPSG code, well above the EPSG range
+const GPKG_CUSTOM_SRS_ID_START: i32 = 100000;
+
+/// Mandatory GeoPackage metadata tables (OGC 12-128r18, tables 2.1 and 2.2)
+/// and the mandatory spatial reference systems. The ones we write in are
+/// added by `ensure_gpkg_srs`.
+const GPKG_BASE_SCHEMA: [&str; 5] = [
+    "PRAGMA application_id = 1196444487", // 'GPKG'
+    "CREATE TABLE IF NOT EXISTS gpkg_spatial_ref_sys (
+        srs_name TEXT NOT NULL,
+        srs_id INTEGER PRIMARY KEY,
+        organization TEXT NOT NULL,
+        organization_coordsys_id INTEGER NOT NULL,
+        definition TEXT NOT NULL,
+        description TEXT
+    )",
+    "CREATE TABLE IF NOT EXISTS gpkg_contents (
+        table_name TEXT NOT NULL PRIMARY KEY,
+        data_type TEXT NOT NULL,
+        identifier TEXT UNIQUE,
+        description TEXT DEFAULT '',
+        last_change DATETIME NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ','now')),
+        min_x DOUBLE,
+        min_y DOUBLE,
+        max_x DOUBLE,
+        max_y DOUBLE,
+        srs_id INTEGER,
+        CONSTRAINT fk_gc_r_srs_id FOREIGN KEY (srs_id) REFERENCES gpkg_spatial_ref_sys(srs_id)
+    )",
+    "CREATE TABLE IF NOT EXISTS gpkg_geometry_columns (
+        table_name TEXT NOT NULL,
+        column_name TEXT NOT NULL,
+        geometry_type_name TEXT NOT NULL,
+        srs_id INTEGER NOT NULL,
+        z TINYINT NOT NULL,
+        m TINYINT NOT NULL,
+        CONSTRAINT pk_geom_cols PRIMARY KEY (table_name, column_name),
+        CONSTRAINT fk_gc_tn FOREIGN KEY (table_name) REFERENCES gpkg_contents(table_name),
+        CONSTRAINT fk_gc_srs FOREIGN KEY (srs_id) REFERENCES gpkg_spatial_ref_sys (srs_id)
+    )",
+    "INSERT OR IGNORE INTO gpkg_spatial_ref_sys VALUES
+        ('Undefined cartesian SRS', -1, 'NONE', -1, 'undefined', NULL),
+        ('Undefined geographic SRS', 0, 'NONE', 0, 'undefined', NULL),
+        ('WGS 84 geodetic', 4326, 'EPSG', 4326, 'GEOGCS[\"WGS 84\",DATUM[\"WGS_1984\",SPHEROID[\"WGS 84\",6378137,298.257223563]],PRIMEM[\"Greenwich\",0],UNIT[\"degree\",0.0174532925199433]]', NULL)",
+];
+
+/// Open the GeoPackage at `path` for writing, creating it (and its metadata
+/// tables) if it does not exist yet.
+pub async fn open_gpkg_for_writing(path: &Path) -> Result<SqlitePool, sqlx::Error> {
+    let options = SqliteConnectOptions::new()
+        .filename(path)
+        .create_if_missing(true);
+    let gpkg_pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(options)
+        .await?;
+    for statement in GPKG_BASE_SCHEMA {
+        sqlx::query(statement).execute(&gpkg_pool).await?;
+    }
+    return Ok(gpkg_pool);
+}
+
+/// `srs_id` of `crs` in the GeoPackage, adding it to the spatial reference
+/// systems if needed. Systems with an EPSG code use it as id, others get the
+/// next free id from `GPKG_CUSTOM_SRS_ID_START` on.
+pub async fn ensure_gpkg_srs(gpkg_pool: &SqlitePool, crs: &InternalCrs) -> Result<i32, sqlx::Error> {
+    let (organization, srs_id) = match crs.epsg_code() {
+        Some(code) => ("EPSG", code),
+        None => {
+            let existing_srs_id: Option<i32> = sqlx::query_scalar(
+                "SELECT srs_id FROM gpkg_spatial_ref_sys WHERE definition = ?",
+            )
+            .bind(crs.wkt())
+            .fetch_optional(gpkg_pool)
+            .await?;
+            if let Some(srs_id) = existing_srs_id {
+                return Ok(srs_id);
+            }
+            let srs_id: i32 = sqlx::query_scalar(
+                "SELECT max(coalesce(max(srs_id) + 1, 0), ?) FROM gpkg_spatial_ref_sys",
+            )
+            .bind(GPKG_CUSTOM_SRS_ID_START)
+            .fetch_one(gpkg_pool)
+            .await?;
+            ("NONE", srs_id)
+        }
+    };
+    sqlx::query("INSERT OR IGNORE INTO gpkg_spatial_ref_sys VALUES (?, ?, ?, ?, ?, NULL)")
+        .bind(crs.name())
+        .bind(srs_id)
+        .bind(organization)
+        .bind(srs_id)
+        .bind(crs.wkt())
+        .execute(gpkg_pool)
+        .await?;
+    return Ok(srs_id);
+}
+
+/// Register a features table (with a geometry column named `geom`) in the
+/// GeoPackage metadata tables. The table itself must already exist, as must
+/// the spatial reference system `srs_id` (see `ensure_gpkg_srs`).
+pub async fn register_gpkg_features_table(
+    gpkg_pool: &SqlitePool,
+    table_name: &str,
+    geometry_type_name: &str,
+    srs_id: i32,
+    bbox: Option<Rect<f64>>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT OR REPLACE INTO gpkg_contents
+            (table_name, data_type, identifier, min_x, min_y, max_x, max_y, srs_id)
+            VALUES (?, 'features', ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(table_name)
+    .bind(table_name)
+    .bind(bbox.map(|bbox| bbox.min().x))
+    .bind(bbox.map(|bbox| bbox.min().y))
+    .bind(bbox.map(|bbox| bbox.max().x))
+    .bind(bbox.map(|bbox| bbox.max().y))
+    .bind(srs_id)
+    .execute(gpkg_pool)
+    .await?;
+    sqlx::query(
+        "INSERT OR REPLACE INTO gpkg_geometry_columns VALUES (?, 'geom', ?, ?, 0, 0)",
+    )
+    .bind(table_name)
+    .bind(geometry_type_name)
+    .bind(srs_id)
+    .execute(gpkg_pool)
+    .await?;
+    return Ok(());
+}
+
+/// Register a non-spatial attributes table in the GeoPackage metadata tables.
+/// The table itself must already exist.
+pub async fn register_gpkg_attributes_table(
+    gpkg_pool: &SqlitePool,
+    table_name: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT OR REPLACE INTO gpkg_contents (table_name, data_type, identifier)
+            VALUES (?, 'attributes', ?)",
+    )
+    .bind(table_name)
+    .bind(table_name)
+    .execute(gpkg_pool)
+    .await?;
+    return Ok(());
+}
+
+/// Table names cannot be bound as query parameters, so only allow names
+/// that are safe to put in a query as a quoted identifier.
+pub fn check_gpkg_table_name(table_name: &str) -> Result<(), String> {
+    let is_valid_table_name = !table_name.is_empty()
+        && table_name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !is_valid_table_name {
+        return Err(format!("Invalid GeoPackage table name: {:?}", table_name));
+    }
+    return Ok(());
+}
+
+/// Write `multi_polygon` as the single row of the features table
+/// `table_name` in the GeoPackage at `path`. An existing table of that name
+/// is replaced.
+pub async fn write_gpkg_multi_polygon(
+    path: &Path,
+    table_name: &str,
+    multi_polygon: &MultiPolygon<f64>,
+    crs: &InternalCrs,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    check_gpkg_table_name(table_name)?;
+    let gpkg_pool = open_gpkg_for_writing(path).await?;
+    let mut tx = gpkg_pool.begin().await?;
+    sqlx::query(&format!("DROP TABLE IF EXISTS \"{}\"", table_name))
+        .execute(&mut tx)
+        .await?;
+    sqlx::query(&format!(
+        "CREATE TABLE \"{}\" (fid INTEGER PRIMARY KEY AUTOINCREMENT, geom MULTIPOLYGON NOT NULL)",
+        table_name
+    ))
+    .execute(&mut tx)
+    .await?;
+    sqlx::query(&format!("INSERT INTO \"{}\" (geom) VALUES (?)", table_name))
+        .bind(wkb::Encode(Geometry::MultiPolygon(multi_polygon.clone())))
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+
+    let srs_id = ensure_gpkg_srs(&gpkg_pool, crs).await?;
+    register_gpkg_features_table(
+        &gpkg_pool,
+        table_name,
+        "MULTIPOLYGON",
+        srs_id,
+        multi_polygon.bounding_rect(),
+    )
+    .await?;
+    gpkg_pool.close().await;
+    return Ok(());
+}
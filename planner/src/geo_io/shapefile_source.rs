@@ -0,0 +1,286 @@
+//! Shapefiles, plain or zipped (like the GRB archives)
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs::{self, File},
+    io::{Cursor, Read},
+    path::{Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use geo::{Geometry, Rect};
+use serde_json::Value;
+use shapefile::{dbase::FieldValue, ShapeReader};
+
+use super::feature_source::{envelope_intersects, polygonal, Attributes, FeatureSource, SourceFeature};
+
+/// dBase attribute value as JSON. Dates become `YYYY-MM-DD` strings.
+fn field_value_to_json(field_value: FieldValue) -> Value {
+    match field_value {
+        FieldValue::Character(value) => value.map_or(Value::Null, Value::from),
+        FieldValue::Numeric(value) => value.map_or(Value::Null, Value::from),
+        FieldValue::Float(value) => value.map_or(Value::Null, |value| Value::from(value as f64)),
+        FieldValue::Logical(value) => value.map_or(Value::Null, Value::from),
+        FieldValue::Integer(value) => Value::from(value),
+        FieldValue::Double(value) | FieldValue::Currency(value) => Value::from(value),
+        FieldValue::Memo(value) => Value::from(value),
+        FieldValue::Date(value) => value.map_or(Value::Null, |date| {
+            Value::from(format!("{:04}-{:02}-{:02}", date.year(), date.month(), date.day()))
+        }),
+        other => Value::from(format!("{:?}", other)),
+    }
+}
+
+/// Polygon features of a shapefile, read from the `.shp`, `.shx` and `.dbf`
+/// contents.
+fn read_shapefile_buffers(
+    name: &str,
+    shp: Vec<u8>,
+    shx: Vec<u8>,
+    dbf: Vec<u8>,
+) -> Result<Vec<SourceFeature>, Box<dyn Error + Send + Sync>> {
+    let shape_reader = ShapeReader::with_shx(Cursor::new(shp), Cursor::new(shx))?;
+    let dbase_reader = shapefile::dbase::Reader::new(Cursor::new(dbf))?;
+    let mut shapefile_reader = shapefile::Reader::new(shape_reader, dbase_reader);
+
+    let mut features = Vec::new();
+    for shape_record in shapefile_reader.iter_shapes_and_records() {
+        let (shape, record) = shape_record?;
+        let geometry = Geometry::<f64>::try_from(shape)
+            .map_err(|err| format!("Invalid shape in {}: {:?}", name, err))?;
+        let geometry = polygonal(geometry).ok_or_else(|| format!("Expected polygon shapes in {}", name))?;
+        let attributes = HashMap::<String, FieldValue>::from(record)
+            .into_iter()
+            .map(|(field, value)| (field, field_value_to_json(value)))
+            .collect::<Attributes>();
+        features.push(SourceFeature { geometry, attributes });
+    }
+    return Ok(features);
+}
+
+fn read_zip_entry(archive: &mut zip::ZipArchive<File>, name: &str) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let mut bytes = Vec::new();
+    archive.by_name(name)?.read_to_end(&mut bytes)?;
+    return Ok(bytes);
+}
+
+/// Which shapefile of a zip archive to read
+#[derive(Debug, Clone, Copy)]
+pub enum ZippedShapefile<'a> {
+    /// The first one in the archive
+    First,
+    /// The one with this file stem
+    Layer(&'a str),
+    /// The first one in this directory of the archive, e.g. `Shapefile/`
+    InDirectory(&'a str),
+}
+
+impl<'a> ZippedShapefile<'a> {
+    fn matches(&self, filename: &str) -> bool {
+        match self {
+            ZippedShapefile::First => true,
+            ZippedShapefile::Layer(layer) => Path::new(filename).file_stem().map_or(false, |stem| stem == *layer),
+            ZippedShapefile::InDirectory(directory) => filename.starts_with(directory),
+        }
+    }
+}
+
+/// Name of the `.shp` entry in `archive` that `shapefile` selects
+fn find_shp_entry(
+    archive: &zip::ZipArchive<File>,
+    shapefile: ZippedShapefile,
+    path: &Path,
+) -> Result<String, String> {
+    return archive
+        .file_names()
+        .filter(|filename| filename.to_lowercase().ends_with(".shp"))
+        .find(|filename| shapefile.matches(filename))
+        .map(str::to_owned)
+        .ok_or_else(|| format!("No shapefile ({:?}) in {:?}", shapefile, path));
+}
+
+/// Name of the entry next to `shp_filename` with `extension` (e.g. `dbf`),
+/// in whatever case the archive uses for it
+fn find_sibling_entry(archive: &zip::ZipArchive<File>, shp_filename: &str, extension: &str) -> Option<String> {
+    let base_filename = &shp_filename[..shp_filename.len() - ".shp".len()];
+    return archive
+        .file_names()
+        .find(|filename| {
+            filename.len() == base_filename.len() + 1 + extension.len()
+                && filename.starts_with(base_filename)
+                && filename[base_filename.len() + 1..].eq_ignore_ascii_case(extension)
+        })
+        .map(str::to_owned);
+}
+
+fn read_sibling_entry(
+    archive: &mut zip::ZipArchive<File>,
+    shp_filename: &str,
+    extension: &str,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let filename = find_sibling_entry(archive, shp_filename, extension)
+        .ok_or_else(|| format!("No .{} next to {}", extension, shp_filename))?;
+    return read_zip_entry(archive, &filename);
+}
+
+/// `.prj` WKT of the selected shapefile in the zip archive at `path`, if it
+/// has one. Does not decode the shapefile itself.
+fn read_zipped_prj(path: &Path, shapefile: ZippedShapefile) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+    let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+    let shp_filename = find_shp_entry(&archive, shapefile, path)?;
+    return Ok(read_sibling_entry(&mut archive, &shp_filename, "prj")
+        .ok()
+        .and_then(|prj| String::from_utf8(prj).ok()));
+}
+
+/// Polygon features and `.prj` WKT (if any) of the selected shapefile in the
+/// zip archive at `path`.
+pub fn read_zipped_shapefile(
+    path: &Path,
+    shapefile: ZippedShapefile,
+) -> Result<(Vec<SourceFeature>, Option<String>), Box<dyn Error + Send + Sync>> {
+    let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+    let shp_filename = find_shp_entry(&archive, shapefile, path)?;
+    let shp = read_zip_entry(&mut archive, &shp_filename)?;
+    let shx = read_sibling_entry(&mut archive, &shp_filename, "shx")?;
+    let dbf = read_sibling_entry(&mut archive, &shp_filename, "dbf")?;
+    let prj = read_sibling_entry(&mut archive, &shp_filename, "prj")
+        .ok()
+        .and_then(|prj| String::from_utf8(prj).ok());
+    let name = format!("{} in {}", shp_filename, path.display());
+    return Ok((read_shapefile_buffers(&name, shp, shx, dbf)?, prj));
+}
+
+/// Polygon features and `.prj` WKT (if any) of the shapefile at `path`
+/// (`.shp`, with the `.shx` and `.dbf` next to it).
+pub fn read_shapefile(path: &Path) -> Result<(Vec<SourceFeature>, Option<String>), Box<dyn Error + Send + Sync>> {
+    let shp = fs::read(path)?;
+    let shx = fs::read(path.with_extension("shx"))?;
+    let dbf = fs::read(path.with_extension("dbf"))?;
+    let prj = fs::read_to_string(path.with_extension("prj")).ok();
+    return Ok((read_shapefile_buffers(&path.display().to_string(), shp, shx, dbf)?, prj));
+}
+
+/// Shapefile (`.shp`) or zipped shapefile (`.zip`)
+#[derive(Debug, Clone)]
+pub struct ShapefileSource {
+    path: PathBuf,
+    /// Shapefile in a zip archive
+    layer: Option<String>,
+}
+
+impl ShapefileSource {
+    pub fn new(path: impl Into<PathBuf>, layer: Option<String>) -> Self {
+        ShapefileSource { path: path.into(), layer }
+    }
+
+    fn is_zip(&self) -> bool {
+        self.path
+            .extension()
+            .map_or(false, |extension| extension.eq_ignore_ascii_case("zip"))
+    }
+
+    fn zipped_shapefile(&self) -> ZippedShapefile {
+        match &self.layer {
+            Some(layer) => ZippedShapefile::Layer(layer),
+            None => ZippedShapefile::First,
+        }
+    }
+
+    async fn read(&self) -> Result<(Vec<SourceFeature>, Option<String>), Box<dyn Error + Send + Sync>> {
+        let source = self.clone();
+        // Decoding is blocking
+        return tokio::task::spawn_blocking(move || {
+            if source.is_zip() {
+                read_zipped_shapefile(&source.path, source.zipped_shapefile())
+            } else {
+                read_shapefile(&source.path)
+            }
+        })
+        .await?;
+    }
+}
+
+#[async_trait]
+impl FeatureSource for ShapefileSource {
+    fn describe(&self) -> String {
+        format!("Shapefile {}", self.path.display())
+    }
+
+    async fn crs(&self) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        if !self.is_zip() {
+            return Ok(tokio::fs::read_to_string(self.path.with_extension("prj")).await.ok());
+        }
+        let source = self.clone();
+        return tokio::task::spawn_blocking(move || read_zipped_prj(&source.path, source.zipped_shapefile())).await?;
+    }
+
+    async fn features(
+        &self,
+        bbox: Option<Rect<f64>>,
+    ) -> Result<Vec<SourceFeature>, Box<dyn Error + Send + Sync>> {
+        let (mut features, _) = self.read().await?;
+        features.retain(|feature| envelope_intersects(&feature.geometry, bbox));
+        return Ok(features);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use std::{
+        fs::File,
+        io::{Read, Write},
+    };
+
+    use geo::{coord, Rect};
+    use serde_json::Value;
+    use shapefile::dbase::{Date, FieldValue};
+
+    use crate::{
+        geo_io::FeatureSource,
+        test_util::{temp_path, write_zipped_shapefiles},
+    };
+
+    use super::{field_value_to_json, ShapefileSource};
+
+    #[test]
+    fn field_value_to_json__types() {
+        assert_eq!(field_value_to_json(FieldValue::Character(Some("Gbg".to_owned()))), Value::from("Gbg"));
+        assert_eq!(field_value_to_json(FieldValue::Character(None)), Value::Null);
+        assert_eq!(field_value_to_json(FieldValue::Numeric(Some(2.5))), Value::from(2.5));
+        assert_eq!(field_value_to_json(FieldValue::Logical(Some(true))), Value::Bool(true));
+        assert_eq!(
+            field_value_to_json(FieldValue::Date(Some(Date::new(5, 3, 2022)))),
+            Value::from("2022-03-05")
+        );
+    }
+
+    #[tokio::test]
+    async fn shapefile_source__upper_case_zip_entries() {
+        let square = Rect::new(coord! { x: 0.0, y: 0.0 }, coord! { x: 10.0, y: 10.0 }).to_polygon();
+        let lower_case_path = temp_path("lower-case-shapefile.zip");
+        write_zipped_shapefiles(&lower_case_path, &[("LAKES", vec![(square, "meer")])]);
+        let path = temp_path("upper-case-shapefile.zip");
+        let mut lower_case_archive = zip::ZipArchive::new(File::open(&lower_case_path).unwrap()).unwrap();
+        let mut zip_writer = zip::ZipWriter::new(File::create(&path).unwrap());
+        for i in 0..lower_case_archive.len() {
+            let mut entry = lower_case_archive.by_index(i).unwrap();
+            let mut bytes = Vec::new();
+            entry.read_to_end(&mut bytes).unwrap();
+            zip_writer.start_file(entry.name().to_uppercase(), zip::write::FileOptions::default()).unwrap();
+            zip_writer.write_all(&bytes).unwrap();
+        }
+        zip_writer.start_file("LAKES.PRJ", zip::write::FileOptions::default()).unwrap();
+        zip_writer.write_all(b"PROJCS[\"Belge 1972 / Belgian Lambert 72\"]").unwrap();
+        zip_writer.finish().unwrap();
+
+        let source = ShapefileSource::new(&path, None);
+        assert_eq!(source.crs().await.unwrap().as_deref(), Some("PROJCS[\"Belge 1972 / Belgian Lambert 72\"]"));
+        let features = source.features(None).await.unwrap();
+        assert_eq!(features.len(), 1);
+        assert_eq!(features[0].attributes["LBLTYPE"], Value::from("meer"));
+    }
+}
@@ -0,0 +1,24 @@
+//! Conversions from `geos` results back to `geo` types
+
+use geo::{Geometry, MultiPolygon, Polygon};
+
+use crate::winding::ensure_sfa_winding;
+
+/// Convert the result of a GEOS operation on polygons (union, buffer...) to a
+/// multi-polygon with SFA winding. Depending on the input GEOS returns a
+/// Polygon, MultiPolygon or GeometryCollection; anything but polygons is
+/// dropped.
+pub fn geos_to_multi_polygon(geometry: geos::Geometry) -> Result<MultiPolygon<f64>, geos::Error> {
+    let mut multi_polygon = match Geometry::<f64>::try_from(geometry)? {
+        Geometry::MultiPolygon(multi_polygon) => multi_polygon,
+        Geometry::Polygon(polygon) => MultiPolygon(vec![polygon]),
+        Geometry::GeometryCollection(collection) => MultiPolygon::from_iter(
+            collection
+                .into_iter()
+                .filter_map(|geometry| Polygon::try_from(geometry).ok()),
+        ),
+        _ => MultiPolygon(vec![]),
+    };
+    ensure_sfa_winding(&mut multi_polygon);
+    return Ok(multi_polygon);
+}
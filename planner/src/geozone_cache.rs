@@ -0,0 +1,407 @@
+//! Offline cache of droneguide geozones
+//!
+//! Geozones and the conditions of their categories are stored in a local
+//! GeoPackage together with the time they were fetched and the bounding box
+//! they were fetched for. When the droneguide service cannot be reached, the
+//! last fetch is replayed from disk instead.
+
+use std::{
+    error::Error,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use geo::{coord, Geometry, MultiPolygon, Rect};
+use geozero::wkb;
+use log::warn;
+use sqlx::sqlite::SqlitePoolOptions;
+
+use crate::{
+    airspace::Geozone,
+    droneguide::{fetch_geozones, CategoryConditions, DroneguideUrls},
+    geo_io::{open_gpkg_for_writing, register_gpkg_attributes_table, register_gpkg_features_table},
+};
+
+const GEOZONES_TABLE: &str = "geozones";
+const CONDITIONS_TABLE: &str = "geozone_conditions";
+const METADATA_TABLE: &str = "geozone_cache_metadata";
+
+/// Geozones as read back from the cache
+#[derive(Debug, Clone)]
+pub struct CachedGeozones {
+    pub fetched_at: SystemTime,
+    /// Bounding box (WGS84 degrees) the geozones were fetched for
+    pub bbox: Rect<f64>,
+    pub geozones: Vec<Geozone>,
+}
+
+impl CachedGeozones {
+    /// Whether the cache is younger than `ttl`.
+    pub fn is_fresh(&self, ttl: Duration) -> bool {
+        match self.fetched_at.elapsed() {
+            Ok(age) => age <= ttl,
+            // Fetched "in the future"; the clock was adjusted. Don't trust it.
+            Err(_) => false,
+        }
+    }
+
+    /// Whether the cached bounding box contains `bbox` entirely.
+    pub fn covers(&self, bbox: &Rect<f64>) -> bool {
+        self.bbox.min().x <= bbox.min().x
+            && self.bbox.min().y <= bbox.min().y
+            && self.bbox.max().x >= bbox.max().x
+            && self.bbox.max().y >= bbox.max().y
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GeozoneCache {
+    path: PathBuf,
+    ttl: Duration,
+}
+
+impl GeozoneCache {
+    pub fn new(path: impl Into<PathBuf>, ttl: Duration) -> Self {
+        GeozoneCache {
+            path: path.into(),
+            ttl,
+        }
+    }
+
+    /// Read the cache from disk. Returns `None` if nothing was cached yet.
+    pub async fn load(&self) -> Result<Option<CachedGeozones>, Box<dyn Error + Send + Sync>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let uri = format!("sqlite://{}", self.path.display());
+        let gpkg_pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect(&uri)
+            .await?;
+
+        let metadata: Option<(i64, f64, f64, f64, f64)> = sqlx::query_as(
+            "SELECT fetched_at, min_x, min_y, max_x, max_y FROM geozone_cache_metadata",
+        )
+        .fetch_optional(&gpkg_pool)
+        .await?;
+        let (fetched_at, min_x, min_y, max_x, max_y) = match metadata {
+            Some(metadata) => metadata,
+            None => return Ok(None),
+        };
+
+        let mut category_conditions = CategoryConditions::new();
+        let condition_rows: Vec<(String, String)> =
+            sqlx::query_as("SELECT category, condition FROM geozone_conditions ORDER BY fid")
+                .fetch_all(&gpkg_pool)
+                .await?;
+        for (category, condition) in condition_rows {
+            category_conditions
+                .entry(category)
+                .or_insert_with(Vec::new)
+                .push(condition);
+        }
+
+        let geozone_rows: Vec<(wkb::Decode<Geometry<f64>>, String, String, i64, i64, String)> =
+            sqlx::query_as(
+                "SELECT geom, name, category, lower, upper, activation FROM geozones ORDER BY fid",
+            )
+            .fetch_all(&gpkg_pool)
+            .await?;
+        let geozones = geozone_rows
+            .into_iter()
+            .map(|(geom, name, category, lower, upper, activation)| {
+                let geometry = match geom.geometry {
+                    Some(Geometry::MultiPolygon(multi_polygon)) => multi_polygon,
+                    Some(Geometry::Polygon(polygon)) => MultiPolygon(vec![polygon]),
+                    _ => return Err(format!("cached geozone {} has no polygon geometry", name)),
+                };
+                let activation = serde_json::from_str(&activation)
+                    .map_err(|err| format!("cached geozone {} has an invalid activation: {}", name, err))?;
+                let conditions = category_conditions
+                    .get(&category)
+                    .cloned()
+                    .unwrap_or_default();
+                Ok(Geozone {
+                    name,
+                    category,
+                    lower: lower as u32,
+                    upper: upper as u32,
+                    conditions,
+                    activation,
+                    geometry,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Some(CachedGeozones {
+            fetched_at: UNIX_EPOCH + Duration::from_secs(fetched_at as u64),
+            bbox: Rect::new(coord! { x: min_x, y: min_y }, coord! { x: max_x, y: max_y }),
+            geozones,
+        }))
+    }
+
+    /// Replace the cache contents with `geozones`, fetched for `bbox`.
+    pub async fn store(
+        &self,
+        bbox: Rect<f64>,
+        geozones: &[Geozone],
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let gpkg_pool = open_gpkg_for_writing(&self.path).await?;
+        let mut tx = gpkg_pool.begin().await?;
+
+        for statement in [
+            // Recreated rather than emptied so caches written by older
+            // versions pick up new columns.
+            "DROP TABLE IF EXISTS geozones",
+            "CREATE TABLE geozones (
+                fid INTEGER PRIMARY KEY AUTOINCREMENT,
+                geom MULTIPOLYGON NOT NULL,
+                name TEXT NOT NULL,
+                category TEXT NOT NULL,
+                lower INTEGER NOT NULL,
+                upper INTEGER NOT NULL,
+                activation TEXT NOT NULL
+            )",
+            "CREATE TABLE IF NOT EXISTS geozone_conditions (
+                fid INTEGER PRIMARY KEY AUTOINCREMENT,
+                category TEXT NOT NULL,
+                condition TEXT NOT NULL
+            )",
+            "CREATE TABLE IF NOT EXISTS geozone_cache_metadata (
+                fid INTEGER PRIMARY KEY AUTOINCREMENT,
+                fetched_at INTEGER NOT NULL,
+                min_x DOUBLE NOT NULL,
+                min_y DOUBLE NOT NULL,
+                max_x DOUBLE NOT NULL,
+                max_y DOUBLE NOT NULL
+            )",
+            "DELETE FROM geozone_conditions",
+            "DELETE FROM geozone_cache_metadata",
+        ] {
+            sqlx::query(statement).execute(&mut tx).await?;
+        }
+
+        let mut category_conditions = CategoryConditions::new();
+        for geozone in geozones {
+            sqlx::query(
+                "INSERT INTO geozones (geom, name, category, lower, upper, activation)
+                    VALUES (?, ?, ?, ?, ?, ?)",
+            )
+            .bind(wkb::Encode(Geometry::MultiPolygon(geozone.geometry.clone())))
+            .bind(&geozone.name)
+            .bind(&geozone.category)
+            .bind(geozone.lower as i64)
+            .bind(geozone.upper as i64)
+            .bind(serde_json::to_string(&geozone.activation)?)
+            .execute(&mut tx)
+            .await?;
+            category_conditions
+                .entry(geozone.category.clone())
+                .or_insert_with(|| geozone.conditions.clone());
+        }
+        for (category, conditions) in &category_conditions {
+            for condition in conditions {
+                sqlx::query("INSERT INTO geozone_conditions (category, condition) VALUES (?, ?)")
+                    .bind(category)
+                    .bind(condition)
+                    .execute(&mut tx)
+                    .await?;
+            }
+        }
+
+        let fetched_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        sqlx::query(
+            "INSERT INTO geozone_cache_metadata (fetched_at, min_x, min_y, max_x, max_y)
+                VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(fetched_at)
+        .bind(bbox.min().x)
+        .bind(bbox.min().y)
+        .bind(bbox.max().x)
+        .bind(bbox.max().y)
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+
+        register_gpkg_features_table(&gpkg_pool, GEOZONES_TABLE, "MULTIPOLYGON", 4326, Some(bbox))
+            .await?;
+        register_gpkg_attributes_table(&gpkg_pool, CONDITIONS_TABLE).await?;
+        register_gpkg_attributes_table(&gpkg_pool, METADATA_TABLE).await?;
+        gpkg_pool.close().await;
+        return Ok(());
+    }
+
+    /// Fetch the geozones in `bbox` from the droneguide service and store them
+    /// in the cache, regardless of the cache's age.
+    pub async fn refresh(
+        &self,
+        urls: &DroneguideUrls,
+        bbox: Rect<f64>,
+    ) -> Result<Vec<Geozone>, Box<dyn Error + Send + Sync>> {
+        let geozones = fetch_geozones(urls, bbox).await?.geozones;
+        self.store(bbox, &geozones).await?;
+        return Ok(geozones);
+    }
+
+    /// Geozones in `bbox`, from the cache if it is fresh and covers `bbox`,
+    /// from the droneguide service otherwise. If the service cannot be reached
+    /// a stale cache is used as a fallback.
+    pub async fn fetch_geozones(
+        &self,
+        urls: &DroneguideUrls,
+        bbox: Rect<f64>,
+    ) -> Result<Vec<Geozone>, Box<dyn Error + Send + Sync>> {
+        let maybe_cached = match self.load().await {
+            Ok(maybe_cached) => maybe_cached.filter(|cached| cached.covers(&bbox)),
+            Err(err) => {
+                warn!("Ignoring unreadable geozone cache {:?}: {}", self.path, err);
+                None
+            }
+        };
+        if let Some(cached) = &maybe_cached && cached.is_fresh(self.ttl) {
+            return Ok(cached.geozones.clone());
+        }
+
+        match self.refresh(urls, bbox).await {
+            Ok(geozones) => Ok(geozones),
+            Err(err) => match maybe_cached {
+                Some(cached) => {
+                    warn!("Could not refresh geozones ({}), using stale cache", err);
+                    Ok(cached.geozones)
+                }
+                None => Err(err),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use std::{net::SocketAddr, time::Duration};
+
+    use geo::{coord, Rect};
+
+    use crate::{
+        droneguide::DroneguideUrls,
+        test_util::{serve_canned_http, temp_path, unreachable_address},
+    };
+
+    use super::GeozoneCache;
+
+    const GEOZONES_JSON: &str = r#"{
+        "type": "FeatureCollection",
+        "features": [
+            {
+                "type": "Feature",
+                "geometry": {
+                    "type": "Polygon",
+                    "coordinates": [[[4.4, 50.9], [4.5, 50.9], [4.5, 51.0], [4.4, 51.0], [4.4, 50.9]]]
+                },
+                "properties": {
+                    "name": "EBR57 EEPOEL",
+                    "lowerLimit": 0,
+                    "upperLimit": 609.6,
+                    "categories": "Mil R"
+                }
+            },
+            {
+                "type": "Feature",
+                "geometry": {
+                    "type": "MultiPolygon",
+                    "coordinates": [[[[4.6, 50.9], [4.7, 50.9], [4.7, 51.0], [4.6, 51.0], [4.6, 50.9]]]]
+                },
+                "properties": {
+                    "name": "EBCTR BRUSSELS",
+                    "lowerLimit": 90,
+                    "upperLimit": 300,
+                    "categories": "CTR"
+                }
+            }
+        ]
+    }"#;
+
+    const CONDITIONS_JSON: &str = r#"{
+        "features": [
+            { "attributes": { "condition_en": "Prohibited", "Generieke_categorie_geozone": "MIL R" } },
+            { "attributes": { "condition_en": "Permission of ATC required", "Generieke_categorie_geozone": "CTR" } }
+        ]
+    }"#;
+
+    /// Stand-in for the droneguide FeatureServer layers, answering with
+    /// canned JSON.
+    async fn serve_canned_droneguide() -> DroneguideUrls {
+        let address = serve_canned_http(|target| {
+            let body = if target.starts_with("/Condition/") { CONDITIONS_JSON } else { GEOZONES_JSON };
+            (200, body.to_owned())
+        }).await;
+        droneguide_urls(address)
+    }
+
+    fn droneguide_urls(address: SocketAddr) -> DroneguideUrls {
+        DroneguideUrls {
+            geozone_url: format!("http://{}/Geozone/FeatureServer/0/query", address),
+            condition_url: format!("http://{}/Condition/FeatureServer/0/query", address),
+        }
+    }
+
+    fn bbox() -> Rect<f64> {
+        Rect::new(coord! { x: 4.3, y: 50.8 }, coord! { x: 4.8, y: 51.1 })
+    }
+
+    #[tokio::test]
+    async fn fetch_geozones__stores_fetched() {
+        let cache = GeozoneCache::new(temp_path("stores-fetched.gpkg"), Duration::from_secs(3600));
+        let urls = serve_canned_droneguide().await;
+
+        let geozones = cache.fetch_geozones(&urls, bbox()).await.unwrap();
+        assert_eq!(geozones.len(), 2);
+        assert_eq!(geozones[0].conditions, vec!["Prohibited".to_owned()]);
+
+        let cached = cache.load().await.unwrap().unwrap();
+        assert!(cached.covers(&bbox()));
+        assert!(cached.is_fresh(Duration::from_secs(3600)));
+        let names = cached.geozones.iter().map(|g| g.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["EBR57 EEPOEL", "EBCTR BRUSSELS"]);
+        assert_eq!(cached.geozones[1].lower, 90);
+        assert_eq!(cached.geozones[1].upper, 300);
+        assert_eq!(cached.geozones[1].conditions, vec!["Permission of ATC required".to_owned()]);
+    }
+
+    #[tokio::test]
+    async fn fetch_geozones__fresh_cache_skips_network() {
+        let path = temp_path("fresh-skips-network.gpkg");
+        let online_cache = GeozoneCache::new(&path, Duration::from_secs(3600));
+        online_cache.refresh(&serve_canned_droneguide().await, bbox()).await.unwrap();
+
+        let geozones = online_cache
+            .fetch_geozones(&droneguide_urls(unreachable_address().await), bbox())
+            .await
+            .unwrap();
+        assert_eq!(geozones.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn fetch_geozones__offline_replays_stale_cache() {
+        let path = temp_path("offline-replays-stale.gpkg");
+        GeozoneCache::new(&path, Duration::from_secs(3600))
+            .refresh(&serve_canned_droneguide().await, bbox())
+            .await
+            .unwrap();
+
+        let stale_cache = GeozoneCache::new(&path, Duration::ZERO);
+        let geozones = stale_cache
+            .fetch_geozones(&droneguide_urls(unreachable_address().await), bbox())
+            .await
+            .unwrap();
+        assert_eq!(geozones.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn fetch_geozones__offline_without_cache_fails() {
+        let cache = GeozoneCache::new(temp_path("offline-no-cache.gpkg"), Duration::from_secs(3600));
+        let result = cache.fetch_geozones(&droneguide_urls(unreachable_address().await), bbox()).await;
+        assert!(result.is_err());
+    }
+}
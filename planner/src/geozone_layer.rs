@@ -0,0 +1,78 @@
+//! Geozones (restricted airspace) as an obstacle layer for planning
+
+use geo::MultiPolygon;
+use geos::Geom;
+
+use crate::{
+    airspace::{AMSLHeightMeters, Geozone},
+    crs::InternalCrs,
+    geos_ext::geos_to_multi_polygon,
+    geozone_policy::{OperatorProfile, PolicyRules, ZoneDecision},
+    nav_graph::graph_types::PenalizedArea,
+    winding::ensure_sfa_winding,
+};
+
+/// Geozones relevant to a flight, split by how the planner should treat them.
+/// Geometries are in the internal CRS of the nav graph they are for.
+#[derive(Debug, Clone)]
+pub struct GeozoneLayer {
+    /// Zones to treat as obstacles
+    pub forbidden: MultiPolygon<f64>,
+    /// Zones that may be crossed at a cost
+    pub penalized: Vec<PenalizedArea>,
+}
+
+/// Build the layer from all `geozones` whose vertical band overlaps
+/// `cruise_altitude`, deciding per zone based on `rules` and `profile`. Zones
+/// that are simply allowed are left out.
+pub fn create_geozone_layer<'a>(
+    geozones: impl IntoIterator<Item = &'a Geozone>,
+    cruise_altitude: AMSLHeightMeters,
+    rules: &PolicyRules,
+    profile: &OperatorProfile,
+    crs: &InternalCrs,
+) -> GeozoneLayer {
+    let to_int = |geometry: &MultiPolygon<f64>| {
+        let mut geometry = geometry.clone();
+        crs.to_int(&mut geometry).unwrap();
+        ensure_sfa_winding(&mut geometry);
+        geometry
+    };
+
+    let mut layer = GeozoneLayer {
+        forbidden: MultiPolygon(vec![]),
+        penalized: Vec::new(),
+    };
+    for geozone in geozones.into_iter().filter(|geozone| geozone.overlaps_altitude(cruise_altitude)) {
+        match rules.decide(geozone, profile) {
+            ZoneDecision::Forbidden => layer.forbidden.0.extend(to_int(&geozone.geometry)),
+            ZoneDecision::AllowedWithPenalty(penalty) => layer
+                .penalized
+                .push(PenalizedArea::new(to_int(&geozone.geometry), penalty)),
+            ZoneDecision::Allowed => {}
+        }
+    }
+    return layer;
+}
+
+/// Merge `other` into `obstacles` by taking the union of all their polygons.
+///
+/// Overlapping polygons are dissolved into one. This matters because the
+/// visibility graph drops any vertex lying inside another obstacle, which
+/// would otherwise leave holes in the resulting graph.
+pub fn merge_obstacles(
+    obstacles: &MultiPolygon<f64>,
+    other: &MultiPolygon<f64>,
+) -> Result<MultiPolygon<f64>, geos::Error> {
+    if other.0.is_empty() {
+        return Ok(obstacles.clone());
+    }
+
+    let geos_polygons = obstacles
+        .iter()
+        .chain(other.iter())
+        .map(|polygon| polygon.clone().try_into())
+        .collect::<Result<Vec<geos::Geometry>, _>>()?;
+    let merged = geos::Geometry::create_geometry_collection(geos_polygons)?.unary_union()?;
+    return geos_to_multi_polygon(merged);
+}
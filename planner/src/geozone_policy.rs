@@ -0,0 +1,178 @@
+//! Evaluation of geozone categories against an operator profile
+//!
+//! The droneguide service only publishes free-text conditions per category.
+//! A rules table (see `PolicyRules`) encodes those conditions so they can be
+//! decided on per operator: whether a zone is forbidden, allowed, or allowed
+//! at a cost.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::Deserialize;
+
+use crate::airspace::Geozone;
+
+/// EU operational subcategory of the open category (Commission Implementing
+/// Regulation 2019/947), determined by the drone's class marking.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+pub enum DroneClass {
+    A1,
+    A2,
+    A3,
+}
+
+impl Default for DroneClass {
+    fn default() -> Self {
+        DroneClass::A3
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OperatorProfile {
+    pub drone_class: DroneClass,
+    /// Permits held by the operator, e.g. `"ctr"`
+    #[serde(default)]
+    pub permits: HashSet<String>,
+    /// Names of specific geozones the operator is authorised to enter
+    #[serde(default)]
+    pub authorised_zones: HashSet<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ZoneDecision {
+    /// Zone is an obstacle
+    Forbidden,
+    /// Zone does not affect planning
+    Allowed,
+    /// Zone may be crossed at an extra cost per metre flown inside it
+    AllowedWithPenalty(f64),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CategoryRule {
+    /// Drone classes that may enter zones of this category at all
+    pub drone_classes: HashSet<DroneClass>,
+    /// Permit the operator needs to enter, if any
+    #[serde(default)]
+    pub required_permit: Option<String>,
+    /// Extra cost per metre flown inside the zone, if entering is allowed but
+    /// discouraged
+    #[serde(default)]
+    pub penalty: Option<f64>,
+}
+
+/// Rules per (lowercased) geozone category. Categories without a rule are
+/// forbidden, so the default (empty) table treats every zone as an obstacle.
+///
+/// Can be read from JSON, e.g.:
+/// ```json
+/// { "ctr": { "droneClasses": ["A1", "A2", "A3"], "requiredPermit": "ctr", "penalty": 0.5 } }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct PolicyRules(pub HashMap<String, CategoryRule>);
+
+impl PolicyRules {
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    pub fn decide(&self, geozone: &Geozone, profile: &OperatorProfile) -> ZoneDecision {
+        if profile.authorised_zones.contains(&geozone.name) {
+            return ZoneDecision::Allowed;
+        }
+        let rule = match self.0.get(&geozone.category) {
+            Some(rule) => rule,
+            None => return ZoneDecision::Forbidden,
+        };
+        if !rule.drone_classes.contains(&profile.drone_class) {
+            return ZoneDecision::Forbidden;
+        }
+        if let Some(permit) = &rule.required_permit && !profile.permits.contains(permit) {
+            return ZoneDecision::Forbidden;
+        }
+        match rule.penalty {
+            Some(penalty) => ZoneDecision::AllowedWithPenalty(penalty),
+            None => ZoneDecision::Allowed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use geo::MultiPolygon;
+
+    use crate::airspace::Geozone;
+
+    use super::{DroneClass, OperatorProfile, PolicyRules, ZoneDecision};
+
+    fn rules() -> PolicyRules {
+        PolicyRules::from_json(
+            r#"{
+                "ctr": { "droneClasses": ["A1", "A2", "A3"], "requiredPermit": "ctr" },
+                "nature": { "droneClasses": ["A1", "A2"], "penalty": 0.5 }
+            }"#,
+        )
+        .unwrap()
+    }
+
+    fn geozone(name: &str, category: &str) -> Geozone {
+        Geozone {
+            name: name.to_owned(),
+            category: category.to_owned(),
+            lower: 0,
+            upper: 100,
+            conditions: Vec::new(),
+            activation: Default::default(),
+            geometry: MultiPolygon(vec![]),
+        }
+    }
+
+    fn profile(drone_class: DroneClass, permits: &[&str]) -> OperatorProfile {
+        OperatorProfile {
+            drone_class,
+            permits: permits.iter().map(|p| p.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn decide__unknown_category_forbidden() {
+        let decision = rules().decide(&geozone("EBR57", "mil r"), &profile(DroneClass::A1, &["ctr"]));
+        assert_eq!(decision, ZoneDecision::Forbidden);
+    }
+
+    #[test]
+    fn decide__missing_permit_forbidden() {
+        let decision = rules().decide(&geozone("EBCTR", "ctr"), &profile(DroneClass::A1, &[]));
+        assert_eq!(decision, ZoneDecision::Forbidden);
+    }
+
+    #[test]
+    fn decide__permit_held_allowed() {
+        let decision = rules().decide(&geozone("EBCTR", "ctr"), &profile(DroneClass::A1, &["ctr"]));
+        assert_eq!(decision, ZoneDecision::Allowed);
+    }
+
+    #[test]
+    fn decide__drone_class_forbidden() {
+        let decision = rules().decide(&geozone("Reserve", "nature"), &profile(DroneClass::A3, &[]));
+        assert_eq!(decision, ZoneDecision::Forbidden);
+    }
+
+    #[test]
+    fn decide__penalty() {
+        let decision = rules().decide(&geozone("Reserve", "nature"), &profile(DroneClass::A2, &[]));
+        assert_eq!(decision, ZoneDecision::AllowedWithPenalty(0.5));
+    }
+
+    #[test]
+    fn decide__authorised_zone_allowed() {
+        let mut profile = profile(DroneClass::A3, &[]);
+        profile.authorised_zones.insert("EBR57".to_owned());
+        let decision = rules().decide(&geozone("EBR57", "mil r"), &profile);
+        assert_eq!(decision, ZoneDecision::Allowed);
+    }
+}
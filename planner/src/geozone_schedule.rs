@@ -0,0 +1,210 @@
+//! Activation schedules of geozones
+//!
+//! Some geozones (military areas, events...) are only active during a date
+//! range and/or during certain hours on certain days. Times are in UTC, as in
+//! the droneguide service.
+
+use derive_more::Constructor;
+use serde::{Deserialize, Serialize};
+
+/// Milliseconds since the Unix epoch, the representation of dates in ArcGIS
+/// FeatureServer responses.
+pub type UnixMillis = i64;
+
+const MILLIS_PER_MINUTE: i64 = 60 * 1000;
+const MILLIS_PER_DAY: i64 = 24 * 60 * MILLIS_PER_MINUTE;
+const WEEKDAY_NAMES: [&str; 7] = ["MON", "TUE", "WED", "THU", "FRI", "SAT", "SUN"];
+
+/// Closed time interval
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Constructor)]
+pub struct TimeWindow {
+    pub start: UnixMillis,
+    pub end: UnixMillis,
+}
+
+impl TimeWindow {
+    fn overlaps(&self, other: &TimeWindow) -> bool {
+        self.start <= other.end && other.start <= self.end
+    }
+}
+
+/// Window of activation recurring on specific days of the week.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct DailySchedule {
+    /// Days the zone is active, Monday first
+    pub weekdays: [bool; 7],
+    /// Minutes after midnight
+    pub start_minute: u32,
+    /// Minutes after midnight. If before `start_minute` the activation runs
+    /// past midnight into the next day.
+    pub end_minute: u32,
+}
+
+impl DailySchedule {
+    /// Parse a schedule from a comma separated list of days (`MON` to `SUN`,
+    /// or `ANY`) and `HH:MM` start and end times.
+    pub fn parse(days: &str, start_time: &str, end_time: &str) -> Result<Self, String> {
+        return Ok(DailySchedule {
+            weekdays: parse_weekdays(days)?,
+            start_minute: parse_time_of_day(start_time)?,
+            end_minute: parse_time_of_day(end_time)?,
+        });
+    }
+
+    fn overlaps(&self, window: &TimeWindow) -> bool {
+        // A full week always contains an active period (if any day is active)
+        if window.end - window.start >= 7 * MILLIS_PER_DAY {
+            return self.weekdays.iter().any(|&active| active);
+        }
+        let end_offset = if self.end_minute < self.start_minute {
+            self.end_minute as i64 + 24 * 60
+        } else {
+            self.end_minute as i64
+        };
+        // Start a day early, activations may run past midnight.
+        let first_day = window.start.div_euclid(MILLIS_PER_DAY) - 1;
+        let last_day = window.end.div_euclid(MILLIS_PER_DAY);
+        (first_day..=last_day)
+            .filter(|&day| self.weekdays[weekday_index(day)])
+            .any(|day| {
+                let midnight = day * MILLIS_PER_DAY;
+                TimeWindow::new(
+                    midnight + self.start_minute as i64 * MILLIS_PER_MINUTE,
+                    midnight + end_offset * MILLIS_PER_MINUTE,
+                )
+                .overlaps(window)
+            })
+    }
+}
+
+/// When a geozone is active. The default is permanently active.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Activation {
+    /// Inactive before this time, if given
+    pub from: Option<UnixMillis>,
+    /// Inactive after this time, if given
+    pub until: Option<UnixMillis>,
+    /// Only active during these recurring periods within `from`..`until`, if
+    /// given
+    pub daily: Option<DailySchedule>,
+}
+
+impl Activation {
+    pub fn is_permanent(&self) -> bool {
+        self.from.is_none() && self.until.is_none() && self.daily.is_none()
+    }
+
+    /// Whether the zone is active at any moment during `window`.
+    pub fn is_active_during(&self, window: TimeWindow) -> bool {
+        let window = TimeWindow::new(
+            self.from.map_or(window.start, |from| window.start.max(from)),
+            self.until.map_or(window.end, |until| window.end.min(until)),
+        );
+        if window.start > window.end {
+            return false;
+        }
+        match &self.daily {
+            Some(daily) => daily.overlaps(&window),
+            None => true,
+        }
+    }
+}
+
+/// Index into `WEEKDAY_NAMES` of a day counted since the Unix epoch (which was
+/// a Thursday).
+fn weekday_index(days_since_epoch: i64) -> usize {
+    (days_since_epoch + 3).rem_euclid(7) as usize
+}
+
+fn parse_weekdays(days: &str) -> Result<[bool; 7], String> {
+    let mut weekdays = [false; 7];
+    for day in days.split(',').map(|day| day.trim().to_uppercase()) {
+        if day == "ANY" {
+            return Ok([true; 7]);
+        }
+        let index = WEEKDAY_NAMES
+            .iter()
+            .position(|&name| name == day)
+            .ok_or_else(|| format!("unknown weekday {:?}", day))?;
+        weekdays[index] = true;
+    }
+    return Ok(weekdays);
+}
+
+fn parse_time_of_day(time: &str) -> Result<u32, String> {
+    let invalid = || format!("invalid time of day {:?}, expected HH:MM", time);
+    let (hours, minutes) = time.trim().split_once(':').ok_or_else(invalid)?;
+    let hours: u32 = hours.parse().map_err(|_| invalid())?;
+    let minutes: u32 = minutes.parse().map_err(|_| invalid())?;
+    // 24:00 is a common way to write the end of the day
+    if minutes >= 60 || hours * 60 + minutes > 24 * 60 {
+        return Err(invalid());
+    }
+    return Ok(hours * 60 + minutes);
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use super::{Activation, DailySchedule, TimeWindow, UnixMillis, MILLIS_PER_DAY};
+
+    /// Monday 2022-06-06 00:00 UTC
+    const MONDAY: UnixMillis = 1654473600000;
+    const HOUR: UnixMillis = 60 * 60 * 1000;
+
+    fn weekday_mornings() -> Activation {
+        Activation {
+            daily: Some(DailySchedule::parse("MON,TUE,WED,THU,FRI", "08:00", "12:00").unwrap()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn is_active_during__permanent() {
+        assert!(Activation::default().is_active_during(TimeWindow::new(MONDAY, MONDAY + HOUR)));
+    }
+
+    #[test]
+    fn is_active_during__outside_date_range() {
+        let activation = Activation { from: Some(MONDAY), until: Some(MONDAY + MILLIS_PER_DAY), daily: None };
+        let window = TimeWindow::new(MONDAY + 2 * MILLIS_PER_DAY, MONDAY + 2 * MILLIS_PER_DAY + HOUR);
+        assert!(!activation.is_active_during(window));
+    }
+
+    #[test]
+    fn is_active_during__within_daily() {
+        let window = TimeWindow::new(MONDAY + 9 * HOUR, MONDAY + 10 * HOUR);
+        assert!(weekday_mornings().is_active_during(window));
+    }
+
+    #[test]
+    fn is_active_during__outside_daily() {
+        let window = TimeWindow::new(MONDAY + 13 * HOUR, MONDAY + 14 * HOUR);
+        assert!(!weekday_mornings().is_active_during(window));
+    }
+
+    #[test]
+    fn is_active_during__weekend() {
+        let saturday = MONDAY + 5 * MILLIS_PER_DAY;
+        let window = TimeWindow::new(saturday + 9 * HOUR, saturday + 10 * HOUR);
+        assert!(!weekday_mornings().is_active_during(window));
+    }
+
+    #[test]
+    fn is_active_during__overnight() {
+        let activation = Activation {
+            daily: Some(DailySchedule::parse("SUN", "22:00", "02:00").unwrap()),
+            ..Default::default()
+        };
+        // Monday 01:00, still Sunday night's activation
+        let window = TimeWindow::new(MONDAY + HOUR, MONDAY + HOUR + 1);
+        assert!(activation.is_active_during(window));
+    }
+
+    #[test]
+    fn parse__invalid() {
+        assert!(DailySchedule::parse("MON", "25:00", "12:00").is_err());
+        assert!(DailySchedule::parse("MONDAY", "08:00", "12:00").is_err());
+    }
+}
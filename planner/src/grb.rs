@@ -3,94 +3,568 @@
 //! See: https://overheid.vlaanderen.be/informatie-vlaanderen/producten-diensten/basiskaart-vlaanderen-grb
 
 use std::{
-    fs::{self, File},
-    io::{Cursor, Error, Read},
+    collections::{HashMap, HashSet},
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
 };
 
-use geo::{Geometry, MultiPolygon};
+use geo::{prelude::BoundingRect, Geometry, MultiPolygon};
 use geos::Geom;
+use geozero::wkb;
 use proj::{Proj, Transform};
-use shapefile::ShapeReader;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use strum_macros::Display;
 
-use crate::crs::ETRS_CRS;
+use crate::{
+    crs::{InternalCrs, ETRS_CRS},
+    geo_io::{
+        check_gpkg_table_name, ensure_gpkg_srs, open_gpkg_for_writing, read_zipped_shapefile,
+        register_gpkg_features_table, write_gpkg_multi_polygon, ZippedShapefile,
+    },
+    geos_ext::geos_to_multi_polygon,
+    geozone_policy::DroneClass,
+    grb_cache::{file_sha256, GrbTileCache, TileUnions},
+};
 
 static GRB_CRS: &str = "EPSG:31370";
-// Based on European Commission Implementing Regulation 2019/947
-// for self-build (no class) / A3 drone
-static BUILDING_BUFFER: f64 = 150.0 + 10.; // In CALC_CRS units (probably metres)
+/// Directory of the building layer (`Gbg`) in a GRB archive. The archives
+/// hold other layers too, in other directories.
+const GRB_BUILDINGS_DIRECTORY: &str = "Shapefile/";
+/// Margin added to the regulatory distances
+const SAFETY_MARGIN: f64 = 10.0;
+/// Number of buildings merged with a single cascaded union. Batches are
+/// processed in parallel.
+const UNION_BATCH_SIZE: usize = 1000;
 
-pub fn grb_zips_to_residential_geometry_first() -> Result<MultiPolygon<f64>, Error> {
-    let grb_to_calc_proj = Proj::new_known_crs(&GRB_CRS, &ETRS_CRS, None).unwrap();
-    let mut merged_geometry: geos::Geometry = MultiPolygon::new(Vec::new()).try_into().unwrap();
+/// Kind of building, from the `LBLTYPE` attribute of GRB `Gbg` (gebouw aan
+/// de grond) records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Display, Serialize, Deserialize)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum BuildingType {
+    /// Hoofdgebouw
+    Residential,
+    /// Industrial buildings and installations
+    Industrial,
+    /// Bijgebouw: sheds, garages...
+    Annex,
+    /// Anything else (or no label)
+    Other,
+}
 
-    let mut done: u16 = 0;
+impl BuildingType {
+    fn from_label(label: &str) -> Self {
+        let label = label.trim().to_lowercase();
+        if label == "hoofdgebouw" {
+            BuildingType::Residential
+        } else if label == "bijgebouw" {
+            BuildingType::Annex
+        } else if label.contains("industr") {
+            BuildingType::Industrial
+        } else {
+            BuildingType::Other
+        }
+    }
+}
 
-    let before_all_start = std::time::Instant::now();
+/// Horizontal distance (metres) to keep from each type of building.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BufferDistances {
+    pub residential: f64,
+    pub industrial: f64,
+    pub annex: f64,
+    pub other: f64,
+}
 
-    let dir = fs::read_dir("./data/iv-grb")?;
-    for entry in dir {
-        let path = entry.as_ref().unwrap().path();
-        if !path
-            .file_name()
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .ends_with("_Shapefile.zip")
-        {
-            continue;
+impl BufferDistances {
+    /// Distances based on European Commission Implementing Regulation
+    /// 2019/947 for the open category subcategory a drone flies under, plus
+    /// `SAFETY_MARGIN`. Self-built drones without class marking fly A3.
+    pub fn for_drone_class(drone_class: DroneClass) -> Self {
+        let distance = match drone_class {
+            // May fly over uninvolved people (not over assemblies)
+            DroneClass::A1 => 0.0,
+            // 30 m horizontal distance from uninvolved people
+            DroneClass::A2 => 30.0,
+            // 150 m from residential, commercial, industrial or recreational
+            // areas
+            DroneClass::A3 => 150.0,
+        } + SAFETY_MARGIN;
+        BufferDistances {
+            residential: distance,
+            industrial: distance,
+            annex: distance,
+            other: distance,
         }
-        let file = match File::open(&path) {
-            Err(error) => {
-                if error.kind() == std::io::ErrorKind::IsADirectory {
-                    continue;
-                }
-                return Err(error);
+    }
+
+    /// Read distances from JSON, e.g. `{ "residential": 160, "industrial":
+    /// 160, "annex": 60, "other": 160 }`.
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    pub fn distance(&self, building_type: BuildingType) -> f64 {
+        match building_type {
+            BuildingType::Residential => self.residential,
+            BuildingType::Industrial => self.industrial,
+            BuildingType::Annex => self.annex,
+            BuildingType::Other => self.other,
+        }
+    }
+}
+
+/// Buffer distances from the `preprocess-grb` options: `--drone-class
+/// A1|A2|A3` (default A3) and/or `--buffers <distances.json>`, the latter
+/// taking precedence.
+pub fn buffer_distances_from_options(
+    options: &[&str],
+) -> Result<BufferDistances, Box<dyn Error + Send + Sync>> {
+    let mut drone_class = DroneClass::default();
+    let mut buffers_path = None;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let value = options
+            .next()
+            .ok_or_else(|| format!("Missing value for {}", option))?;
+        match *option {
+            "--drone-class" => {
+                drone_class = serde_json::from_value(serde_json::Value::String(value.to_uppercase()))
+                    .map_err(|_| format!("Unknown drone class {:?}", value))?
             }
-            Ok(f) => f,
-        };
+            "--buffers" => buffers_path = Some(PathBuf::from(value)),
+            _ => return Err(format!("Unknown option {:?}", option).into()),
+        }
+    }
+    return match buffers_path {
+        Some(path) => Ok(BufferDistances::from_json(&fs::read_to_string(path)?)?),
+        None => Ok(BufferDistances::for_drone_class(drone_class)),
+    };
+}
+
+/// GRB shapefile archives (`*_Shapefile.zip`) in `input_dir`, sorted by name.
+fn grb_zip_paths(input_dir: &Path) -> Result<Vec<PathBuf>, std::io::Error> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(input_dir)? {
+        let path = entry?.path();
+        let is_grb_zip = path
+            .file_name()
+            .and_then(|file_name| file_name.to_str())
+            .map_or(false, |file_name| file_name.ends_with("_Shapefile.zip"));
+        if is_grb_zip && path.is_file() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    return Ok(paths);
+}
 
-        if done == 5 {
-            break;
+/// Building polygons in a GRB shapefile archive, in `GRB_CRS`, grouped by
+/// type.
+fn read_grb_zip_buildings(
+    path: &Path,
+) -> Result<HashMap<BuildingType, Vec<MultiPolygon<f64>>>, Box<dyn Error + Send + Sync>> {
+    let (features, _) = read_zipped_shapefile(path, ZippedShapefile::InDirectory(GRB_BUILDINGS_DIRECTORY))?;
+    let mut buildings = HashMap::<BuildingType, Vec<MultiPolygon<f64>>>::new();
+    for feature in features {
+        let building_type = match feature.attributes.get("LBLTYPE") {
+            Some(Value::String(label)) => BuildingType::from_label(label),
+            _ => BuildingType::Other,
         };
-        done += 1;
+        buildings.entry(building_type).or_default().push(feature.geometry);
+    }
+    return Ok(buildings);
+}
 
-        println!("Processing {:?}", &path);
-        let before_processing_start = std::time::Instant::now();
+fn empty_geos_geometry() -> Result<geos::Geometry<'static>, geos::Error> {
+    MultiPolygon::<f64>::new(Vec::new()).try_into()
+}
 
-        let mut archive = zip::ZipArchive::new(file)?;
-        let filenames: Vec<String> = archive.file_names().map(|s| s.into()).collect();
-        let shp_filename = filenames
-            .iter()
-            .find(|filename| filename.starts_with("Shapefile/") && filename.ends_with(".shp"))
-            .unwrap();
-        let base_filename = shp_filename.trim_end_matches(".shp");
-        let shp_file = archive.by_name(shp_filename)?;
-        let shp_buff = Cursor::new(shp_file.bytes().try_collect::<Vec<u8>>()?);
-        let shx_file = archive.by_name(&(base_filename.to_owned() + ".shx"))?;
-        let shx_buff = Cursor::new(shx_file.bytes().try_collect::<Vec<u8>>()?);
-        let dbf_file = archive.by_name(&(base_filename.to_owned() + ".dbf"))?;
-        let dbf_buff = Cursor::new(dbf_file.bytes().try_collect::<Vec<u8>>()?);
-
-        let shape_reader = ShapeReader::with_shx(shp_buff, shx_buff).unwrap();
-        let dbase_reader = shapefile::dbase::Reader::new(dbf_buff).unwrap();
-        let mut shapefile_reader = shapefile::Reader::new(shape_reader, dbase_reader);
-
-        for shape_record in shapefile_reader.iter_shapes_and_records() {
-            let (shape, _record) = shape_record.unwrap();
-            let geo_geom = Geometry::<f64>::try_from(shape).unwrap();
-            let mut geo_polygon = MultiPolygon::try_from(geo_geom).unwrap();
-            geo_polygon.transform(&grb_to_calc_proj).unwrap();
-            let geos_geom: geos::Geometry = geo_polygon.clone().try_into().unwrap();
-            let buffered = geos_geom.buffer(BUILDING_BUFFER, 2).unwrap();
+/// Union of `geometries` in one go. GEOS unions a collection as a cascade
+/// (merging nearby geometries first), which is much cheaper than adding the
+/// geometries to the result one by one.
+fn cascaded_union(geometries: Vec<geos::Geometry>) -> Result<geos::Geometry, geos::Error> {
+    if geometries.is_empty() {
+        return empty_geos_geometry();
+    }
+    geos::Geometry::create_geometry_collection(geometries)?.unary_union()
+}
 
-            merged_geometry = merged_geometry.union(&buffered).unwrap();
+/// Union of `geometries`, merged pairwise in parallel as a balanced tree.
+fn tree_union(geometries: Vec<geos::Geometry>) -> Result<geos::Geometry, geos::Error> {
+    geometries
+        .into_par_iter()
+        .map(Ok)
+        .try_reduce_with(|a, b| a.union(&b))
+        .unwrap_or_else(empty_geos_geometry)
+}
+
+/// Buffer `buildings` (in `GRB_CRS`) by `buffer_distance` and merge them, in
+/// `ETRS_CRS`.
+fn buffered_building_union(
+    buildings: &[MultiPolygon<f64>],
+    buffer_distance: f64,
+) -> Result<geos::Geometry<'static>, Box<dyn Error + Send + Sync>> {
+    let batch_unions = buildings
+        .par_chunks(UNION_BATCH_SIZE)
+        .map_init(
+            // `proj::Proj` is `!Send`, create one per worker thread
+            || Proj::new_known_crs(&GRB_CRS, &ETRS_CRS, None),
+            |grb_to_calc_proj, batch| -> Result<geos::Geometry<'static>, Box<dyn Error + Send + Sync>> {
+                let grb_to_calc_proj = grb_to_calc_proj.as_ref().map_err(|err| err.to_string())?;
+                let buffered = batch
+                    .iter()
+                    .map(|building| -> Result<geos::Geometry<'static>, Box<dyn Error + Send + Sync>> {
+                        let geos_geom: geos::Geometry = building.transformed(grb_to_calc_proj)?.try_into()?;
+                        Ok(geos_geom.buffer(buffer_distance, 2)?)
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(cascaded_union(buffered)?)
+            },
+        )
+        .collect::<Result<Vec<_>, _>>()?;
+    return Ok(tree_union(batch_unions)?);
+}
+
+/// Obstacle layer created from GRB buildings, in `ETRS_CRS`
+#[derive(Debug, Clone)]
+pub struct GrbObstacles {
+    /// Buffered buildings per source type, with the distance they were
+    /// buffered by
+    pub by_type: Vec<(BuildingType, f64, MultiPolygon<f64>)>,
+    /// All buffered buildings dissolved, for planning
+    pub merged: MultiPolygon<f64>,
+}
+
+/// Buffer the buildings in a GRB archive by the distance for their type and
+/// merge them per type.
+fn grb_zip_unions(
+    path: &Path,
+    buffer_distances: &BufferDistances,
+) -> Result<TileUnions, Box<dyn Error + Send + Sync>> {
+    println!("Processing {:?}", path);
+    let before_processing_start = std::time::Instant::now();
+    let mut unions = read_grb_zip_buildings(path)?
+        .into_iter()
+        .map(|(building_type, buildings)| {
+            let distance = buffer_distances.distance(building_type);
+            Ok((building_type, buffered_building_union(&buildings, distance)?))
+        })
+        .collect::<Result<Vec<_>, Box<dyn Error + Send + Sync>>>()?;
+    unions.sort_by_key(|(building_type, _)| *building_type);
+    println!("{:?} took: {:.2?}", path, before_processing_start.elapsed());
+    return Ok(unions);
+}
+
+/// Buffer every building in the GRB archives in `input_dir` by the distance
+/// for its type and merge them into an obstacle layer. Archives are processed
+/// in parallel. With a `tile_cache`, only archives that changed since the
+/// last run are processed; the others are read from the cache.
+pub fn grb_zips_to_obstacles(
+    input_dir: &Path,
+    buffer_distances: &BufferDistances,
+    mut tile_cache: Option<&mut GrbTileCache>,
+) -> Result<GrbObstacles, Box<dyn Error + Send + Sync>> {
+    let before_all_start = std::time::Instant::now();
+
+    let zip_paths = grb_zip_paths(input_dir)?;
+    if zip_paths.is_empty() {
+        return Err(format!("No *_Shapefile.zip GRB archives in {:?}", input_dir).into());
+    }
+    let cache = tile_cache.as_deref();
+    let zip_unions = zip_paths
+        .par_iter()
+        .map(|path| -> Result<(String, String, TileUnions, bool), Box<dyn Error + Send + Sync>> {
+            let tile_name = path
+                .file_name()
+                .and_then(|file_name| file_name.to_str())
+                .ok_or_else(|| format!("Invalid archive name {:?}", path))?
+                .to_owned();
+            let sha256 = file_sha256(path)?;
+            if let Some(cache) = cache && let Some(unions) = cache.get(&tile_name, &sha256)? {
+                return Ok((tile_name, sha256, unions, false));
+            }
+            let unions = grb_zip_unions(path, buffer_distances)?;
+            Ok((tile_name, sha256, unions, true))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if let Some(cache) = tile_cache.as_deref_mut() {
+        for (tile_name, sha256, unions, is_processed) in &zip_unions {
+            if *is_processed {
+                cache.put(tile_name, sha256, unions)?;
+            }
         }
+        cache.retain(&zip_unions.iter().map(|(tile_name, ..)| tile_name.clone()).collect::<HashSet<_>>())?;
+        cache.save()?;
+    }
+    let processed_count = zip_unions.iter().filter(|(.., is_processed)| *is_processed).count();
+    println!("Processed {} of {} tiles", processed_count, zip_unions.len());
 
-        println!("Took: {:.2?}", before_processing_start.elapsed());
+    let mut unions_by_type = HashMap::<BuildingType, Vec<geos::Geometry>>::new();
+    for (building_type, zip_union) in zip_unions.into_iter().flat_map(|(_, _, unions, _)| unions) {
+        unions_by_type.entry(building_type).or_default().push(zip_union);
     }
+    let mut type_unions = unions_by_type
+        .into_iter()
+        .map(|(building_type, zip_unions)| Ok((building_type, tree_union(zip_unions)?)))
+        .collect::<Result<Vec<_>, geos::Error>>()?;
+    type_unions.sort_by_key(|(building_type, _)| *building_type);
+    let merged_geometry = tree_union(type_unions.iter().map(|(_, union)| union.clone()).collect())?;
 
     println!("All: {:.2?}", before_all_start.elapsed());
 
-    let mut geometry: Geometry<f64> = merged_geometry.try_into().unwrap();
-    return Ok(MultiPolygon::new(Vec::new()));
+    return Ok(GrbObstacles {
+        by_type: type_unions
+            .into_iter()
+            .map(|(building_type, union)| {
+                Ok((building_type, buffer_distances.distance(building_type), geos_to_multi_polygon(union)?))
+            })
+            .collect::<Result<Vec<_>, geos::Error>>()?,
+        merged: geos_to_multi_polygon(merged_geometry)?,
+    });
+}
+
+/// Write the per-type obstacles to the features table `table_name`, one row
+/// per building type.
+async fn write_gpkg_obstacles_by_type(
+    path: &Path,
+    table_name: &str,
+    by_type: &[(BuildingType, f64, MultiPolygon<f64>)],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    check_gpkg_table_name(table_name)?;
+    let gpkg_pool = open_gpkg_for_writing(path).await?;
+    let mut tx = gpkg_pool.begin().await?;
+    sqlx::query(&format!("DROP TABLE IF EXISTS \"{}\"", table_name))
+        .execute(&mut tx)
+        .await?;
+    sqlx::query(&format!(
+        "CREATE TABLE \"{}\" (
+            fid INTEGER PRIMARY KEY AUTOINCREMENT,
+            geom MULTIPOLYGON NOT NULL,
+            building_type TEXT NOT NULL,
+            buffer_distance DOUBLE NOT NULL
+        )",
+        table_name
+    ))
+    .execute(&mut tx)
+    .await?;
+    for (building_type, buffer_distance, multi_polygon) in by_type {
+        sqlx::query(&format!(
+            "INSERT INTO \"{}\" (geom, building_type, buffer_distance) VALUES (?, ?, ?)",
+            table_name
+        ))
+        .bind(wkb::Encode(Geometry::MultiPolygon(multi_polygon.clone())))
+        .bind(building_type.to_string())
+        .bind(buffer_distance)
+        .execute(&mut tx)
+        .await?;
+    }
+    tx.commit().await?;
+
+    let bbox = MultiPolygon::from_iter(by_type.iter().flat_map(|(_, _, multi_polygon)| multi_polygon.0.iter().cloned()))
+        .bounding_rect();
+    let srs_id = ensure_gpkg_srs(&gpkg_pool, &InternalCrs::EtrsLaea).await?;
+    register_gpkg_features_table(&gpkg_pool, table_name, "MULTIPOLYGON", srs_id, bbox).await?;
+    gpkg_pool.close().await;
+    return Ok(());
+}
+
+/// Preprocessing command: create the obstacle layer from the GRB archives in
+/// `input_dir` and write it to the GeoPackage at `output_path`. The dissolved
+/// layer is named after the file (e.g. `sv-zaventem.gpkg` -> `sv-zaventem`),
+/// as the server expects; the layer per building type gets a `-by-type`
+/// suffix. Processed tiles are cached in a `<name>-tiles` directory next to
+/// the output, so a re-run only processes the archives that changed.
+pub async fn preprocess_grb(
+    input_dir: PathBuf,
+    output_path: PathBuf,
+    buffer_distances: BufferDistances,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let table_name = output_path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .ok_or_else(|| format!("Invalid output path {:?}", output_path))?
+        .to_owned();
+    let cache_dir = output_path.with_file_name(format!("{}-tiles", table_name));
+    // Buffering and merging is CPU bound, and `proj::Proj` is `!Send`
+    let obstacles = tokio::task::spawn_blocking(move || {
+        let mut tile_cache = GrbTileCache::open(&cache_dir, &buffer_distances)?;
+        grb_zips_to_obstacles(&input_dir, &buffer_distances, Some(&mut tile_cache))
+    }).await??;
+    println!("Writing {} polygons to {:?}", obstacles.merged.0.len(), output_path);
+    write_gpkg_multi_polygon(&output_path, &table_name, &obstacles.merged, &InternalCrs::EtrsLaea).await?;
+    write_gpkg_obstacles_by_type(&output_path, &format!("{}-by-type", table_name), &obstacles.by_type).await?;
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    extern crate test;
+
+    use approx::assert_relative_eq;
+    use geo::{coord, prelude::BoundingRect, MultiPolygon, Rect};
+    use geos::Geom;
+    use proj::{Proj, Transform};
+    use test::Bencher;
+
+    use crate::crs::ETRS_CRS;
+
+    use crate::{
+        geo_io::GpkgReader,
+        geozone_policy::DroneClass,
+        test_util::{temp_path, write_zipped_shapefiles},
+    };
+
+    use super::{
+        buffered_building_union, preprocess_grb, read_grb_zip_buildings, BufferDistances, BuildingType, GRB_CRS,
+    };
+
+    const BUFFER_DISTANCE: f64 = 160.0;
+
+    /// `n` by `n` grid of 12 m square buildings, 60 m apart (in `GRB_CRS`),
+    /// so the buffers overlap heavily like in a town.
+    fn synthetic_buildings(n: usize) -> Vec<MultiPolygon<f64>> {
+        (0..n * n)
+            .map(|i| {
+                let x = 150_000.0 + (i % n) as f64 * 60.0;
+                let y = 200_000.0 + (i / n) as f64 * 60.0;
+                let square = Rect::new(coord! { x: x, y: y }, coord! { x: x + 12.0, y: y + 12.0 });
+                MultiPolygon(vec![square.to_polygon()])
+            })
+            .collect()
+    }
+
+    /// The previous approach: add every buffered building to the result one
+    /// by one.
+    fn sequential_union(buildings: &[MultiPolygon<f64>]) -> geos::Geometry<'static> {
+        let grb_to_calc_proj = Proj::new_known_crs(&GRB_CRS, &ETRS_CRS, None).unwrap();
+        let mut merged_geometry: geos::Geometry = MultiPolygon::<f64>::new(Vec::new()).try_into().unwrap();
+        for building in buildings {
+            let geos_geom: geos::Geometry = building.transformed(&grb_to_calc_proj).unwrap().try_into().unwrap();
+            let buffered = geos_geom.buffer(BUFFER_DISTANCE, 2).unwrap();
+            merged_geometry = merged_geometry.union(&buffered).unwrap();
+        }
+        merged_geometry
+    }
+
+    #[test]
+    fn buffered_building_union__same_as_sequential() {
+        let buildings = synthetic_buildings(12);
+        let cascaded = buffered_building_union(&buildings, BUFFER_DISTANCE).unwrap();
+        let sequential = sequential_union(&buildings);
+        assert_relative_eq!(cascaded.area().unwrap(), sequential.area().unwrap(), max_relative = 1e-9);
+        assert!(cascaded.sym_difference(&sequential).unwrap().area().unwrap() < 1.0);
+    }
+
+    #[test]
+    fn buffered_building_union__empty() {
+        let union = buffered_building_union(&[], BUFFER_DISTANCE).unwrap();
+        assert_eq!(union.area().unwrap(), 0.0);
+    }
+
+    #[bench]
+    fn union__sequential(b: &mut Bencher) {
+        let buildings = synthetic_buildings(30);
+        b.iter(|| sequential_union(&buildings));
+    }
+
+    #[bench]
+    fn union__cascaded(b: &mut Bencher) {
+        let buildings = synthetic_buildings(30);
+        b.iter(|| buffered_building_union(&buildings, BUFFER_DISTANCE).unwrap());
+    }
+
+    #[test]
+    fn read_grb_zip_buildings__only_building_layer() {
+        let square = |x: f64| Rect::new(coord! { x: x, y: 200_000.0 }, coord! { x: x + 12.0, y: 200_012.0 }).to_polygon();
+        let path = temp_path("grb-two-layers_Shapefile.zip");
+        // Another layer first, outside `Shapefile/`
+        write_zipped_shapefiles(&path, &[
+            ("Shapefilecomponenten/Wbn", vec![(square(150_000.0), "wegbaan")]),
+            ("Shapefile/Gbg", vec![(square(150_100.0), "hoofdgebouw"), (square(150_200.0), "bijgebouw")]),
+        ]);
+
+        let buildings = read_grb_zip_buildings(&path).unwrap();
+        let bounding_rects = |building_type| buildings[&building_type]
+            .iter()
+            .map(|building| building.bounding_rect().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(buildings.len(), 2);
+        assert_eq!(bounding_rects(BuildingType::Residential), vec![square(150_100.0).bounding_rect().unwrap()]);
+        assert_eq!(bounding_rects(BuildingType::Annex), vec![square(150_200.0).bounding_rect().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn preprocess_grb__buffers_merges_and_writes_layers() {
+        let square = |x: f64| Rect::new(coord! { x: x, y: 200_000.0 }, coord! { x: x + 12.0, y: 200_012.0 }).to_polygon();
+        let input_dir = temp_path("grb-preprocess");
+        std::fs::create_dir_all(&input_dir).unwrap();
+        // The annex buffer overlaps the residential one next to it, the
+        // building in the other tile is far away
+        write_zipped_shapefiles(&input_dir.join("first_Shapefile.zip"), &[
+            ("Shapefile/Gbg", vec![(square(150_000.0), "hoofdgebouw"), (square(150_030.0), "bijgebouw")]),
+        ]);
+        write_zipped_shapefiles(&input_dir.join("second_Shapefile.zip"), &[
+            ("Shapefile/Gbg", vec![(square(152_000.0), "hoofdgebouw")]),
+        ]);
+        let buffer_distances = BufferDistances { residential: 20.0, industrial: 20.0, annex: 5.0, other: 20.0 };
+        let output_path = input_dir.join("grb-obstacles.gpkg");
+
+        preprocess_grb(input_dir.clone(), output_path.clone(), buffer_distances).await.unwrap();
+
+        let reader = GpkgReader::open(&output_path).await.unwrap();
+        let merged = reader.read_features("grb-obstacles", None).await.unwrap();
+        let mut polygons = merged.iter().flat_map(|feature| feature.geometry.0.iter()).collect::<Vec<_>>();
+        polygons.sort_by(|a, b| {
+            a.bounding_rect().unwrap().min().x.partial_cmp(&b.bounding_rect().unwrap().min().x).unwrap()
+        });
+        assert_eq!(polygons.len(), 2);
+        // In ETRS89-LAEA, from 20 m left of the residential building to 5 m
+        // right of the annex
+        let first_rect = polygons[0].bounding_rect().unwrap();
+        assert!(first_rect.min().x > 3_000_000.0 && first_rect.min().y > 3_000_000.0);
+        assert_relative_eq!(first_rect.width(), 20.0 + 42.0 + 5.0, epsilon = 0.5);
+        assert_relative_eq!(polygons[1].bounding_rect().unwrap().width(), 20.0 + 12.0 + 20.0, epsilon = 0.5);
+
+        let by_type = reader.read_features("grb-obstacles-by-type", None).await.unwrap();
+        let types = by_type
+            .iter()
+            .map(|feature| (feature.attributes["building_type"].clone(), feature.attributes["buffer_distance"].clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(types, vec![("residential".into(), 20.0.into()), ("annex".into(), 5.0.into())]);
+        assert_eq!(by_type[0].geometry.0.len(), 2);
+        assert!(input_dir.join("grb-obstacles-tiles").is_dir());
+    }
+
+    #[test]
+    fn building_type__from_label() {
+        assert_eq!(BuildingType::from_label("hoofdgebouw"), BuildingType::Residential);
+        assert_eq!(BuildingType::from_label("Bijgebouw "), BuildingType::Annex);
+        assert_eq!(BuildingType::from_label("industriegebouw"), BuildingType::Industrial);
+        assert_eq!(BuildingType::from_label("loopbrug"), BuildingType::Other);
+    }
+
+    #[test]
+    fn buffer_distances__per_drone_class() {
+        let a3 = BufferDistances::for_drone_class(DroneClass::A3);
+        let a2 = BufferDistances::for_drone_class(DroneClass::A2);
+        assert_eq!(a3.distance(BuildingType::Residential), 160.0);
+        assert_eq!(a2.distance(BuildingType::Annex), 40.0);
+    }
+
+    #[test]
+    fn buffer_distances__from_json() {
+        let distances = BufferDistances::from_json(
+            r#"{ "residential": 160, "industrial": 200, "annex": 60, "other": 160 }"#,
+        )
+        .unwrap();
+        assert_eq!(distances.distance(BuildingType::Industrial), 200.0);
+        assert_eq!(distances.distance(BuildingType::Annex), 60.0);
+    }
 }
@@ -0,0 +1,240 @@
+//! Per-tile cache for GRB preprocessing
+//!
+//! GRB is published as many `*_Shapefile.zip` tiles, of which only a few
+//! change between releases. The cache directory holds the buffered building
+//! union of every processed tile (per building type, as WKB in `ETRS_CRS`)
+//! and a `manifest.json` with the SHA-256 of the archive each tile was built
+//! from. Tiles whose archive still has the same hash are read back instead of
+//! reprocessed. The manifest also records the buffer distances; other
+//! distances invalidate every tile.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    error::Error,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+
+use geos::Geom;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::grb::{BufferDistances, BuildingType};
+
+const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+/// Buffered building union of one tile per building type, in `ETRS_CRS`
+pub type TileUnions = Vec<(BuildingType, geos::Geometry<'static>)>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TileEntry {
+    sha256: String,
+    building_types: Vec<BuildingType>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TileManifest {
+    buffer_distances: BufferDistances,
+    /// By archive file name
+    tiles: BTreeMap<String, TileEntry>,
+}
+
+/// SHA-256 of the file at `path`, hex encoded.
+pub fn file_sha256(path: &Path) -> Result<String, io::Error> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    return Ok(format!("{:x}", hasher.finalize()));
+}
+
+#[derive(Debug)]
+pub struct GrbTileCache {
+    dir: PathBuf,
+    manifest: TileManifest,
+}
+
+impl GrbTileCache {
+    /// Open (or create) the cache in `dir` for tiles buffered by
+    /// `buffer_distances`. An unreadable manifest is treated as an empty one.
+    pub fn open(dir: &Path, buffer_distances: &BufferDistances) -> Result<Self, io::Error> {
+        fs::create_dir_all(dir)?;
+        let empty_manifest = TileManifest {
+            buffer_distances: buffer_distances.clone(),
+            tiles: BTreeMap::new(),
+        };
+        let manifest = match fs::read_to_string(dir.join(MANIFEST_FILE_NAME)) {
+            Ok(json) => match serde_json::from_str::<TileManifest>(&json) {
+                Ok(manifest) if &manifest.buffer_distances == buffer_distances => manifest,
+                Ok(_) => {
+                    println!("Buffer distances changed, reprocessing all tiles");
+                    empty_manifest
+                }
+                Err(err) => {
+                    warn!("Ignoring invalid GRB tile manifest in {:?}: {}", dir, err);
+                    empty_manifest
+                }
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => empty_manifest,
+            Err(err) => return Err(err),
+        };
+        return Ok(GrbTileCache { dir: dir.to_owned(), manifest });
+    }
+
+    fn geometry_path(&self, tile_name: &str, building_type: BuildingType) -> PathBuf {
+        self.dir.join(format!("{}.{}.wkb", tile_name, building_type))
+    }
+
+    /// Cached unions of `tile_name`, if it was processed from an archive
+    /// with hash `sha256`.
+    pub fn get(&self, tile_name: &str, sha256: &str) -> Result<Option<TileUnions>, Box<dyn Error + Send + Sync>> {
+        let entry = match self.manifest.tiles.get(tile_name) {
+            Some(entry) if entry.sha256 == sha256 => entry,
+            _ => return Ok(None),
+        };
+        let mut unions = Vec::new();
+        for building_type in &entry.building_types {
+            let wkb = match fs::read(self.geometry_path(tile_name, *building_type)) {
+                Ok(wkb) => wkb,
+                // Deleted from under us, reprocess
+                Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(err) => return Err(err.into()),
+            };
+            unions.push((*building_type, geos::Geometry::new_from_wkb(&wkb)?));
+        }
+        return Ok(Some(unions));
+    }
+
+    /// Store the unions of `tile_name`, processed from an archive with hash
+    /// `sha256`. Call `save` to persist the manifest.
+    pub fn put(&mut self, tile_name: &str, sha256: &str, unions: &TileUnions) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.remove(tile_name)?;
+        for (building_type, union) in unions {
+            fs::write(self.geometry_path(tile_name, *building_type), union.to_wkb()?.as_ref())?;
+        }
+        self.manifest.tiles.insert(
+            tile_name.to_owned(),
+            TileEntry {
+                sha256: sha256.to_owned(),
+                building_types: unions.iter().map(|(building_type, _)| *building_type).collect(),
+            },
+        );
+        return Ok(());
+    }
+
+    fn remove(&mut self, tile_name: &str) -> Result<(), io::Error> {
+        if let Some(entry) = self.manifest.tiles.remove(tile_name) {
+            for building_type in entry.building_types {
+                match fs::remove_file(self.geometry_path(tile_name, building_type)) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                    _ => {}
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    /// Drop every tile not in `tile_names`, e.g. archives that were removed
+    /// from the input directory.
+    pub fn retain(&mut self, tile_names: &HashSet<String>) -> Result<(), io::Error> {
+        let stale = self
+            .manifest
+            .tiles
+            .keys()
+            .filter(|tile_name| !tile_names.contains(*tile_name))
+            .cloned()
+            .collect::<Vec<_>>();
+        for tile_name in stale {
+            self.remove(&tile_name)?;
+        }
+        return Ok(());
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let json = serde_json::to_string_pretty(&self.manifest)?;
+        // Write-then-rename so an interrupted run leaves the old manifest
+        let temp_path = self.dir.join(format!("{}.tmp", MANIFEST_FILE_NAME));
+        fs::write(&temp_path, json)?;
+        fs::rename(temp_path, self.dir.join(MANIFEST_FILE_NAME))?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use std::{collections::HashSet, fs};
+
+    use geo::{coord, Rect};
+    use geos::Geom;
+
+    use crate::{
+        geozone_policy::DroneClass,
+        grb::{BufferDistances, BuildingType},
+        test_util::temp_path,
+    };
+
+    use super::{file_sha256, GrbTileCache, TileUnions};
+
+    fn square_unions() -> TileUnions {
+        let square = Rect::new(coord! { x: 0., y: 0. }, coord! { x: 10., y: 10. }).to_polygon();
+        vec![(BuildingType::Residential, square.try_into().unwrap())]
+    }
+
+    #[test]
+    fn file_sha256__known_digest() {
+        let dir = temp_path("sha256");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("abc");
+        fs::write(&path, "abc").unwrap();
+        assert_eq!(
+            file_sha256(&path).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn grb_tile_cache__roundtrip() {
+        let dir = temp_path("grb-cache-roundtrip");
+        let distances = BufferDistances::for_drone_class(DroneClass::A3);
+        let mut cache = GrbTileCache::open(&dir, &distances).unwrap();
+        cache.put("a_Shapefile.zip", "1234", &square_unions()).unwrap();
+        cache.save().unwrap();
+
+        let cache = GrbTileCache::open(&dir, &distances).unwrap();
+        let unions = cache.get("a_Shapefile.zip", "1234").unwrap().unwrap();
+        assert_eq!(unions.len(), 1);
+        assert_eq!(unions[0].0, BuildingType::Residential);
+        assert_eq!(unions[0].1.area().unwrap(), 100.);
+        assert!(cache.get("a_Shapefile.zip", "5678").unwrap().is_none());
+        assert!(cache.get("b_Shapefile.zip", "1234").unwrap().is_none());
+    }
+
+    #[test]
+    fn grb_tile_cache__other_distances_invalidate() {
+        let dir = temp_path("grb-cache-distances");
+        let mut cache = GrbTileCache::open(&dir, &BufferDistances::for_drone_class(DroneClass::A3)).unwrap();
+        cache.put("a_Shapefile.zip", "1234", &square_unions()).unwrap();
+        cache.save().unwrap();
+
+        let cache = GrbTileCache::open(&dir, &BufferDistances::for_drone_class(DroneClass::A2)).unwrap();
+        assert!(cache.get("a_Shapefile.zip", "1234").unwrap().is_none());
+    }
+
+    #[test]
+    fn grb_tile_cache__retain() {
+        let dir = temp_path("grb-cache-retain");
+        let distances = BufferDistances::for_drone_class(DroneClass::A3);
+        let mut cache = GrbTileCache::open(&dir, &distances).unwrap();
+        cache.put("a_Shapefile.zip", "1234", &square_unions()).unwrap();
+        cache.put("b_Shapefile.zip", "5678", &square_unions()).unwrap();
+        cache.retain(&HashSet::from(["b_Shapefile.zip".to_owned()])).unwrap();
+
+        assert!(cache.get("a_Shapefile.zip", "1234").unwrap().is_none());
+        assert!(cache.get("b_Shapefile.zip", "5678").unwrap().is_some());
+        assert!(!dir.join("a_Shapefile.zip.residential.wkb").exists());
+    }
+}
@@ -0,0 +1,107 @@
+use geo::{
+    line_intersection::{line_intersection, LineIntersection},
+    lines_iter::LinesIter,
+    prelude::{Contains, EuclideanDistance},
+    Coordinate, Line, MultiPolygon, Point,
+};
+
+/// Position of `coord` along `line` as a ratio of the line's length (0 at
+/// `line.start`, 1 at `line.end`). `coord` is assumed to lie on the line.
+fn ratio_along(line: Line<f64>, coord: Coordinate<f64>) -> f64 {
+    let delta = line.delta();
+    let ratio = ((coord.x - line.start.x) * delta.x + (coord.y - line.start.y) * delta.y)
+        / (delta.x * delta.x + delta.y * delta.y);
+    ratio.clamp(0.0, 1.0)
+}
+
+/// Length of the part of `line` that lies in the interior of `multi_polygon`.
+pub fn line_length_within(line: Line<f64>, multi_polygon: &MultiPolygon<f64>) -> f64 {
+    let length = line.start.euclidean_distance(&line.end);
+    if length == 0.0 {
+        return 0.0;
+    }
+
+    // Split the line at every crossing with a polygon ring. Each piece then
+    // lies either entirely inside or entirely outside the multi-polygon.
+    let mut ratios = vec![0.0, 1.0];
+    for edge in multi_polygon.lines_iter() {
+        match line_intersection(line, edge) {
+            Some(LineIntersection::SinglePoint { intersection, .. }) => {
+                ratios.push(ratio_along(line, intersection));
+            }
+            Some(LineIntersection::Collinear { intersection }) => {
+                ratios.push(ratio_along(line, intersection.start));
+                ratios.push(ratio_along(line, intersection.end));
+            }
+            None => {}
+        }
+    }
+    ratios.sort_by(f64::total_cmp);
+    ratios.dedup();
+
+    let delta = line.delta();
+    return ratios
+        .windows(2)
+        .filter(|piece| {
+            let mid_ratio = (piece[0] + piece[1]) / 2.0;
+            let mid = Point::new(
+                line.start.x + mid_ratio * delta.x,
+                line.start.y + mid_ratio * delta.y,
+            );
+            multi_polygon.contains(&mid)
+        })
+        .map(|piece| (piece[1] - piece[0]) * length)
+        .sum();
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use approx::assert_relative_eq;
+    use geo::{Coordinate, Line, LineString, MultiPolygon, Polygon};
+
+    use super::line_length_within;
+
+    /// 10x10 square with a 2x2 hole in the middle
+    fn square_with_hole() -> MultiPolygon<f64> {
+        MultiPolygon(vec![Polygon::new(
+            LineString::from(vec![(0.0, 0.0), (10.0, 0.0), (10.0, 10.0), (0.0, 10.0), (0.0, 0.0)]),
+            vec![LineString::from(vec![(4.0, 4.0), (4.0, 6.0), (6.0, 6.0), (6.0, 4.0), (4.0, 4.0)])],
+        )])
+    }
+
+    fn line(start: (f64, f64), end: (f64, f64)) -> Line<f64> {
+        Line::new(Coordinate::from(start), Coordinate::from(end))
+    }
+
+    #[test]
+    fn line_length_within__crossing() {
+        let length = line_length_within(line((-5.0, 2.0), (15.0, 2.0)), &square_with_hole());
+        assert_relative_eq!(length, 10.0, epsilon = 1e-9);
+    }
+
+    #[test]
+    fn line_length_within__crossing_hole() {
+        let length = line_length_within(line((-5.0, 5.0), (15.0, 5.0)), &square_with_hole());
+        assert_relative_eq!(length, 8.0, epsilon = 1e-9);
+    }
+
+    #[test]
+    fn line_length_within__inside() {
+        let length = line_length_within(line((1.0, 1.0), (1.0, 3.0)), &square_with_hole());
+        assert_relative_eq!(length, 2.0, epsilon = 1e-9);
+    }
+
+    #[test]
+    fn line_length_within__outside() {
+        let length = line_length_within(line((-5.0, -5.0), (15.0, -5.0)), &square_with_hole());
+        assert_relative_eq!(length, 0.0);
+    }
+
+    #[test]
+    fn line_length_within__along_boundary() {
+        let length = line_length_within(line((0.0, 0.0), (10.0, 0.0)), &square_with_hole());
+        assert_relative_eq!(length, 0.0);
+    }
+}
@@ -6,28 +6,52 @@
 #![feature(once_cell)]
 #![feature(box_syntax)]
 #![feature(is_some_with)]
+#![feature(test)]
 
 extern crate approx;
 
+mod airspace;
 mod coord_ext;
 mod crs;
 mod droneguide;
+mod feature_server;
 mod geo_geojson;
 mod geo_io;
+mod geos_ext;
+mod geozone_cache;
+mod geozone_layer;
+mod geozone_policy;
+mod geozone_schedule;
 mod grb;
+mod grb_cache;
 mod intersection;
 mod modulo;
 mod mpi;
 mod server;
+mod simplify;
 mod nav_graph;
+mod osm_water;
+mod water_reconcile;
 mod winding;
 mod dgc;
 mod line_string_ratio;
+mod line_length_within;
+#[cfg(test)]
+mod test_util;
 
-use std::error::Error;
+use std::{error::Error, path::PathBuf};
 
 use server::serve_ui_forever;
 
+const USAGE: &str = "Usage:
+    lakehopper-nav                                         Serve the UI websocket
+    lakehopper-nav preprocess-grb <input dir> <out.gpkg> [--drone-class A1|A2|A3] [--buffers <distances.json>]
+                                                           Create an obstacle layer from GRB shapefile archives
+    lakehopper-nav import-osm-water <extract.osm.pbf> <out.gpkg>
+                                                           Create a water layer from an OpenStreetMap extract
+    lakehopper-nav reconcile-water <waters> <obstacles> <out.gpkg> [--shoreline-margin <m>] [--min-area <m²>]
+                                                           Create the usable water layer: waters minus obstacles";
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     // let bbox = Rect::new(coord! { x: 2.3, y: 49.5}, coord! { x: 6.7, y: 51.5});
@@ -50,7 +74,35 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // let mut core = Core::new().unwrap();
     // let handle = core.handle();
 
-    serve_ui_forever().await?;
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        [] => serve_ui_forever().await?,
+        ["preprocess-grb", input_dir, output_path, ref options @ ..] => {
+            let buffer_distances = grb::buffer_distances_from_options(options)
+                .map_err(|err| -> Box<dyn Error> { format!("{}\n\n{}", err, USAGE).into() })?;
+            grb::preprocess_grb(PathBuf::from(input_dir), PathBuf::from(output_path), buffer_distances)
+                .await
+                .map_err(|err| -> Box<dyn Error> { err })?
+        }
+        ["import-osm-water", input_path, output_path] => {
+            osm_water::import_osm_waters(PathBuf::from(input_path), PathBuf::from(output_path))
+                .await
+                .map_err(|err| -> Box<dyn Error> { err })?
+        }
+        ["reconcile-water", waters_path, obstacles_path, output_path, ref options @ ..] => {
+            let options = water_reconcile::ReconcileOptions::from_options(options)
+                .map_err(|err| -> Box<dyn Error> { format!("{}\n\n{}", err, USAGE).into() })?;
+            water_reconcile::preprocess_waters(
+                PathBuf::from(waters_path),
+                PathBuf::from(obstacles_path),
+                PathBuf::from(output_path),
+                options,
+            )
+            .await
+            .map_err(|err| -> Box<dyn Error> { err })?
+        }
+        _ => return Err(USAGE.into()),
+    }
     Ok(())
 
     // ui_main();
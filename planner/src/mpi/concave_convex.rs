@@ -1,4 +1,4 @@
-use std::f64::consts::PI;
+use std::f64::consts::{PI, TAU};
 
 use geo::MultiPolygon;
 
@@ -12,7 +12,8 @@ pub fn is_locally_concave(mpi: &Mpi, multi_poly: &MultiPolygon<f64>) -> bool {
     let neighbors = mpi.neighbors(multi_poly);
     let left_angle = coord.angle_to(&multi_poly[&neighbors.left]);
     let right_angle = coord.angle_to(&multi_poly[&neighbors.right]);
-    right_angle - left_angle > PI
+    // The angles wrap around at TAU, the difference has to as well
+    (right_angle - left_angle).rem_euclid(TAU) > PI
 }
 
 #[cfg(test)]
@@ -43,6 +44,28 @@ mod tests {
         assert_eq!(is_locally_concave(&mpi, &multi_polygon), true);
     }
 
+    #[test]
+    fn is_locally_concave__concave_across_zero_angle() {
+        // The concave polygon above, rotated a quarter turn: the angle to the
+        // right neighbor is now smaller than the one to the left neighbor
+        let multi_polygon = MultiPolygon(vec![Polygon::new(
+            LineString(vec![
+                Coordinate { x: 0.0, y: 0.0 },
+                Coordinate { x: 0.0, y: 5.0 },
+                Coordinate { x: -2.0, y: 2.0 }, // concave
+                Coordinate { x: -5.0, y: 0.0 },
+                Coordinate { x: 0.0, y: 0.0 },
+            ]),
+            vec![],
+        )]);
+        let mpi = Mpi {
+            polygon_index: 0,
+            ring_index: 0,
+            coord_index: 2,
+        };
+        assert_eq!(is_locally_concave(&mpi, &multi_polygon), true);
+    }
+
     #[test]
     fn is_locally_concave__convex() {
         let multi_polygon = MultiPolygon(vec![Polygon::new(
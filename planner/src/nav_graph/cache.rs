@@ -0,0 +1,332 @@
+//! On-disk cache of nav graphs
+//!
+//! Creating the visibility graph of a real area takes minutes. The cache
+//! directory holds one bincode file per nav graph, named after the SHA-256 of
+//! everything the graph is created from: the features (obstacles, waters and
+//! penalized areas), the internal CRS, the `VisibilityOptimizationMode` and the
+//! maximum edge length.
+//! Every file starts with a header with the cache format version and the key.
+//! Files written in another format version, or for another key, are stale and
+//! recreated.
+//!
+//! The cache is kept below a size limit: after storing a graph, the least
+//! recently written files are removed until the rest fit.
+
+use std::{
+    error::Error,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use bincode::Options;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{crs::InternalCrs, dgc::DebugGeometryCallback};
+
+use super::{create_nav_graph, graph_types::Features, NavGraph, VisibilityOptimizationMode};
+
+/// Bump whenever the serialized form of `NavGraph` changes
+const CACHE_FORMAT_VERSION: u32 = 2;
+
+/// Upper bound on the size of a header, so a corrupt length does not make us
+/// allocate gigabytes
+const HEADER_SIZE_LIMIT: u64 = 1024;
+
+/// Default for `NavGraphCache::with_size_limit`, 2 GiB
+const DEFAULT_SIZE_LIMIT: u64 = 2 * 1024 * 1024 * 1024;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct CacheHeader {
+    format_version: u32,
+    key: String,
+}
+
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new()
+}
+
+/// SHA-256 of everything a nav graph is created from, hex encoded.
+pub fn nav_graph_cache_key(
+    features: &Features,
+    crs: &InternalCrs,
+    optimization_mode: VisibilityOptimizationMode,
+    max_edge_length: Option<f64>,
+) -> Result<String, bincode::Error> {
+    let mut hasher = Sha256::new();
+    bincode_options().serialize_into(&mut hasher, &(features, crs, optimization_mode, max_edge_length))?;
+    return Ok(format!("{:x}", hasher.finalize()));
+}
+
+#[derive(Debug)]
+pub struct NavGraphCache {
+    dir: PathBuf,
+    /// Total size (bytes) of the cached graphs `put` keeps the cache below
+    size_limit: u64,
+}
+
+impl NavGraphCache {
+    /// Open (or create) the cache in `dir`, with the default size limit.
+    pub fn open(dir: &Path) -> Result<Self, io::Error> {
+        fs::create_dir_all(dir)?;
+        return Ok(NavGraphCache { dir: dir.to_owned(), size_limit: DEFAULT_SIZE_LIMIT });
+    }
+
+    /// Keep the cached graphs below `size_limit` bytes in total. The graph
+    /// stored last is always kept, even if it is larger.
+    pub fn with_size_limit(self, size_limit: u64) -> Self {
+        NavGraphCache { size_limit, ..self }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.navgraph", key))
+    }
+
+    /// Cached nav graph for `key`. `None` if there is none, or if it is stale
+    /// or unreadable.
+    pub fn get(&self, key: &str) -> Result<Option<NavGraph>, Box<dyn Error + Send + Sync>> {
+        let path = self.path(key);
+        let mut reader = match File::open(&path) {
+            Ok(file) => BufReader::new(file),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let expected_header = CacheHeader { format_version: CACHE_FORMAT_VERSION, key: key.to_owned() };
+        match bincode_options().with_limit(HEADER_SIZE_LIMIT).deserialize_from::<_, CacheHeader>(&mut reader) {
+            Ok(header) if header == expected_header => {}
+            Ok(header) => {
+                println!(
+                    "Nav graph cache {:?} is stale (format version {}, key {}), recreating",
+                    path, header.format_version, header.key
+                );
+                return Ok(None);
+            }
+            Err(err) => {
+                warn!("Ignoring unreadable nav graph cache {:?}: {}", path, err);
+                return Ok(None);
+            }
+        }
+        return match bincode_options().deserialize_from::<_, NavGraph>(&mut reader) {
+            Ok(nav_graph) => Ok(Some(nav_graph)),
+            Err(err) => {
+                warn!("Ignoring unreadable nav graph cache {:?}: {}", path, err);
+                Ok(None)
+            }
+        };
+    }
+
+    /// Store `nav_graph` under `key`, replacing any stale file.
+    pub fn put(&self, key: &str, nav_graph: &NavGraph) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let path = self.path(key);
+        // Renamed into place once complete, so an interrupted write never
+        // leaves a truncated file under `key`
+        let partial_path = path.with_extension("navgraph.partial");
+        let mut writer = BufWriter::new(File::create(&partial_path)?);
+        let header = CacheHeader { format_version: CACHE_FORMAT_VERSION, key: key.to_owned() };
+        bincode_options().serialize_into(&mut writer, &header)?;
+        bincode_options().serialize_into(&mut writer, nav_graph)?;
+        writer.flush()?;
+        drop(writer);
+        fs::rename(partial_path, &path)?;
+        self.evict(&path)?;
+        return Ok(());
+    }
+
+    /// Remove the least recently written graphs, except `keep`, until the
+    /// cache fits within the size limit.
+    fn evict(&self, keep: &Path) -> Result<(), io::Error> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().and_then(|extension| extension.to_str()) != Some("navgraph") {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            files.push((metadata.modified()?, metadata.len(), path));
+        }
+        let mut total_size: u64 = files.iter().map(|(_, size, _)| size).sum();
+        files.sort();
+        for (_, size, path) in files {
+            if total_size <= self.size_limit {
+                break;
+            }
+            if path == keep {
+                continue;
+            }
+            println!("Evicting nav graph cache {:?}", path);
+            fs::remove_file(&path)?;
+            total_size -= size;
+        }
+        return Ok(());
+    }
+}
+
+/// Like `create_nav_graph`, but loaded from `cache` if it was created for the
+/// same inputs before, and stored in `cache` otherwise. The duration is how
+/// long creating or loading took.
+pub fn create_nav_graph_cached(
+    cache: &NavGraphCache,
+    features: &Features,
+    crs: &InternalCrs,
+    dgc: DebugGeometryCallback,
+    optimization_mode: VisibilityOptimizationMode,
+    max_edge_length: Option<f64>,
+) -> Result<(NavGraph, Duration), Box<dyn Error + Send + Sync>> {
+    let key = nav_graph_cache_key(features, crs, optimization_mode, max_edge_length)?;
+    let before_loading = Instant::now();
+    if let Some(nav_graph) = cache.get(&key)? {
+        let duration = before_loading.elapsed();
+        println!("Loaded nav graph {} from cache. Took {}ms", key, duration.as_millis());
+        return Ok((nav_graph, duration));
+    }
+    let (nav_graph, duration) = create_nav_graph(features, crs, dgc, optimization_mode, max_edge_length);
+    // Not being able to cache is no reason to fail, the graph is there
+    if let Err(err) = cache.put(&key, &nav_graph) {
+        warn!("Could not cache nav graph {}: {}", key, err);
+    }
+    return Ok((nav_graph, duration));
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use std::fs;
+
+    use bincode::Options;
+    use geo::MultiPolygon;
+
+    use crate::{
+        crs::InternalCrs,
+        nav_graph::{create_nav_graph, graph_types::Features, VisibilityOptimizationMode},
+        test_util::{assert_same_nav_graph, local_square, temp_path},
+    };
+
+    use super::{bincode_options, create_nav_graph_cached, nav_graph_cache_key, CacheHeader, NavGraphCache};
+
+    /// Two 20 m square obstacles and a 20 m square water
+    fn features() -> Features {
+        Features {
+            obstacles: MultiPolygon(vec![local_square(0.0, 0.0, 20.0), local_square(100.0, 0.0, 20.0)]),
+            waters: MultiPolygon(vec![local_square(50.0, 100.0, 20.0)]),
+            penalized_areas: Vec::new(),
+            arbitrary: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn nav_graph_cache_key__depends_on_inputs() {
+        let features = features();
+        let crs = InternalCrs::EtrsLaea;
+        let key = |features: &Features, crs: &InternalCrs, mode| nav_graph_cache_key(features, crs, mode, None).unwrap();
+        let sweep_key = key(&features, &crs, VisibilityOptimizationMode::Sweep);
+        assert_eq!(sweep_key, key(&features.clone(), &crs, VisibilityOptimizationMode::Sweep));
+        assert_ne!(sweep_key, key(&features, &crs, VisibilityOptimizationMode::Naive));
+        assert_ne!(sweep_key, key(&features, &InternalCrs::Utm { zone: 31, north: true }, VisibilityOptimizationMode::Sweep));
+        assert_ne!(
+            sweep_key,
+            nav_graph_cache_key(&features, &crs, VisibilityOptimizationMode::Sweep, Some(500.0)).unwrap()
+        );
+
+        let mut moved_obstacle = features.clone();
+        moved_obstacle.obstacles.0[0].exterior_mut(|exterior| exterior.0[0].x += 1.0);
+        assert_ne!(sweep_key, key(&moved_obstacle, &crs, VisibilityOptimizationMode::Sweep));
+        let mut without_waters = features.clone();
+        without_waters.waters = MultiPolygon(vec![]);
+        assert_ne!(sweep_key, key(&without_waters, &crs, VisibilityOptimizationMode::Sweep));
+    }
+
+    #[test]
+    fn nav_graph_cache__roundtrip() {
+        let cache = NavGraphCache::open(&temp_path("nav-graph-cache-roundtrip")).unwrap();
+        let (nav_graph, _) = create_nav_graph(&features(), &InternalCrs::EtrsLaea, None, VisibilityOptimizationMode::Sweep, None);
+        assert!(cache.get("key").unwrap().is_none());
+        cache.put("key", &nav_graph).unwrap();
+
+        let cached = cache.get("key").unwrap().unwrap();
+        assert_same_nav_graph(&cached, &nav_graph);
+        assert_eq!(cached.crs, nav_graph.crs);
+        assert_eq!(cached.features.obstacles, nav_graph.features.obstacles);
+        assert_eq!(cached.features.waters, nav_graph.features.waters);
+    }
+
+    #[test]
+    fn nav_graph_cache__stale_format_version() {
+        let dir = temp_path("nav-graph-cache-stale");
+        let cache = NavGraphCache::open(&dir).unwrap();
+        let (nav_graph, _) = create_nav_graph(&features(), &InternalCrs::EtrsLaea, None, VisibilityOptimizationMode::Sweep, None);
+        cache.put("key", &nav_graph).unwrap();
+
+        let path = dir.join("key.navgraph");
+        let bytes = fs::read(&path).unwrap();
+        let header: CacheHeader = bincode_options().deserialize(&bytes).unwrap();
+        let header_size = bincode_options().serialized_size(&header).unwrap() as usize;
+        let old_header = CacheHeader { format_version: 0, key: header.key };
+        let mut old_bytes = bincode_options().serialize(&old_header).unwrap();
+        old_bytes.extend_from_slice(&bytes[header_size..]);
+        fs::write(&path, old_bytes).unwrap();
+
+        assert!(cache.get("key").unwrap().is_none());
+    }
+
+    #[test]
+    fn nav_graph_cache__other_key_or_corrupt() {
+        let dir = temp_path("nav-graph-cache-corrupt");
+        let cache = NavGraphCache::open(&dir).unwrap();
+        let (nav_graph, _) = create_nav_graph(&features(), &InternalCrs::EtrsLaea, None, VisibilityOptimizationMode::Sweep, None);
+        cache.put("key", &nav_graph).unwrap();
+
+        fs::copy(dir.join("key.navgraph"), dir.join("other-key.navgraph")).unwrap();
+        assert!(cache.get("other-key").unwrap().is_none());
+
+        let bytes = fs::read(dir.join("key.navgraph")).unwrap();
+        fs::write(dir.join("key.navgraph"), &bytes[..bytes.len() / 2]).unwrap();
+        assert!(cache.get("key").unwrap().is_none());
+
+        fs::write(dir.join("key.navgraph"), [0xff; 64]).unwrap();
+        assert!(cache.get("key").unwrap().is_none());
+    }
+
+    #[test]
+    fn nav_graph_cache__evicts_least_recently_written() {
+        let dir = temp_path("nav-graph-cache-evict");
+        let (nav_graph, _) = create_nav_graph(&features(), &InternalCrs::EtrsLaea, None, VisibilityOptimizationMode::Sweep, None);
+        let cache = NavGraphCache::open(&dir).unwrap();
+        cache.put("first", &nav_graph).unwrap();
+        let file_size = fs::metadata(dir.join("first.navgraph")).unwrap().len();
+
+        let cache = cache.with_size_limit(2 * file_size);
+        for key in ["second", "third"] {
+            // Distinct modification times
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            cache.put(key, &nav_graph).unwrap();
+        }
+        assert!(cache.get("first").unwrap().is_none());
+        assert!(cache.get("second").unwrap().is_some());
+        assert!(cache.get("third").unwrap().is_some());
+
+        // The graph just stored stays, even over the limit
+        let cache = cache.with_size_limit(0);
+        cache.put("fourth", &nav_graph).unwrap();
+        assert!(cache.get("third").unwrap().is_none());
+        assert!(cache.get("fourth").unwrap().is_some());
+    }
+
+    #[test]
+    fn create_nav_graph_cached__stores_then_loads() {
+        let dir = temp_path("nav-graph-cache-create");
+        let cache = NavGraphCache::open(&dir).unwrap();
+        let features = features();
+        let mode = VisibilityOptimizationMode::Sweep;
+        let (created, _) = create_nav_graph_cached(&cache, &features, &InternalCrs::EtrsLaea, None, mode, None).unwrap();
+        let key = nav_graph_cache_key(&features, &InternalCrs::EtrsLaea, mode, None).unwrap();
+        assert!(dir.join(format!("{}.navgraph", key)).exists());
+
+        let (loaded, _) = create_nav_graph_cached(&cache, &features, &InternalCrs::EtrsLaea, None, mode, None).unwrap();
+        assert_same_nav_graph(&loaded, &created);
+    }
+}
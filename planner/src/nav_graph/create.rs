@@ -1,16 +1,17 @@
-use std::{collections::HashMap, time::Duration};
+use std::{collections::HashMap, sync::atomic::{AtomicUsize, Ordering}, time::Duration};
 
 use geo::{
     prelude::{Contains, EuclideanDistance},
-    Coordinate, Point,
+    Coordinate, Line, Point,
 };
 use petgraph::{graph::NodeIndex, Graph};
+use rayon::prelude::*;
 
-use crate::{coord_ext::OrderedCoordinate, dgc::DebugGeometryCallback, mpi::is_locally_concave};
+use crate::{coord_ext::OrderedCoordinate, crs::InternalCrs, dgc::DebugGeometryCallback};
 
 use super::{
     graph_types::{Edge, Features, NodeData},
-    visibility::{visible_vertices, VisibilityOptimizationMode},
+    visibility::{is_concave_vertex, visible_vertices, VisibilityOptimizationMode},
     NavGraph,
 };
 
@@ -23,12 +24,19 @@ fn add_visible_edges(
     dgc: DebugGeometryCallback,
     optimization_mode: VisibilityOptimizationMode,
 ) {
+    let ws_visible = visible_vertices(
+        p, ws, &nav_graph.features, dgc.clone(), optimization_mode, nav_graph.max_edge_length
+    );
+    insert_visible_edges(p, ws_visible, nav_graph);
+}
+
+pub(super) fn insert_visible_edges(p: &NodeData, ws_visible: Vec<NodeData>, nav_graph: &mut NavGraph) {
     let p_coord = nav_graph.features.coord(&p);
-    let ws_visible = visible_vertices(p, ws, &nav_graph.features, dgc.clone(), optimization_mode);
     for w_visible in ws_visible {
         let w_visible_coord = nav_graph.features.coord(&w_visible);
         let weight = p_coord.euclidean_distance(&w_visible_coord);
-        let edge = Edge::new(weight);
+        let penalty = nav_graph.features.penalty(Line::new(p_coord, w_visible_coord));
+        let edge = Edge::with_penalty(weight, penalty);
         nav_graph.graph.update_edge(
             nav_graph.node_data_index_map[&p],
             nav_graph.node_data_index_map[&w_visible],
@@ -38,68 +46,115 @@ fn add_visible_edges(
 }
 
 
-pub fn create_nav_graph<'a>(
+/// Whether the visibility sweep passes `node_data`. Every vertex of the graph
+/// is, but it needs the vertices of concave obstacle corners as well to know
+/// which obstacle edges lie in front of it.
+pub(super) fn is_sweep_vertex(node_data: &NodeData, features: &Features) -> bool {
+    // Do not consider water nodes for the graph
+    if let NodeData::PartOfWater(_) = node_data {
+        return false;
+    }
+
+    // Do not consider points within obstacles for the graph
+    let lies_within_obstacle = features
+        .obstacles
+        .contains(&Point(features.coord(node_data)));
+    if lies_within_obstacle { return false; }
+
+    return true;
+}
+
+/// Whether `node_data` is a vertex of the graph created from `features`
+pub(super) fn is_graph_vertex(
+    node_data: &NodeData,
     features: &Features,
-    dgc: DebugGeometryCallback,
     optimization_mode: VisibilityOptimizationMode,
-) -> (NavGraph, Duration) {
-    println!(
-        "Creating nav graph with optimization mode: {:?}",
-        optimization_mode
-    );
+) -> bool {
+    if !is_sweep_vertex(node_data, features) {
+        return false;
+    }
 
+    // Points that are locally concave (pointing inward) would never be part
+    // of the optimal path (why enter a hole if you can just skip it) => do
+    // not consider.
+    return optimization_mode != VisibilityOptimizationMode::Bitangent || !is_concave_vertex(node_data, features);
+}
+
+/// Nav graph with a node for every vertex the graph is made of, but no edges
+/// yet. Also returns the vertices to sweep, those in the graph in node order.
+fn create_nav_graph_nodes(
+    features: &Features,
+    crs: &InternalCrs,
+    optimization_mode: VisibilityOptimizationMode,
+    max_edge_length: Option<f64>,
+) -> (NavGraph, Vec<NodeData>) {
     let mut graph = Graph::new_undirected();
-    let mut vertices = features
+    let vertices = features
         .iter()
-        .filter(|node_data| {
-            // Do not consider water nodes for the graph
-            if let NodeData::PartOfWater(_) = node_data {
-                return false;
-            }
-
-            // Do not consider points within obstacles for the graph
-            let lies_within_obstacle = features
-                .obstacles
-                .contains(&Point(features.coord(node_data)));
-            if lies_within_obstacle { return false; }
-
-            // return if optimization_mode == VisibilityOptimizationMode::OptimizedSweep {
-            //     // Points that are locally concave (pointing inward) would never
-            //     // be part of the optimal path (why enter a hole if you can just
-            //     // skip it) => do not consider.
-            //     let is_locally_concave = match node_data {
-            //         NodeData::PartOfObstacle(mpi) => is_locally_concave(mpi, &features.obstacles),
-            //         NodeData::PartOfWater(_) => return false,
-            //         NodeData::Arbitrary(_) => return true,
-            //     };
-            //     return !is_locally_concave;
-            // } else { true };
-            return true;
-        })
+        .filter(|node_data| is_sweep_vertex(node_data, features))
         .collect::<Vec<_>>();
 
     let node_data_index_map = vertices
         .iter()
+        .filter(|node_data| is_graph_vertex(node_data, features, optimization_mode))
         .map(|node_data| (*node_data, graph.add_node(*node_data)))
         .collect::<HashMap<_, _>>();
 
-    let mut nav_graph = NavGraph {
+    let nav_graph = NavGraph {
         graph,
         node_data_index_map,
         features: features.clone(),
+        crs: crs.clone(),
+        max_edge_length,
     };
 
+    return (nav_graph, vertices);
+}
+
+
+/// Nav graph of `features`. With a `max_edge_length`, vertices further apart
+/// than that are not connected, e.g. because the drone cannot fly that far on
+/// one charge. Like the edge lengths, it is a length in `crs`: scale a
+/// geodesic distance by `InternalCrs::max_scale_factor` first.
+pub fn create_nav_graph<'a>(
+    features: &Features,
+    crs: &InternalCrs,
+    dgc: DebugGeometryCallback,
+    optimization_mode: VisibilityOptimizationMode,
+    max_edge_length: Option<f64>,
+) -> (NavGraph, Duration) {
+    println!(
+        "Creating nav graph with optimization mode: {:?}",
+        optimization_mode
+    );
+
+    let (mut nav_graph, vertices) = create_nav_graph_nodes(features, crs, optimization_mode, max_edge_length);
+    let graph_vertices = vertices
+        .iter()
+        .filter(|vertex| nav_graph.node_data_index_map.contains_key(vertex))
+        .collect::<Vec<_>>();
+
     println!("Adding visible edges...");
     let before_adding_edges = std::time::Instant::now();
-    for (i, vertex) in vertices.clone().iter().enumerate() {
-        print!("\r{}/{}       ", i, vertices.len() - 1);
-        add_visible_edges(
-            vertex,
-            &mut vertices,
-            &mut nav_graph,
-            dgc.clone(),
-            optimization_mode,
-        );
+    // Visibility only reads the features, so every vertex is handled on its
+    // own, in parallel. Each worker keeps its own copy of the vertices to sort
+    // in place. The edges are inserted afterwards, in vertex order, giving
+    // the same graph as inserting them one vertex at a time.
+    let vertices_done = AtomicUsize::new(0);
+    let vertices_ws_visible = graph_vertices
+        .par_iter()
+        .map_init(
+            || vertices.clone(),
+            |ws, vertex| {
+                let ws_visible = visible_vertices(vertex, ws, features, dgc.clone(), optimization_mode, max_edge_length);
+                let done = vertices_done.fetch_add(1, Ordering::Relaxed) + 1;
+                print!("\r{}/{}       ", done, graph_vertices.len());
+                ws_visible
+            },
+        )
+        .collect::<Vec<_>>();
+    for (vertex, ws_visible) in graph_vertices.iter().zip(vertices_ws_visible) {
+        insert_visible_edges(vertex, ws_visible, &mut nav_graph);
     }
     let duration = before_adding_edges.elapsed();
     println!(
@@ -127,3 +182,113 @@ pub fn add_coord_to_nav_graph(
 
     return (p, p_index);
 }
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    extern crate test;
+
+    use std::collections::BTreeSet;
+
+    use geo::{polygon, prelude::EuclideanDistance, MultiPolygon};
+    use test::Bencher;
+
+    use crate::{
+        coord_ext::OrderedCoordinate,
+        crs::InternalCrs,
+        nav_graph::{graph_types::{Features, NodeData, PenalizedArea}, NavGraph, VisibilityOptimizationMode},
+        test_util::{assert_same_nav_graph, grid_features, local, local_polygon, local_square},
+    };
+
+    use super::{add_visible_edges, create_nav_graph, create_nav_graph_nodes};
+
+    /// The previous approach: visible vertices computed and inserted one
+    /// vertex at a time, sorting a single list of vertices in place.
+    fn create_nav_graph_serial(features: &Features, optimization_mode: VisibilityOptimizationMode) -> NavGraph {
+        let (mut nav_graph, mut vertices) =
+            create_nav_graph_nodes(features, &InternalCrs::EtrsLaea, optimization_mode, None);
+        for vertex in vertices.clone().iter() {
+            if nav_graph.node_data_index_map.contains_key(vertex) {
+                add_visible_edges(vertex, &mut vertices, &mut nav_graph, None, optimization_mode);
+            }
+        }
+        nav_graph
+    }
+
+    /// An `n` by `n` grid of 20 m square obstacles, 100 m apart, plus an
+    /// obstacle with a hole, a penalized area and two arbitrary points
+    fn features(n: usize) -> Features {
+        let mut features = grid_features(n);
+        features.obstacles.0.push(local_polygon(polygon!(
+            exterior: [(x: -300.0, y: -300.0), (x: -100.0, y: -300.0), (x: -100.0, y: -100.0), (x: -300.0, y: -100.0)],
+            interiors: [[(x: -250.0, y: -250.0), (x: -250.0, y: -150.0), (x: -150.0, y: -150.0), (x: -150.0, y: -250.0)]],
+        )));
+        features.penalized_areas =
+            vec![PenalizedArea::new(MultiPolygon(vec![local_square(50.0, 50.0, 100.0)]), 2.0)];
+        features.arbitrary = vec![OrderedCoordinate(local(-400.0, -400.0)), OrderedCoordinate(local(-200.0, -200.0))];
+        features
+    }
+
+    #[test]
+    fn create_nav_graph__same_as_serial() {
+        let features = features(4);
+        for optimization_mode in [
+            VisibilityOptimizationMode::Naive,
+            VisibilityOptimizationMode::Sweep,
+            VisibilityOptimizationMode::OptimizedSweep,
+            VisibilityOptimizationMode::Bitangent,
+        ] {
+            let (nav_graph, _) = create_nav_graph(&features, &InternalCrs::EtrsLaea, None, optimization_mode, None);
+            assert_same_nav_graph(&nav_graph, &create_nav_graph_serial(&features, optimization_mode));
+        }
+    }
+
+    /// Edges as pairs of their endpoints, the smaller one first
+    fn edge_set(nav_graph: &NavGraph) -> BTreeSet<(NodeData, NodeData)> {
+        nav_graph.graph
+            .edge_indices()
+            .map(|edge_index| {
+                let (a, b) = nav_graph.graph.edge_endpoints(edge_index).unwrap();
+                let (a, b) = (nav_graph.graph[a], nav_graph.graph[b]);
+                (a.min(b), a.max(b))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn create_nav_graph__max_edge_length() {
+        let features = features(4);
+        let max_edge_length = 150.0;
+        for optimization_mode in [
+            VisibilityOptimizationMode::Naive,
+            VisibilityOptimizationMode::Sweep,
+            VisibilityOptimizationMode::OptimizedSweep,
+            VisibilityOptimizationMode::Bitangent,
+        ] {
+            let (nav_graph, _) =
+                create_nav_graph(&features, &InternalCrs::EtrsLaea, None, optimization_mode, Some(max_edge_length));
+            assert!(nav_graph.graph.edge_weights().all(|edge| edge.length <= max_edge_length));
+            // Pruning leaves the edges in range as they were
+            let (unpruned, _) = create_nav_graph(&features, &InternalCrs::EtrsLaea, None, optimization_mode, None);
+            let edges_in_range = edge_set(&unpruned)
+                .into_iter()
+                .filter(|(a, b)| features.coord(a).euclidean_distance(&features.coord(b)) <= max_edge_length)
+                .collect::<BTreeSet<_>>();
+            assert!(!edges_in_range.is_empty());
+            assert_eq!(edge_set(&nav_graph), edges_in_range);
+        }
+    }
+
+    #[bench]
+    fn create_nav_graph__serial(b: &mut Bencher) {
+        let features = features(8);
+        b.iter(|| create_nav_graph_serial(&features, VisibilityOptimizationMode::Sweep));
+    }
+
+    #[bench]
+    fn create_nav_graph__parallel(b: &mut Bencher) {
+        let features = features(8);
+        b.iter(|| create_nav_graph(&features, &InternalCrs::EtrsLaea, None, VisibilityOptimizationMode::Sweep, None));
+    }
+}
@@ -1,33 +1,98 @@
 use geo::{GeometryCollection, Line, MultiPoint, Point};
 use geojson::{Feature, FeatureCollection};
-use proj::Transform;
-
-use crate::crs::create_to_ext_proj;
 
 use super::graph_types::NavGraph;
 
 pub fn nav_graph_to_feature_collection(
     nav_graph: &NavGraph,
 ) -> geojson::FeatureCollection {
-    let proj = create_to_ext_proj();
     let features = &nav_graph.features;
-    let vertices_geom = MultiPoint::from_iter(
-        nav_graph.graph
-            .node_weights()
-            .map(|node_data| Point(features.coord(node_data).transformed(&proj).unwrap())),
-    );
+    // Project every vertex once, in one batch; edges look their endpoints up
+    // by node index
+    let mut coords = nav_graph.graph
+        .node_weights()
+        .map(|node_data| features.coord(node_data))
+        .collect::<Vec<_>>();
+    nav_graph.crs.to_ext_coords(&mut coords).unwrap();
+    let vertices_geom = MultiPoint::from_iter(coords.iter().map(|coord| Point(*coord)));
     let vertices_feature = Feature::from(geojson::Geometry::from(&vertices_geom));
     let edge_geom = GeometryCollection::from_iter(nav_graph.graph.edge_indices().map(|edge_index| {
         let (start_index, end_index) = nav_graph.graph.edge_endpoints(edge_index).unwrap();
-        Line::new(
-            features.coord(
-                nav_graph.graph.node_weight(start_index).unwrap()
-            ).transformed(&proj).unwrap(),
-            features.coord(
-                nav_graph.graph.node_weight(end_index).unwrap()
-            ).transformed(&proj).unwrap(),
-        )
+        Line::new(coords[start_index.index()], coords[end_index.index()])
     }));
     let edge_feature = Feature::from(geojson::Geometry::from(&edge_geom));
     return FeatureCollection::from_iter([vertices_feature, edge_feature]);
 }
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    extern crate test;
+
+    use geo::{GeometryCollection, Line, MultiPoint, Point};
+    use geojson::{Feature, FeatureCollection};
+    use proj::{Proj, Transform};
+    use test::Bencher;
+
+    use crate::{
+        crs::{InternalCrs, ETRS_CRS, WSG_CRS},
+        nav_graph::{create_nav_graph, NavGraph, VisibilityOptimizationMode},
+        test_util::grid_features,
+    };
+
+    use super::nav_graph_to_feature_collection;
+
+    /// Nav graph around an `n` by `n` grid of 20 m square obstacles, 100 m
+    /// apart, see `grid_features`
+    fn grid_nav_graph(n: usize) -> NavGraph {
+        let (nav_graph, _) = create_nav_graph(
+            &grid_features(n), &InternalCrs::EtrsLaea, None, VisibilityOptimizationMode::Sweep, None
+        );
+        nav_graph
+    }
+
+    /// The previous approach: a fresh projection per call, every coordinate
+    /// projected on its own (edge endpoints again for every edge).
+    fn feature_collection_proj_per_coord(nav_graph: &NavGraph) -> FeatureCollection {
+        let proj = Proj::new_known_crs(ETRS_CRS, WSG_CRS, None).unwrap();
+        let features = &nav_graph.features;
+        let vertices_geom = MultiPoint::from_iter(
+            nav_graph.graph
+                .node_weights()
+                .map(|node_data| Point(features.coord(node_data).transformed(&proj).unwrap())),
+        );
+        let edge_geom = GeometryCollection::from_iter(nav_graph.graph.edge_indices().map(|edge_index| {
+            let (start_index, end_index) = nav_graph.graph.edge_endpoints(edge_index).unwrap();
+            Line::new(
+                features.coord(nav_graph.graph.node_weight(start_index).unwrap()).transformed(&proj).unwrap(),
+                features.coord(nav_graph.graph.node_weight(end_index).unwrap()).transformed(&proj).unwrap(),
+            )
+        }));
+        FeatureCollection::from_iter([
+            Feature::from(geojson::Geometry::from(&vertices_geom)),
+            Feature::from(geojson::Geometry::from(&edge_geom)),
+        ])
+    }
+
+    #[test]
+    fn nav_graph_to_feature_collection__same_as_proj_per_coord() {
+        let nav_graph = grid_nav_graph(3);
+        assert_eq!(
+            nav_graph_to_feature_collection(&nav_graph),
+            feature_collection_proj_per_coord(&nav_graph)
+        );
+    }
+
+    #[bench]
+    fn nav_graph_to_feature_collection__proj_per_coord(b: &mut Bencher) {
+        let nav_graph = grid_nav_graph(6);
+        b.iter(|| feature_collection_proj_per_coord(&nav_graph));
+    }
+
+    #[bench]
+    fn nav_graph_to_feature_collection__batched(b: &mut Bencher) {
+        let nav_graph = grid_nav_graph(6);
+        b.iter(|| nav_graph_to_feature_collection(&nav_graph));
+    }
+}
@@ -1,27 +1,61 @@
-use std::{collections::HashMap};
+use std::{cmp::Ordering, collections::HashMap};
 
 use derive_more::{Add, Constructor};
-use geo::{Coordinate, MultiPolygon};
+use geo::{Coordinate, Line, MultiPolygon};
 use petgraph::{graph::NodeIndex, Graph, Undirected};
+use serde::{Deserialize, Serialize};
 
-use crate::{coord_ext::OrderedCoordinate, mpi::{Mpi, MpiCoordsIterable}};
+use crate::{coord_ext::OrderedCoordinate, crs::InternalCrs, line_length_within::line_length_within, mpi::{Mpi, MpiCoordsIterable}};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub enum NodeData {
     PartOfWater(Mpi),
     PartOfObstacle(Mpi),
     Arbitrary(usize),
 }
 
-#[derive(Debug, Clone, Copy, Default, Constructor, PartialEq, PartialOrd, Add)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Add, Serialize, Deserialize)]
 pub struct Edge {
+    /// Distance flown, used for range checks
     pub length: f64,
+    /// What path searches minimise: `length` plus any penalty for crossing
+    /// discouraged areas. Never smaller than `length`.
+    pub cost: f64,
 }
 
-#[derive(Debug, Clone)]
+impl Edge {
+    pub fn new(length: f64) -> Self {
+        Edge { length, cost: length }
+    }
+
+    pub fn with_penalty(length: f64, penalty: f64) -> Self {
+        Edge { length, cost: length + penalty }
+    }
+}
+
+/// Edges are ordered by cost first, so path searches prefer cheap paths over
+/// short ones.
+impl PartialOrd for Edge {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match self.cost.partial_cmp(&other.cost) {
+            Some(Ordering::Equal) => self.length.partial_cmp(&other.length),
+            ordering => ordering,
+        }
+    }
+}
+
+/// Area that may be crossed, at `penalty` extra cost per metre flown inside it.
+#[derive(Debug, Clone, Constructor, Serialize, Deserialize)]
+pub struct PenalizedArea {
+    pub area: MultiPolygon<f64>,
+    pub penalty: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Features {
     pub obstacles: MultiPolygon<f64>,
     pub waters: MultiPolygon<f64>,
+    pub penalized_areas: Vec<PenalizedArea>,
     pub arbitrary: Vec<OrderedCoordinate>,
 }
 
@@ -34,6 +68,14 @@ impl Features {
         }
     }
 
+    /// Total penalty for flying along `line` through the penalized areas.
+    pub fn penalty(&self, line: Line<f64>) -> f64 {
+        self.penalized_areas
+            .iter()
+            .map(|penalized| penalized.penalty * line_length_within(line, &penalized.area))
+            .sum()
+    }
+
     pub fn iter(&self) -> Box<dyn Iterator<Item = NodeData> + '_> {
         box self.obstacles.indexed_coords_iter().map(|mpi| NodeData::PartOfObstacle(mpi)).chain(
             self.waters.indexed_coords_iter().map(|mpi| NodeData::PartOfWater(mpi))
@@ -44,9 +86,14 @@ impl Features {
 }
 
 /// NavGraph contains both a graph as well as a map of node indices to node data
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NavGraph {
     pub graph: Graph<NodeData, Edge, Undirected>,
     pub node_data_index_map: HashMap<NodeData, NodeIndex>,
     pub features: Features,
+    /// CRS of the features, every projection to and from the graph uses it
+    pub crs: InternalCrs,
+    /// Longest edge the graph may have, see `visible_vertices`. Also applies
+    /// to vertices added later.
+    pub max_edge_length: Option<f64>,
 }
@@ -3,12 +3,18 @@ mod create;
 mod graph_geojson;
 pub mod graph_types;
 mod bounded_astar;
+mod cache;
 mod planning;
 mod shortest_path;
+mod tiled;
+mod update;
 
+pub use cache::{create_nav_graph_cached, NavGraphCache};
 pub use create::{add_coord_to_nav_graph, create_nav_graph};
 pub use visibility::VisibilityOptimizationMode;
 pub use graph_geojson::nav_graph_to_feature_collection;
 pub use graph_types::{Edge, NavGraph, NodeData};
-pub use planning::plan_path_or_recharge;
+pub use planning::{plan_leg, plan_path_or_recharge, LegPlan, PlannedLeg};
 pub use shortest_path::{calculate_shortest_path, calculate_shortest_path_between_coords};
+pub use tiled::{TileIndex, TiledNavGraph, TiledNodeIndex};
+pub use update::{insert_obstacle_into_nav_graph, remove_obstacle_from_nav_graph};
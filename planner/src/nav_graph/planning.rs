@@ -1,6 +1,6 @@
 use std::{error::Error, collections::{HashSet, BinaryHeap, BTreeMap, HashMap, hash_map::Entry}};
 
-use geo::{prelude::{EuclideanDistance, ClosestPoint}, Point, LineString, GeometryCollection, Geometry, Coordinate};
+use geo::{prelude::{EuclideanDistance, ClosestPoint, GeodesicLength}, Point, LineString, GeometryCollection, Geometry, Coordinate};
 use ordered_float::OrderedFloat;
 use petgraph::{graph::NodeIndex, algo::astar};
 use strum_macros::Display;
@@ -23,6 +23,186 @@ pub enum PlannerError {
 }
 impl Error for PlannerError {}
 
+/// Result of planning a single leg
+#[derive(Debug, Clone)]
+pub enum LegPlan {
+    /// `end` is within range
+    ToEnd(Vec<(NodeIndex, Edge)>),
+    /// `end` is out of range, recharge first
+    ToRecharge {
+        /// How far the drone could have flown along the path to `end`
+        last_reachable_coord: Coordinate<f64>,
+        path: Vec<(NodeIndex, Edge)>,
+        recharge_point: NodeIndex,
+    },
+}
+
+/// Geometry and totals of a planned leg, e.g. for export
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlannedLeg {
+    pub path: LineString<f64>,
+    /// Length in the nav graph's (projected) CRS
+    pub length: f64,
+    /// Length on the WGS84 ellipsoid, what the drone actually flies
+    pub geodesic_length: f64,
+    pub cost: f64,
+    /// Where the leg ends to recharge, `None` for the leg reaching the end
+    pub recharge_point: Option<Coordinate<f64>>,
+}
+
+impl PlannedLeg {
+    pub fn new(nav_graph: &NavGraph, leg_plan: &LegPlan) -> Self {
+        let (path, recharge_point) = match leg_plan {
+            LegPlan::ToEnd(path) => (path, None),
+            LegPlan::ToRecharge { path, recharge_point, .. } => (path, Some(*recharge_point)),
+        };
+        // Path edges are cumulative
+        let total = path.last().map_or(Edge::default(), |(_, edge)| *edge);
+        let path = path_line_string(nav_graph, path);
+        PlannedLeg {
+            geodesic_length: geodesic_length(nav_graph, &path),
+            path,
+            length: total.length,
+            cost: total.cost,
+            recharge_point: recharge_point.map(|node_index| {
+                nav_graph.features.coord(nav_graph.graph.node_weight(node_index).unwrap())
+            }),
+        }
+    }
+
+    /// Relative difference between the geodesic and projected length, e.g.
+    /// `0.001` if the leg is 0.1% longer in reality than in the projection
+    pub fn distortion(&self) -> f64 {
+        if self.length == 0.0 {
+            return 0.0;
+        }
+        return self.geodesic_length / self.length - 1.0;
+    }
+}
+
+fn path_line_string(nav_graph: &NavGraph, path: &[(NodeIndex, Edge)]) -> LineString<f64> {
+    return LineString::from_iter(path.iter()
+        .map(|(n, _)| nav_graph.features.coord(nav_graph.graph.node_weight(*n).unwrap()))
+    );
+}
+
+/// Length of `line_string` (in the nav graph's CRS) on the WGS84 ellipsoid
+fn geodesic_length(nav_graph: &NavGraph, line_string: &LineString<f64>) -> f64 {
+    let mut line_string = line_string.clone();
+    nav_graph.crs.to_ext(&mut line_string).unwrap();
+    return line_string.geodesic_length();
+}
+
+/// Plan a leg of at most `leg_max_distance` from `leg_start`: either straight
+/// to `end` or, if that is out of range, to the water vertex closest to
+/// where the drone would run out of range.
+///
+/// `leg_max_distance` is checked against the geodesic length of the leg, not
+/// its length in the nav graph's projection.
+pub fn plan_leg(
+    nav_graph: &NavGraph,
+    leg_max_distance: f64,
+    leg_start: NodeIndex,
+    end: NodeIndex,
+    dgc: DebugGeometryCallback,
+) -> Result<LegPlan, PlannerError> {
+    let end_coord = nav_graph.features.coord(nav_graph.graph.node_weight(end).unwrap());
+    let leg_start_coord = nav_graph.features.coord(nav_graph.graph.node_weight(leg_start).unwrap());
+
+    let (leg_path_to_end_data, _) = bounded_astar(
+        &nav_graph.graph,
+        leg_start,
+        |n, _| if n == end { IsGoalResult::Goal } else { IsGoalResult::NotGoal },
+        |e| *e.weight(),
+        |node_index| {
+            let node_data = nav_graph.graph.node_weight(node_index).unwrap();
+            let coord = nav_graph.features.coord(node_data);
+            Edge::new(coord.euclidean_distance(&end_coord))
+        },
+    );
+    let (Edge { length: leg_distance_to_end, .. }, leg_path_to_end) = leg_path_to_end_data.ok_or(PlannerError::NoPathToEnd)?;
+    println!("leg_distance_to_end: {}", leg_distance_to_end);
+    println!("leg_max_distance: {}", leg_max_distance);
+
+    let path_to_end_geometry = path_line_string(nav_graph, &leg_path_to_end);
+    if let Some(dgc) = dgc {
+        dgc.try_send(path_to_end_geometry.clone().into());
+    }
+
+    // The range budget is a real-world distance, but the graph is searched
+    // with projected lengths. Scale the budget by the distortion along the
+    // direct path; candidate legs are still verified geodesically below.
+    let geodesic_distance_to_end = geodesic_length(nav_graph, &path_to_end_geometry);
+    println!("geodesic_distance_to_end: {}", geodesic_distance_to_end);
+    if geodesic_distance_to_end <= leg_max_distance {
+        println!("Goal reached");
+        return Ok(LegPlan::ToEnd(leg_path_to_end));
+    }
+    let projected_max_distance = leg_max_distance * leg_distance_to_end / geodesic_distance_to_end;
+
+    let last_reachable_point = line_string_point_at_length(
+        path_to_end_geometry,
+        projected_max_distance
+    ).unwrap();
+
+    let mut possible_recharge_points = nav_graph.features.waters.indexed_coords_iter()
+        .map(|mpi| (mpi, (&nav_graph.features.waters)[&mpi]))
+        .filter(|(_, coord)| {
+            let leg_start_to_recharge_distance = leg_start_coord.euclidean_distance(coord);
+            leg_start_to_recharge_distance <= projected_max_distance
+        })
+        .collect::<Vec<_>>();
+    possible_recharge_points.sort_by_key(|(_, coord)| OrderedFloat(coord.euclidean_distance(&last_reachable_point)));
+
+    let (
+        best_recharge_point_mpi,
+        best_recharge_point_path,
+     ) = possible_recharge_points.iter()
+        .find_map(|(recharge_point_mpi, recharge_point_coord)| {
+            let (start_to_recharge_point_path_data, _) = bounded_astar(
+                &nav_graph.graph,
+                leg_start,
+                |n, Edge { length: path_length, .. }| {
+                    if path_length > projected_max_distance {
+                        return IsGoalResult::MaximumExtend;
+                    }
+
+                    let node_data = nav_graph.graph.node_weight(n).unwrap();
+                    if let NodeData::PartOfWater(n_mpi) = node_data {
+                        if n_mpi == recharge_point_mpi {
+                            return IsGoalResult::Goal;
+                        }
+                    }
+                    return IsGoalResult::NotGoal;
+                },
+                |e| *e.weight(),
+                |node_index| {
+                    let node_coord = nav_graph.features.coord(nav_graph.graph.node_weight(node_index).unwrap());
+                    Edge::new(node_coord.euclidean_distance(recharge_point_coord))
+                }
+            );
+            let (_, path_to_charge_point) = start_to_recharge_point_path_data?;
+            let geodesic_distance_to_charge_point = geodesic_length(
+                nav_graph, &path_line_string(nav_graph, &path_to_charge_point)
+            );
+            if geodesic_distance_to_charge_point > leg_max_distance {
+                println!(
+                    "Rejected recharge point, geodesically {} > {}",
+                    geodesic_distance_to_charge_point, leg_max_distance
+                );
+                return None;
+            }
+            Some((recharge_point_mpi, path_to_charge_point))
+        })
+        .ok_or(PlannerError::NoPathToWater)?;
+
+    return Ok(LegPlan::ToRecharge {
+        last_reachable_coord: last_reachable_point,
+        path: best_recharge_point_path,
+        recharge_point: nav_graph.node_data_index_map[&NodeData::PartOfWater(*best_recharge_point_mpi)],
+    });
+}
+
 /// New approach using a single A* search
 pub fn plan_path_or_recharge(
     nav_graph: &NavGraph,
@@ -35,147 +215,30 @@ pub fn plan_path_or_recharge(
     let end_coord = nav_graph.features.coord(nav_graph.graph.node_weight(end).unwrap());
     
     let mut leg_start = start;
-    let mut prev_leg_start = leg_start;
     let mut leg_max_distance = max_distance_initially;
     let mut legs = Vec::<(Coordinate<f64>, Vec<(NodeIndex, Edge)>)>::new();
 
     loop {
-        let leg_start_coord = nav_graph.features.coord(nav_graph.graph.node_weight(leg_start).unwrap());
-
-        let (leg_path_to_end_data, _) = bounded_astar(
-            &nav_graph.graph,
-            leg_start,
-            |n, _| if n == end { IsGoalResult::Goal } else { IsGoalResult::NotGoal },
-            |e| *e.weight(),
-            |node_index| {
-                let node_data = nav_graph.graph.node_weight(node_index).unwrap();
-                let coord = nav_graph.features.coord(node_data);
-                Edge::new(coord.euclidean_distance(&end_coord))
-            },
-        );
-        let (Edge { length: leg_distance_to_end }, leg_path_to_end) = leg_path_to_end_data.ok_or(PlannerError::NoPathToEnd)?;
-        println!("leg_distance_to_end: {}", leg_distance_to_end);
-        println!("leg_max_distance: {}", leg_max_distance);
-
-        if leg_start == start {
-            let path_geometry = LineString(
-                leg_path_to_end
-                    .iter()
-                    .map(|(node_index, _)| {
-                        let node_data = &nav_graph.graph.node_weight(*node_index).unwrap();
-                        nav_graph.features.coord(node_data)
-                    })
-                    .collect::<Vec<_>>(),
-            );
-            dgc.clone().unwrap().try_send(path_geometry.into());
-        }
-
-        if leg_distance_to_end <= leg_max_distance {
-            println!("Goal reached");
-            legs.push((end_coord, leg_path_to_end));
-            break;
-        }
-
-        let last_reachable_point = line_string_point_at_length(
-            LineString::from_iter(leg_path_to_end.iter()
-                .map(|(n, _)| nav_graph.features.coord(nav_graph.graph.node_weight(*n).unwrap()))
-            ),
-            leg_max_distance
-        ).unwrap();
-
-        let mut possible_recharge_points = nav_graph.features.waters.indexed_coords_iter()
-            .map(|mpi| (mpi, (&nav_graph.features.waters)[&mpi]))
-            .filter(|(_, coord)| {
-                let leg_start_to_recharge_distance = leg_start_coord.euclidean_distance(coord);
-                leg_start_to_recharge_distance <= leg_max_distance
-            })
-            .collect::<Vec<_>>();
-        possible_recharge_points.sort_by_key(|(_, coord)| OrderedFloat(coord.euclidean_distance(&last_reachable_point)));
-    
-        let (
-            best_recharge_point_mpi,
-            best_recharge_point_coord,
-            best_recharge_point_path,
-         ) = possible_recharge_points.iter()
-            .find_map(|(recharge_point_mpi, recharge_point_coord)| {
-                let (start_to_recharge_point_path_data, _) = bounded_astar(
-                    &nav_graph.graph,
-                    leg_start,
-                    |n, Edge { length: path_length }| {
-                        if path_length > max_distance_initially {
-                            return IsGoalResult::MaximumExtend;
-                        }
-    
-                        let node_data = nav_graph.graph.node_weight(n).unwrap();
-                        if let NodeData::PartOfWater(n_mpi) = node_data {
-                            if n_mpi == recharge_point_mpi {
-                                return IsGoalResult::Goal;
-                            }
-                        }
-                        return IsGoalResult::NotGoal;
-                    },
-                    |e| *e.weight(),
-                    |node_index| {
-                        let node_coord = nav_graph.features.coord(nav_graph.graph.node_weight(node_index).unwrap());
-                        Edge::new(node_coord.euclidean_distance(recharge_point_coord))
-                    }
-                );
-                let (_, path_to_charge_point) = start_to_recharge_point_path_data?;
-                Some((recharge_point_mpi, recharge_point_coord, path_to_charge_point))
-            })
-            .ok_or(PlannerError::NoPathToWater)?;
-
-        let best_recharge_point = nav_graph.node_data_index_map[&NodeData::PartOfWater(*best_recharge_point_mpi)];
-
-        legs.push((last_reachable_point, best_recharge_point_path));
-
-        prev_leg_start = leg_start;
-        leg_start = best_recharge_point;
-
-        if leg_start == prev_leg_start {
-            println!("Loop");
-            break;
+        // Only the first leg's direct path is drawn for debugging
+        let leg_dgc = if leg_start == start { dgc.clone() } else { None };
+        match plan_leg(nav_graph, leg_max_distance, leg_start, end, leg_dgc)? {
+            LegPlan::ToEnd(path) => {
+                legs.push((end_coord, path));
+                break;
+            }
+            LegPlan::ToRecharge { last_reachable_coord, path, recharge_point } => {
+                legs.push((last_reachable_coord, path));
+                if recharge_point == leg_start {
+                    println!("Loop");
+                    break;
+                }
+                leg_start = recharge_point;
+            }
         }
 
         leg_max_distance = max_distance_after_charge;
     }
 
-    // for leg in legs {
-    //     let leg_geometry = LineString(
-    //         leg
-    //             .iter()
-    //             .map(|(node_index, _)| {
-    //                 let node_data = &nav_graph.graph.node_weight(*node_index).unwrap();
-    //                 nav_graph.features.coord(node_data)
-    //             })
-    //             .collect::<Vec<_>>(),
-    //     );
-    //     dgc.clone().unwrap().try_send(leg_geometry.into());
-    // }
-
-    // dgc.clone().unwrap().try_send(Geometry::GeometryCollection(GeometryCollection(reachable_waters.iter().map(|(water_index, _)| {
-    //     let water = &nav_graph.features.waters.0[*water_index];
-    //     Geometry::Polygon(water.clone())
-    // }).collect::<Vec<_>>())));
-
-    // match end_reason {
-    //     ReasonToEndRechargeSearch::MaxDistanceReached => {
-    //         return Err(PlannerError::MaxDistanceReached);
-    //     },
-    //     ReasonToEndRechargeSearch::WaterReached(_) => {
-    //         let path_geometry = LineString(
-    //             shortest_path_to_water
-    //                 .iter()
-    //                 .map(|(node_index, _)| {
-    //                     let node_data = &nav_graph.graph.node_weight(*node_index).unwrap();
-    //                     nav_graph.features.coord(node_data)
-    //                 })
-    //                 .collect::<Vec<_>>(),
-    //         );
-    //         dgc.unwrap().try_send(path_geometry.into());
-    //     },
-    // }
-
     return Ok(legs);
 }
 
@@ -210,7 +273,7 @@ pub fn plan_path_or_recharge_old(
                 Edge::new(coord.euclidean_distance(&end_coord))
             },
         );
-        let (Edge { length: leg_distance_to_end }, leg_path_to_end) = leg_path_to_end_data.ok_or(PlannerError::NoPathToEnd)?;
+        let (Edge { length: leg_distance_to_end, .. }, leg_path_to_end) = leg_path_to_end_data.ok_or(PlannerError::NoPathToEnd)?;
         println!("leg_distance_to_end: {}", leg_distance_to_end);
         println!("leg_max_distance: {}", leg_max_distance);
 
@@ -258,7 +321,7 @@ pub fn plan_path_or_recharge_old(
                 let (start_to_recharge_point_path_data, _) = bounded_astar(
                     &nav_graph.graph,
                     leg_start,
-                    |n, Edge { length: path_length }| {
+                    |n, Edge { length: path_length, .. }| {
                         if path_length > max_distance_initially {
                             return IsGoalResult::MaximumExtend;
                         }
@@ -335,3 +398,64 @@ pub fn plan_path_or_recharge_old(
 
     return Ok(legs);
 }
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use geo::{coord, MultiPolygon};
+    use petgraph::graph::NodeIndex;
+
+    use crate::{
+        crs::InternalCrs,
+        nav_graph::{add_coord_to_nav_graph, create_nav_graph, graph_types::Features, NavGraph, VisibilityOptimizationMode},
+    };
+
+    use super::{plan_leg, LegPlan, PlannedLeg, PlannerError};
+
+    /// Nav graph without obstacles or waters, with a leg of about 10 km due
+    /// north along the central meridian of UTM zone 31, where the projection
+    /// makes distances 0.04% shorter than they are
+    fn central_meridian_leg() -> (NavGraph, NodeIndex, NodeIndex) {
+        let crs = InternalCrs::Utm { zone: 31, north: true };
+        let features = Features {
+            obstacles: MultiPolygon(vec![]),
+            waters: MultiPolygon(vec![]),
+            penalized_areas: Vec::new(),
+            arbitrary: Vec::new(),
+        };
+        let mode = VisibilityOptimizationMode::Sweep;
+        let (mut nav_graph, _) = create_nav_graph(&features, &crs, None, mode, None);
+        let mut coords = [coord! { x: 3.0, y: 45.0 }, coord! { x: 3.0, y: 45.09 }];
+        crs.to_int_coords(&mut coords).unwrap();
+        let (_, start) = add_coord_to_nav_graph(coords[0], &mut nav_graph, None, mode);
+        let (_, end) = add_coord_to_nav_graph(coords[1], &mut nav_graph, None, mode);
+        (nav_graph, start, end)
+    }
+
+    #[test]
+    fn planned_leg__geodesic_length() {
+        let (nav_graph, start, end) = central_meridian_leg();
+        let leg_plan = plan_leg(&nav_graph, 20_000.0, start, end, None).unwrap();
+        let leg = PlannedLeg::new(&nav_graph, &leg_plan);
+        assert!((leg.geodesic_length - 10_001.9).abs() < 1.0);
+        assert!((leg.length - 9_997.9).abs() < 1.0);
+        assert!((leg.distortion() - 0.0004).abs() < 0.00001);
+    }
+
+    #[test]
+    fn plan_leg__within_geodesic_budget() {
+        let (nav_graph, start, end) = central_meridian_leg();
+        let leg_plan = plan_leg(&nav_graph, 10_005.0, start, end, None).unwrap();
+        assert!(matches!(leg_plan, LegPlan::ToEnd(_)));
+    }
+
+    #[test]
+    fn plan_leg__projected_within_budget_geodesic_not() {
+        let (nav_graph, start, end) = central_meridian_leg();
+        // Projected ~9998 m fits, geodesic ~10002 m does not; without waters
+        // there is nowhere to recharge
+        let result = plan_leg(&nav_graph, 10_000.0, start, end, None);
+        assert!(matches!(result, Err(PlannerError::NoPathToWater)));
+    }
+}
@@ -1,8 +1,6 @@
 use geo::{Coordinate, prelude::EuclideanDistance};
 use petgraph::{algo::astar, stable_graph::NodeIndex};
 
-use crate::crs::create_to_int_proj;
-
 use super::{NavGraph, Edge, add_coord_to_nav_graph, VisibilityOptimizationMode};
 
 pub fn calculate_shortest_path_between_coords(
@@ -11,15 +9,9 @@ pub fn calculate_shortest_path_between_coords(
     end_coord: Coordinate<f64>,
     visibility_optimization_mode: VisibilityOptimizationMode,
 ) -> Option<(Edge, Vec<NodeIndex>)> {
-    // Projection needs to be in a separate scope because `proj::Proj`
-    // is `!Send`.
-    let (start_coord, end_coord) = {
-        let proj = create_to_int_proj();
-        (
-            proj.project(start_coord, false).unwrap(),
-            proj.project(end_coord, false).unwrap(),
-        )
-    };
+    let mut coords = [start_coord, end_coord];
+    nav_graph.crs.to_int_coords(&mut coords).unwrap();
+    let [start_coord, end_coord] = coords;
     let (_, start_index) = add_coord_to_nav_graph(start_coord, nav_graph, None, visibility_optimization_mode);
     let (_, end_index) = add_coord_to_nav_graph(end_coord, nav_graph, None, visibility_optimization_mode);
     
@@ -41,4 +33,80 @@ pub fn calculate_shortest_path(
             Edge::new(coord.euclidean_distance(&end_coord))
         },
     )
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use approx::assert_relative_eq;
+    use geo::{polygon, Coordinate};
+
+    use crate::{
+        crs::InternalCrs,
+        nav_graph::{add_coord_to_nav_graph, create_nav_graph, graph_types::Features, NavGraph, VisibilityOptimizationMode},
+        test_util::{local, local_features},
+    };
+
+    use super::calculate_shortest_path;
+
+    /// Concave obstacles (a U, an L and a square with a hole) and a triangle
+    fn features() -> Features {
+        local_features(vec![
+            polygon![
+                (x: 0.0, y: 0.0), (x: 60.0, y: 0.0), (x: 60.0, y: 60.0), (x: 45.0, y: 60.0),
+                (x: 45.0, y: 15.0), (x: 15.0, y: 15.0), (x: 15.0, y: 60.0), (x: 0.0, y: 60.0),
+            ],
+            polygon![
+                (x: 100.0, y: 0.0), (x: 160.0, y: 0.0), (x: 160.0, y: 20.0),
+                (x: 120.0, y: 20.0), (x: 120.0, y: 70.0), (x: 100.0, y: 70.0),
+            ],
+            polygon!(
+                exterior: [(x: 40.0, y: 100.0), (x: 90.0, y: 100.0), (x: 90.0, y: 150.0), (x: 40.0, y: 150.0)],
+                interiors: [[(x: 55.0, y: 115.0), (x: 55.0, y: 135.0), (x: 75.0, y: 135.0), (x: 75.0, y: 115.0)]],
+            ),
+            polygon![(x: 130.0, y: 110.0), (x: 170.0, y: 100.0), (x: 150.0, y: 150.0)],
+        ])
+    }
+
+    /// Starts and ends of paths: in the U, in between and around the obstacles
+    fn coords() -> Vec<Coordinate<f64>> {
+        [(30.0, 40.0), (80.0, 190.0), (180.0, 50.0), (-20.0, -20.0), (110.0, 90.0), (140.0, 40.0)]
+            .into_iter()
+            .map(|(x, y)| local(x, y))
+            .collect()
+    }
+
+    /// Lengths of the shortest paths between every two of `coords()`
+    fn shortest_path_lengths(nav_graph: &mut NavGraph, optimization_mode: VisibilityOptimizationMode) -> Vec<f64> {
+        let node_indices = coords()
+            .into_iter()
+            .map(|coord| add_coord_to_nav_graph(coord, nav_graph, None, optimization_mode).1)
+            .collect::<Vec<_>>();
+        let mut lengths = Vec::new();
+        for (i, start_index) in node_indices.iter().enumerate() {
+            for end_index in &node_indices[i + 1..] {
+                let (edge, _) = calculate_shortest_path(nav_graph, *start_index, *end_index).unwrap();
+                lengths.push(edge.length);
+            }
+        }
+        lengths
+    }
+
+    #[test]
+    fn calculate_shortest_path__bitangent_same_as_sweep() {
+        let features = features();
+        let (mut sweep, _) =
+            create_nav_graph(&features, &InternalCrs::EtrsLaea, None, VisibilityOptimizationMode::Sweep, None);
+        let (mut bitangent, _) =
+            create_nav_graph(&features, &InternalCrs::EtrsLaea, None, VisibilityOptimizationMode::Bitangent, None);
+        // The concave vertices: two of the U, one of the L and the hole's four
+        assert_eq!(sweep.graph.node_count() - bitangent.graph.node_count(), 7);
+        assert!(bitangent.graph.edge_count() < sweep.graph.edge_count());
+
+        let sweep_lengths = shortest_path_lengths(&mut sweep, VisibilityOptimizationMode::Sweep);
+        let bitangent_lengths = shortest_path_lengths(&mut bitangent, VisibilityOptimizationMode::Bitangent);
+        for (bitangent_length, sweep_length) in bitangent_lengths.into_iter().zip(sweep_lengths) {
+            assert_relative_eq!(bitangent_length, sweep_length, epsilon = 1e-6);
+        }
+    }
+}
@@ -0,0 +1,457 @@
+//! Tiled nav graphs, for areas too large for a single visibility graph
+//!
+//! The area is split into square tiles of `tile_size` (in the internal CRS,
+//! aligned to its origin). Every tile has its own nav graph, created from the
+//! obstacles and waters clipped to the tile. All vertices of a tile's graph
+//! lie within the tile, so its edges do too and the clipped obstacles are all
+//! that can block them.
+//!
+//! Neighbouring tiles share border nodes, spaced at most
+//! `border_node_spacing` apart along their common border. A route crosses from
+//! one tile into the next through one of them, which makes it at most about
+//! the spacing longer per crossing than in a single graph.
+//!
+//! Tiles are created on first use, when a route search reaches them, and kept
+//! for later searches. With a `NavGraphCache` they are stored on disk as well.
+
+use std::{
+    collections::{hash_map::Entry, BinaryHeap, HashMap},
+    error::Error,
+};
+
+use geo::{
+    prelude::{BoundingRect, EuclideanDistance, Intersects},
+    Coordinate, Line, LineString, MultiPolygon, Point, Rect,
+};
+use geos::Geom;
+use petgraph::{graph::NodeIndex, visit::EdgeRef};
+
+use crate::{
+    coord_ext::OrderedCoordinate, crs::InternalCrs, geos_ext::geos_to_multi_polygon,
+    line_length_within::line_length_within,
+};
+
+use super::{
+    add_coord_to_nav_graph, bounded_astar::MinScored, create_nav_graph, create_nav_graph_cached,
+    graph_types::{Features, NodeData},
+    Edge, NavGraph, NavGraphCache, VisibilityOptimizationMode,
+};
+
+/// Column and row of a tile
+pub type TileIndex = (i32, i32);
+
+/// A node in the graph of one of the tiles
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TiledNodeIndex {
+    pub tile: TileIndex,
+    pub node: NodeIndex,
+}
+
+/// Border node key: the neighbouring tile and the position along the border
+type BorderKey = (TileIndex, usize);
+
+#[derive(Debug)]
+struct Tile {
+    nav_graph: NavGraph,
+    /// Key of the border node that is `NodeData::Arbitrary(i)`, for every
+    /// `i` below the number of border nodes
+    border_keys: Vec<BorderKey>,
+    border_nodes: HashMap<BorderKey, NodeIndex>,
+}
+
+#[derive(Debug)]
+pub struct TiledNavGraph {
+    /// Features of the whole area
+    features: Features,
+    crs: InternalCrs,
+    /// Tiles outside these bounds are never created
+    bounds: Rect<f64>,
+    tile_size: f64,
+    border_node_spacing: f64,
+    optimization_mode: VisibilityOptimizationMode,
+    /// Edges of the tiles' graphs are at most this long
+    max_edge_length: Option<f64>,
+    tiles: HashMap<TileIndex, Tile>,
+    cache: Option<NavGraphCache>,
+}
+
+/// `polygons` clipped to `rect`
+fn clip_multi_polygon(polygons: &MultiPolygon<f64>, rect: Rect<f64>) -> Result<MultiPolygon<f64>, geos::Error> {
+    let rect_geometry: geos::Geometry = rect.to_polygon().try_into()?;
+    let mut clipped = MultiPolygon(vec![]);
+    for polygon in polygons.iter().filter(|polygon| polygon.intersects(&rect)) {
+        let polygon_geometry: geos::Geometry = polygon.clone().try_into()?;
+        clipped.0.extend(geos_to_multi_polygon(polygon_geometry.intersection(&rect_geometry)?)?.0);
+    }
+    return Ok(clipped);
+}
+
+impl TiledNavGraph {
+    /// Tiled nav graph for `features`, no tiles are created yet. Pass a
+    /// `cache` to keep created tiles on disk across sessions. `tile_size` and
+    /// `border_node_spacing` must be positive.
+    pub fn new(
+        features: Features,
+        crs: InternalCrs,
+        tile_size: f64,
+        border_node_spacing: f64,
+        optimization_mode: VisibilityOptimizationMode,
+        max_edge_length: Option<f64>,
+        cache: Option<NavGraphCache>,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        if !(tile_size.is_finite() && tile_size > 0.0) {
+            return Err(format!("Tile size must be positive, got {}", tile_size).into());
+        }
+        if !(border_node_spacing.is_finite() && border_node_spacing > 0.0) {
+            return Err(format!("Border node spacing must be positive, got {}", border_node_spacing).into());
+        }
+        let bounds = features.obstacles.iter()
+            .chain(features.waters.iter())
+            .filter_map(|polygon| polygon.bounding_rect())
+            .reduce(|a, b| Rect::new(
+                Coordinate { x: a.min().x.min(b.min().x), y: a.min().y.min(b.min().y) },
+                Coordinate { x: a.max().x.max(b.max().x), y: a.max().y.max(b.max().y) },
+            ))
+            .ok_or("No obstacles or waters, cannot determine the area to tile")?;
+        return Ok(TiledNavGraph {
+            features,
+            crs,
+            bounds,
+            tile_size,
+            border_node_spacing,
+            optimization_mode,
+            max_edge_length,
+            tiles: HashMap::new(),
+            cache,
+        });
+    }
+
+    pub fn tile_index(&self, coord: Coordinate<f64>) -> TileIndex {
+        ((coord.x / self.tile_size).floor() as i32, (coord.y / self.tile_size).floor() as i32)
+    }
+
+    fn tile_rect(&self, (column, row): TileIndex) -> Rect<f64> {
+        Rect::new(
+            Coordinate { x: column as f64 * self.tile_size, y: row as f64 * self.tile_size },
+            Coordinate { x: (column + 1) as f64 * self.tile_size, y: (row + 1) as f64 * self.tile_size },
+        )
+    }
+
+    fn is_within_bounds(&self, tile_index: TileIndex) -> bool {
+        self.tile_rect(tile_index).intersects(&self.bounds)
+    }
+
+    pub fn tile_size(&self) -> f64 {
+        self.tile_size
+    }
+
+    pub fn optimization_mode(&self) -> VisibilityOptimizationMode {
+        self.optimization_mode
+    }
+
+    /// Number of tiles created so far
+    pub fn tile_count(&self) -> usize {
+        self.tiles.len()
+    }
+
+    /// Border nodes of `tile_index`, in a fixed order. Both tiles along a
+    /// border get the same coordinates for it. Positions within an obstacle
+    /// are skipped.
+    fn border_nodes(&self, tile_index: TileIndex) -> Vec<(BorderKey, Coordinate<f64>)> {
+        let (column, row) = tile_index;
+        let rect = self.tile_rect(tile_index);
+        let count = (self.tile_size / self.border_node_spacing).ceil().max(1.0) as usize;
+        let offset = |position: usize| (position as f64 + 0.5) * self.tile_size / count as f64;
+        // Neighbour, and whether the border is vertical with its x (else
+        // horizontal with its y)
+        let borders = [
+            ((column - 1, row), true, rect.min().x),
+            ((column + 1, row), true, rect.max().x),
+            ((column, row - 1), false, rect.min().y),
+            ((column, row + 1), false, rect.max().y),
+        ];
+        return borders
+            .into_iter()
+            .filter(|(neighbor, _, _)| self.is_within_bounds(*neighbor))
+            .flat_map(|(neighbor, is_vertical, fixed)| {
+                (0..count).map(move |position| {
+                    let coord = match is_vertical {
+                        true => Coordinate { x: fixed, y: rect.min().y + offset(position) },
+                        false => Coordinate { x: rect.min().x + offset(position), y: fixed },
+                    };
+                    ((neighbor, position), coord)
+                })
+            })
+            .filter(|(_, coord)| !self.features.obstacles.intersects(&Point(*coord)))
+            .collect();
+    }
+
+    /// Create the graph of `tile_index`, unless it exists already.
+    fn ensure_tile(&mut self, tile_index: TileIndex) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if self.tiles.contains_key(&tile_index) {
+            return Ok(());
+        }
+        let rect = self.tile_rect(tile_index);
+        let border_nodes = self.border_nodes(tile_index);
+        let features = Features {
+            obstacles: clip_multi_polygon(&self.features.obstacles, rect)?,
+            waters: clip_multi_polygon(&self.features.waters, rect)?,
+            penalized_areas: self.features.penalized_areas.clone(),
+            arbitrary: border_nodes.iter().map(|(_, coord)| OrderedCoordinate(*coord)).collect(),
+        };
+        println!("Creating nav graph of tile {:?}", tile_index);
+        let (mut nav_graph, _) = match &self.cache {
+            Some(cache) => create_nav_graph_cached(
+                cache, &features, &self.crs, None, self.optimization_mode, self.max_edge_length
+            )?,
+            None => create_nav_graph(&features, &self.crs, None, self.optimization_mode, self.max_edge_length),
+        };
+
+        // Clipping gives obstacles an edge along the tile border that is not
+        // a real obstacle edge. Edges along it might run through the obstacle.
+        let runs_through_obstacle = nav_graph.graph
+            .edge_references()
+            .filter(|edge| {
+                let a = nav_graph.features.coord(&nav_graph.graph[edge.source()]);
+                let b = nav_graph.features.coord(&nav_graph.graph[edge.target()]);
+                let is_along_border = (a.x == b.x && (a.x == rect.min().x || a.x == rect.max().x))
+                    || (a.y == b.y && (a.y == rect.min().y || a.y == rect.max().y));
+                is_along_border && line_length_within(Line::new(a, b), &self.features.obstacles) > 0.0
+            })
+            .map(|edge| edge.id())
+            .collect::<Vec<_>>();
+        // Removing an edge moves the last edge into its index
+        for edge_index in runs_through_obstacle.into_iter().rev() {
+            nav_graph.graph.remove_edge(edge_index);
+        }
+
+        let border_keys = border_nodes.into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+        let border_nodes = border_keys
+            .iter()
+            .enumerate()
+            .filter_map(|(i, key)| {
+                let node_index = nav_graph.node_data_index_map.get(&NodeData::Arbitrary(i))?;
+                Some((*key, *node_index))
+            })
+            .collect();
+        self.tiles.insert(tile_index, Tile { nav_graph, border_keys, border_nodes });
+        return Ok(());
+    }
+
+    /// Graph of `tile_index`, created if needed
+    pub fn tile_nav_graph(&mut self, tile_index: TileIndex) -> Result<&NavGraph, Box<dyn Error + Send + Sync>> {
+        self.ensure_tile(tile_index)?;
+        return Ok(&self.tiles[&tile_index].nav_graph);
+    }
+
+    pub fn coord(&self, node: TiledNodeIndex) -> Coordinate<f64> {
+        let nav_graph = &self.tiles[&node.tile].nav_graph;
+        nav_graph.features.coord(&nav_graph.graph[node.node])
+    }
+
+    /// Add `coord` to the graph of the tile it lies in.
+    pub fn add_coord(&mut self, coord: Coordinate<f64>) -> Result<TiledNodeIndex, Box<dyn Error + Send + Sync>> {
+        let tile_index = self.tile_index(coord);
+        if !self.is_within_bounds(tile_index) {
+            return Err(format!("{:?} lies outside the tiled area", coord).into());
+        }
+        self.ensure_tile(tile_index)?;
+        let nav_graph = &mut self.tiles.get_mut(&tile_index).unwrap().nav_graph;
+        let (_, node) = add_coord_to_nav_graph(coord, nav_graph, None, self.optimization_mode);
+        return Ok(TiledNodeIndex { tile: tile_index, node });
+    }
+
+    /// Neighbours of `node` with the cost to reach them: the nodes it has an
+    /// edge to in its tile and, for a border node, the same border node in
+    /// the neighbouring tile (creating that tile).
+    fn neighbors(&mut self, node: TiledNodeIndex) -> Result<Vec<(TiledNodeIndex, Edge)>, Box<dyn Error + Send + Sync>> {
+        let tile = &self.tiles[&node.tile];
+        let mut neighbors = tile.nav_graph.graph
+            .edges(node.node)
+            .map(|edge| {
+                let other = if edge.source() == node.node { edge.target() } else { edge.source() };
+                (TiledNodeIndex { tile: node.tile, node: other }, *edge.weight())
+            })
+            .collect::<Vec<_>>();
+        let border_key = match tile.nav_graph.graph[node.node] {
+            NodeData::Arbitrary(i) => tile.border_keys.get(i).copied(),
+            _ => None,
+        };
+        if let Some((neighbor_tile, position)) = border_key {
+            self.ensure_tile(neighbor_tile)?;
+            if let Some(neighbor_node) = self.tiles[&neighbor_tile].border_nodes.get(&(node.tile, position)) {
+                neighbors.push((TiledNodeIndex { tile: neighbor_tile, node: *neighbor_node }, Edge::default()));
+            }
+        }
+        return Ok(neighbors);
+    }
+
+    /// Cheapest path from `start` to `end` across tiles (A*), with its total
+    /// length and cost. Only the tiles the search reaches are created.
+    pub fn shortest_path(
+        &mut self,
+        start: TiledNodeIndex,
+        end: TiledNodeIndex,
+    ) -> Result<Option<(Edge, Vec<TiledNodeIndex>)>, Box<dyn Error + Send + Sync>> {
+        let end_coord = self.coord(end);
+        let estimate = |coord: Coordinate<f64>| Edge::new(coord.euclidean_distance(&end_coord));
+
+        let mut visit_next = BinaryHeap::new();
+        let mut scores = HashMap::<TiledNodeIndex, Edge>::new();
+        let mut came_from = HashMap::<TiledNodeIndex, TiledNodeIndex>::new();
+        scores.insert(start, Edge::default());
+        visit_next.push(MinScored(estimate(self.coord(start)), start));
+
+        while let Some(MinScored(estimate_score, node)) = visit_next.pop() {
+            let score = scores[&node];
+            if node == end {
+                let mut path = vec![end];
+                while let Some(previous) = came_from.get(path.last().unwrap()) {
+                    path.push(*previous);
+                }
+                path.reverse();
+                return Ok(Some((score, path)));
+            }
+            // Already visited with a lower score
+            if estimate_score > score + estimate(self.coord(node)) {
+                continue;
+            }
+            for (neighbor, edge) in self.neighbors(node)? {
+                let neighbor_score = score + edge;
+                match scores.entry(neighbor) {
+                    Entry::Occupied(mut entry) => {
+                        if !(neighbor_score < *entry.get()) {
+                            continue;
+                        }
+                        entry.insert(neighbor_score);
+                    }
+                    Entry::Vacant(entry) => {
+                        entry.insert(neighbor_score);
+                    }
+                }
+                came_from.insert(neighbor, node);
+                visit_next.push(MinScored(neighbor_score + estimate(self.coord(neighbor)), neighbor));
+            }
+        }
+        return Ok(None);
+    }
+
+    /// Cheapest path between two coordinates (in the internal CRS) across
+    /// tiles, see `shortest_path`.
+    pub fn shortest_path_between_coords(
+        &mut self,
+        start_coord: Coordinate<f64>,
+        end_coord: Coordinate<f64>,
+    ) -> Result<Option<(Edge, LineString<f64>)>, Box<dyn Error + Send + Sync>> {
+        let start = self.add_coord(start_coord)?;
+        let end = self.add_coord(end_coord)?;
+        let maybe_path = self.shortest_path(start, end)?;
+        return Ok(maybe_path.map(|(total, path)| {
+            let mut coords = path.into_iter().map(|node| self.coord(node)).collect::<Vec<_>>();
+            // Crossing into the next tile repeats the border node
+            coords.dedup();
+            (total, LineString(coords))
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use geo::polygon;
+
+    use crate::{
+        crs::InternalCrs,
+        line_length_within::line_length_within,
+        nav_graph::{
+            add_coord_to_nav_graph, calculate_shortest_path, create_nav_graph, graph_types::Features,
+            VisibilityOptimizationMode,
+        },
+        test_util::{local, local_features},
+    };
+
+    use super::TiledNavGraph;
+
+    const TILE_SIZE: f64 = 100.0;
+    const BORDER_NODE_SPACING: f64 = 5.0;
+
+    /// Obstacles over three by two tiles of 100 m from the origin of the local
+    /// grid, some of them crossing tile borders
+    fn features() -> Features {
+        local_features(vec![
+            polygon![(x: 20.0, y: 30.0), (x: 80.0, y: 22.0), (x: 87.0, y: 61.0), (x: 26.0, y: 66.0)],
+            // Wall across the border between the first and second column
+            polygon![(x: 93.0, y: 11.0), (x: 109.0, y: 14.0), (x: 111.0, y: 163.0), (x: 95.0, y: 159.0)],
+            polygon![(x: 141.0, y: 73.0), (x: 183.0, y: 81.0), (x: 177.0, y: 124.0), (x: 139.0, y: 118.0)],
+            polygon![(x: 231.0, y: 144.0), (x: 267.0, y: 139.0), (x: 259.0, y: 181.0)],
+        ])
+    }
+
+    fn tiled_nav_graph() -> TiledNavGraph {
+        TiledNavGraph::new(
+            features(), InternalCrs::EtrsLaea, TILE_SIZE, BORDER_NODE_SPACING, VisibilityOptimizationMode::Sweep,
+            None, None,
+        ).unwrap()
+    }
+
+    #[test]
+    fn shortest_path_between_coords__close_to_single_graph() {
+        let (start, end) = (local(5.0, 95.0), local(290.0, 190.0));
+        let mut tiled = tiled_nav_graph();
+        let (tiled_total, tiled_path) = tiled.shortest_path_between_coords(start, end).unwrap().unwrap();
+
+        let (mut single, _) = create_nav_graph(&features(), &InternalCrs::EtrsLaea, None, VisibilityOptimizationMode::Sweep, None);
+        let (_, start_index) = add_coord_to_nav_graph(start, &mut single, None, VisibilityOptimizationMode::Sweep);
+        let (_, end_index) = add_coord_to_nav_graph(end, &mut single, None, VisibilityOptimizationMode::Sweep);
+        let (single_total, _) = calculate_shortest_path(&single, start_index, end_index).unwrap();
+
+        assert_eq!(tiled_path.0.first(), Some(&start));
+        assert_eq!(tiled_path.0.last(), Some(&end));
+        assert!(tiled_total.length >= single_total.length - 1e-6);
+        // Crosses three tile borders, each at most about a border node
+        // spacing longer
+        assert!(tiled_total.length <= single_total.length + 4.0 * BORDER_NODE_SPACING);
+    }
+
+    #[test]
+    fn shortest_path_between_coords__avoids_obstacles_across_borders() {
+        let mut tiled = tiled_nav_graph();
+        let (_, path) = tiled.shortest_path_between_coords(local(90.0, 80.0), local(115.0, 80.0)).unwrap().unwrap();
+        let obstacles = &features().obstacles;
+        let length_within_obstacles: f64 = path.lines().map(|line| line_length_within(line, obstacles)).sum();
+        assert!(length_within_obstacles < 1e-6);
+        // Around the wall, which ends at y 163
+        assert!(path.0.iter().any(|coord| coord.y < local(0.0, 14.0).y || coord.y > local(0.0, 159.0).y));
+    }
+
+    #[test]
+    fn shortest_path_between_coords__creates_tiles_lazily() {
+        let mut tiled = tiled_nav_graph();
+        assert_eq!(tiled.tile_count(), 0);
+        tiled.shortest_path_between_coords(local(10.0, 10.0), local(15.0, 80.0)).unwrap().unwrap();
+        // The start tile, plus at most the neighbours of border nodes the
+        // search reached
+        assert!(tiled.tile_count() < 6);
+    }
+
+    #[test]
+    fn new__invalid_sizes() {
+        let new = |tile_size: f64, border_node_spacing: f64| TiledNavGraph::new(
+            features(), InternalCrs::EtrsLaea, tile_size, border_node_spacing, VisibilityOptimizationMode::Sweep,
+            None, None,
+        );
+        assert!(new(0.0, BORDER_NODE_SPACING).is_err());
+        assert!(new(-TILE_SIZE, BORDER_NODE_SPACING).is_err());
+        assert!(new(f64::NAN, BORDER_NODE_SPACING).is_err());
+        assert!(new(TILE_SIZE, 0.0).is_err());
+        assert!(new(TILE_SIZE, f64::INFINITY).is_err());
+    }
+
+    #[test]
+    fn add_coord__outside_area() {
+        let mut tiled = tiled_nav_graph();
+        assert!(tiled.add_coord(local(-500.0, 0.0)).is_err());
+        assert!(tiled.shortest_path_between_coords(local(5.0, 5.0), local(5.0, 5.0)).unwrap().is_some());
+    }
+}
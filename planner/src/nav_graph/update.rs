@@ -0,0 +1,403 @@
+//! Incremental nav graph updates for a single obstacle polygon, e.g. a
+//! temporary no-fly area, instead of creating the whole graph again.
+//!
+//! Only edges whose line crosses the polygon can change. Their visibility is
+//! checked again pair by pair, as in `VisibilityOptimizationMode::Naive`.
+//! Pairs that cannot cross the polygon are skipped cheaply first, see
+//! `CrossingCone`.
+//! Vertices that join the graph get a sweep over all vertices in the given
+//! optimization mode. For the `Naive`, `Sweep` and `Bitangent` modes the result
+//! is the graph `create_nav_graph` would create for the updated features.
+//! `OptimizedSweep` graphs can differ from that, create them again instead.
+//! Polygons must be wound like the other obstacles (see `ensure_sfa_winding`).
+
+use std::f64::consts::PI;
+
+use geo::{
+    prelude::{Contains, EuclideanDistance, Intersects},
+    Coordinate, Line, Point, Polygon,
+};
+
+use super::{
+    create::{insert_visible_edges, is_graph_vertex, is_sweep_vertex},
+    graph_types::{Features, NodeData},
+    visibility::{is_bitangent, visible_vertices, VisibilityOptimizationMode},
+    NavGraph,
+};
+
+/// Whether `p` sees `w`
+fn is_visible(p: &NodeData, w: &NodeData, nav_graph: &NavGraph) -> bool {
+    return visible_vertices(
+        p, &mut vec![*w], &nav_graph.features, None, VisibilityOptimizationMode::Naive, nav_graph.max_edge_length
+    ).contains(w);
+}
+
+/// Whether creating the graph would connect `a` and `b`: either sees the other
+/// (and, in `Bitangent` mode, the edge is bitangent)
+fn is_edge(
+    a: &NodeData,
+    b: &NodeData,
+    nav_graph: &NavGraph,
+    optimization_mode: VisibilityOptimizationMode,
+) -> bool {
+    if optimization_mode == VisibilityOptimizationMode::Bitangent && !is_bitangent(a, b, &nav_graph.features) {
+        return false;
+    }
+    return is_visible(a, b, nav_graph) || is_visible(b, a, nav_graph);
+}
+
+fn line_between(a: &NodeData, b: &NodeData, features: &Features) -> Line<f64> {
+    return Line::new(features.coord(a), features.coord(b));
+}
+
+/// The directions from `apex` in which a line can reach a polygon: every
+/// line from `apex` crossing it lies within the cone, between the lines
+/// through its outermost vertices.
+struct CrossingCone {
+    apex: Coordinate<f64>,
+    /// To the mean of the polygon's vertices, angles are relative to it
+    reference: Coordinate<f64>,
+    min_angle: f64,
+    max_angle: f64,
+}
+
+/// Tolerance (radians) on the bounds of a `CrossingCone`, so rounding never
+/// excludes a line along them
+const CROSSING_CONE_TOLERANCE: f64 = 1e-9;
+
+impl CrossingCone {
+    /// `None` if `apex` lies within the convex hull of `polygon`: lines in
+    /// any direction can cross it.
+    fn new(apex: Coordinate<f64>, polygon: &Polygon<f64>) -> Option<Self> {
+        let exterior = &polygon.exterior().0;
+        let sum = exterior.iter().fold(Coordinate { x: 0.0, y: 0.0 }, |sum, coord| sum + *coord);
+        let mean = Coordinate { x: sum.x / exterior.len() as f64, y: sum.y / exterior.len() as f64 };
+        let reference = mean - apex;
+        if reference.x == 0.0 && reference.y == 0.0 {
+            return None;
+        }
+        let mut cone = CrossingCone { apex, reference, min_angle: 0.0, max_angle: 0.0 };
+        for coord in exterior {
+            let angle = cone.angle(*coord);
+            cone.min_angle = cone.min_angle.min(angle);
+            cone.max_angle = cone.max_angle.max(angle);
+        }
+        // Vertices all around `apex` (or on both sides, on the hull's
+        // boundary), it is not outside the hull
+        if cone.max_angle - cone.min_angle >= PI - CROSSING_CONE_TOLERANCE {
+            return None;
+        }
+        return Some(cone);
+    }
+
+    /// Angle from `reference` to the direction of `coord`, in (-PI, PI]
+    fn angle(&self, coord: Coordinate<f64>) -> f64 {
+        let direction = coord - self.apex;
+        let cross = self.reference.x * direction.y - self.reference.y * direction.x;
+        let dot = self.reference.x * direction.x + self.reference.y * direction.y;
+        return cross.atan2(dot);
+    }
+
+    fn contains(&self, coord: Coordinate<f64>) -> bool {
+        let angle = self.angle(coord);
+        return self.min_angle - CROSSING_CONE_TOLERANCE <= angle && angle <= self.max_angle + CROSSING_CONE_TOLERANCE;
+    }
+}
+
+/// Vertices of `nav_graph` that can have an edge crossing `polygon`: with a
+/// maximum edge length, only those within it of the polygon. Each comes with
+/// its `CrossingCone`.
+fn crossing_candidates(nav_graph: &NavGraph, polygon: &Polygon<f64>) -> Vec<(NodeData, Option<CrossingCone>)> {
+    return nav_graph.graph
+        .node_weights()
+        .filter(|node_data| match nav_graph.max_edge_length {
+            Some(max_edge_length) => {
+                Point(nav_graph.features.coord(node_data)).euclidean_distance(polygon) <= max_edge_length
+            }
+            None => true,
+        })
+        .map(|node_data| (*node_data, CrossingCone::new(nav_graph.features.coord(node_data), polygon)))
+        .collect();
+}
+
+fn remove_node(nav_graph: &mut NavGraph, node_data: &NodeData) {
+    let node_index = nav_graph.node_data_index_map.remove(node_data).unwrap();
+    nav_graph.graph.remove_node(node_index);
+    // The last node takes the index of the removed one
+    if let Some(moved_node_data) = nav_graph.graph.node_weight(node_index) {
+        nav_graph.node_data_index_map.insert(*moved_node_data, node_index);
+    }
+}
+
+/// Add `new_vertices` to the graph, connected to every vertex they see or
+/// that sees them.
+fn add_vertices(
+    new_vertices: &[NodeData],
+    nav_graph: &mut NavGraph,
+    optimization_mode: VisibilityOptimizationMode,
+) {
+    for new_vertex in new_vertices {
+        let node_index = nav_graph.graph.add_node(*new_vertex);
+        nav_graph.node_data_index_map.insert(*new_vertex, node_index);
+    }
+    let vertices = nav_graph.graph.node_weights().copied().collect::<Vec<_>>();
+    let mut sweep_vertices = nav_graph.features
+        .iter()
+        .filter(|node_data| is_sweep_vertex(node_data, &nav_graph.features))
+        .collect::<Vec<_>>();
+    for new_vertex in new_vertices {
+        let mut ws_visible = visible_vertices(
+            new_vertex, &mut sweep_vertices, &nav_graph.features, None, optimization_mode, nav_graph.max_edge_length
+        );
+        // Existing vertices would have seen the new vertex in their own sweep
+        ws_visible.extend(
+            vertices
+                .iter()
+                .filter(|w| {
+                    !new_vertices.contains(w)
+                        && is_visible(w, new_vertex, nav_graph)
+                        && (optimization_mode != VisibilityOptimizationMode::Bitangent
+                            || is_bitangent(w, new_vertex, &nav_graph.features))
+                })
+                .copied(),
+        );
+        insert_visible_edges(new_vertex, ws_visible, nav_graph);
+    }
+}
+
+/// Add `polygon` to the obstacles of `nav_graph`: vertices it covers are
+/// removed, edges it blocks deleted and its own vertices added with their
+/// visible edges. Returns the index of the polygon in the obstacles.
+pub fn insert_obstacle_into_nav_graph(
+    polygon: Polygon<f64>,
+    nav_graph: &mut NavGraph,
+    optimization_mode: VisibilityOptimizationMode,
+) -> usize {
+    let polygon_index = nav_graph.features.obstacles.0.len();
+    nav_graph.features.obstacles.0.push(polygon.clone());
+
+    let covered_vertices = nav_graph.graph
+        .node_weights()
+        .filter(|node_data| polygon.contains(&Point(nav_graph.features.coord(node_data))))
+        .copied()
+        .collect::<Vec<_>>();
+    for covered_vertex in &covered_vertices {
+        remove_node(nav_graph, covered_vertex);
+    }
+
+    let mut blocked_edges = nav_graph.graph
+        .edge_indices()
+        .filter(|edge_index| {
+            let (a_index, b_index) = nav_graph.graph.edge_endpoints(*edge_index).unwrap();
+            let a = &nav_graph.graph[a_index];
+            let b = &nav_graph.graph[b_index];
+            line_between(a, b, &nav_graph.features).intersects(&polygon)
+                && !is_edge(a, b, nav_graph, optimization_mode)
+        })
+        .collect::<Vec<_>>();
+    // Removing an edge moves the last edge into its index, remove from the
+    // back so the indices still to remove stay valid
+    blocked_edges.sort_by(|a, b| b.cmp(a));
+    for edge_index in blocked_edges {
+        nav_graph.graph.remove_edge(edge_index);
+    }
+
+    let new_vertices = nav_graph.features
+        .iter()
+        .filter(|node_data| match node_data {
+            NodeData::PartOfObstacle(mpi) => mpi.polygon_index == polygon_index,
+            _ => false,
+        })
+        .filter(|node_data| is_graph_vertex(node_data, &nav_graph.features, optimization_mode))
+        .collect::<Vec<_>>();
+    add_vertices(&new_vertices, nav_graph, optimization_mode);
+
+    return polygon_index;
+}
+
+/// Remove obstacle `polygon_index` from `nav_graph`: its vertices are removed,
+/// edges it blocked restored and vertices it covered added again. Later
+/// obstacles move up one index, like in a `Vec`. Returns the removed polygon.
+pub fn remove_obstacle_from_nav_graph(
+    polygon_index: usize,
+    nav_graph: &mut NavGraph,
+    optimization_mode: VisibilityOptimizationMode,
+) -> Polygon<f64> {
+    let own_vertices = nav_graph.graph
+        .node_weights()
+        .filter(|node_data| match node_data {
+            NodeData::PartOfObstacle(mpi) => mpi.polygon_index == polygon_index,
+            _ => false,
+        })
+        .copied()
+        .collect::<Vec<_>>();
+    for own_vertex in &own_vertices {
+        remove_node(nav_graph, own_vertex);
+    }
+
+    let polygon = nav_graph.features.obstacles.0.remove(polygon_index);
+    for node_data in nav_graph.graph.node_weights_mut() {
+        if let NodeData::PartOfObstacle(mpi) = node_data && mpi.polygon_index > polygon_index {
+            mpi.polygon_index -= 1;
+        }
+    }
+    nav_graph.node_data_index_map = nav_graph.graph
+        .node_indices()
+        .map(|node_index| (nav_graph.graph[node_index], node_index))
+        .collect();
+
+    // Only edges crossing the polygon were blocked by it
+    let candidates = crossing_candidates(nav_graph, &polygon);
+    let mut unblocked_edges = Vec::new();
+    for (i, (a, a_cone)) in candidates.iter().enumerate() {
+        for (b, b_cone) in &candidates[i + 1..] {
+            let a_coord = nav_graph.features.coord(a);
+            let b_coord = nav_graph.features.coord(b);
+            let can_cross = a_cone.as_ref().map_or(true, |a_cone| a_cone.contains(b_coord))
+                && b_cone.as_ref().map_or(true, |b_cone| b_cone.contains(a_coord));
+            let is_unblocked = can_cross
+                && Line::new(a_coord, b_coord).intersects(&polygon)
+                && nav_graph.graph
+                    .find_edge(nav_graph.node_data_index_map[a], nav_graph.node_data_index_map[b])
+                    .is_none()
+                && is_edge(a, b, nav_graph, optimization_mode);
+            if is_unblocked {
+                unblocked_edges.push((*a, *b));
+            }
+        }
+    }
+    for (a, b) in unblocked_edges {
+        insert_visible_edges(&a, vec![b], nav_graph);
+    }
+
+    let uncovered_vertices = nav_graph.features
+        .iter()
+        .filter(|node_data| {
+            !nav_graph.node_data_index_map.contains_key(node_data)
+                && is_graph_vertex(node_data, &nav_graph.features, optimization_mode)
+        })
+        .collect::<Vec<_>>();
+    add_vertices(&uncovered_vertices, nav_graph, optimization_mode);
+
+    return polygon;
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use std::collections::BTreeMap;
+
+    use geo::{coord, polygon, Polygon};
+
+    use crate::{
+        coord_ext::OrderedCoordinate,
+        crs::InternalCrs,
+        nav_graph::{create_nav_graph, graph_types::{Features, NodeData}, NavGraph, VisibilityOptimizationMode},
+        test_util::{local, local_features, local_polygon},
+    };
+
+    use super::{insert_obstacle_into_nav_graph, remove_obstacle_from_nav_graph, CrossingCone};
+
+    const MODES: [VisibilityOptimizationMode; 3] = [
+        VisibilityOptimizationMode::Naive,
+        VisibilityOptimizationMode::Sweep,
+        VisibilityOptimizationMode::Bitangent,
+    ];
+
+    /// Irregular obstacles (no three vertices on a line) and an arbitrary
+    /// point
+    fn features() -> Features {
+        Features {
+            arbitrary: vec![OrderedCoordinate(local(77.0, 43.0))],
+            ..local_features(vec![
+                polygon![(x: 0.0, y: 3.0), (x: 41.0, y: 0.0), (x: 37.0, y: 29.0), (x: 4.0, y: 33.0)],
+                polygon![(x: 103.0, y: 7.0), (x: 131.0, y: 11.0), (x: 118.0, y: 47.0)],
+                polygon![(x: 57.0, y: 83.0), (x: 89.0, y: 71.0), (x: 97.0, y: 101.0), (x: 61.0, y: 109.0)],
+                polygon![(x: 151.0, y: 89.0), (x: 173.0, y: 97.0), (x: 163.0, y: 127.0)],
+            ])
+        }
+    }
+
+    /// No-fly area between the obstacles, covering the arbitrary point and a
+    /// vertex of the third obstacle
+    fn no_fly_area() -> Polygon<f64> {
+        local_polygon(polygon![(x: 61.0, y: 23.0), (x: 93.0, y: 31.0), (x: 99.0, y: 79.0), (x: 66.0, y: 59.0)])
+    }
+
+    fn edges_by_vertices(nav_graph: &NavGraph) -> BTreeMap<(NodeData, NodeData), (u64, u64)> {
+        return nav_graph.graph
+            .edge_indices()
+            .map(|edge_index| {
+                let (a, b) = nav_graph.graph.edge_endpoints(edge_index).unwrap();
+                let (a, b) = (nav_graph.graph[a], nav_graph.graph[b]);
+                let edge = nav_graph.graph[edge_index];
+                ((a.min(b), a.max(b)), (edge.length.to_bits(), edge.cost.to_bits()))
+            })
+            .collect();
+    }
+
+    fn assert_same_as_created(nav_graph: &NavGraph, features: &Features, mode: VisibilityOptimizationMode) {
+        let (created, _) = create_nav_graph(features, &InternalCrs::EtrsLaea, None, mode, None);
+        assert_eq!(nav_graph.features.obstacles, created.features.obstacles);
+        let mut vertices = nav_graph.graph.node_weights().copied().collect::<Vec<_>>();
+        let mut created_vertices = created.graph.node_weights().copied().collect::<Vec<_>>();
+        vertices.sort();
+        created_vertices.sort();
+        assert_eq!(vertices, created_vertices);
+        assert_eq!(edges_by_vertices(nav_graph), edges_by_vertices(&created));
+        for (node_data, node_index) in &nav_graph.node_data_index_map {
+            assert_eq!(&nav_graph.graph[*node_index], node_data);
+        }
+        assert_eq!(nav_graph.node_data_index_map.len(), nav_graph.graph.node_count());
+    }
+
+    #[test]
+    fn insert_obstacle_into_nav_graph__same_as_created() {
+        let mut with_no_fly_area = features();
+        with_no_fly_area.obstacles.0.push(no_fly_area());
+        for mode in MODES {
+            let (mut nav_graph, _) = create_nav_graph(&features(), &InternalCrs::EtrsLaea, None, mode, None);
+            let polygon_index = insert_obstacle_into_nav_graph(no_fly_area(), &mut nav_graph, mode);
+            assert_eq!(polygon_index, 4);
+            assert!(!nav_graph.node_data_index_map.contains_key(&NodeData::Arbitrary(0)));
+            assert_same_as_created(&nav_graph, &with_no_fly_area, mode);
+        }
+    }
+
+    #[test]
+    fn remove_obstacle_from_nav_graph__same_as_created() {
+        let mut with_no_fly_area = features();
+        with_no_fly_area.obstacles.0.insert(1, no_fly_area());
+        for mode in MODES {
+            let (mut nav_graph, _) = create_nav_graph(&with_no_fly_area, &InternalCrs::EtrsLaea, None, mode, None);
+            let removed = remove_obstacle_from_nav_graph(1, &mut nav_graph, mode);
+            assert_eq!(removed, no_fly_area());
+            assert!(nav_graph.node_data_index_map.contains_key(&NodeData::Arbitrary(0)));
+            assert_same_as_created(&nav_graph, &features(), mode);
+        }
+    }
+
+    #[test]
+    fn crossing_cone__directions_towards_polygon() {
+        let polygon = polygon![(x: 10.0, y: -1.0), (x: 12.0, y: -1.0), (x: 12.0, y: 1.0), (x: 10.0, y: 1.0)];
+        let cone = CrossingCone::new(coord! { x: 0.0, y: 0.0 }, &polygon).unwrap();
+        assert!(cone.contains(coord! { x: 20.0, y: 0.0 }));
+        assert!(cone.contains(coord! { x: 20.0, y: 2.0 }));
+        assert!(!cone.contains(coord! { x: 20.0, y: 5.0 }));
+        assert!(!cone.contains(coord! { x: -20.0, y: 0.0 }));
+        // Within the polygon's hull, every direction can cross it
+        assert!(CrossingCone::new(coord! { x: 11.0, y: 0.0 }, &polygon).is_none());
+        assert!(CrossingCone::new(coord! { x: 12.0, y: 0.0 }, &polygon).is_none());
+    }
+
+    #[test]
+    fn insert_then_remove_obstacle__same_as_before() {
+        for mode in MODES {
+            let (mut nav_graph, _) = create_nav_graph(&features(), &InternalCrs::EtrsLaea, None, mode, None);
+            let polygon_index = insert_obstacle_into_nav_graph(no_fly_area(), &mut nav_graph, mode);
+            remove_obstacle_from_nav_graph(polygon_index, &mut nav_graph, mode);
+            assert_same_as_created(&nav_graph, &features(), mode);
+        }
+    }
+}
@@ -15,13 +15,13 @@ use geo::{
     prelude::{Contains, EuclideanDistance},
     Coordinate, Line, MultiPolygon, Point, GeometryCollection
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use take_until::TakeUntilExt;
 
 use crate::{
     coord_ext::{cmp_angle, cmp_distance},
     intersection::get_proper_ray_line_intersection,
-    mpi::{Mpi, NeighborsGetter, intersects_polygon_locally},
+    mpi::{Mpi, NeighborsGetter, intersects_polygon_locally, is_locally_concave},
     dgc::DebugGeometryCallback,
 };
 
@@ -32,11 +32,12 @@ use super::graph_types::{NodeData, Features};
 /// features geometry's CRS.
 static SAME_POINT_VISIBILITY_DISTANCE: f64 = 0.5;
 
-#[derive(PartialEq, Clone, Copy, Debug, Deserialize)]
+#[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
 pub enum VisibilityOptimizationMode {
     Naive, // No optimizations
     Sweep, // Sweep optimization (de Berg et al. 2008)
     OptimizedSweep, // Sweep with inner-outer ring culling and `in front` angle range optimizations
+    Bitangent, // Sweep keeping only bitangent edges between convex vertices (reduced visibility graph)
 }
 
 #[derive(Constructor)]
@@ -126,6 +127,48 @@ fn is_visible_from(
     }
 }
 
+/// Whether `node_data` is an obstacle vertex pointing into its obstacle. A
+/// shortest path never turns at such a vertex.
+pub(super) fn is_concave_vertex(node_data: &NodeData, features: &Features) -> bool {
+    return match node_data {
+        NodeData::PartOfObstacle(mpi) => is_locally_concave(mpi, &features.obstacles),
+        NodeData::PartOfWater(_) | NodeData::Arbitrary(_) => false,
+    };
+}
+
+/// Whether the line through `vertex` and `other_coord` touches the obstacle
+/// of `vertex` without crossing it there: both neighbors of `vertex` lie on
+/// the same side of the line (or on it).
+fn is_tangent_at(vertex: &NodeData, other_coord: Coordinate<f64>, features: &Features) -> bool {
+    return match vertex {
+        NodeData::PartOfObstacle(mpi) => {
+            let coord = features.coord(vertex);
+            let neighbors = mpi.neighbors(&features.obstacles);
+            let left_orientation = RobustKernel::orient2d(other_coord, coord, (&features.obstacles)[&neighbors.left]);
+            let right_orientation = RobustKernel::orient2d(other_coord, coord, (&features.obstacles)[&neighbors.right]);
+            !matches!(
+                (left_orientation, right_orientation),
+                (Orientation::Clockwise, Orientation::CounterClockwise) |
+                (Orientation::CounterClockwise, Orientation::Clockwise)
+            )
+        }
+        // Not part of an obstacle, so nothing to wrap around: the start or
+        // end of a path
+        NodeData::PartOfWater(_) | NodeData::Arbitrary(_) => true,
+    };
+}
+
+/// Whether `p`-`w` is an edge of the reduced visibility graph: neither end is
+/// a concave vertex and the line is tangent at both ends. A path turning at a
+/// vertex wraps around its obstacle, so it arrives and leaves along tangents.
+/// The cheapest path through penalized areas might not, though.
+pub(super) fn is_bitangent(p: &NodeData, w: &NodeData, features: &Features) -> bool {
+    return !is_concave_vertex(p, features)
+        && !is_concave_vertex(w, features)
+        && is_tangent_at(p, features.coord(w), features)
+        && is_tangent_at(w, features.coord(p), features);
+}
+
 // Implements steps 6 and 7 of the VisibleVertices() algorithm by de Berg et
 // al..
 //
@@ -176,7 +219,9 @@ fn update_possible_obstacle_edges(
 }
 
 /// `p` is the point (/vertex) in question  
-/// `ws` is the collection of other points to check visibility to
+/// `ws` is the collection of other points to check visibility to  
+/// `max_edge_length`, if any, is how far `p` can see: vertices further away
+/// are never visible
 ///
 ///  From "Computational Geometry: Algorithms and Applications" (de Berg et al.,
 /// 2008)
@@ -186,8 +231,13 @@ pub fn visible_vertices<'a>(
     features: &Features,
     _dgc: DebugGeometryCallback,
     optimization_mode: VisibilityOptimizationMode,
+    max_edge_length: Option<f64>,
 ) -> Vec<NodeData> {
     let p_coord = features.coord(&p);
+    let is_within_range = |w: &NodeData| match max_edge_length {
+        Some(max_edge_length) => features.coord(w).euclidean_distance(&p_coord) <= max_edge_length,
+        None => true,
+    };
 
     // 1. Sort the obstacle vertices according to the [ccw] angle that the
     //    halfline from p to each vertex makes with the positive x-axis. In case
@@ -197,13 +247,17 @@ pub fn visible_vertices<'a>(
     match optimization_mode {
         VisibilityOptimizationMode::Naive => { }
         VisibilityOptimizationMode::Sweep |
-        VisibilityOptimizationMode::OptimizedSweep => {
+        VisibilityOptimizationMode::OptimizedSweep |
+        VisibilityOptimizationMode::Bitangent => {
             // Sort in-place. This speeds up sorting since it's likely that a
             // neighboring vertex (that we'll process later if p is part of an obstacle)
             // has similar angle/distance order.
+            // Coinciding vertices are ordered by node, so the result does not
+            // depend on how `ws` was sorted before (e.g. by another thread).
             ws.sort_by(|a, b| {
                 cmp_angle(&p_coord, &features.coord(a), &features.coord(b))
                     .then_with(|| cmp_distance(&p_coord, &features.coord(a), &features.coord(b)))
+                    .then_with(|| a.cmp(b))
             });
         }
     }
@@ -215,7 +269,8 @@ pub fn visible_vertices<'a>(
     let ws_applicable = match p {
         NodeData::PartOfObstacle(p_mpi) => match optimization_mode {
             VisibilityOptimizationMode::Naive |
-            VisibilityOptimizationMode::Sweep => ws_iter.collect::<Vec<_>>(),
+            VisibilityOptimizationMode::Sweep |
+            VisibilityOptimizationMode::Bitangent => ws_iter.collect::<Vec<_>>(),
             VisibilityOptimizationMode::OptimizedSweep => {
                 // Only consider vertices "in front" of p, with "in front"
                 // meaning within the range of angles between p's two neighbors.
@@ -268,6 +323,43 @@ pub fn visible_vertices<'a>(
         .copied()
         .collect::<Vec<_>>();
 
+    // Prune the vertices out of range. Obstacle vertices out of range with an
+    // incident edge that comes within range stay in the sweep though (without
+    // checking their visibility): passing them keeps T up to date.
+    //
+    // This gives the same visible vertices in range as without pruning, in
+    // every mode. A segment p-w in range lies within `max_edge_length` of p,
+    // so only obstacle edges in range can block it. Both ends of such an edge
+    // stay in the sweep, so T holds the same edges in range as without
+    // pruning; T may hold different edges out of range, which never matter.
+    // If the first vertices were pruned, rho points at a later one, but no
+    // edge in range ends in between: T starts with the edges in range the
+    // unpruned sweep has there. The w_prev shortcut is not affected either:
+    // a w_prev on p-w is closer than w, so in range and never pruned. With
+    // OptimizedSweep, the "in front" range is taken before pruning.
+    let ws_applicable = match max_edge_length {
+        None => ws_applicable,
+        Some(max_edge_length) => ws_applicable.into_iter()
+            .filter(|w| {
+                if is_within_range(w) {
+                    return true;
+                }
+                return match (optimization_mode, w) {
+                    (VisibilityOptimizationMode::Naive, _) => false,
+                    (_, NodeData::PartOfObstacle(w_mpi)) => {
+                        let w_coord = features.coord(w);
+                        let w_neighbors = w_mpi.neighbors(&features.obstacles);
+                        [w_neighbors.left, w_neighbors.right].iter().any(|neighbor_mpi| {
+                            let incident_edge = Line::new(w_coord, (&features.obstacles)[neighbor_mpi]);
+                            Point(p_coord).euclidean_distance(&incident_edge) <= max_edge_length
+                        })
+                    }
+                    _ => false,
+                };
+            })
+            .collect::<Vec<_>>(),
+    };
+
     // _dgc.clone().unwrap().try_send(geo::Geometry::GeometryCollection(GeometryCollection::from_iter(ws_applicable.iter().map(|w| {
     //     Point(features.coord(w))
     // })))).unwrap();
@@ -275,10 +367,18 @@ pub fn visible_vertices<'a>(
 
     let mut possible_obstacle_edges = match optimization_mode {
         VisibilityOptimizationMode::Naive => {
-            features.obstacles.lines_iter().collect::<Vec<Line<f64>>>()
+            features.obstacles
+                .lines_iter()
+                // Edges out of range cannot block edges in range
+                .filter(|edge| match max_edge_length {
+                    Some(max_edge_length) => Point(p_coord).euclidean_distance(edge) <= max_edge_length,
+                    None => true,
+                })
+                .collect::<Vec<Line<f64>>>()
         }
         VisibilityOptimizationMode::Sweep |
-        VisibilityOptimizationMode::OptimizedSweep => {
+        VisibilityOptimizationMode::OptimizedSweep |
+        VisibilityOptimizationMode::Bitangent => {
             // 2. Let rho be the half-line parallel to the positive x-axis starting at p.
             //    Find the obstacle edges that are properly intersected by rho, and store
             //    them in a balanced search tree T in the order in which they are
@@ -318,7 +418,7 @@ pub fn visible_vertices<'a>(
     // 4. for [w in ws_applicable]
     let mut ws_visible = ws_applicable.iter().filter(|w| {
         // 5. if VISIBLE(wi) then Add w to W
-        let is_visible =
+        let is_visible = is_within_range(w) &&
             is_visible_from(p, w_prev_info.as_ref(), w, &possible_obstacle_edges, features, optimization_mode, _dgc.clone());
         w_prev_info = Some(WPrevInfo::new(&w, is_visible));
 
@@ -326,13 +426,21 @@ pub fn visible_vertices<'a>(
         if optimization_mode != VisibilityOptimizationMode::Naive && let NodeData::PartOfObstacle(w_mpi) = w {
             update_possible_obstacle_edges(p_coord, w_mpi, &features.obstacles, &mut possible_obstacle_edges);
         }
-        
+
+        // Only now: the next w needs to know whether this one is visible,
+        // bitangent or not
+        if optimization_mode == VisibilityOptimizationMode::Bitangent {
+            return is_visible && is_bitangent(p, w, features);
+        }
         return is_visible;
     })
     .copied()
     .collect::<Vec<_>>();
 
-    ws_visible.extend(ws_same_location);
+    // Concave vertices are no vertices of the reduced visibility graph
+    ws_visible.extend(ws_same_location.into_iter().filter(|w| {
+        optimization_mode != VisibilityOptimizationMode::Bitangent || !is_concave_vertex(w, features)
+    }));
 
     return ws_visible;
 }
\ No newline at end of file
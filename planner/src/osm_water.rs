@@ -0,0 +1,357 @@
+//! Water layer from OpenStreetMap `.osm.pbf` extracts
+//!
+//! Waters are closed ways and `type=multipolygon` relations with water tags
+//! (see `water_type`). Closed ways that are members of such a relation are
+//! part of the relation's water, not waters of their own. The member ways of a relation are joined into rings by
+//! their shared end nodes; inner rings become holes of the outer ring they
+//! lie in.
+//!
+//! See: https://wiki.openstreetmap.org/wiki/Tag:natural%3Dwater
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error::Error,
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use geo::{prelude::Contains, Coordinate, LineString, MultiPolygon, Point, Polygon};
+use log::warn;
+use osmpbfreader::{NodeId, OsmId, OsmObj, OsmPbfReader, Tags, WayId};
+use serde_json::Value;
+
+use crate::{
+    crs::InternalCrs,
+    geo_io::{features_bounding_rect, write_gpkg_features, Attributes, SourceFeature},
+    winding::ensure_sfa_winding,
+};
+
+fn tag<'a>(tags: &'a Tags, key: &str) -> Option<&'a str> {
+    tags.get(key).map(|value| value.as_str())
+}
+
+/// Kind of water `tags` describe (`lake`, `river`, `reservoir`...), `None`
+/// if they don't describe a water area.
+fn water_type(tags: &Tags) -> Option<String> {
+    if tag(tags, "area") == Some("no") {
+        return None;
+    }
+    if let Some(water) = tag(tags, "water") {
+        return Some(water.to_owned());
+    }
+    if tag(tags, "natural") == Some("water") {
+        return Some("water".to_owned());
+    }
+    if let Some(waterway @ ("riverbank" | "dock" | "boatyard")) = tag(tags, "waterway") {
+        return Some(waterway.to_owned());
+    }
+    if let Some(landuse @ ("reservoir" | "basin")) = tag(tags, "landuse") {
+        return Some(landuse.to_owned());
+    }
+    return None;
+}
+
+fn is_multipolygon(tags: &Tags) -> bool {
+    tag(tags, "type") == Some("multipolygon")
+}
+
+/// Join `ways` (node id sequences) into closed rings, reversing ways where
+/// needed. Fails if the ways don't close.
+fn assemble_rings(mut ways: Vec<Vec<i64>>) -> Result<Vec<Vec<i64>>, String> {
+    let mut rings = Vec::new();
+    ways.retain(|way| way.len() >= 2);
+    while let Some(mut ring) = ways.pop() {
+        while ring.first() != ring.last() {
+            let ring_end = *ring.last().unwrap();
+            let next_position = ways
+                .iter()
+                .position(|way| way.first() == Some(&ring_end) || way.last() == Some(&ring_end))
+                .ok_or_else(|| format!("ring not closed at node {}", ring_end))?;
+            let mut next = ways.swap_remove(next_position);
+            if next.first() != Some(&ring_end) {
+                next.reverse();
+            }
+            ring.extend_from_slice(&next[1..]);
+        }
+        // First, last and at least two others
+        if ring.len() < 4 {
+            return Err(format!("degenerate ring at node {}", ring[0]));
+        }
+        rings.push(ring);
+    }
+    return Ok(rings);
+}
+
+/// Polygons from `outers` with the `inners` they contain as holes. Inner
+/// rings outside every outer are dropped.
+fn build_multi_polygon(outers: Vec<LineString<f64>>, inners: Vec<LineString<f64>>) -> MultiPolygon<f64> {
+    let mut polygons = outers
+        .into_iter()
+        .map(|outer| Polygon::new(outer, vec![]))
+        .collect::<Vec<_>>();
+    for inner in inners {
+        let inner_point = Point(inner.0[0]);
+        // Contains is false for points on the boundary, try each vertex
+        let outer_index = polygons.iter().position(|polygon| {
+            inner.0.iter().any(|coord| polygon.exterior_polygon_contains(Point(*coord)))
+        });
+        match outer_index {
+            Some(outer_index) => polygons[outer_index].interiors_push(inner),
+            None => warn!("Dropping inner ring at {:?} outside every outer ring", inner_point),
+        }
+    }
+    let mut multi_polygon = MultiPolygon(polygons);
+    ensure_sfa_winding(&mut multi_polygon);
+    return multi_polygon;
+}
+
+trait ExteriorPolygonContains {
+    fn exterior_polygon_contains(&self, point: Point<f64>) -> bool;
+}
+
+impl ExteriorPolygonContains for Polygon<f64> {
+    /// Whether `point` lies in the exterior ring, ignoring any holes
+    fn exterior_polygon_contains(&self, point: Point<f64>) -> bool {
+        Polygon::new(self.exterior().clone(), vec![]).contains(&point)
+    }
+}
+
+/// OSM objects needed to assemble the waters
+struct OsmObjects(BTreeMap<OsmId, OsmObj>);
+
+impl OsmObjects {
+    fn coord(&self, node_id: i64) -> Result<Coordinate<f64>, String> {
+        match self.0.get(&OsmId::Node(NodeId(node_id))) {
+            Some(OsmObj::Node(node)) => Ok(Coordinate { x: node.lon(), y: node.lat() }),
+            _ => Err(format!("node {} missing from extract", node_id)),
+        }
+    }
+
+    fn line_string(&self, node_ids: &[i64]) -> Result<LineString<f64>, String> {
+        node_ids.iter().map(|node_id| self.coord(*node_id)).collect()
+    }
+
+    fn way_node_ids(&self, way_id: WayId) -> Result<Vec<i64>, String> {
+        match self.0.get(&OsmId::Way(way_id)) {
+            Some(OsmObj::Way(way)) => Ok(way.nodes.iter().map(|node_id| node_id.0).collect()),
+            _ => Err(format!("way {} missing from extract", way_id.0)),
+        }
+    }
+}
+
+fn water_attributes(osm_id: String, tags: &Tags, water_type: String) -> Attributes {
+    let mut attributes = Attributes::new();
+    attributes.insert("osm_id".to_owned(), Value::from(osm_id));
+    attributes.insert("name".to_owned(), tag(tags, "name").map_or(Value::Null, Value::from));
+    attributes.insert("water_type".to_owned(), Value::from(water_type));
+    return attributes;
+}
+
+/// Water areas in the `.osm.pbf` extract at `path`, in WGS84, with their
+/// OSM id, name and water type as attributes. Objects that cannot be
+/// assembled (e.g. relations cut off by the extract boundary) are skipped.
+pub fn read_osm_waters(path: &Path) -> Result<Vec<SourceFeature>, Box<dyn Error + Send + Sync>> {
+    let mut pbf = OsmPbfReader::new(File::open(path)?);
+    let objects = OsmObjects(pbf.get_objs_and_deps(|obj| match obj {
+        OsmObj::Way(way) => way.is_closed() && water_type(&way.tags).is_some(),
+        OsmObj::Relation(relation) => is_multipolygon(&relation.tags) && water_type(&relation.tags).is_some(),
+        OsmObj::Node(_) => false,
+    })?);
+
+    // Outer rings are often tagged like their relation as well, importing
+    // them again would duplicate the water
+    let water_relation_ways = objects.0
+        .values()
+        .filter_map(|obj| obj.relation())
+        .filter(|relation| is_multipolygon(&relation.tags) && water_type(&relation.tags).is_some())
+        .flat_map(|relation| relation.refs.iter().filter_map(|member| member.member.way()))
+        .collect::<BTreeSet<_>>();
+
+    let mut features = Vec::new();
+    for obj in objects.0.values() {
+        let feature = match obj {
+            OsmObj::Way(way) => {
+                let water_type = match water_type(&way.tags) {
+                    Some(water_type) if way.is_closed() && !water_relation_ways.contains(&way.id) => water_type,
+                    // Member of a water relation
+                    _ => continue,
+                };
+                let node_ids = way.nodes.iter().map(|node_id| node_id.0).collect::<Vec<_>>();
+                objects
+                    .line_string(&node_ids)
+                    .map(|ring| build_multi_polygon(vec![ring], vec![]))
+                    .map(|geometry| SourceFeature {
+                        geometry,
+                        attributes: water_attributes(format!("way/{}", way.id.0), &way.tags, water_type),
+                    })
+            }
+            OsmObj::Relation(relation) => {
+                let water_type = match water_type(&relation.tags) {
+                    Some(water_type) if is_multipolygon(&relation.tags) => water_type,
+                    _ => continue,
+                };
+                let rings = |role: &str| -> Result<Vec<LineString<f64>>, String> {
+                    let ways = relation
+                        .refs
+                        .iter()
+                        .filter(|member| member.role.as_str() == role || (role == "outer" && member.role.is_empty()))
+                        .filter_map(|member| member.member.way())
+                        .map(|way_id| objects.way_node_ids(way_id))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    assemble_rings(ways)?
+                        .iter()
+                        .map(|ring| objects.line_string(ring))
+                        .collect()
+                };
+                rings("outer")
+                    .and_then(|outers| Ok(build_multi_polygon(outers, rings("inner")?)))
+                    .map(|geometry| SourceFeature {
+                        geometry,
+                        attributes: water_attributes(format!("relation/{}", relation.id.0), &relation.tags, water_type),
+                    })
+            }
+            OsmObj::Node(_) => continue,
+        };
+        match feature {
+            Ok(feature) => features.push(feature),
+            Err(err) => warn!("Skipping {:?}: {}", obj.id(), err),
+        }
+    }
+    return Ok(features);
+}
+
+/// Import command: extract the waters from the `.osm.pbf` file at
+/// `input_path` and write them, in the internal CRS for their area (see
+/// `InternalCrs::for_area`), to the GeoPackage at `output_path`. The layer is
+/// named after the file, like `preprocess_grb` does.
+pub async fn import_osm_waters(
+    input_path: PathBuf,
+    output_path: PathBuf,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let table_name = output_path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .ok_or_else(|| format!("Invalid output path {:?}", output_path))?
+        .to_owned();
+    // Decoding is blocking
+    let (features, crs) = tokio::task::spawn_blocking(move || -> Result<_, Box<dyn Error + Send + Sync>> {
+        let mut features = read_osm_waters(&input_path)?;
+        let crs = features_bounding_rect(&features).map_or_else(InternalCrs::default, InternalCrs::for_area);
+        for feature in &mut features {
+            crs.to_int(&mut feature.geometry)?;
+        }
+        Ok((features, crs))
+    })
+    .await??;
+    println!("Writing {} waters in {} to {:?}", features.len(), crs.name(), output_path);
+    write_gpkg_features(&output_path, &table_name, &features, &crs).await?;
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use geo::{line_string, winding_order::Winding};
+    use osmpbfreader::Tags;
+    use serde_json::Value;
+
+    use crate::{
+        geo_io::GpkgReader,
+        test_util::{temp_path, write_osm_pbf},
+    };
+
+    use super::{assemble_rings, build_multi_polygon, import_osm_waters, water_type};
+
+    fn tags(pairs: &[(&str, &str)]) -> Tags {
+        let mut tags = Tags::new();
+        for (key, value) in pairs {
+            tags.insert((*key).into(), (*value).into());
+        }
+        tags
+    }
+
+    #[test]
+    fn water_type__tags() {
+        assert_eq!(water_type(&tags(&[("natural", "water")])), Some("water".to_owned()));
+        assert_eq!(water_type(&tags(&[("natural", "water"), ("water", "lake")])), Some("lake".to_owned()));
+        assert_eq!(water_type(&tags(&[("waterway", "riverbank")])), Some("riverbank".to_owned()));
+        assert_eq!(water_type(&tags(&[("landuse", "reservoir")])), Some("reservoir".to_owned()));
+        assert_eq!(water_type(&tags(&[("waterway", "river")])), None);
+        assert_eq!(water_type(&tags(&[("natural", "water"), ("area", "no")])), None);
+        assert_eq!(water_type(&tags(&[("building", "yes")])), None);
+    }
+
+    #[test]
+    fn assemble_rings__joins_and_reverses() {
+        let rings = assemble_rings(vec![vec![1, 2, 3], vec![5, 4, 3], vec![5, 6, 1]]).unwrap();
+        assert_eq!(rings.len(), 1);
+        assert_eq!(rings[0].len(), 7);
+        assert_eq!(rings[0].first(), rings[0].last());
+    }
+
+    #[test]
+    fn assemble_rings__multiple_rings() {
+        let rings = assemble_rings(vec![vec![1, 2, 3, 1], vec![4, 5], vec![5, 6, 4]]).unwrap();
+        assert_eq!(rings.len(), 2);
+    }
+
+    #[test]
+    fn assemble_rings__unclosed() {
+        assert!(assemble_rings(vec![vec![1, 2, 3], vec![3, 4]]).is_err());
+    }
+
+    #[test]
+    fn build_multi_polygon__holes_and_winding() {
+        // Outer ring clockwise, as OSM does not prescribe winding
+        let outer = line_string![(x: 0., y: 0.), (x: 0., y: 10.), (x: 10., y: 10.), (x: 10., y: 0.), (x: 0., y: 0.)];
+        let island = line_string![(x: 2., y: 2.), (x: 4., y: 2.), (x: 4., y: 4.), (x: 2., y: 2.)];
+        let elsewhere = line_string![(x: 20., y: 2.), (x: 24., y: 2.), (x: 24., y: 4.), (x: 20., y: 2.)];
+        let multi_polygon = build_multi_polygon(vec![outer], vec![island, elsewhere]);
+        assert_eq!(multi_polygon.0.len(), 1);
+        assert_eq!(multi_polygon.0[0].interiors().len(), 1);
+        assert!(multi_polygon.0[0].exterior().is_ccw());
+        assert!(multi_polygon.0[0].interiors()[0].is_cw());
+    }
+
+    #[tokio::test]
+    async fn import_osm_waters__ways_and_relations() {
+        let square = |first_id: i64, lon: f64, lat: f64, size: f64| {
+            [(0.0, 0.0), (size, 0.0), (size, size), (0.0, size)]
+                .into_iter()
+                .enumerate()
+                .map(move |(i, (x, y))| (first_id + i as i64, lon + x, lat + y))
+        };
+        let nodes = square(1, 4.50, 50.90, 0.001)
+            .chain(square(11, 4.51, 50.90, 0.004))
+            .chain(square(21, 4.511, 50.901, 0.001))
+            .collect::<Vec<_>>();
+        let water: &[(&str, &str)] = &[("natural", "water")];
+        let ways: &[(i64, &[(&str, &str)], &[i64])] = &[
+            (1, &[("natural", "water"), ("name", "Vijver")], &[1, 2, 3, 4, 1]),
+            // Outer ring of the relation, tagged like it
+            (2, water, &[11, 12, 13, 14, 11]),
+            (3, &[], &[21, 22, 23, 24, 21]),
+            // Not closed
+            (4, water, &[11, 12, 13]),
+            (5, &[("waterway", "river")], &[1, 2, 3, 1]),
+        ];
+        let relations: &[(i64, &[(&str, &str)], &[(i64, &str)])] = &[
+            (10, &[("type", "multipolygon"), ("natural", "water"), ("water", "lake")], &[(2, "outer"), (3, "inner")]),
+        ];
+        let input_path = temp_path("osm-waters.osm.pbf");
+        write_osm_pbf(&input_path, &nodes, ways, relations);
+        let output_path = temp_path("osm-waters.gpkg");
+
+        import_osm_waters(input_path, output_path.clone()).await.unwrap();
+
+        let reader = GpkgReader::open(&output_path).await.unwrap();
+        let features = reader.read_features("osm-waters", None).await.unwrap();
+        let osm_ids = features.iter().map(|feature| feature.attributes["osm_id"].clone()).collect::<Vec<_>>();
+        assert_eq!(osm_ids, vec![Value::from("way/1"), Value::from("relation/10")]);
+        assert_eq!(features[0].attributes["name"], Value::from("Vijver"));
+        assert_eq!(features[1].attributes["water_type"], Value::from("lake"));
+        assert_eq!(features[1].geometry.0.len(), 1);
+        assert_eq!(features[1].geometry.0[0].interiors().len(), 1);
+    }
+}
@@ -1,9 +1,50 @@
 use serde::Deserialize;
 
-use crate::nav_graph::VisibilityOptimizationMode;
+use crate::{
+    airspace::AMSLHeightMeters, geozone_policy::OperatorProfile,
+    geozone_schedule::UnixMillis, nav_graph::VisibilityOptimizationMode,
+};
 
 use super::common::LatLng;
 
+/// When and how fast the drone flies, to estimate the time window of each leg.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "DepartureFields")]
+pub struct Departure {
+    pub time: UnixMillis,
+    /// Meters per second, positive
+    pub cruise_speed: f64,
+    /// Seconds spent recharging between legs, not negative
+    pub recharge_duration: f64,
+}
+
+/// `Departure` as sent by the client, before validation
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DepartureFields {
+    time: UnixMillis,
+    cruise_speed: f64,
+    recharge_duration: f64,
+}
+
+impl TryFrom<DepartureFields> for Departure {
+    type Error = String;
+
+    fn try_from(fields: DepartureFields) -> Result<Self, Self::Error> {
+        if !(fields.cruise_speed.is_finite() && fields.cruise_speed > 0.0) {
+            return Err(format!("cruise speed must be positive, got {}", fields.cruise_speed));
+        }
+        if !(fields.recharge_duration.is_finite() && fields.recharge_duration >= 0.0) {
+            return Err(format!("recharge duration must not be negative, got {}", fields.recharge_duration));
+        }
+        return Ok(Departure {
+            time: fields.time,
+            cruise_speed: fields.cruise_speed,
+            recharge_duration: fields.recharge_duration,
+        });
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PlanClientMsg {
@@ -12,6 +53,16 @@ pub struct PlanClientMsg {
     pub max_distance_initially: f64,
     pub max_distance_after_charge: f64,
     pub visibility_optimization_mode: VisibilityOptimizationMode,
+    /// Only geozones whose vertical band overlaps this altitude are treated
+    /// as obstacles.
+    pub cruise_altitude: AMSLHeightMeters,
+    /// Decides which geozones are forbidden, allowed or penalized.
+    #[serde(default)]
+    pub operator_profile: OperatorProfile,
+    /// Only geozones active during a leg are considered for that leg. Without
+    /// a departure every geozone is considered, regardless of its schedule.
+    #[serde(default)]
+    pub departure: Option<Departure>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -20,16 +71,76 @@ pub struct PlanClientMsg {
 pub enum ClientMessage {
     MapReady,
     LoadWaters,
+    /// Geozones from the droneguide service
+    LoadGeozones,
+    /// Geozones from the restricted airspace GeoPackage exported from
+    /// droneguide.be
     LoadRestrictedAirspace,
+    /// Geozones from the local OpenAir, GeoJSON or GeoPackage file `path` in
+    /// the airspace directory (`data/airspace`)
+    LoadAirspaceFile { path: String },
     #[serde(rename_all = "camelCase")]
     VisibilityGraph {
-        
-        visibility_optimization_mode: VisibilityOptimizationMode
+        visibility_optimization_mode: VisibilityOptimizationMode,
+        /// Leave out edges longer than this on the ellipsoid, in meters
+        #[serde(default)]
+        max_edge_length: Option<f64>,
     },
     #[serde(rename_all = "camelCase")]
     CalcPath {
         start: LatLng, end: LatLng,
-        visibility_optimization_mode: VisibilityOptimizationMode
+        visibility_optimization_mode: VisibilityOptimizationMode,
+        /// Search a tiled nav graph with tiles of this size (meters) instead
+        /// of the loaded nav graph, see `TiledNavGraph`
+        #[serde(default)]
+        tile_size: Option<f64>,
+    },
+    /// Add the polygon with corners `area` as an obstacle to the loaded nav
+    /// graph, without creating the graph again (except in `OptimizedSweep`
+    /// mode). It stays an obstacle when the graph is created again, e.g. to
+    /// plan.
+    #[serde(rename_all = "camelCase")]
+    AddNoFlyArea {
+        area: Vec<LatLng>,
+        visibility_optimization_mode: VisibilityOptimizationMode,
+    },
+    /// Remove the no-fly area added last from the loaded nav graph
+    #[serde(rename_all = "camelCase")]
+    RemoveNoFlyArea {
+        visibility_optimization_mode: VisibilityOptimizationMode,
     },
     Plan(PlanClientMsg),
+    /// Write the obstacles, nav graph and last plan to the GeoPackage file
+    /// `path` in the export directory (`data/exports`)
+    ExportGpkg { path: String },
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use super::Departure;
+
+    #[test]
+    fn departure__valid() {
+        let departure: Departure =
+            serde_json::from_str(r#"{"time": 1000, "cruiseSpeed": 12.5, "rechargeDuration": 600}"#).unwrap();
+        assert_eq!(departure.time, 1000);
+        assert_eq!(departure.cruise_speed, 12.5);
+        assert_eq!(departure.recharge_duration, 600.0);
+    }
+
+    #[test]
+    fn departure__rejects_non_positive_cruise_speed() {
+        for cruise_speed in ["0", "-5", "1e400"] {
+            let json = format!(r#"{{"time": 1000, "cruiseSpeed": {}, "rechargeDuration": 600}}"#, cruise_speed);
+            assert!(serde_json::from_str::<Departure>(&json).is_err(), "cruise speed {} accepted", cruise_speed);
+        }
+    }
+
+    #[test]
+    fn departure__rejects_negative_recharge_duration() {
+        let json = r#"{"time": 1000, "cruiseSpeed": 12.5, "rechargeDuration": -1}"#;
+        assert!(serde_json::from_str::<Departure>(json).is_err());
+    }
 }
@@ -1,33 +1,80 @@
-use std::error::Error;
+use std::{
+    error::Error,
+    path::{Component, Path, PathBuf},
+    time::{Duration, Instant},
+};
 
 use derive_more::Display;
 use futures::{SinkExt, StreamExt};
-use geo::{LineString, MultiPolygon, Point};
+use geo::{prelude::BoundingRect, Coordinate, LineString, MultiPolygon, Point, Polygon};
+use geojson::Feature;
+use proj::Transform;
 use tokio::{sync::mpsc::{self, Sender}, net::TcpStream};
 use tokio_tungstenite::{accept_async, tungstenite::Message as WsMessage};
 
 use crate::{
-    crs::create_to_int_proj,
+    crs::{with_proj, InternalCrs},
+    airspace::{
+        provider_for_path, AMSLHeightMeters, AirspaceProvider, DroneguideProvider, Geozone, GpkgProvider,
+    },
+    droneguide::DroneguideUrls,
     geo_geojson::{geometry_to_feature, multi_polygon_to_feature},
-    geo_io::load_gpkg_multi_polygon,
+    geo_io::{load_file_multi_polygon, write_gpkg_multi_polygon, write_gpkg_nav_graph, write_gpkg_plan},
+    geozone_cache::GeozoneCache,
+    geozone_layer::{create_geozone_layer, merge_obstacles},
+    geozone_policy::{OperatorProfile, PolicyRules},
+    geozone_schedule::{TimeWindow, UnixMillis},
     server::server_msg::ServerMessage,
     nav_graph::{
-        add_coord_to_nav_graph, create_nav_graph, nav_graph_to_feature_collection,
-        graph_types::{NavGraph, Features}, plan_path_or_recharge, calculate_shortest_path_between_coords,
+        add_coord_to_nav_graph, create_nav_graph_cached, nav_graph_to_feature_collection, NavGraphCache,
+        graph_types::{NavGraph, Features}, plan_leg, LegPlan, PlannedLeg, calculate_shortest_path_between_coords,
+        insert_obstacle_into_nav_graph, remove_obstacle_from_nav_graph, TiledNavGraph, VisibilityOptimizationMode,
     }, dgc::create_dgc,
+    simplify::simplify_containing,
+    winding::ensure_sfa_winding,
 };
 
 use super::{
-    client_msg::{ClientMessage, PlanClientMsg},
+    client_msg::{ClientMessage, Departure, PlanClientMsg},
     server_msg::{ShortestPath, NavGraphLoaded},
 };
 
 
+/// What decides which geozones end up in a nav graph
+#[derive(Debug, Clone, PartialEq)]
+struct GeozoneParams {
+    cruise_altitude: AMSLHeightMeters,
+    operator_profile: OperatorProfile,
+    /// Indices into `UiContext::maybe_geozones` of the zones active during
+    /// the leg the graph is for
+    active_geozones: Vec<usize>,
+}
+
 #[derive(Debug, Default)]
 struct UiContext {
+    /// CRS the obstacles, waters and nav graphs are in, chosen for the area
+    /// of the obstacles when they are loaded
+    internal_crs: InternalCrs,
     maybe_waters: Option<MultiPolygon<f64>>,
     maybe_obstacles: Option<MultiPolygon<f64>>,
+    maybe_geozones: Option<Vec<Geozone>>,
+    maybe_policy_rules: Option<PolicyRules>,
     nav_graph: Option<NavGraph>,
+    /// Parameters the geozones in `nav_graph` were evaluated for, `None` if
+    /// the graph was created without geozones.
+    nav_graph_geozone_params: Option<GeozoneParams>,
+    /// No-fly areas added by the client, in the order they were added, in
+    /// the internal CRS. They are the last obstacles of `nav_graph`, also
+    /// when it is created again.
+    no_fly_areas: Vec<Polygon<f64>>,
+    /// Tiled nav graph of the obstacles, waters and no-fly areas, kept for
+    /// the next `CalcPath` with the same tile size and optimization mode.
+    /// Dropped when any of them change.
+    tiled_nav_graph: Option<TiledNavGraph>,
+    /// Where nav graphs are cached, `NAV_GRAPH_CACHE_DIR` if `None`
+    nav_graph_cache_dir: Option<PathBuf>,
+    /// Legs of the last plan
+    last_plan: Option<Vec<PlannedLeg>>,
 }
 
 #[derive(Debug, Clone, Display)]
@@ -40,6 +87,322 @@ impl Error for WebsocketMessageNotTextError {}
 
 const EMPTY_MULTI_POLYGON: geo::MultiPolygon<f64> = geo::MultiPolygon(vec![]);
 
+/// Max distance (metres) obstacle outlines may grow when simplified before
+/// creating the nav graph, see `simplify_containing`
+const OBSTACLE_SIMPLIFICATION_TOLERANCE: f64 = 5.0;
+
+/// See `NavGraphCache`
+const NAV_GRAPH_CACHE_DIR: &str = "data/nav-graph-cache";
+/// Max distance (metres) between the nodes tiles share along their borders,
+/// see `TiledNavGraph`
+const TILED_BORDER_NODE_SPACING: f64 = 10.0;
+
+const RESTRICTED_AIRSPACE_PATH: &str = "data/droneguide/restricted-airspace.gpkg";
+const RESTRICTED_AIRSPACE_LAYER: &str = "restricted-airspace";
+/// Directory `ClientMessage::LoadAirspaceFile` reads airspace files from
+const AIRSPACE_FILE_DIR: &str = "data/airspace";
+
+const GEOZONE_CACHE_PATH: &str = "data/droneguide/geozones-cache.gpkg";
+const GEOZONE_CACHE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Optional rules table, see `PolicyRules`. Without it every geozone is
+/// forbidden.
+const GEOZONE_POLICY_RULES_PATH: &str = "data/droneguide/policy-rules.json";
+
+/// Directory `ClientMessage::ExportGpkg` writes GeoPackages to
+const GPKG_EXPORT_DIR: &str = "data/exports";
+
+/// Path of the file `file_name` in `directory`. Clients only choose the name
+/// of the file, so paths with separators or `..` are rejected.
+fn file_in_directory(directory: &str, file_name: &str) -> Result<PathBuf, Box<dyn Error + Send + Sync>> {
+    let mut components = Path::new(file_name).components();
+    let is_plain_file_name = matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    ) && !file_name.contains(['/', '\\']);
+    if !is_plain_file_name {
+        return Err(format!("{:?} is not a plain file name", file_name).into());
+    }
+    return Ok(Path::new(directory).join(file_name));
+}
+
+async fn load_policy_rules() -> Result<PolicyRules, Box<dyn Error + Send + Sync>> {
+    match tokio::fs::read_to_string(GEOZONE_POLICY_RULES_PATH).await {
+        Ok(json) => Ok(PolicyRules::from_json(&json)?),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(PolicyRules::default()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Features to create a nav graph from. If `geozone_params` are given and
+/// geozones are loaded, the active geozones overlapping the cruise altitude
+/// are evaluated for the operator profile: forbidden zones are merged into the
+/// obstacles, penalized ones become penalized areas.
+fn features_for_geozone_params(
+    ui_context: &UiContext,
+    geozone_params: Option<&GeozoneParams>,
+) -> Result<Features, Box<dyn Error + Send + Sync>> {
+    let obstacles = ui_context.maybe_obstacles.as_ref().ok_or(
+        "Obstacles not loaded yet. Please load the obstacles first.",
+    )?;
+    let waters_default = &EMPTY_MULTI_POLYGON;
+    let waters = ui_context.maybe_waters.as_ref().unwrap_or(waters_default);
+    let (obstacles, penalized_areas) = match (&ui_context.maybe_geozones, geozone_params) {
+        (Some(geozones), Some(geozone_params)) => {
+            let policy_rules_default = PolicyRules::default();
+            let policy_rules = ui_context.maybe_policy_rules.as_ref().unwrap_or(&policy_rules_default);
+            let layer = create_geozone_layer(
+                geozone_params.active_geozones.iter().map(|&index| &geozones[index]),
+                geozone_params.cruise_altitude,
+                policy_rules,
+                &geozone_params.operator_profile,
+                &ui_context.internal_crs,
+            );
+            (merge_obstacles(obstacles, &layer.forbidden)?, layer.penalized)
+        }
+        _ => (obstacles.clone(), Vec::new()),
+    };
+    Ok(Features {
+        obstacles,
+        waters: waters.clone(),
+        penalized_areas,
+        arbitrary: Vec::new(),
+    })
+}
+
+fn open_nav_graph_cache(ui_context: &UiContext) -> Result<NavGraphCache, Box<dyn Error + Send + Sync>> {
+    let dir = ui_context.nav_graph_cache_dir.as_deref().unwrap_or(Path::new(NAV_GRAPH_CACHE_DIR));
+    return Ok(NavGraphCache::open(dir)?);
+}
+
+async fn send_nav_graph(
+    ui_context: &mut UiContext,
+    features: Features,
+    geozone_params: Option<GeozoneParams>,
+    visibility_optimization_mode: VisibilityOptimizationMode,
+    max_edge_length: Option<f64>,
+    server_msg_tx_ch: &Sender<ServerMessage>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let dgc = create_dgc(server_msg_tx_ch.clone(), ui_context.internal_crs.clone());
+
+    // for _ in 0..9 {
+    //     create_nav_graph(&features, Some(dgc.clone()), visibility_optimization_mode);
+    // }
+
+    let (obstacles, simplification_report) =
+        simplify_containing(&features.obstacles, OBSTACLE_SIMPLIFICATION_TOLERANCE);
+    println!(
+        "Simplified obstacles: removed {} of {} vertices",
+        simplification_report.vertices_removed(),
+        simplification_report.vertices_before
+    );
+    let mut features = Features { obstacles, ..features };
+    // Not simplified, so they stay the polygons the client added
+    features.obstacles.0.extend(ui_context.no_fly_areas.iter().cloned());
+
+    let nav_graph_cache = open_nav_graph_cache(ui_context)?;
+    let (nav_graph, duration) = create_nav_graph_cached(
+        &nav_graph_cache, &features, &ui_context.internal_crs, Some(dgc), visibility_optimization_mode,
+        max_edge_length
+    )?;
+    let graph_feature_collection = nav_graph_to_feature_collection(&nav_graph);
+    ui_context.nav_graph = Some(nav_graph);
+    ui_context.nav_graph_geozone_params = geozone_params;
+    server_msg_tx_ch
+        .send(ServerMessage::NavGraph(NavGraphLoaded::new(graph_feature_collection, duration.as_millis())))
+        .await?;
+    Ok(())
+}
+
+/// No-fly area with the corners `coords` (internal CRS) in any order, wound
+/// like the other obstacles
+fn no_fly_area_polygon(coords: Vec<Coordinate<f64>>) -> Polygon<f64> {
+    let mut multi_polygon = MultiPolygon(vec![Polygon::new(LineString(coords), vec![])]);
+    ensure_sfa_winding(&mut multi_polygon);
+    return multi_polygon.0.remove(0);
+}
+
+/// Create the nav graph again as it was created, e.g. with the no-fly areas
+/// changed
+async fn recreate_nav_graph(
+    ui_context: &mut UiContext,
+    visibility_optimization_mode: VisibilityOptimizationMode,
+    server_msg_tx_ch: &Sender<ServerMessage>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let max_edge_length = ui_context.nav_graph.as_ref().and_then(|nav_graph| nav_graph.max_edge_length);
+    let geozone_params = ui_context.nav_graph_geozone_params.clone();
+    let features = features_for_geozone_params(ui_context, geozone_params.as_ref())?;
+    send_nav_graph(
+        ui_context, features, geozone_params, visibility_optimization_mode, max_edge_length, server_msg_tx_ch
+    ).await
+}
+
+/// Send `nav_graph` again after it was updated in place, which took
+/// `duration`
+async fn send_updated_nav_graph(
+    nav_graph: &NavGraph,
+    duration: Duration,
+    server_msg_tx_ch: &Sender<ServerMessage>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let graph_feature_collection = nav_graph_to_feature_collection(nav_graph);
+    server_msg_tx_ch
+        .send(ServerMessage::NavGraph(NavGraphLoaded::new(graph_feature_collection, duration.as_millis())))
+        .await?;
+    Ok(())
+}
+
+/// Load the zones from `provider` around the obstacles as the geozones to
+/// plan with.
+async fn load_airspace(
+    ui_context: &mut UiContext,
+    provider: &dyn AirspaceProvider,
+    server_msg_tx_ch: &Sender<ServerMessage>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let obstacles = ui_context.maybe_obstacles.as_ref().ok_or(
+        "Obstacles not loaded yet. Please load the obstacles first.",
+    )?;
+    let mut bbox_polygon = obstacles
+        .bounding_rect()
+        .ok_or("Obstacles are empty, cannot determine geozone bounding box.")?
+        .to_polygon();
+    ui_context.internal_crs.to_ext(&mut bbox_polygon)?;
+    let bbox = bbox_polygon.bounding_rect().unwrap();
+    println!("Loading airspace from {}", provider.describe());
+    let geozones = provider.zones(bbox).await?;
+    let geozones_geometry = MultiPolygon::from_iter(
+        geozones.iter().flat_map(|geozone| geozone.geometry.0.iter().cloned()),
+    );
+    ui_context.maybe_geozones = Some(geozones);
+    ui_context.nav_graph_geozone_params = None;
+    ui_context.maybe_policy_rules = Some(load_policy_rules().await?);
+    // Geozones are already in the external CRS
+    let geozones_feature = Feature::from(geojson::Geometry::from(&geozones_geometry));
+    server_msg_tx_ch
+        .send(ServerMessage::Geozones(geozones_feature))
+        .await?;
+    Ok(())
+}
+
+/// Relative margin on top of the scale factor when converting a geodesic
+/// maximum edge length, for the scale between the sampled points
+const MAX_EDGE_LENGTH_MARGIN: f64 = 0.001;
+
+/// Nav graph edges are as long as in the internal CRS, flying them is as long
+/// as on the ellipsoid. The projected length that is at least
+/// `geodesic_max_edge_length` long on the ellipsoid anywhere around the
+/// obstacles, so pruning longer edges never drops one shorter than that on
+/// the ellipsoid.
+fn projected_max_edge_length(
+    ui_context: &UiContext,
+    geodesic_max_edge_length: f64,
+) -> Result<f64, Box<dyn Error + Send + Sync>> {
+    let area = ui_context.maybe_obstacles.as_ref()
+        .and_then(|obstacles| obstacles.bounding_rect())
+        .ok_or("Obstacles not loaded yet. Please load the obstacles first.")?;
+    let scale_factor = ui_context.internal_crs.max_scale_factor(area)?;
+    return Ok(geodesic_max_edge_length * scale_factor * (1.0 + MAX_EDGE_LENGTH_MARGIN));
+}
+
+/// Tiled nav graph of the obstacles, waters and no-fly areas with tiles of
+/// `tile_size`: the one kept in `ui_context` if it matches, a new one
+/// otherwise. No tiles are created yet for a new one.
+fn tiled_nav_graph(
+    ui_context: &mut UiContext,
+    visibility_optimization_mode: VisibilityOptimizationMode,
+    tile_size: f64,
+) -> Result<&mut TiledNavGraph, Box<dyn Error + Send + Sync>> {
+    let is_kept = ui_context.tiled_nav_graph.as_ref().map_or(false, |tiled_nav_graph| {
+        tiled_nav_graph.tile_size() == tile_size && tiled_nav_graph.optimization_mode() == visibility_optimization_mode
+    });
+    if !is_kept {
+        let features = features_for_geozone_params(ui_context, None)?;
+        let (mut obstacles, _) = simplify_containing(&features.obstacles, OBSTACLE_SIMPLIFICATION_TOLERANCE);
+        obstacles.0.extend(ui_context.no_fly_areas.iter().cloned());
+        ui_context.tiled_nav_graph = Some(TiledNavGraph::new(
+            Features { obstacles, ..features },
+            ui_context.internal_crs.clone(),
+            tile_size,
+            TILED_BORDER_NODE_SPACING,
+            visibility_optimization_mode,
+            None,
+            Some(open_nav_graph_cache(ui_context)?),
+        )?);
+    }
+    return Ok(ui_context.tiled_nav_graph.as_mut().unwrap());
+}
+
+/// Shortest path between `start_coord` and `end_coord` (external CRS) on a
+/// `TiledNavGraph` of the obstacles and waters with tiles of `tile_size`.
+/// Only the tiles the search reaches are created, and they are kept for later
+/// requests, so this works for areas too large for a single nav graph.
+fn calculate_tiled_shortest_path(
+    ui_context: &mut UiContext,
+    start_coord: Coordinate<f64>,
+    end_coord: Coordinate<f64>,
+    visibility_optimization_mode: VisibilityOptimizationMode,
+    tile_size: f64,
+) -> Result<Option<ShortestPath>, Box<dyn Error + Send + Sync>> {
+    let internal_crs = ui_context.internal_crs.clone();
+    let mut coords = [start_coord, end_coord];
+    internal_crs.to_int_coords(&mut coords)?;
+    let [start_coord, end_coord] = coords;
+    let tiled_nav_graph = tiled_nav_graph(ui_context, visibility_optimization_mode, tile_size)?;
+    let maybe_path = tiled_nav_graph.shortest_path_between_coords(start_coord, end_coord)?;
+    println!("Searched {} tiles", tiled_nav_graph.tile_count());
+    return Ok(maybe_path.map(|(total, path_geometry)| {
+        let path_feature = geometry_to_feature(path_geometry.into(), &internal_crs);
+        ShortestPath::new(path_feature, total.length)
+    }));
+}
+
+/// Make sure `ui_context.nav_graph` has the geozones relevant to a leg flown
+/// during `leg_window` (all geozones if unknown) and no edges left out,
+/// rebuilding it if needed. Planning searches the direct path to the end
+/// first, which can need edges longer than a leg, see `plan_leg`.
+async fn ensure_nav_graph_for_leg(
+    ui_context: &mut UiContext,
+    cruise_altitude: AMSLHeightMeters,
+    operator_profile: &OperatorProfile,
+    leg_window: Option<TimeWindow>,
+    visibility_optimization_mode: VisibilityOptimizationMode,
+    server_msg_tx_ch: &Sender<ServerMessage>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let geozone_params = ui_context.maybe_geozones.as_ref().map(|geozones| {
+        let active_geozones = geozones
+            .iter()
+            .enumerate()
+            .filter(|(_, geozone)| match leg_window {
+                Some(leg_window) => geozone.activation.is_active_during(leg_window),
+                None => true,
+            })
+            .map(|(index, _)| index)
+            .collect();
+        GeozoneParams {
+            cruise_altitude,
+            operator_profile: operator_profile.clone(),
+            active_geozones,
+        }
+    });
+    let has_all_edges = match &ui_context.nav_graph {
+        Some(nav_graph) => nav_graph.max_edge_length.is_none(),
+        // Without geozones the nav graph has to be loaded first
+        None => geozone_params.is_none(),
+    };
+    if ui_context.nav_graph_geozone_params == geozone_params && has_all_edges {
+        return Ok(());
+    }
+    let features = features_for_geozone_params(ui_context, geozone_params.as_ref())?;
+    send_nav_graph(
+        ui_context, features, geozone_params, visibility_optimization_mode, None, server_msg_tx_ch
+    ).await
+}
+
+/// Estimated time window of a leg of at most `leg_max_distance` starting at
+/// `leg_start_time`.
+fn estimate_leg_window(departure: &Departure, leg_start_time: UnixMillis, leg_max_distance: f64) -> TimeWindow {
+    let max_duration = (leg_max_distance / departure.cruise_speed * 1000.0).ceil() as UnixMillis;
+    TimeWindow::new(leg_start_time, leg_start_time + max_duration)
+}
+
 async fn handle_client_msg(
     message: ClientMessage,
     ui_context: &mut UiContext,
@@ -67,12 +430,21 @@ async fn handle_client_msg(
                     // let name = "perf-34";
                     let path = "data/iv-grb/sv-zaventem.gpkg";
                     let name = "sv-zaventem";
-                    let obstacles = load_gpkg_multi_polygon(path, name).await?;
+                    let (obstacles, crs) = load_file_multi_polygon(path, Some(name), None).await?;
+                    println!("Planning in {}", crs.name());
+                    // Waters loaded before are in the previous CRS
+                    if let Some(waters) = &mut ui_context.maybe_waters && crs != ui_context.internal_crs {
+                        with_proj(&ui_context.internal_crs.definition(), &crs.definition(), |proj| {
+                            waters.transform(proj)
+                        })??;
+                    }
+                    ui_context.internal_crs = crs;
                     ui_context.maybe_obstacles = Some(obstacles.clone());
+                    ui_context.tiled_nav_graph = None;
                     obstacles
                 }
             };
-            let obstacles_feature = multi_polygon_to_feature(obstacles);
+            let obstacles_feature = multi_polygon_to_feature(obstacles, &ui_context.internal_crs);
             server_msg_tx_ch
                 .send(ServerMessage::Obstacles(obstacles_feature))
                 .await?;
@@ -97,8 +469,10 @@ async fn handle_client_msg(
                     // let name = "pe";
                     let path = "data/osm-water/sv-zaventem.gpkg";
                     let name = "sv-zaventem";
-                    let waters = load_gpkg_multi_polygon(path, name).await?;
+                    let (waters, _) =
+                        load_file_multi_polygon(path, Some(name), Some(&ui_context.internal_crs)).await?;
                     ui_context.maybe_waters = Some(waters.clone());
+                    ui_context.tiled_nav_graph = None;
                     waters
                 }
             };
@@ -112,7 +486,7 @@ async fn handle_client_msg(
             //     // } else { None }
             // });
             // let pois_feature = feature_from_points(pois);
-            let waters_feature = multi_polygon_to_feature(waters);
+            let waters_feature = multi_polygon_to_feature(waters, &ui_context.internal_crs);
             server_msg_tx_ch
                 .send(ServerMessage::Waters(waters_feature))
                 .await?;
@@ -123,41 +497,41 @@ async fn handle_client_msg(
             //     }))
             //     .await?;
         }
+        ClientMessage::LoadGeozones => {
+            let provider = DroneguideProvider::new(DroneguideUrls::default())
+                .with_cache(GeozoneCache::new(GEOZONE_CACHE_PATH, GEOZONE_CACHE_TTL));
+            load_airspace(ui_context, &provider, &server_msg_tx_ch).await?;
+        }
         ClientMessage::LoadRestrictedAirspace => {
-            let path = "data/droneguide/restricted-airspace.gpkg";
-            let name = "restricted-airspace";
-            let restricted_airspace = load_gpkg_multi_polygon(path, name).await?;
-            let restricted_airspace_feature = multi_polygon_to_feature(restricted_airspace);
-            server_msg_tx_ch
-                .send(ServerMessage::RestrictedAirspace(restricted_airspace_feature))
-                .await?;
+            let provider = GpkgProvider::new(RESTRICTED_AIRSPACE_PATH, Some(RESTRICTED_AIRSPACE_LAYER.to_owned()));
+            load_airspace(ui_context, &provider, &server_msg_tx_ch).await?;
         }
-        ClientMessage::VisibilityGraph { visibility_optimization_mode } => {
-            let obstacles = ui_context.maybe_obstacles.as_ref().ok_or(
-                "Obstacles loaded yet. Please load the obstacles first.",
+        ClientMessage::LoadAirspaceFile { path } => {
+            let provider = provider_for_path(file_in_directory(AIRSPACE_FILE_DIR, &path)?)?;
+            load_airspace(ui_context, provider.as_ref(), &server_msg_tx_ch).await?;
+        }
+        ClientMessage::VisibilityGraph { visibility_optimization_mode, max_edge_length } => {
+            let features = features_for_geozone_params(ui_context, None)?;
+            let max_edge_length = max_edge_length
+                .map(|max_edge_length| projected_max_edge_length(ui_context, max_edge_length))
+                .transpose()?;
+            send_nav_graph(
+                ui_context, features, None, visibility_optimization_mode, max_edge_length, &server_msg_tx_ch
+            ).await?;
+        }
+        ClientMessage::CalcPath {
+            start: start_lat_lng, end: end_lat_lng, visibility_optimization_mode, tile_size: Some(tile_size)
+        } => {
+            let shortest_path = calculate_tiled_shortest_path(
+                ui_context, start_lat_lng.into(), end_lat_lng.into(), visibility_optimization_mode, tile_size
             )?;
-            let waters_default = &EMPTY_MULTI_POLYGON;
-            let waters = ui_context.maybe_waters.as_ref().unwrap_or(waters_default);
-            let features = Features {
-                obstacles: obstacles.clone(),
-                waters: waters.clone(),
-                arbitrary: Vec::new(),
-            };
-
-            let dgc = create_dgc(server_msg_tx_ch.clone());
-
-            // for _ in 0..9 {
-            //     create_nav_graph(&features, Some(dgc.clone()), visibility_optimization_mode);
-            // }
-
-            let (nav_graph, duration) = create_nav_graph(&features, Some(dgc), visibility_optimization_mode);
-            let graph_feature_collection = nav_graph_to_feature_collection(&nav_graph);
-            ui_context.nav_graph = Some(nav_graph);
             server_msg_tx_ch
-                .send(ServerMessage::NavGraph(NavGraphLoaded::new(graph_feature_collection, duration.as_millis())))
-                .await?;
+                .send(ServerMessage::ShortestPathCalculated(shortest_path))
+                .await?
         }
-        ClientMessage::CalcPath { start: start_lat_lng, end: end_lat_lng, visibility_optimization_mode } => {
+        ClientMessage::CalcPath {
+            start: start_lat_lng, end: end_lat_lng, visibility_optimization_mode, tile_size: None
+        } => {
             let nav_graph = ui_context.nav_graph.as_mut().ok_or(
                 "Nav graph not loaded yet. Please load the nav graph first.",
             )?;
@@ -181,7 +555,7 @@ async fn handle_client_msg(
                         })
                         .collect::<Vec<_>>(),
                 );
-                let path_feature = geometry_to_feature(path_geometry.into());
+                let path_feature = geometry_to_feature(path_geometry.into(), &nav_graph.crs);
                 Some(ShortestPath::new(path_feature, distance))
             } else {
                 None
@@ -190,56 +564,143 @@ async fn handle_client_msg(
                 .send(ServerMessage::ShortestPathCalculated(shortest_path))
                 .await?
         }
+        ClientMessage::AddNoFlyArea { area, visibility_optimization_mode } => {
+            let nav_graph = ui_context.nav_graph.as_mut().ok_or(
+                "Nav graph not loaded yet. Please load the nav graph first.",
+            )?;
+            if area.len() < 3 {
+                return Err("A no-fly area needs at least 3 corners.".into());
+            }
+            let mut coords = area.into_iter().map(Into::into).collect::<Vec<Coordinate<f64>>>();
+            nav_graph.crs.to_int_coords(&mut coords)?;
+            let no_fly_area = no_fly_area_polygon(coords);
+            ui_context.no_fly_areas.push(no_fly_area.clone());
+            ui_context.tiled_nav_graph = None;
+            // Updating in place only gives the graph creating it would give
+            // for the other modes
+            if visibility_optimization_mode == VisibilityOptimizationMode::OptimizedSweep {
+                recreate_nav_graph(ui_context, visibility_optimization_mode, &server_msg_tx_ch).await?;
+                return Ok(());
+            }
+            let start_time = Instant::now();
+            let obstacle_index = insert_obstacle_into_nav_graph(no_fly_area, nav_graph, visibility_optimization_mode);
+            let duration = start_time.elapsed();
+            println!("Added no-fly area as obstacle {} in {:?}", obstacle_index, duration);
+            send_updated_nav_graph(nav_graph, duration, &server_msg_tx_ch).await?;
+        }
+        ClientMessage::RemoveNoFlyArea { visibility_optimization_mode } => {
+            let nav_graph = ui_context.nav_graph.as_mut().ok_or(
+                "Nav graph not loaded yet. Please load the nav graph first.",
+            )?;
+            ui_context.no_fly_areas.pop().ok_or("No no-fly areas added to the nav graph.")?;
+            ui_context.tiled_nav_graph = None;
+            if visibility_optimization_mode == VisibilityOptimizationMode::OptimizedSweep {
+                recreate_nav_graph(ui_context, visibility_optimization_mode, &server_msg_tx_ch).await?;
+                return Ok(());
+            }
+            // The last no-fly area is the last obstacle
+            let obstacle_index = nav_graph.features.obstacles.0.len() - 1;
+            let start_time = Instant::now();
+            remove_obstacle_from_nav_graph(obstacle_index, nav_graph, visibility_optimization_mode);
+            let duration = start_time.elapsed();
+            println!("Removed no-fly area obstacle {} in {:?}", obstacle_index, duration);
+            send_updated_nav_graph(nav_graph, duration, &server_msg_tx_ch).await?;
+        }
         ClientMessage::Plan(PlanClientMsg {
             start: start_lat_lng, end: end_lat_lng,
             max_distance_initially, max_distance_after_charge,
-            visibility_optimization_mode
+            visibility_optimization_mode, cruise_altitude, operator_profile, departure
         }) => {
-            let nav_graph = ui_context.nav_graph.as_mut().ok_or(
-                "Nav graph not loaded yet. Please load the nav graph first.",
-            )?;
+            let dgc = create_dgc(server_msg_tx_ch.clone(), ui_context.internal_crs.clone());
 
-            let dgc = create_dgc(server_msg_tx_ch.clone());
+            let mut coords: [Coordinate<f64>; 2] = [start_lat_lng.into(), end_lat_lng.into()];
+            ui_context.internal_crs.to_int_coords(&mut coords)?;
+            let [start_coord, end_coord] = coords;
 
-            // Projection needs to be in a separate scope because `proj::Proj`
-            // is `!Send`.
-            let (start_coord, end_coord) = {
-                let proj = create_to_int_proj();
-                (
-                    proj.project(start_lat_lng.into(), false)?,
-                    proj.project(end_lat_lng.into(), false)?,
-                )
-            };
-            let (_, start_index) = add_coord_to_nav_graph(start_coord, nav_graph, None, visibility_optimization_mode);
-            let (_, end_index) = add_coord_to_nav_graph(end_coord, nav_graph, None, visibility_optimization_mode);
-            let planner_legs = plan_path_or_recharge(
-                &nav_graph,
-                max_distance_initially,
-                max_distance_after_charge,
-                start_index,
-                end_index,
-                Some(dgc)
-            )?;
-            let planner_legs_geometries = planner_legs
-                .iter()
-                .map(|(last_reachable_coord, leg_path)| {
-                    let leg_path_geometry = LineString(
-                        leg_path
-                            .iter()
-                            .map(|(node_index, _)| {
-                                let node_data = &nav_graph.graph.node_weight(*node_index).unwrap();
-                                nav_graph.features.coord(node_data)
-                            })
-                            .collect::<Vec<_>>(),
-                    );
-                    [
-                        geometry_to_feature(Point(*last_reachable_coord).into()),
-                        geometry_to_feature(leg_path_geometry.into())
-                    ]
-                })
-                .collect::<Vec<_>>();
+            // Legs are planned one by one: which geozones are active depends
+            // on when the leg is flown, so each leg may need a different
+            // graph.
+            let mut leg_start_coord = start_coord;
+            let mut leg_start_time = departure.as_ref().map(|departure| departure.time);
+            let mut leg_max_distance = max_distance_initially;
+            let mut planner_legs_geometries = Vec::new();
+            let mut planned_legs = Vec::new();
+            loop {
+                let leg_window = departure.as_ref().zip(leg_start_time).map(|(departure, leg_start_time)| {
+                    estimate_leg_window(departure, leg_start_time, leg_max_distance)
+                });
+                ensure_nav_graph_for_leg(
+                    ui_context, cruise_altitude, &operator_profile, leg_window,
+                    visibility_optimization_mode, &server_msg_tx_ch
+                ).await?;
+                let nav_graph = ui_context.nav_graph.as_mut().ok_or(
+                    "Nav graph not loaded yet. Please load the nav graph first.",
+                )?;
+
+                let (_, leg_start_index) = add_coord_to_nav_graph(leg_start_coord, nav_graph, None, visibility_optimization_mode);
+                let (_, end_index) = add_coord_to_nav_graph(end_coord, nav_graph, None, visibility_optimization_mode);
+                // Only the first leg's direct path is drawn for debugging
+                let leg_dgc = if planner_legs_geometries.is_empty() { dgc.clone() } else { None };
+                let leg_plan = plan_leg(nav_graph, leg_max_distance, leg_start_index, end_index, leg_dgc)?;
+                let planned_leg = PlannedLeg::new(nav_graph, &leg_plan);
+                let (last_reachable_coord, maybe_recharge_point) = match leg_plan {
+                    LegPlan::ToEnd(_) => (end_coord, None),
+                    LegPlan::ToRecharge { last_reachable_coord, recharge_point, .. } => {
+                        (last_reachable_coord, Some(recharge_point))
+                    }
+                };
+
+                planner_legs_geometries.push([
+                    geometry_to_feature(Point(last_reachable_coord).into(), &nav_graph.crs),
+                    geometry_to_feature(planned_leg.path.clone().into(), &nav_graph.crs)
+                ]);
+                println!(
+                    "Leg {}: {:.1} m projected, {:.1} m geodesic ({:+.3}% distortion)",
+                    planned_legs.len(), planned_leg.length, planned_leg.geodesic_length,
+                    planned_leg.distortion() * 100.0
+                );
+                let leg_length = planned_leg.geodesic_length;
+                planned_legs.push(planned_leg);
+
+                let recharge_point = match maybe_recharge_point {
+                    Some(recharge_point) => recharge_point,
+                    None => break,
+                };
+                let recharge_coord = nav_graph.features.coord(nav_graph.graph.node_weight(recharge_point).unwrap());
+                if recharge_coord == leg_start_coord {
+                    println!("Loop");
+                    break;
+                }
+                leg_start_time = departure.as_ref().zip(leg_start_time).map(|(departure, leg_start_time)| {
+                    let flight_duration = leg_length / departure.cruise_speed;
+                    leg_start_time + ((flight_duration + departure.recharge_duration) * 1000.0).round() as UnixMillis
+                });
+                leg_start_coord = recharge_coord;
+                leg_max_distance = max_distance_after_charge;
+            }
+            ui_context.last_plan = Some(planned_legs);
             server_msg_tx_ch.send(ServerMessage::PlannerPathCalculated(planner_legs_geometries)).await?;
         }
+        ClientMessage::ExportGpkg { path } => {
+            let path = file_in_directory(GPKG_EXPORT_DIR, &path)?;
+            let path = path.as_path();
+            let obstacles = ui_context.maybe_obstacles.as_ref().ok_or(
+                "Obstacles not loaded yet. Please load the obstacles first.",
+            )?;
+            tokio::fs::create_dir_all(GPKG_EXPORT_DIR).await?;
+            write_gpkg_multi_polygon(path, "obstacles", obstacles, &ui_context.internal_crs).await?;
+            if let Some(nav_graph) = &ui_context.nav_graph {
+                // Export the simplified obstacles the graph was created for
+                // as well
+                write_gpkg_multi_polygon(path, "nav-graph-obstacles", &nav_graph.features.obstacles, &nav_graph.crs).await?;
+                write_gpkg_nav_graph(path, "nav-graph", nav_graph).await?;
+                if let Some(last_plan) = &ui_context.last_plan {
+                    // Planned on the nav graph, in its CRS
+                    write_gpkg_plan(path, "plan", last_plan, &nav_graph.crs).await?;
+                }
+            }
+            println!("Exported to {:?}", path);
+        }
     }
     Ok(())
 }
@@ -309,3 +770,210 @@ pub async fn handle_tcp_stream(stream: TcpStream) -> Result<(), Box<dyn Error>>
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use std::path::Path;
+
+    use geo::{winding_order::Winding, MultiPolygon, Point};
+    use serde_json::{json, Value};
+    use tokio::sync::mpsc;
+
+    use crate::{
+        crs::InternalCrs,
+        server::server_msg::ServerMessage,
+        test_util::{local, local_square, temp_path},
+    };
+
+    use super::{file_in_directory, handle_client_msg, UiContext};
+
+    /// Handle the client message `message` (as JSON), returning the messages
+    /// sent back
+    async fn handle_json_msg(message: Value, ui_context: &mut UiContext) -> Vec<ServerMessage> {
+        let (server_msg_tx_ch, mut server_msg_rx_ch) = mpsc::channel(10);
+        let received = tokio::spawn(async move {
+            let mut server_msgs = Vec::new();
+            while let Some(server_msg) = server_msg_rx_ch.recv().await {
+                server_msgs.push(server_msg);
+            }
+            server_msgs
+        });
+        handle_client_msg(serde_json::from_value(message).unwrap(), ui_context, server_msg_tx_ch).await.unwrap();
+        received.await.unwrap()
+    }
+
+    /// `x` and `y` on the local grid as a Leaflet `LatLng`
+    fn local_lat_lng(x: f64, y: f64) -> Value {
+        let mut point = Point(local(x, y));
+        InternalCrs::EtrsLaea.to_ext(&mut point).unwrap();
+        json!({ "lat": point.y(), "lng": point.x() })
+    }
+
+    #[test]
+    fn file_in_directory__plain_file_name() {
+        assert_eq!(
+            file_in_directory("data/exports", "plan.gpkg").unwrap(),
+            Path::new("data/exports/plan.gpkg")
+        );
+    }
+
+    #[test]
+    fn file_in_directory__rejects_paths() {
+        for file_name in ["", ".", "..", "../plan.gpkg", "sub/plan.gpkg", "/etc/passwd", "sub\\plan.gpkg"] {
+            assert!(file_in_directory("data/exports", file_name).is_err(), "{:?} accepted", file_name);
+        }
+    }
+
+    #[tokio::test]
+    async fn plan__legs_across_open_space_after_pruned_nav_graph() {
+        // Only a lake to recharge at between start and end, 1600 m apart
+        let mut ui_context = UiContext {
+            maybe_obstacles: Some(MultiPolygon(vec![local_square(0.0, 500.0, 20.0)])),
+            maybe_waters: Some(MultiPolygon(vec![local_square(800.0, -20.0, 40.0)])),
+            nav_graph_cache_dir: Some(temp_path("plan-open-space-cache")),
+            ..Default::default()
+        };
+        // Edges longer than 500 m left out of the view, shorter than a leg
+        handle_json_msg(
+            json!({ "type": "visibility-graph", "data": { "visibilityOptimizationMode": "Sweep", "maxEdgeLength": 500.0 } }),
+            &mut ui_context,
+        ).await;
+        assert!(ui_context.nav_graph.as_ref().unwrap().max_edge_length.is_some());
+
+        let server_msgs = handle_json_msg(
+            json!({ "type": "plan", "data": {
+                "start": local_lat_lng(0.0, 0.0),
+                "end": local_lat_lng(1600.0, 0.0),
+                "maxDistanceInitially": 1000.0,
+                "maxDistanceAfterCharge": 1000.0,
+                "visibilityOptimizationMode": "Sweep",
+                "cruiseAltitude": 40,
+            } }),
+            &mut ui_context,
+        ).await;
+
+        let leg_count = server_msgs.iter().find_map(|server_msg| match server_msg {
+            ServerMessage::PlannerPathCalculated(legs) => Some(legs.len()),
+            _ => None,
+        });
+        assert_eq!(leg_count, Some(2));
+        assert_eq!(ui_context.nav_graph.as_ref().unwrap().max_edge_length, None);
+        let recharge_point = ui_context.last_plan.as_ref().unwrap()[0].recharge_point.unwrap();
+        assert!(recharge_point.x >= local(800.0, 0.0).x && recharge_point.x <= local(840.0, 0.0).x);
+    }
+
+    /// Corners of a 40 by 100 m no-fly area across the line from the origin
+    /// of the local grid to 400 m east, clockwise
+    fn no_fly_area_corners() -> Vec<(f64, f64)> {
+        vec![(180.0, -50.0), (180.0, 50.0), (220.0, 50.0), (220.0, -50.0)]
+    }
+
+    fn add_no_fly_area_msg(visibility_optimization_mode: &str) -> Value {
+        let area = no_fly_area_corners().into_iter().map(|(x, y)| local_lat_lng(x, y)).collect::<Vec<_>>();
+        json!({ "type": "add-no-fly-area", "data": {
+            "area": area,
+            "visibilityOptimizationMode": visibility_optimization_mode,
+        } })
+    }
+
+    fn visibility_graph_msg(visibility_optimization_mode: &str, max_edge_length: Option<f64>) -> Value {
+        json!({ "type": "visibility-graph", "data": {
+            "visibilityOptimizationMode": visibility_optimization_mode,
+            "maxEdgeLength": max_edge_length,
+        } })
+    }
+
+    #[tokio::test]
+    async fn add_no_fly_area__clockwise_corners() {
+        let mut ui_context = UiContext {
+            maybe_obstacles: Some(MultiPolygon(vec![local_square(0.0, 500.0, 20.0)])),
+            nav_graph_cache_dir: Some(temp_path("no-fly-area-clockwise-cache")),
+            ..Default::default()
+        };
+        handle_json_msg(visibility_graph_msg("Bitangent", None), &mut ui_context).await;
+        handle_json_msg(add_no_fly_area_msg("Bitangent"), &mut ui_context).await;
+
+        let nav_graph = ui_context.nav_graph.as_ref().unwrap();
+        let no_fly_area = nav_graph.features.obstacles.0.last().unwrap();
+        assert!(no_fly_area.exterior().is_ccw());
+        // Convex, so every corner is a bitangent graph vertex
+        for (x, y) in no_fly_area_corners() {
+            let corner = local(x, y);
+            assert!(
+                nav_graph.graph.node_weights().any(|node_data| {
+                    let coord = nav_graph.features.coord(node_data);
+                    (coord.x - corner.x).abs() < 1e-3 && (coord.y - corner.y).abs() < 1e-3
+                }),
+                "corner {:?} missing", (x, y)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn add_no_fly_area__kept_when_recreated() {
+        let mut ui_context = UiContext {
+            maybe_obstacles: Some(MultiPolygon(vec![local_square(0.0, 500.0, 20.0)])),
+            nav_graph_cache_dir: Some(temp_path("no-fly-area-recreated-cache")),
+            ..Default::default()
+        };
+        // Planning recreates a pruned nav graph
+        handle_json_msg(visibility_graph_msg("Sweep", Some(300.0)), &mut ui_context).await;
+        handle_json_msg(add_no_fly_area_msg("Sweep"), &mut ui_context).await;
+        handle_json_msg(
+            json!({ "type": "plan", "data": {
+                "start": local_lat_lng(0.0, 0.0),
+                "end": local_lat_lng(400.0, 0.0),
+                "maxDistanceInitially": 10000.0,
+                "maxDistanceAfterCharge": 10000.0,
+                "visibilityOptimizationMode": "Sweep",
+                "cruiseAltitude": 40,
+            } }),
+            &mut ui_context,
+        ).await;
+
+        let nav_graph = ui_context.nav_graph.as_ref().unwrap();
+        assert_eq!(nav_graph.max_edge_length, None);
+        assert_eq!(nav_graph.features.obstacles.0.len(), 2);
+        assert_eq!(nav_graph.features.obstacles.0.last(), ui_context.no_fly_areas.last());
+        let plan = ui_context.last_plan.as_ref().unwrap();
+        // Around the area instead of straight through it
+        assert!(plan[0].length > 400.0);
+        assert!(plan[0].path.0.iter().any(|coord| (coord.y - local(0.0, 0.0).y).abs() > 50.0 - 1e-6));
+
+        // Created again without it after removing it
+        handle_json_msg(
+            json!({ "type": "remove-no-fly-area", "data": { "visibilityOptimizationMode": "OptimizedSweep" } }),
+            &mut ui_context,
+        ).await;
+        assert!(ui_context.no_fly_areas.is_empty());
+        assert_eq!(ui_context.nav_graph.as_ref().unwrap().features.obstacles.0.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn calc_path__keeps_tiled_nav_graph() {
+        let mut ui_context = UiContext {
+            maybe_obstacles: Some(MultiPolygon(vec![local_square(0.0, 0.0, 20.0), local_square(300.0, 300.0, 20.0)])),
+            nav_graph_cache_dir: Some(temp_path("calc-path-tiled-cache")),
+            ..Default::default()
+        };
+        let calc_path_msg = |tile_size: f64| json!({ "type": "calc-path", "data": {
+            "start": local_lat_lng(30.0, 30.0),
+            "end": local_lat_lng(290.0, 290.0),
+            "visibilityOptimizationMode": "Sweep",
+            "tileSize": tile_size,
+        } });
+
+        handle_json_msg(calc_path_msg(100.0), &mut ui_context).await;
+        let tile_count = ui_context.tiled_nav_graph.as_ref().unwrap().tile_count();
+        assert!(tile_count > 0);
+        // The tiles of the first search are reused
+        let server_msgs = handle_json_msg(calc_path_msg(100.0), &mut ui_context).await;
+        assert!(server_msgs.iter().any(|server_msg| matches!(server_msg, ServerMessage::ShortestPathCalculated(Some(_)))));
+        assert_eq!(ui_context.tiled_nav_graph.as_ref().unwrap().tile_count(), tile_count);
+
+        handle_json_msg(calc_path_msg(200.0), &mut ui_context).await;
+        assert_eq!(ui_context.tiled_nav_graph.as_ref().unwrap().tile_size(), 200.0);
+    }
+}
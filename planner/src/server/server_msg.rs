@@ -27,7 +27,7 @@ pub struct NavGraphLoaded {
 pub enum ServerMessage {
     Obstacles(Feature),
     Waters(Feature),
-    RestrictedAirspace(Feature),
+    Geozones(Feature),
     NavGraph(NavGraphLoaded),
     DebugGeometries(Feature),
     ShortestPathCalculated(Option<ShortestPath>),
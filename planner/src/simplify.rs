@@ -0,0 +1,323 @@
+//! Obstacle simplification that never shrinks the obstacles
+//!
+//! Visibility graph creation time grows with the number of obstacle vertices.
+//! Buffered building outlines have many (near-)collinear vertices that can be
+//! dropped. Unlike Douglas-Peucker, the simplified obstacles are guaranteed to
+//! contain the original ones, so no edge that was blocked becomes visible.
+//!
+//! With SFA winding the polygon lies to the left of every ring (outer rings
+//! CCW, inner rings CW). Dropping a vertex where its ring turns right (or goes
+//! straight) only adds the triangle between the vertex and its neighbours to
+//! the polygon. As long as no other vertex lies in that triangle, no other
+//! edge can cross it either (it would have to cross the new edge twice), so
+//! the rings stay simple and disjoint.
+
+use geo::{Coordinate, LineString, MultiPolygon, Polygon};
+
+use crate::winding::ensure_sfa_winding;
+
+/// Vertex counts before and after `simplify_containing`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SimplificationReport {
+    pub vertices_before: usize,
+    pub vertices_after: usize,
+}
+
+impl SimplificationReport {
+    pub fn vertices_removed(&self) -> usize {
+        self.vertices_before - self.vertices_after
+    }
+}
+
+/// All ring vertices sorted by x, to find the vertices in a triangle
+struct VertexIndex {
+    /// (coordinate, ring index, vertex index in ring)
+    vertices: Vec<(Coordinate<f64>, usize, usize)>,
+}
+
+impl VertexIndex {
+    fn new(rings: &[Vec<Coordinate<f64>>]) -> Self {
+        let mut vertices = rings
+            .iter()
+            .enumerate()
+            .flat_map(|(ring_index, ring)| {
+                ring.iter()
+                    .enumerate()
+                    .map(move |(vertex_index, coord)| (*coord, ring_index, vertex_index))
+            })
+            .collect::<Vec<_>>();
+        vertices.sort_by(|(a, ..), (b, ..)| a.x.total_cmp(&b.x));
+        VertexIndex { vertices }
+    }
+
+    /// Whether any vertex other than `own` (ring index, vertex indices) lies
+    /// in or on the triangle `a`, `b`, `c`. Vertices that were already
+    /// dropped still count, which is conservative.
+    fn any_in_triangle(
+        &self,
+        a: Coordinate<f64>,
+        b: Coordinate<f64>,
+        c: Coordinate<f64>,
+        own_ring: usize,
+        own_vertices: &[usize],
+    ) -> bool {
+        let min_x = a.x.min(b.x).min(c.x);
+        let max_x = a.x.max(b.x).max(c.x);
+        let min_y = a.y.min(b.y).min(c.y);
+        let max_y = a.y.max(b.y).max(c.y);
+        let start = self.vertices.partition_point(|(coord, ..)| coord.x < min_x);
+        self.vertices[start..]
+            .iter()
+            .take_while(|(coord, ..)| coord.x <= max_x)
+            .filter(|(coord, ..)| min_y <= coord.y && coord.y <= max_y)
+            .filter(|(_, ring_index, vertex_index)| {
+                !(*ring_index == own_ring && own_vertices.contains(vertex_index))
+            })
+            .any(|(coord, ..)| in_triangle(*coord, a, b, c))
+    }
+}
+
+fn cross(o: Coordinate<f64>, a: Coordinate<f64>, b: Coordinate<f64>) -> f64 {
+    (a.x - o.x) * (b.y - o.y) - (a.y - o.y) * (b.x - o.x)
+}
+
+/// Whether `p` lies in or on triangle `a`, `b`, `c` (any orientation). For a
+/// degenerate triangle, whether `p` lies on the line through it.
+fn in_triangle(p: Coordinate<f64>, a: Coordinate<f64>, b: Coordinate<f64>, c: Coordinate<f64>) -> bool {
+    let d1 = cross(a, b, p);
+    let d2 = cross(b, c, p);
+    let d3 = cross(c, a, p);
+    let has_negative = d1 < 0. || d2 < 0. || d3 < 0.;
+    let has_positive = d1 > 0. || d2 > 0. || d3 > 0.;
+    return !(has_negative && has_positive);
+}
+
+/// Distance from `p` to the segment `a`-`b`
+fn segment_distance(p: Coordinate<f64>, a: Coordinate<f64>, b: Coordinate<f64>) -> f64 {
+    let ab = b - a;
+    let length_squared = ab.x * ab.x + ab.y * ab.y;
+    let t = if length_squared == 0. {
+        0.
+    } else {
+        (((p - a).x * ab.x + (p - a).y * ab.y) / length_squared).clamp(0., 1.)
+    };
+    let closest = a + ab * t;
+    return (p - closest).x.hypot((p - closest).y);
+}
+
+/// Drop vertices of `rings[ring_index]` (open, SFA winding) where the ring
+/// does not turn left, as long as every dropped vertex stays within
+/// `tolerance` of the edge replacing it. The first vertex is always kept.
+fn simplify_ring(
+    rings: &[Vec<Coordinate<f64>>],
+    ring_index: usize,
+    vertex_index: &VertexIndex,
+    tolerance: f64,
+) -> Vec<Coordinate<f64>> {
+    let ring = &rings[ring_index];
+    // Kept vertices, by index into `ring`
+    let mut kept: Vec<usize> = vec![0];
+    // Vertices dropped since the last kept one
+    let mut dropped: Vec<usize> = Vec::new();
+    for i in 1..ring.len() {
+        let a = *kept.last().unwrap();
+        let v = i;
+        let c = (i + 1) % ring.len();
+        // A ring needs at least three vertices
+        let remaining = kept.len() + (ring.len() - i);
+        // The vertices dropped since `a` lie on the boundary of the area
+        // already added, not inside the triangle
+        let own_vertices = [a, v, c].iter().chain(dropped.iter()).copied().collect::<Vec<_>>();
+        let is_droppable = remaining > 3
+            && cross(ring[a], ring[v], ring[c]) <= 0.
+            && dropped
+                .iter()
+                .chain(std::iter::once(&v))
+                .all(|&d| segment_distance(ring[d], ring[a], ring[c]) <= tolerance)
+            && !vertex_index.any_in_triangle(ring[a], ring[v], ring[c], ring_index, &own_vertices);
+        if is_droppable {
+            dropped.push(v);
+        } else {
+            kept.push(v);
+            dropped.clear();
+        }
+    }
+    return kept.into_iter().map(|i| ring[i]).collect();
+}
+
+/// Open ring (without the closing coordinate)
+fn open_ring(line_string: &LineString<f64>) -> Vec<Coordinate<f64>> {
+    let mut coords = line_string.0.clone();
+    if coords.len() > 1 && coords.first() == coords.last() {
+        coords.pop();
+    }
+    return coords;
+}
+
+fn closed_line_string(mut coords: Vec<Coordinate<f64>>) -> LineString<f64> {
+    if let Some(first) = coords.first().cloned() {
+        coords.push(first);
+    }
+    return LineString(coords);
+}
+
+/// Simplify `multi_polygon` such that the result contains it and every
+/// original boundary point lies within `tolerance` of the simplified boundary.
+/// Rings of the result have SFA winding. Only vertices where a ring bends
+/// outward from the polygon (or runs straight) are candidates, see the module
+/// documentation.
+pub fn simplify_containing(
+    multi_polygon: &MultiPolygon<f64>,
+    tolerance: f64,
+) -> (MultiPolygon<f64>, SimplificationReport) {
+    let mut multi_polygon = multi_polygon.clone();
+    ensure_sfa_winding(&mut multi_polygon);
+
+    // Exterior and interiors of every polygon, flattened
+    let mut rings = Vec::new();
+    let mut ring_counts = Vec::new();
+    for polygon in &multi_polygon {
+        rings.push(open_ring(polygon.exterior()));
+        rings.extend(polygon.interiors().iter().map(open_ring));
+        ring_counts.push(1 + polygon.interiors().len());
+    }
+    let vertices_before = rings.iter().map(Vec::len).sum();
+
+    let vertex_index = VertexIndex::new(&rings);
+    let simplified_rings = (0..rings.len())
+        .map(|ring_index| {
+            if rings[ring_index].len() <= 3 {
+                return rings[ring_index].clone();
+            }
+            simplify_ring(&rings, ring_index, &vertex_index, tolerance)
+        })
+        .collect::<Vec<_>>();
+    let vertices_after = simplified_rings.iter().map(Vec::len).sum();
+
+    let mut simplified_rings = simplified_rings.into_iter();
+    let polygons = ring_counts
+        .into_iter()
+        .map(|ring_count| {
+            let exterior = closed_line_string(simplified_rings.next().unwrap());
+            let interiors = (1..ring_count)
+                .map(|_| closed_line_string(simplified_rings.next().unwrap()))
+                .collect();
+            Polygon::new(exterior, interiors)
+        })
+        .collect::<Vec<_>>();
+
+    return (
+        MultiPolygon(polygons),
+        SimplificationReport { vertices_before, vertices_after },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use geo::{coord, line_string, polygon, winding_order::Winding, MultiPolygon, Polygon};
+    use geos::Geom;
+
+    use super::simplify_containing;
+
+    fn assert_contains(simplified: &MultiPolygon<f64>, original: &MultiPolygon<f64>) {
+        let simplified: geos::Geometry = simplified.try_into().unwrap();
+        let original: geos::Geometry = original.try_into().unwrap();
+        assert!(simplified.is_valid());
+        assert!(original.difference(&simplified).unwrap().area().unwrap() < 1e-9);
+    }
+
+    #[test]
+    fn simplify_containing__collinear() {
+        let original = MultiPolygon(vec![polygon![
+            (x: 0., y: 0.), (x: 5., y: 0.), (x: 10., y: 0.),
+            (x: 10., y: 10.), (x: 0., y: 10.), (x: 0., y: 5.),
+        ]]);
+        let (simplified, report) = simplify_containing(&original, 0.1);
+        assert_eq!(report.vertices_before, 6);
+        assert_eq!(report.vertices_removed(), 2);
+        assert_contains(&simplified, &original);
+    }
+
+    #[test]
+    fn simplify_containing__collinear_run() {
+        let original = MultiPolygon(vec![polygon![
+            (x: 0., y: 0.), (x: 2., y: 0.), (x: 4., y: 0.), (x: 6., y: 0.), (x: 8., y: 0.), (x: 10., y: 0.),
+            (x: 10., y: 10.), (x: 0., y: 10.),
+        ]]);
+        let (simplified, report) = simplify_containing(&original, 0.1);
+        assert_eq!(report.vertices_after, 4);
+        assert_contains(&simplified, &original);
+    }
+
+    #[test]
+    fn simplify_containing__keeps_convex_vertices() {
+        // Slightly convex bump on the bottom edge: dropping it would shrink
+        // the polygon
+        let original = MultiPolygon(vec![polygon![
+            (x: 0., y: 0.), (x: 5., y: -0.1), (x: 10., y: 0.),
+            (x: 10., y: 10.), (x: 0., y: 10.),
+        ]]);
+        let (simplified, report) = simplify_containing(&original, 1.);
+        assert_eq!(report.vertices_removed(), 0);
+        assert_eq!(simplified, original);
+    }
+
+    #[test]
+    fn simplify_containing__fills_notches_within_tolerance() {
+        let original = MultiPolygon(vec![polygon![
+            (x: 0., y: 0.), (x: 5., y: 0.5), (x: 10., y: 0.),
+            (x: 10., y: 10.), (x: 5., y: 7.), (x: 0., y: 10.),
+        ]]);
+        let (simplified, report) = simplify_containing(&original, 1.);
+        // Only the shallow notch is within tolerance
+        assert_eq!(report.vertices_removed(), 1);
+        assert_contains(&simplified, &original);
+    }
+
+    #[test]
+    fn simplify_containing__tolerance_over_dropped_run() {
+        // (9, 0.3) is close to the bottom edge, but dropping it too would put
+        // the already dropped (2, 1.05) out of tolerance
+        let original = MultiPolygon(vec![polygon![
+            (x: 0., y: 0.), (x: 2., y: 1.05), (x: 9., y: 0.3), (x: 10., y: 0.),
+            (x: 10., y: 10.), (x: 0., y: 10.),
+        ]]);
+        let (simplified, report) = simplify_containing(&original, 1.);
+        assert_eq!(report.vertices_removed(), 1);
+        assert!(simplified.0[0].exterior().0.contains(&coord! { x: 9., y: 0.3 }));
+        assert_contains(&simplified, &original);
+    }
+
+    #[test]
+    fn simplify_containing__other_vertex_in_triangle() {
+        let original = MultiPolygon(vec![
+            polygon![
+                (x: 0., y: 0.), (x: 5., y: 3.), (x: 10., y: 0.),
+                (x: 10., y: 10.), (x: 0., y: 10.),
+            ],
+            // Small obstacle in the notch
+            polygon![(x: 4.9, y: 0.5), (x: 5.1, y: 0.5), (x: 5., y: 0.7)],
+        ]);
+        let (simplified, report) = simplify_containing(&original, 10.);
+        assert_eq!(report.vertices_removed(), 0);
+        assert_eq!(simplified, original);
+    }
+
+    #[test]
+    fn simplify_containing__hole_sfa_winding() {
+        let original = MultiPolygon(vec![Polygon::new(
+            line_string![(x: 0., y: 0.), (x: 20., y: 0.), (x: 20., y: 20.), (x: 0., y: 20.), (x: 0., y: 0.)],
+            // Hole with a collinear vertex, wrongly wound CCW
+            vec![line_string![
+                (x: 5., y: 5.), (x: 10., y: 5.), (x: 15., y: 5.), (x: 15., y: 15.), (x: 5., y: 15.), (x: 5., y: 5.)
+            ]],
+        )]);
+        let (simplified, report) = simplify_containing(&original, 0.1);
+        assert_eq!(report.vertices_removed(), 1);
+        assert!(simplified.0[0].exterior().is_ccw());
+        assert!(simplified.0[0].interiors()[0].is_cw());
+        assert_contains(&simplified, &original);
+    }
+}
@@ -0,0 +1,275 @@
+//! Helpers shared between tests
+
+use std::{
+    fs::{self, File},
+    io::Write,
+    net::SocketAddr,
+    path::Path,
+};
+
+use geo::{coord, prelude::MapCoords, Coordinate, MultiPolygon, Polygon, Rect};
+use shapefile::dbase::{FieldValue, Record, TableWriterBuilder};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+use crate::nav_graph::{graph_types::Features, NavGraph};
+
+/// Origin of the local grid test features are laid out on, in the default
+/// internal CRS (ETRS89-LAEA), near Zaventem
+const LOCAL_ORIGIN: Coordinate<f64> = Coordinate { x: 3_950_000.0, y: 3_080_000.0 };
+
+/// Minimal HTTP/1.1 stand-in server, e.g. for an ArcGIS FeatureServer.
+/// `respond` is given the request target (path and query string) and returns
+/// the status code and JSON body to answer with.
+pub async fn serve_canned_http<F>(respond: F) -> SocketAddr
+where
+    F: Fn(&str) -> (u16, String) + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                if n == 0 { break; }
+                request.extend_from_slice(&buf[..n]);
+            }
+            let request = String::from_utf8_lossy(&request);
+            // Request line: `GET /path?query HTTP/1.1`
+            let target = request.split(' ').nth(1).unwrap_or("/");
+            let (status, body) = respond(target);
+            let response = format!(
+                "HTTP/1.1 {} Canned\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+    });
+    address
+}
+
+/// Address nothing is listening on, as if offline.
+pub async fn unreachable_address() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap()
+}
+
+/// Fresh path in the temporary directory, removing any leftover file or
+/// directory from a previous run.
+pub fn temp_path(name: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("lakehopper-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_dir_all(&path);
+    path
+}
+
+/// `x` and `y` in metres on the local grid
+pub fn local(x: f64, y: f64) -> Coordinate<f64> {
+    coord! { x: LOCAL_ORIGIN.x + x, y: LOCAL_ORIGIN.y + y }
+}
+
+/// `polygon`, given in metres on the local grid
+pub fn local_polygon(polygon: Polygon<f64>) -> Polygon<f64> {
+    polygon.map_coords(|&(x, y)| (LOCAL_ORIGIN.x + x, LOCAL_ORIGIN.y + y))
+}
+
+/// Square of `size` metres with its lower left corner at `x`, `y` on the local
+/// grid
+pub fn local_square(x: f64, y: f64, size: f64) -> Polygon<f64> {
+    Rect::new(local(x, y), local(x + size, y + size)).to_polygon()
+}
+
+/// Features with only the obstacles `obstacles`, given in metres on the local
+/// grid
+pub fn local_features(obstacles: Vec<Polygon<f64>>) -> Features {
+    Features {
+        obstacles: MultiPolygon::from_iter(obstacles.into_iter().map(local_polygon)),
+        waters: MultiPolygon(vec![]),
+        penalized_areas: Vec::new(),
+        arbitrary: Vec::new(),
+    }
+}
+
+/// An `n` by `n` grid of 20 m square obstacles, 100 m apart, from the origin
+/// of the local grid
+pub fn grid_features(n: usize) -> Features {
+    let obstacles = MultiPolygon::from_iter((0..n * n).map(|i| {
+        local_square((i % n) as f64 * 100.0, (i / n) as f64 * 100.0, 20.0)
+    }));
+    Features {
+        obstacles,
+        waters: MultiPolygon(vec![]),
+        penalized_areas: Vec::new(),
+        arbitrary: Vec::new(),
+    }
+}
+
+/// Assert that `a` and `b` have the same nodes and edges, in the same order.
+pub fn assert_same_nav_graph(a: &NavGraph, b: &NavGraph) {
+    let nodes = |nav_graph: &NavGraph| nav_graph.graph.raw_nodes().iter().map(|n| n.weight).collect::<Vec<_>>();
+    let edges = |nav_graph: &NavGraph| nav_graph.graph.raw_edges().iter()
+        .map(|e| (e.source(), e.target(), e.weight))
+        .collect::<Vec<_>>();
+    assert_eq!(nodes(a), nodes(b));
+    assert_eq!(edges(a), edges(b));
+    assert_eq!(a.node_data_index_map, b.node_data_index_map);
+}
+
+/// Write a zip archive at `path` with a polygon shapefile per entry of
+/// `layers`: the path in the archive without extension (e.g.
+/// `Shapefile/Gbg`), and its polygons with their `LBLTYPE` label.
+pub fn write_zipped_shapefiles(path: &Path, layers: &[(&str, Vec<(Polygon<f64>, &str)>)]) {
+    let mut zip_writer = zip::ZipWriter::new(File::create(path).unwrap());
+    for (i, (layer_path, polygons)) in layers.iter().enumerate() {
+        let shp_path = path.with_file_name(format!("{}-layer-{}.shp", path.file_stem().unwrap().to_str().unwrap(), i));
+        let table_builder = TableWriterBuilder::new().add_character_field("LBLTYPE".try_into().unwrap(), 50);
+        let mut writer = shapefile::Writer::from_path(&shp_path, table_builder).unwrap();
+        for (polygon, label) in polygons {
+            let mut record = Record::default();
+            record.insert("LBLTYPE".to_owned(), FieldValue::Character(Some((*label).to_owned())));
+            writer.write_shape_and_record(&shapefile::Polygon::from(polygon.clone()), &record).unwrap();
+        }
+        // Headers are completed when the writer is dropped
+        drop(writer);
+        for extension in ["shp", "shx", "dbf"] {
+            let layer_file_path = shp_path.with_extension(extension);
+            zip_writer
+                .start_file(format!("{}.{}", layer_path, extension), zip::write::FileOptions::default())
+                .unwrap();
+            zip_writer.write_all(&fs::read(&layer_file_path).unwrap()).unwrap();
+            fs::remove_file(layer_file_path).unwrap();
+        }
+    }
+    zip_writer.finish().unwrap();
+}
+
+/// Protocol Buffers encoding, just enough for `write_osm_pbf`
+struct Protobuf(Vec<u8>);
+
+impl Protobuf {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push((value & 0x7f) as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn uint(&mut self, field: u64, value: u64) {
+        self.varint(field << 3);
+        self.varint(value);
+    }
+
+    fn sint(&mut self, field: u64, value: i64) {
+        self.uint(field, ((value << 1) ^ (value >> 63)) as u64);
+    }
+
+    fn bytes(&mut self, field: u64, bytes: &[u8]) {
+        self.varint(field << 3 | 2);
+        self.varint(bytes.len() as u64);
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn packed(&mut self, field: u64, values: impl IntoIterator<Item = u64>) {
+        let mut packed = Protobuf(Vec::new());
+        values.into_iter().for_each(|value| packed.varint(value));
+        self.bytes(field, &packed.0);
+    }
+}
+
+/// Strings of an OSM PBF block, referenced by index
+struct OsmStringTable(Vec<String>);
+
+impl OsmStringTable {
+    fn id(&mut self, string: &str) -> u64 {
+        let position = self.0.iter().position(|s| s == string).unwrap_or_else(|| {
+            self.0.push(string.to_owned());
+            self.0.len() - 1
+        });
+        position as u64
+    }
+
+    fn tags(&mut self, message: &mut Protobuf, tags: &[(&str, &str)]) {
+        let ids = tags.iter().map(|(key, value)| (self.id(key), self.id(value))).collect::<Vec<_>>();
+        message.packed(2, ids.iter().map(|(key, _)| *key));
+        message.packed(3, ids.iter().map(|(_, value)| *value));
+    }
+}
+
+/// Ids delta coded and zigzag encoded, as in OSM PBF ways and relations
+fn osm_deltas(ids: impl IntoIterator<Item = i64>) -> Vec<u64> {
+    let mut previous = 0;
+    ids.into_iter()
+        .map(|id| {
+            let delta = id - previous;
+            previous = id;
+            ((delta << 1) ^ (delta >> 63)) as u64
+        })
+        .collect()
+}
+
+/// Write an `.osm.pbf` extract at `path` with a single uncompressed data
+/// block: `nodes` as id, longitude and latitude, `ways` as id, tags and node
+/// ids, and `relations` as id, tags and their way members with their role.
+pub fn write_osm_pbf(
+    path: &Path,
+    nodes: &[(i64, f64, f64)],
+    ways: &[(i64, &[(&str, &str)], &[i64])],
+    relations: &[(i64, &[(&str, &str)], &[(i64, &str)])],
+) {
+    let mut strings = OsmStringTable(vec![String::new()]);
+    let mut node_group = Protobuf(Vec::new());
+    for (id, lon, lat) in nodes {
+        let mut node = Protobuf(Vec::new());
+        node.sint(1, *id);
+        // In the default granularity of 100 nanodegrees
+        node.sint(8, (lat * 1e7).round() as i64);
+        node.sint(9, (lon * 1e7).round() as i64);
+        node_group.bytes(1, &node.0);
+    }
+    let mut way_group = Protobuf(Vec::new());
+    for (id, tags, node_ids) in ways {
+        let mut way = Protobuf(Vec::new());
+        way.uint(1, *id as u64);
+        strings.tags(&mut way, tags);
+        way.packed(8, osm_deltas(node_ids.iter().copied()));
+        way_group.bytes(3, &way.0);
+    }
+    let mut relation_group = Protobuf(Vec::new());
+    for (id, tags, members) in relations {
+        let mut relation = Protobuf(Vec::new());
+        relation.uint(1, *id as u64);
+        strings.tags(&mut relation, tags);
+        let roles = members.iter().map(|(_, role)| strings.id(role)).collect::<Vec<_>>();
+        relation.packed(8, roles);
+        relation.packed(9, osm_deltas(members.iter().map(|(way_id, _)| *way_id)));
+        // Every member is a way
+        relation.packed(10, members.iter().map(|_| 1));
+        relation_group.bytes(4, &relation.0);
+    }
+
+    let mut string_table = Protobuf(Vec::new());
+    strings.0.iter().for_each(|string| string_table.bytes(1, string.as_bytes()));
+    let mut block = Protobuf(Vec::new());
+    block.bytes(1, &string_table.0);
+    for group in [node_group, way_group, relation_group] {
+        block.bytes(2, &group.0);
+    }
+    let mut blob = Protobuf(Vec::new());
+    blob.bytes(1, &block.0);
+    blob.uint(2, block.0.len() as u64);
+    let mut blob_header = Protobuf(Vec::new());
+    blob_header.bytes(1, b"OSMData");
+    blob_header.uint(3, blob.0.len() as u64);
+
+    let mut file = File::create(path).unwrap();
+    file.write_all(&(blob_header.0.len() as u32).to_be_bytes()).unwrap();
+    file.write_all(&blob_header.0).unwrap();
+    file.write_all(&blob.0).unwrap();
+}
@@ -0,0 +1,330 @@
+//! Usable water area: water minus obstacles
+//!
+//! `create_nav_graph` silently drops water vertices that lie inside an
+//! obstacle, so waters overlapping buffered buildings used to be differenced
+//! by hand (e.g. `water-sv-phd-diff.gpkg`). This computes the difference per
+//! water body, shrinks it by a shoreline margin and reports the lakes that
+//! become unusable or get split by obstacles.
+
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+};
+
+use derive_more::Display;
+use geo::{prelude::Area, prelude::BoundingRect, MultiPolygon, Polygon, Rect};
+use geos::Geom;
+use rayon::prelude::*;
+use serde_json::Value;
+
+use crate::{
+    geo_io::{
+        envelope_intersects, feature_source_for_path, load_features, load_multi_polygon,
+        write_gpkg_features, SourceFeature,
+    },
+    geos_ext::geos_to_multi_polygon,
+};
+
+/// Area difference (m²) below which a water counts as unchanged
+const AREA_TOLERANCE: f64 = 1.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReconcileOptions {
+    /// Distance (m) to keep from the shore and from obstacles
+    pub shoreline_margin: f64,
+    /// Parts smaller than this (m²) are dropped as slivers
+    pub min_area: f64,
+}
+
+impl Default for ReconcileOptions {
+    fn default() -> Self {
+        ReconcileOptions { shoreline_margin: 0.0, min_area: 100.0 }
+    }
+}
+
+impl ReconcileOptions {
+    /// Options from the `reconcile-water` arguments: `--shoreline-margin <m>`
+    /// and `--min-area <m²>`.
+    pub fn from_options(options: &[&str]) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let mut reconcile_options = ReconcileOptions::default();
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            let value = options
+                .next()
+                .ok_or_else(|| format!("Missing value for {}", option))?;
+            let value = value
+                .parse::<f64>()
+                .ok()
+                .filter(|value| *value >= 0.0)
+                .ok_or_else(|| format!("Invalid value {:?} for {}", value, option))?;
+            match *option {
+                "--shoreline-margin" => reconcile_options.shoreline_margin = value,
+                "--min-area" => reconcile_options.min_area = value,
+                _ => return Err(format!("Unknown option {:?}", option).into()),
+            }
+        }
+        return Ok(reconcile_options);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum WaterStatus {
+    #[display(fmt = "unchanged")]
+    Unchanged,
+    #[display(fmt = "shrunk")]
+    Shrunk,
+    /// Falls apart in more polygons than it had
+    #[display(fmt = "split")]
+    Split,
+    /// Nothing (larger than `min_area`) left
+    #[display(fmt = "unusable")]
+    Unusable,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WaterReport {
+    /// `name` attribute, or the index of the feature
+    pub label: String,
+    pub area_before: f64,
+    pub area_after: f64,
+    pub parts_before: usize,
+    pub parts_after: usize,
+}
+
+impl WaterReport {
+    pub fn status(&self) -> WaterStatus {
+        if self.parts_after == 0 {
+            return WaterStatus::Unusable;
+        }
+        if self.parts_after > self.parts_before {
+            return WaterStatus::Split;
+        }
+        if self.area_after < self.area_before - AREA_TOLERANCE {
+            return WaterStatus::Shrunk;
+        }
+        return WaterStatus::Unchanged;
+    }
+}
+
+fn water_label(index: usize, water: &SourceFeature) -> String {
+    match water.attributes.get("name") {
+        Some(Value::String(name)) if !name.is_empty() => name.clone(),
+        _ => format!("#{}", index),
+    }
+}
+
+/// `water` minus `obstacles`, shrunk by the shoreline margin, without parts
+/// smaller than the minimum area.
+fn usable_water(
+    water: &MultiPolygon<f64>,
+    obstacles: &[(Rect<f64>, Polygon<f64>)],
+    options: &ReconcileOptions,
+) -> Result<MultiPolygon<f64>, geos::Error> {
+    let mut usable: geos::Geometry = water.clone().try_into()?;
+    // Only the obstacles near the water, differencing against all of them
+    // per water is needlessly slow
+    let nearby_obstacles = obstacles
+        .iter()
+        .filter(|(rect, _)| envelope_intersects(water, Some(*rect)))
+        .map(|(_, polygon)| polygon.clone().try_into())
+        .collect::<Result<Vec<geos::Geometry>, _>>()?;
+    if !nearby_obstacles.is_empty() {
+        let obstacles = geos::Geometry::create_geometry_collection(nearby_obstacles)?.unary_union()?;
+        usable = usable.difference(&obstacles)?;
+    }
+    if options.shoreline_margin > 0.0 {
+        usable = usable.buffer(-options.shoreline_margin, 8)?;
+    }
+    let mut usable = geos_to_multi_polygon(usable)?;
+    usable.0.retain(|polygon| polygon.unsigned_area() >= options.min_area);
+    return Ok(usable);
+}
+
+/// Usable part of each of `waters` (in a metric CRS) given `obstacles`, with a
+/// report per water. Unusable waters are left out of the result, the others
+/// keep their attributes and get their `original_area` and `usable_area`.
+pub fn reconcile_waters(
+    waters: &[SourceFeature],
+    obstacles: &MultiPolygon<f64>,
+    options: &ReconcileOptions,
+) -> Result<(Vec<SourceFeature>, Vec<WaterReport>), Box<dyn Error + Send + Sync>> {
+    let obstacles = obstacles
+        .iter()
+        .filter_map(|polygon| Some((polygon.bounding_rect()?, polygon.clone())))
+        .collect::<Vec<_>>();
+    let usable_waters = waters
+        .par_iter()
+        .map(|water| usable_water(&water.geometry, &obstacles, options))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut features = Vec::new();
+    let mut reports = Vec::new();
+    for (index, (water, usable)) in waters.iter().zip(usable_waters).enumerate() {
+        let report = WaterReport {
+            label: water_label(index, water),
+            area_before: water.geometry.unsigned_area(),
+            area_after: usable.unsigned_area(),
+            parts_before: water.geometry.0.len(),
+            parts_after: usable.0.len(),
+        };
+        if report.status() != WaterStatus::Unusable {
+            let mut attributes = water.attributes.clone();
+            attributes.insert("original_area".to_owned(), Value::from(report.area_before));
+            attributes.insert("usable_area".to_owned(), Value::from(report.area_after));
+            features.push(SourceFeature { geometry: usable, attributes });
+        }
+        reports.push(report);
+    }
+    return Ok((features, reports));
+}
+
+/// Layer to read from the file at `path`: GeoPackages written by the
+/// preprocessing commands hold the layer named after the file (next to e.g. a
+/// `-by-type` layer), other formats hold a single one.
+fn preprocessed_layer(path: &Path) -> Option<&str> {
+    let is_gpkg = path
+        .extension()
+        .map_or(false, |extension| extension.eq_ignore_ascii_case("gpkg"));
+    return if is_gpkg { path.file_stem().and_then(|stem| stem.to_str()) } else { None };
+}
+
+/// Preprocessing command: write the usable part of the waters at
+/// `waters_path` given the obstacles at `obstacles_path` to the GeoPackage at
+/// `output_path` (layer named after the file, like `preprocess_grb`), and
+/// print the waters that got split or became unusable.
+pub async fn preprocess_waters(
+    waters_path: PathBuf,
+    obstacles_path: PathBuf,
+    output_path: PathBuf,
+    options: ReconcileOptions,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let table_name = output_path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .ok_or_else(|| format!("Invalid output path {:?}", output_path))?
+        .to_owned();
+    let waters_source = feature_source_for_path(&waters_path, preprocessed_layer(&waters_path))?;
+    // Calculate in the internal CRS for the waters' area
+    let (waters, crs) = load_features(waters_source.as_ref(), None).await?;
+    let obstacles_source = feature_source_for_path(&obstacles_path, preprocessed_layer(&obstacles_path))?;
+    let (obstacles, _) = load_multi_polygon(obstacles_source.as_ref(), Some(&crs)).await?;
+    // Differencing is CPU bound
+    let (usable_waters, reports) =
+        tokio::task::spawn_blocking(move || reconcile_waters(&waters, &obstacles, &options)).await??;
+
+    for report in &reports {
+        let status = report.status();
+        if matches!(status, WaterStatus::Split | WaterStatus::Unusable) {
+            println!(
+                "{} is {}: {:.0} of {:.0} m² left in {} part(s)",
+                report.label, status, report.area_after, report.area_before, report.parts_after
+            );
+        }
+    }
+    let count = |status| reports.iter().filter(|report| report.status() == status).count();
+    println!(
+        "{} waters: {} unchanged, {} shrunk, {} split, {} unusable",
+        reports.len(),
+        count(WaterStatus::Unchanged),
+        count(WaterStatus::Shrunk),
+        count(WaterStatus::Split),
+        count(WaterStatus::Unusable),
+    );
+    println!("Writing {} waters to {:?}", usable_waters.len(), output_path);
+    write_gpkg_features(&output_path, &table_name, &usable_waters, &crs).await?;
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    #![allow(non_snake_case)]
+
+    use approx::assert_relative_eq;
+    use geo::{polygon, prelude::Area, MultiPolygon, Polygon};
+    use serde_json::Value;
+
+    use crate::geo_io::{Attributes, SourceFeature};
+
+    use super::{reconcile_waters, ReconcileOptions, WaterStatus};
+
+    fn square(x: f64, y: f64, size: f64) -> Polygon<f64> {
+        polygon![
+            (x: x, y: y),
+            (x: x + size, y: y),
+            (x: x + size, y: y + size),
+            (x: x, y: y + size),
+            (x: x, y: y),
+        ]
+    }
+
+    fn lake(name: &str) -> SourceFeature {
+        let mut attributes = Attributes::new();
+        attributes.insert("name".to_owned(), Value::from(name));
+        SourceFeature { geometry: MultiPolygon(vec![square(0., 0., 100.)]), attributes }
+    }
+
+    fn options(shoreline_margin: f64) -> ReconcileOptions {
+        ReconcileOptions { shoreline_margin, ..ReconcileOptions::default() }
+    }
+
+    #[test]
+    fn reconcile_waters__unchanged() {
+        let obstacles = MultiPolygon(vec![square(200., 0., 10.)]);
+        let (waters, reports) = reconcile_waters(&[lake("Lake")], &obstacles, &options(0.)).unwrap();
+        assert_eq!(reports[0].status(), WaterStatus::Unchanged);
+        assert_relative_eq!(waters[0].geometry.unsigned_area(), 100. * 100.);
+        assert_eq!(waters[0].attributes["name"], Value::from("Lake"));
+    }
+
+    #[test]
+    fn reconcile_waters__shrunk_by_obstacle() {
+        let obstacles = MultiPolygon(vec![square(-10., -10., 20.)]);
+        let (waters, reports) = reconcile_waters(&[lake("Lake")], &obstacles, &options(0.)).unwrap();
+        assert_eq!(reports[0].status(), WaterStatus::Shrunk);
+        assert_relative_eq!(waters[0].geometry.unsigned_area(), 100. * 100. - 10. * 10.);
+    }
+
+    #[test]
+    fn reconcile_waters__split() {
+        // Bridge across the middle of the lake
+        let obstacles = MultiPolygon(vec![polygon![
+            (x: -10., y: 45.), (x: 110., y: 45.), (x: 110., y: 55.), (x: -10., y: 55.), (x: -10., y: 45.),
+        ]]);
+        let (waters, reports) = reconcile_waters(&[lake("Lake")], &obstacles, &options(0.)).unwrap();
+        assert_eq!(reports[0].status(), WaterStatus::Split);
+        assert_eq!(waters[0].geometry.0.len(), 2);
+    }
+
+    #[test]
+    fn reconcile_waters__unusable() {
+        let obstacles = MultiPolygon(vec![square(-10., -10., 120.)]);
+        let (waters, reports) = reconcile_waters(&[lake("Lake")], &obstacles, &options(0.)).unwrap();
+        assert_eq!(reports[0].status(), WaterStatus::Unusable);
+        assert!(waters.is_empty());
+    }
+
+    #[test]
+    fn reconcile_waters__shoreline_margin() {
+        let (waters, reports) = reconcile_waters(&[lake("Lake")], &MultiPolygon(vec![]), &options(5.)).unwrap();
+        assert_eq!(reports[0].status(), WaterStatus::Shrunk);
+        assert_relative_eq!(waters[0].geometry.unsigned_area(), 90. * 90., epsilon = 1.);
+    }
+
+    #[test]
+    fn reconcile_waters__drops_slivers() {
+        // Leaves a 100 x 0.5 m strip along the shore
+        let obstacles = MultiPolygon(vec![square(-10., 0.5, 120.)]);
+        let (waters, reports) = reconcile_waters(&[lake("Lake")], &obstacles, &options(0.)).unwrap();
+        assert_eq!(reports[0].status(), WaterStatus::Unusable);
+        assert!(waters.is_empty());
+    }
+
+    #[test]
+    fn reconcile_options__from_options() {
+        let options = ReconcileOptions::from_options(&["--shoreline-margin", "15"]).unwrap();
+        assert_eq!(options, ReconcileOptions { shoreline_margin: 15., ..ReconcileOptions::default() });
+        assert!(ReconcileOptions::from_options(&["--shoreline-margin", "-1"]).is_err());
+        assert!(ReconcileOptions::from_options(&["--min-area"]).is_err());
+        assert!(ReconcileOptions::from_options(&["--margin", "1"]).is_err());
+    }
+}
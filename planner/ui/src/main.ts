@@ -1,4 +1,4 @@
-import { Map, TileLayer, DivIcon, GeoJSON as GeoJsonLayer, Marker, PointExpression, PathOptions, LatLng, Control, control as lControls, Layer, Icon } from "leaflet";
+import { Map, TileLayer, DivIcon, GeoJSON as GeoJsonLayer, Marker, PointExpression, PathOptions, LatLng, Control, control as lControls, Layer, Icon, LeafletMouseEvent } from "leaflet";
 import 'leaflet/dist/leaflet.css'
 import { GeoJsonObject, Feature, GeometryCollection, Geometry, FeatureCollection, MultiPolygon, MultiPoint, LineString, Position, Point } from 'geojson';
 import Swal from 'sweetalert2'
@@ -346,7 +346,18 @@ function createControlPanel(
   // }
   let maxDistanceInitially = 600;
   let maxDistanceAfterCharge = 1000;
+  let cruiseAltitude = 40;
+  let droneClass = 'A3';
+  let departureHoursFromNow = 0;
+  const cruiseSpeed = 15;
+  const rechargeDuration = 10 * 60;
   let visibilityOptimizationMode = 'Naive';
+  let tileSize: number | null = null;
+  // Corners clicked for the no-fly area being drawn, null when not drawing
+  let noFlyAreaCorners: LatLng[] | null = null;
+  map.on('click', (event: LeafletMouseEvent) => {
+    noFlyAreaCorners?.push(event.latlng);
+  });
 
   const controlPanel = document.createElement('div');
   Object.assign(controlPanel.style, {
@@ -360,7 +371,7 @@ function createControlPanel(
   } as unknown as CSSStyleDeclaration);
 
   controlPanel.replaceChildren(
-    createOptionSpinner('Visibility optimization mode', ['Naive', 'Sweep', 'OptimizedSweep'], value => {
+    createOptionSpinner('Visibility optimization mode', ['Naive', 'Sweep', 'OptimizedSweep', 'Bitangent'], value => {
       visibilityOptimizationMode = value;
     }),
     createButton('Load graph', () => {
@@ -378,6 +389,21 @@ function createControlPanel(
     createButton('Load restricted airspace', () => {
       transport.emit('load-restricted-airspace', null);
     }),
+    createButton('Load airspace file', () => {
+      const path = prompt('OpenAir, GeoJSON or GeoPackage airspace file name (in data/airspace on the planner host)');
+      if (path) {
+        transport.emit('load-airspace-file', { path });
+      }
+    }),
+    createButton('Export GeoPackage', () => {
+      const path = prompt('GeoPackage file name to write obstacles, graph and plan to (in data/exports on the planner host)');
+      if (path) {
+        transport.emit('export-gpkg', { path });
+      }
+    }),
+    createButton('Load geozones', () => {
+      transport.emit('load-geozones', null);
+    }),
     createButton('Calc direct path', () => {
       const startCoord = startPointMarker.getLatLng();
       const endCoord = endPointMarker.getLatLng();
@@ -385,14 +411,48 @@ function createControlPanel(
         start: startCoord,
         end: endCoord,
         visibilityOptimizationMode,
+        tileSize,
       });
     }),
+    createOptionSpinner('Direct path tile size (m)', ['Off', '500', '1000', '2000'], value => {
+      tileSize = value === 'Off' ? null : Number(value);
+    }),
     createSlider('Max distance initially', 10000, (value) => {
       maxDistanceInitially = value;
     }),
     createSlider('Max distance after charge', 10000, (value) => {
       maxDistanceAfterCharge = value;
     }),
+    createSlider('Cruise altitude (AMSL)', 300, (value) => {
+      cruiseAltitude = Math.round(value);
+    }),
+    createOptionSpinner('Drone class', ['A3', 'A2', 'A1'], value => {
+      droneClass = value;
+    }),
+    createSlider('Departure (hours from now)', 48, (value) => {
+      departureHoursFromNow = value;
+    }),
+    createButton('Draw no-fly area', () => {
+      noFlyAreaCorners = [];
+      Toast.fire({
+        title: 'Click the corners of the no-fly area, then finish it',
+        icon: 'info',
+      });
+    }),
+    createButton('Finish no-fly area', () => {
+      if (noFlyAreaCorners !== null && noFlyAreaCorners.length >= 3) {
+        transport.emit('add-no-fly-area', {
+          area: noFlyAreaCorners,
+          visibilityOptimizationMode,
+        });
+      }
+      noFlyAreaCorners = null;
+    }),
+    createButton('Remove no-fly area', () => {
+      transport.emit('remove-no-fly-area', {
+        visibilityOptimizationMode,
+      });
+    }),
     createButton('Plan path', () => {
       const startCoord = startPointMarker.getLatLng();
       const endCoord = endPointMarker.getLatLng();
@@ -401,7 +461,18 @@ function createControlPanel(
         end: endCoord,
         maxDistanceInitially,
         maxDistanceAfterCharge,
-        visibilityOptimizationMode
+        visibilityOptimizationMode,
+        cruiseAltitude,
+        operatorProfile: {
+          droneClass,
+          permits: [],
+          authorisedZones: [],
+        },
+        departure: {
+          time: Date.now() + Math.round(departureHoursFromNow * 60 * 60 * 1000),
+          cruiseSpeed,
+          rechargeDuration,
+        },
       });
     }),
     createButton('Clear debug', () => {
@@ -461,9 +532,9 @@ async function init() {
     createGeoJsonLayer(map, waters, "#495d69").addTo(map);
     // createGeoJsonLayer(map, pois, "#ff5d69").addTo(map);
   });
-  transport.listen('restricted-airspace', data => {
-    const restricted_airspace: Feature<MultiPolygon> = data;
-    createGeoJsonLayer(map, restricted_airspace, "#845a9e").addTo(map);
+  transport.listen('geozones', data => {
+    const geozones: Feature<MultiPolygon> = data;
+    createGeoJsonLayer(map, geozones, "#9e5a7b").addTo(map);
   });
   transport.listen('nav-graph', (data: {
     graph: FeatureCollection,